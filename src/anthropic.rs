@@ -0,0 +1,301 @@
+use crate::context::{ContentBlock, Message};
+use crate::llm::{
+    check_status, read_sse, ChatFuture, ChatRequest, ChunkSource, LLMError, LLMProvider, LLMResponse,
+    ProviderConfig, SseEvent, StreamObserver, ToolDefinition,
+};
+use reqwest::Client;
+use serde_json::{json, Value};
+
+pub const DEFAULT_BASE_URL: &str = "https://api.anthropic.com/v1";
+pub const DEFAULT_MODEL: &str = "claude-3-5-sonnet-20241022";
+
+const API_VERSION: &str = "2023-06-01";
+
+/// Anthropic Messages API backend
+pub struct AnthropicProvider {
+    client: Client,
+    base_url: String,
+    api_key: String,
+}
+
+impl AnthropicProvider {
+    pub fn new(config: ProviderConfig) -> Self {
+        AnthropicProvider {
+            client: Client::new(),
+            base_url: config.base_url,
+            api_key: config.api_key.unwrap_or_default(),
+        }
+    }
+
+    async fn send(&self, request: &ChatRequest<'_>, observer: &mut dyn StreamObserver) -> Result<LLMResponse, LLMError> {
+        let config = request.config;
+        let mut body = json!({
+            "model": config.model,
+            "max_tokens": config.max_tokens,
+            "messages": to_api_messages(request.messages),
+            "system": request.system,
+            "tools": to_api_tools(request.tools),
+            "stream": true
+        });
+        if let Some(temperature) = config.temperature {
+            body["temperature"] = json!(temperature);
+        }
+        if !config.stop_sequences.is_empty() {
+            body["stop_sequences"] = json!(config.stop_sequences);
+        }
+
+        let mut builder = self.client
+            .post(format!("{}/messages", self.base_url))
+            .header("Content-Type", "application/json")
+            .header("X-API-Key", &self.api_key)
+            .header("anthropic-version", API_VERSION)
+            .json(&body);
+        if let Some(timeout) = config.timeout {
+            builder = builder.timeout(timeout);
+        }
+        let response = builder.send().await?;
+        let mut response = check_status(response).await?;
+
+        // Render the answer as it arrives instead of waiting for the whole message
+        read_event_stream(&mut response, observer).await
+    }
+}
+
+impl LLMProvider for AnthropicProvider {
+    fn describe(&self, model: &str) -> String {
+        format!("anthropic (model: {})", model)
+    }
+
+    fn chat<'a>(&'a self, request: &'a ChatRequest<'a>, observer: &'a mut dyn StreamObserver) -> ChatFuture<'a> {
+        Box::pin(self.send(request, observer))
+    }
+}
+
+fn to_api_tools(tools: &[ToolDefinition]) -> Vec<Value> {
+    tools.iter()
+        .map(|tool| json!({
+            "name": tool.name,
+            "description": tool.description,
+            "input_schema": tool.input_schema
+        }))
+        .collect()
+}
+
+// Convert conversation history into API messages. System messages are sent through
+// the system prompt, and consecutive turns of the same role are merged so that the
+// conversation keeps strictly alternating roles.
+fn to_api_messages(messages: &[Message]) -> Vec<Value> {
+    let mut api_messages: Vec<(String, Vec<ContentBlock>)> = Vec::new();
+
+    for message in messages.iter().filter(|m| m.role != "system") {
+        match api_messages.last_mut() {
+            Some((role, content)) if *role == message.role => {
+                content.extend(message.content.iter().cloned());
+            }
+            _ => api_messages.push((message.role.clone(), message.content.clone())),
+        }
+    }
+
+    api_messages
+        .into_iter()
+        .map(|(role, content)| json!({ "role": role, "content": content }))
+        .collect()
+}
+
+// A content block being assembled from stream deltas
+enum PendingBlock {
+    Text(String),
+    ToolUse { id: String, name: String, input_json: String },
+}
+
+// Builds the final response from Anthropic message stream events
+#[derive(Default)]
+struct StreamAccumulator {
+    blocks: Vec<PendingBlock>,
+    input_tokens: usize,
+    output_tokens: usize,
+    stop_reason: Option<String>,
+}
+
+impl StreamAccumulator {
+    fn handle(&mut self, event: &SseEvent, observer: &mut dyn StreamObserver) -> Result<(), LLMError> {
+        if event.data.is_empty() {
+            return Ok(());
+        }
+        let data: Value = serde_json::from_str(&event.data)
+            .map_err(|e| LLMError::ParseError(format!("invalid stream event: {}", e)))?;
+
+        match data["type"].as_str().unwrap_or(event.event.as_str()) {
+            "message_start" => {
+                let usage = &data["message"]["usage"];
+                self.input_tokens = usage["input_tokens"].as_u64().unwrap_or(0) as usize;
+                self.output_tokens = usage["output_tokens"].as_u64().unwrap_or(0) as usize;
+            }
+            "content_block_start" => {
+                let block = &data["content_block"];
+                let pending = match block["type"].as_str() {
+                    Some("tool_use") => {
+                        let name = block["name"].as_str().unwrap_or_default().to_string();
+                        observer.tool_use_start(&name);
+                        PendingBlock::ToolUse {
+                            id: block["id"].as_str().unwrap_or_default().to_string(),
+                            name,
+                            input_json: String::new(),
+                        }
+                    }
+                    _ => {
+                        let text = block["text"].as_str().unwrap_or_default().to_string();
+                        if !text.is_empty() {
+                            observer.text_delta(&text);
+                        }
+                        PendingBlock::Text(text)
+                    }
+                };
+                self.blocks.push(pending);
+            }
+            "content_block_delta" => {
+                let delta = &data["delta"];
+                match (self.blocks.last_mut(), delta["type"].as_str()) {
+                    (Some(PendingBlock::Text(text)), Some("text_delta")) => {
+                        let fragment = delta["text"].as_str().unwrap_or_default();
+                        observer.text_delta(fragment);
+                        text.push_str(fragment);
+                    }
+                    (Some(PendingBlock::ToolUse { input_json, .. }), Some("input_json_delta")) => {
+                        input_json.push_str(delta["partial_json"].as_str().unwrap_or_default());
+                    }
+                    _ => {}
+                }
+            }
+            "message_delta" => {
+                if let Some(reason) = data["delta"]["stop_reason"].as_str() {
+                    self.stop_reason = Some(reason.to_string());
+                }
+                if let Some(tokens) = data["usage"]["output_tokens"].as_u64() {
+                    self.output_tokens = tokens as usize;
+                }
+            }
+            "error" => {
+                return Err(LLMError::RequestFailed(data["error"]["message"]
+                    .as_str()
+                    .unwrap_or(&event.data)
+                    .to_string()));
+            }
+            _ => {} // ping, content_block_stop, message_stop
+        }
+
+        Ok(())
+    }
+
+    fn finish(self) -> Result<LLMResponse, LLMError> {
+        let truncated = self.stop_reason.as_deref() == Some("max_tokens");
+        let mut content = Vec::new();
+        for block in self.blocks {
+            match block {
+                PendingBlock::Text(text) => {
+                    if !text.is_empty() {
+                        content.push(ContentBlock::Text { text });
+                    }
+                }
+                PendingBlock::ToolUse { id, name, input_json } => {
+                    // A tool call cut off by max_tokens has incomplete input; drop it
+                    if truncated && serde_json::from_str::<Value>(&input_json).is_err() {
+                        continue;
+                    }
+                    let input = if input_json.trim().is_empty() {
+                        json!({})
+                    } else {
+                        serde_json::from_str(&input_json).map_err(|e| {
+                            LLMError::ParseError(format!("invalid input for tool {}: {}", name, e))
+                        })?
+                    };
+                    content.push(ContentBlock::ToolUse { id, name, input });
+                }
+            }
+        }
+
+        Ok(LLMResponse {
+            content,
+            tokens_used: self.input_tokens + self.output_tokens,
+            stop_reason: self.stop_reason,
+        })
+    }
+}
+
+async fn read_event_stream<S: ChunkSource>(source: &mut S, observer: &mut dyn StreamObserver) -> Result<LLMResponse, LLMError> {
+    let mut accumulator = StreamAccumulator::default();
+    read_sse(source, |event| accumulator.handle(event, observer)).await?;
+    accumulator.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::tests::{RecordingObserver, ReplayStream};
+
+    #[test]
+    fn test_tool_results_follow_their_tool_use() {
+        let history = vec![
+            Message::user("Read both files".to_string()),
+            Message::system("context note".to_string()),
+            Message::assistant_with_blocks(
+                vec![
+                    ContentBlock::ToolUse { id: "toolu_1".to_string(), name: "read_file".to_string(), input: json!({"filename": "a"}) },
+                    ContentBlock::ToolUse { id: "toolu_2".to_string(), name: "read_file".to_string(), input: json!({"filename": "b"}) },
+                ],
+                10,
+            ),
+            Message::tool_results(vec![
+                ContentBlock::ToolResult { tool_use_id: "toolu_1".to_string(), content: "A".to_string(), is_error: false },
+                ContentBlock::ToolResult { tool_use_id: "toolu_2".to_string(), content: "missing".to_string(), is_error: true },
+            ]),
+            Message::user("Next paragraph".to_string()),
+        ];
+
+        let api_messages = to_api_messages(&history);
+
+        assert_eq!(api_messages.len(), 3);
+        assert_eq!(api_messages[1]["content"][1], json!({"type": "tool_use", "id": "toolu_2", "name": "read_file", "input": {"filename": "b"}}));
+        assert_eq!(api_messages[2]["role"], "user");
+        assert_eq!(api_messages[2]["content"][1], json!({"type": "tool_result", "tool_use_id": "toolu_2", "content": "missing", "is_error": true}));
+        assert_eq!(api_messages[2]["content"][2], json!({"type": "text", "text": "Next paragraph"}));
+    }
+
+    #[tokio::test]
+    async fn test_replay_recorded_stream() {
+        let recording = include_bytes!("../tests/fixtures/anthropic_stream.sse");
+
+        for chunk_size in [1, 7, recording.len()] {
+            let mut stream = ReplayStream { data: recording.to_vec(), chunk_size };
+            let mut observer = RecordingObserver::default();
+
+            let response = read_event_stream(&mut stream, &mut observer).await.unwrap();
+
+            assert_eq!(observer.events, vec![
+                "text:I'll read the ".to_string(),
+                "text:manifest.\nOne moment — café ☕".to_string(),
+                "tool:read_file".to_string(),
+            ]);
+            assert_eq!(response.content, vec![
+                ContentBlock::Text { text: "I'll read the manifest.\nOne moment — café ☕".to_string() },
+                ContentBlock::ToolUse {
+                    id: "toolu_01T1x1fJ34qAmk2tNTrN7Up6".to_string(),
+                    name: "read_file".to_string(),
+                    input: json!({"filename": "Cargo.toml"}),
+                },
+            ]);
+            assert_eq!(response.tokens_used, 412 + 58);
+            assert_eq!(response.stop_reason.as_deref(), Some("tool_use"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_error_event() {
+        let recording = b"event: error\ndata: {\"type\": \"error\", \"error\": {\"type\": \"overloaded_error\", \"message\": \"Overloaded\"}}\n\n";
+        let mut stream = ReplayStream { data: recording.to_vec(), chunk_size: 16 };
+
+        let result = read_event_stream(&mut stream, &mut RecordingObserver::default()).await;
+
+        assert!(matches!(result, Err(LLMError::RequestFailed(message)) if message == "Overloaded"));
+    }
+}
@@ -0,0 +1,479 @@
+use crate::expand::Expander;
+use std::io;
+
+// Variables whose values are expressions naming other variables are evaluated in
+// turn, up to this depth, so that `a=b b=a` cannot loop forever
+const MAX_DEPTH: usize = 64;
+
+// Operators, longest first
+const OPERATORS: &[&str] = &[
+    "<<=", ">>=", "**", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "*=", "/=", "%=", "+=", "-=",
+    "&=", "^=", "|=", "+", "-", "*", "/", "%", "<", ">", "&", "^", "|", "!", "~", "?", ":", "=", "(", ")", ",",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Operator(&'static str),
+    End,
+}
+
+// A value, with the variable it came from when it can be assigned to
+struct Operand {
+    value: i64,
+    name: Option<String>,
+}
+
+impl Operand {
+    fn value(value: i64) -> Self {
+        Operand { value, name: None }
+    }
+}
+
+/// Evaluate an arithmetic expression, as in `$(( ))`, `(( ))` and `let`, with
+/// C's operators and precedence on 64-bit integers. Variables are named without
+/// `$`; an unset or empty one is 0 and one holding an expression is evaluated.
+/// `=`, `+=`, `++` and the like assign through the expander. The expression has
+/// already been through parameter expansion and command substitution.
+pub fn evaluate(expression: &str, expander: &mut dyn Expander) -> io::Result<i64> {
+    Evaluator::new(expression, expander, 0).evaluate()
+}
+
+struct Evaluator<'a> {
+    chars: Vec<char>,
+    position: usize,
+    expander: &'a mut dyn Expander,
+    // Above zero in a part that is not evaluated, such as the right of `0 && x`:
+    // it is parsed, but assigns nothing and cannot divide by zero
+    skipping: usize,
+    depth: usize,
+}
+
+impl<'a> Evaluator<'a> {
+    fn new(expression: &str, expander: &'a mut dyn Expander, depth: usize) -> Self {
+        Evaluator { chars: expression.chars().collect(), position: 0, expander, skipping: 0, depth }
+    }
+
+    fn evaluate(&mut self) -> io::Result<i64> {
+        if self.peek()?.0 == Token::End {
+            return Ok(0);
+        }
+        let value = self.comma()?.value;
+        match self.peek()? {
+            (Token::End, _) => Ok(value),
+            _ => Err(self.error("syntax error in expression")),
+        }
+    }
+
+    // The error, naming the expression and the text where evaluation stopped
+    fn error(&self, message: &str) -> io::Error {
+        self.error_at(self.position, message)
+    }
+
+    fn error_at(&self, position: usize, message: &str) -> io::Error {
+        let expression: String = self.chars.iter().collect();
+        let rest: String = self.chars[position.min(self.chars.len())..].iter().collect();
+        match rest.trim() {
+            "" => io::Error::other(format!("{}: {}", expression.trim(), message)),
+            rest => io::Error::other(format!("{}: {} (error token is \"{}\")", expression.trim(), message, rest)),
+        }
+    }
+
+    fn skip_blanks(&mut self) {
+        while self.chars.get(self.position).is_some_and(|c| c.is_whitespace()) {
+            self.position += 1;
+        }
+    }
+
+    // The next token and its length in characters, without consuming it
+    fn peek(&mut self) -> io::Result<(Token, usize)> {
+        self.skip_blanks();
+        let rest = &self.chars[self.position..];
+        let Some(&first) = rest.first() else {
+            return Ok((Token::End, 0));
+        };
+
+        if first.is_ascii_digit() {
+            let length = rest.iter().take_while(|c| c.is_alphanumeric() || matches!(c, '#' | '@' | '_')).count();
+            let text: String = rest[..length].iter().collect();
+            let number = parse_number(&text).ok_or_else(|| self.error("value too great for base"))?;
+            return Ok((Token::Number(number), length));
+        }
+        if first.is_alphabetic() || first == '_' {
+            let length = rest.iter().take_while(|c| c.is_alphanumeric() || **c == '_').count();
+            return Ok((Token::Name(rest[..length].iter().collect()), length));
+        }
+        let text: String = rest.iter().take(3).collect();
+        match OPERATORS.iter().find(|op| text.starts_with(**op)) {
+            Some(op) => Ok((Token::Operator(op), op.len())),
+            None => Err(self.error("syntax error: invalid arithmetic operator")),
+        }
+    }
+
+    fn peek_operator(&mut self) -> io::Result<Option<&'static str>> {
+        Ok(match self.peek()?.0 {
+            Token::Operator(op) => Some(op),
+            _ => None,
+        })
+    }
+
+    fn advance(&mut self, length: usize) {
+        self.position += length;
+    }
+
+    // expression (',' expression)*: the value of the last one
+    fn comma(&mut self) -> io::Result<Operand> {
+        let mut operand = self.assignment()?;
+        while self.peek_operator()? == Some(",") {
+            self.advance(1);
+            operand = self.assignment()?;
+        }
+        Ok(operand)
+    }
+
+    // name (= | op=) assignment, grouped from the right, or a conditional
+    fn assignment(&mut self) -> io::Result<Operand> {
+        let target = self.conditional()?;
+        let Some(op) = self.peek_operator()?.filter(|op| is_assignment(op)) else {
+            return Ok(target);
+        };
+        let Some(name) = target.name else {
+            return Err(self.error("attempted assignment to non-variable"));
+        };
+        self.advance(op.len());
+        let right_start = self.position;
+        let value = self.assignment()?.value;
+        let value = match op {
+            "=" => value,
+            _ => self.apply(&op[..op.len() - 1], target.value, value, right_start)?,
+        };
+        self.assign(&name, value)?;
+        Ok(Operand::value(value))
+    }
+
+    // condition ? expression : conditional; only the branch taken is evaluated
+    fn conditional(&mut self) -> io::Result<Operand> {
+        let condition = self.binary(1)?;
+        if self.peek_operator()? != Some("?") {
+            return Ok(condition);
+        }
+        self.advance(1);
+        let taken = condition.value != 0;
+
+        self.skipping += usize::from(!taken);
+        let then_value = self.comma()?.value;
+        self.skipping -= usize::from(!taken);
+        if self.peek_operator()? != Some(":") {
+            return Err(self.error("`:' expected for conditional expression"));
+        }
+        self.advance(1);
+        self.skipping += usize::from(taken);
+        let else_value = self.conditional()?.value;
+        self.skipping -= usize::from(taken);
+
+        Ok(Operand::value(if taken { then_value } else { else_value }))
+    }
+
+    // Binary operators of at least `min_precedence`, by precedence climbing. `**`
+    // groups from the right; `&&` and `||` skip their right side when the left
+    // decides the result.
+    fn binary(&mut self, min_precedence: u8) -> io::Result<Operand> {
+        let mut left = self.unary()?;
+        loop {
+            let Some(op) = self.peek_operator()? else {
+                return Ok(left);
+            };
+            // `a--b` and `a++b` are a minus or plus followed by a sign
+            let op = match op {
+                "++" => "+",
+                "--" => "-",
+                op => op,
+            };
+            let Some(precedence) = precedence(op).filter(|p| *p >= min_precedence) else {
+                return Ok(left);
+            };
+            self.advance(op.len());
+            self.skip_blanks();
+            let right_start = self.position;
+            let next = if op == "**" { precedence } else { precedence + 1 };
+
+            let value = match op {
+                "&&" | "||" => {
+                    let decided = (op == "&&") == (left.value == 0);
+                    self.skipping += usize::from(decided);
+                    let right = self.binary(next)?.value;
+                    self.skipping -= usize::from(decided);
+                    match op {
+                        "&&" => i64::from(left.value != 0 && right != 0),
+                        _ => i64::from(left.value != 0 || right != 0),
+                    }
+                }
+                _ => {
+                    let right = self.binary(next)?.value;
+                    self.apply(op, left.value, right, right_start)?
+                }
+            };
+            left = Operand::value(value);
+        }
+    }
+
+    // ++name, --name, -, +, ! and ~
+    fn unary(&mut self) -> io::Result<Operand> {
+        let (token, length) = self.peek()?;
+        let Token::Operator(op @ ("++" | "--" | "-" | "+" | "!" | "~")) = token else {
+            return self.primary();
+        };
+        if let ("++" | "--", Some(name)) = (op, self.name_after(length)?) {
+            self.advance(length);
+            let (_, name_length) = self.peek()?;
+            self.advance(name_length);
+            let value = self.variable(&name)?.wrapping_add(if op == "++" { 1 } else { -1 });
+            self.assign(&name, value)?;
+            return Ok(Operand::value(value));
+        }
+
+        // A `++` or `--` not before a name is two signs
+        self.advance(1);
+        let value = self.unary()?.value;
+        Ok(Operand::value(match op {
+            "-" | "--" => value.wrapping_neg(),
+            "!" => i64::from(value == 0),
+            "~" => !value,
+            _ => value,
+        }))
+    }
+
+    // The variable name right after an operator `length` characters long, if any
+    fn name_after(&mut self, length: usize) -> io::Result<Option<String>> {
+        let position = self.position;
+        self.advance(length);
+        let token = self.peek();
+        self.position = position;
+        Ok(match token? {
+            (Token::Name(name), _) => Some(name),
+            _ => None,
+        })
+    }
+
+    // A number, a variable with an optional `++` or `--` after it, or ( expression )
+    fn primary(&mut self) -> io::Result<Operand> {
+        let (token, length) = self.peek()?;
+        match token {
+            Token::Number(value) => {
+                self.advance(length);
+                Ok(Operand::value(value))
+            }
+            Token::Name(name) => {
+                self.advance(length);
+                let value = self.variable(&name)?;
+                if let Some(op @ ("++" | "--")) = self.peek_operator()? {
+                    self.advance(2);
+                    self.assign(&name, value.wrapping_add(if op == "++" { 1 } else { -1 }))?;
+                    return Ok(Operand::value(value));
+                }
+                Ok(Operand { value, name: Some(name) })
+            }
+            Token::Operator("(") => {
+                self.advance(1);
+                let value = self.comma()?.value;
+                if self.peek_operator()? != Some(")") {
+                    return Err(self.error("missing `)'"));
+                }
+                self.advance(1);
+                Ok(Operand::value(value))
+            }
+            _ => Err(self.error("syntax error: operand expected")),
+        }
+    }
+
+    // The value of a variable: 0 when unset or empty, and otherwise its text
+    // evaluated as an expression of its own
+    fn variable(&mut self, name: &str) -> io::Result<i64> {
+        let value = self.expander.variable(name).unwrap_or_default();
+        if self.skipping > 0 || value.trim().is_empty() {
+            return Ok(0);
+        }
+        if let Ok(number) = value.trim().parse::<i64>() {
+            return Ok(number);
+        }
+        if self.depth >= MAX_DEPTH {
+            return Err(io::Error::other(format!("{}: expression recursion level exceeded", value.trim())));
+        }
+        Evaluator::new(&value, &mut *self.expander, self.depth + 1).evaluate()
+    }
+
+    fn assign(&mut self, name: &str, value: i64) -> io::Result<()> {
+        if self.skipping > 0 {
+            return Ok(());
+        }
+        self.expander.assign_variable(name, &value.to_string())
+    }
+
+    // A binary operator applied to its operands; the right one starts at
+    // `right_start`, which errors point to
+    fn apply(&self, op: &str, left: i64, right: i64, right_start: usize) -> io::Result<i64> {
+        Ok(match op {
+            "*" => left.wrapping_mul(right),
+            "/" | "%" if right == 0 => {
+                if self.skipping > 0 {
+                    return Ok(0);
+                }
+                return Err(self.error_at(right_start, "division by 0"));
+            }
+            "/" => left.wrapping_div(right),
+            "%" => left.wrapping_rem(right),
+            "+" => left.wrapping_add(right),
+            "-" => left.wrapping_sub(right),
+            "**" if right < 0 => {
+                if self.skipping > 0 {
+                    return Ok(0);
+                }
+                return Err(self.error_at(right_start, "exponent less than 0"));
+            }
+            "**" => left.wrapping_pow(right.min(u32::MAX as i64) as u32),
+            "<<" => left.wrapping_shl(right as u32),
+            ">>" => left.wrapping_shr(right as u32),
+            "<" => i64::from(left < right),
+            "<=" => i64::from(left <= right),
+            ">" => i64::from(left > right),
+            ">=" => i64::from(left >= right),
+            "==" => i64::from(left == right),
+            "!=" => i64::from(left != right),
+            "&" => left & right,
+            "^" => left ^ right,
+            "|" => left | right,
+            _ => unreachable!("not a binary operator: {}", op),
+        })
+    }
+}
+
+fn is_assignment(op: &str) -> bool {
+    op.ends_with('=') && !matches!(op, "==" | "!=" | "<=" | ">=")
+}
+
+// Binary operators from `||`, which binds loosest, to `**`
+fn precedence(op: &str) -> Option<u8> {
+    Some(match op {
+        "||" => 1,
+        "&&" => 2,
+        "|" => 3,
+        "^" => 4,
+        "&" => 5,
+        "==" | "!=" => 6,
+        "<" | "<=" | ">" | ">=" => 7,
+        "<<" | ">>" => 8,
+        "+" | "-" => 9,
+        "*" | "/" | "%" => 10,
+        "**" => 11,
+        _ => return None,
+    })
+}
+
+// A decimal number, 0x1F in hex, 017 in octal, or base#digits for bases 2 to 64,
+// whose digits are 0-9, a-z, A-Z, @ and _ (letters of either case up to base 36)
+fn parse_number(text: &str) -> Option<i64> {
+    let (base, digits) = match text.split_once('#') {
+        Some((base, digits)) => (base.parse::<u32>().ok().filter(|base| (2..=64).contains(base))?, digits),
+        None if text.starts_with("0x") || text.starts_with("0X") => (16, &text[2..]),
+        None if text.len() > 1 && text.starts_with('0') => (8, &text[1..]),
+        None => (10, text),
+    };
+    if digits.is_empty() {
+        return None;
+    }
+    digits.chars().try_fold(0i64, |value, c| {
+        let digit = match c {
+            '0'..='9' => c as u32 - '0' as u32,
+            'a'..='z' => c as u32 - 'a' as u32 + 10,
+            'A'..='Z' if base <= 36 => c as u32 - 'A' as u32 + 10,
+            'A'..='Z' => c as u32 - 'A' as u32 + 36,
+            '@' => 62,
+            '_' => 63,
+            _ => return None,
+        };
+        (digit < base).then(|| value.wrapping_mul(base as i64).wrapping_add(digit as i64))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    struct Variables(HashMap<String, String>);
+
+    impl Expander for Variables {
+        fn variable(&self, name: &str) -> Option<String> {
+            self.0.get(name).cloned()
+        }
+
+        fn command_output(&mut self, _command: &str) -> String {
+            String::new()
+        }
+
+        fn assign_variable(&mut self, name: &str, value: &str) -> io::Result<()> {
+            self.0.insert(name.to_string(), value.to_string());
+            Ok(())
+        }
+    }
+
+    fn variables(pairs: &[(&str, &str)]) -> Variables {
+        Variables(pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect())
+    }
+
+    #[test]
+    fn test_operators_and_precedence() {
+        let mut vars = variables(&[]);
+        let mut eval = |expression: &str| evaluate(expression, &mut vars).unwrap();
+
+        assert_eq!(eval("1 + 2 * 3"), 7);
+        assert_eq!(eval("(1 + 2) * 3"), 9);
+        assert_eq!(eval("2 ** 3 ** 2"), 512);
+        assert_eq!(eval("-2 ** 2"), 4);
+        assert_eq!(eval("-7 / 2"), -3);
+        assert_eq!(eval("-7 % 2"), -1);
+        assert_eq!(eval("1 << 4 | 1"), 17);
+        assert_eq!(eval("5 > 3 && 2 >= 2 || 0"), 1);
+        assert_eq!(eval("!0 + ~0"), 0);
+        assert_eq!(eval("3 == 3 ? 10 : 20"), 10);
+        assert_eq!(eval("0 ? 1 : 0 ? 2 : 3"), 3);
+        assert_eq!(eval("010 + 0x10 + 2#101 + 64#_"), 8 + 16 + 5 + 63);
+        assert_eq!(eval("5--3"), 8);
+        assert_eq!(eval(""), 0);
+    }
+
+    #[test]
+    fn test_variables_and_assignment() {
+        let mut vars = variables(&[("x", "5"), ("word", "abc"), ("expr", "x * 2"), ("empty", "")]);
+        let mut eval = |expression: &str| evaluate(expression, &mut vars).unwrap();
+
+        assert_eq!(eval("x + expr + word + empty + missing"), 15);
+        assert_eq!(eval("y = x += 2"), 7);
+        assert_eq!(eval("x++ + ++x"), 7 + 9);
+        assert_eq!(eval("x--, x"), 8);
+        assert_eq!(eval("n = 1, n <<= 3, n"), 8);
+        // The side not evaluated assigns nothing and cannot divide by zero
+        assert_eq!(eval("0 && (z = 1 / 0)"), 0);
+        assert_eq!(eval("1 ? 2 : (z = 3)"), 2);
+        assert_eq!(vars.0["y"], "7");
+        assert_eq!(vars.0["x"], "8");
+        assert!(!vars.0.contains_key("z"));
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut vars = variables(&[("a", "b"), ("b", "a")]);
+        let mut error = |expression: &str| evaluate(expression, &mut vars).unwrap_err().to_string();
+
+        assert_eq!(error("1/0"), "1/0: division by 0 (error token is \"0\")");
+        assert_eq!(error("5 % (2 - 2)"), "5 % (2 - 2): division by 0 (error token is \"(2 - 2)\")");
+        assert_eq!(error("1 +"), "1 +: syntax error: operand expected");
+        assert_eq!(error("3 = 4"), "3 = 4: attempted assignment to non-variable (error token is \"= 4\")");
+        assert_eq!(error("(1 + 2"), "(1 + 2: missing `)'");
+        assert_eq!(error("1 2"), "1 2: syntax error in expression (error token is \"2\")");
+        assert_eq!(error("08"), "08: value too great for base (error token is \"08\")");
+        assert_eq!(error("2 ** -1"), "2 ** -1: exponent less than 0 (error token is \"-1\")");
+        assert!(error("a").ends_with("expression recursion level exceeded"));
+    }
+}
@@ -1,9 +1,19 @@
-use std::env;
 use std::io::{self, Write};
-use crate::shell::Shell;
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
+use crate::arith::evaluate;
+use crate::glob::GlobOptions;
+use crate::jobs::{parse_signal, signal_names};
+use crate::parser::{is_name, is_reserved_word};
+use crate::router::{Route, RouteMode};
+use crate::shell::{LoopControl, Shell};
+use crate::variables::{quote_value, Variable};
 
 pub struct Builtins;
 
+// An option letter of export, readonly or declare, given with `-` (true) or `+`
+type Flag = (bool, char);
+
 impl Builtins {
     pub fn new() -> Self {
         Builtins
@@ -17,11 +27,31 @@ impl Builtins {
             "pwd" => Some(Box::new(move |shell| Self::pwd(&args, shell))),
             "echo" => Some(Box::new(move |shell| Self::echo(&args, shell))),
             "export" => Some(Box::new(move |shell| Self::export(&args, shell))),
+            "readonly" => Some(Box::new(move |shell| Self::readonly(&args, shell))),
+            "declare" => Some(Box::new(move |shell| Self::declare(&args, shell))),
             "unset" => Some(Box::new(move |shell| Self::unset(&args, shell))),
             "env" => Some(Box::new(move |shell| Self::env(&args, shell))),
             "type" => Some(Box::new(move |shell| Self::type_command(&args, shell))),
             "help" => Some(Box::new(move |shell| Self::help(&args, shell))),
             "history" => Some(Box::new(move |shell| Self::history(&args, shell))),
+            "llm" => Some(Box::new(move |shell| Self::llm(&args, shell))),
+            "route" => Some(Box::new(move |shell| Self::route(&args, shell))),
+            "shopt" => Some(Box::new(move |shell| Self::shopt(&args, shell))),
+            "set" => Some(Box::new(move |shell| Self::set(&args, shell))),
+            "break" => Some(Box::new(move |shell| Self::loop_control("break", &args, shell))),
+            "continue" => Some(Box::new(move |shell| Self::loop_control("continue", &args, shell))),
+            "return" => Some(Box::new(move |shell| Self::return_command(&args, shell))),
+            "local" => Some(Box::new(move |shell| Self::local(&args, shell))),
+            "let" => Some(Box::new(move |shell| Self::let_command(&args, shell))),
+            "shift" => Some(Box::new(move |shell| Self::shift(&args, shell))),
+            "alias" => Some(Box::new(move |shell| Self::alias(&args, shell))),
+            "unalias" => Some(Box::new(move |shell| Self::unalias(&args, shell))),
+            "jobs" => Some(Box::new(move |shell| Self::jobs(&args, shell))),
+            "fg" => Some(Box::new(move |shell| Self::continue_job("fg", &args, shell))),
+            "bg" => Some(Box::new(move |shell| Self::continue_job("bg", &args, shell))),
+            "wait" => Some(Box::new(move |shell| Self::wait(&args, shell))),
+            "kill" => Some(Box::new(move |shell| Self::kill(&args, shell))),
+            "disown" => Some(Box::new(move |shell| Self::disown(&args, shell))),
             _ => None, // Not a builtin command
         }
     }
@@ -138,84 +168,228 @@ impl Builtins {
         result
     }
 
+    // export [-n] [-p] [name[=value]...]: pass variables to the commands the
+    // shell starts, or with -n stop doing so. Without names it lists them.
     fn export(args: &[String], shell: &mut Shell) -> io::Result<()> {
-        if args.is_empty() {
-            // Display all environment variables
-            return Self::env(args, shell);
+        let (flags, names) = Self::flags("export", args, "np")?;
+        let unexport = flags.contains(&(true, 'n'));
+        if names.is_empty() {
+            if !unexport {
+                Self::print_declarations(shell, |variable| variable.exported);
+            }
+            return Ok(());
         }
 
-        for arg in args {
-            if let Some(pos) = arg.find('=') {
-                let (key, value) = arg.split_at(pos);
-                let value = &value[1..]; // Skip the '=' character
-                shell.set_env_var(key.to_string(), value.to_string());
-                env::set_var(key, value);
-            } else {
-                // Export existing variable
-                if let Some(value) = shell.get_env_var(arg) {
-                    env::set_var(arg, value);
-                } else {
-                    eprintln!("export: {}: not found", arg);
-                }
+        for arg in names {
+            let (name, value) = Self::name_and_value("export", arg)?;
+            let variables = shell.variables_mut();
+            let result = match value {
+                Some(value) if unexport => variables.set(name, value),
+                _ if unexport => Ok(()),
+                value => variables.export(name, value),
+            };
+            result.map_err(|e| io::Error::other(format!("export: {}", e)))?;
+            if unexport {
+                variables.unexport(name);
             }
         }
+        Ok(())
+    }
+
+    // readonly [-p] [name[=value]...]: variables that can no longer be set or
+    // unset. Without names it lists them.
+    fn readonly(args: &[String], shell: &mut Shell) -> io::Result<()> {
+        let (_, names) = Self::flags("readonly", args, "p")?;
+        if names.is_empty() {
+            Self::print_declarations(shell, |variable| variable.readonly);
+            return Ok(());
+        }
 
+        for arg in names {
+            let (name, value) = Self::name_and_value("readonly", arg)?;
+            shell
+                .variables_mut()
+                .set_readonly(name, value)
+                .map_err(|e| io::Error::other(format!("readonly: {}", e)))?;
+        }
         Ok(())
     }
 
+    // declare [-gprx] [+x] [name[=value]...]: set variables and their attributes,
+    // -x exported and -r readonly, or with -p show them. Inside a function the
+    // variables are local to it unless -g is given.
+    fn declare(args: &[String], shell: &mut Shell) -> io::Result<()> {
+        let (flags, names) = Self::flags("declare", args, "gprx")?;
+        let has = |on: bool, letter: char| flags.contains(&(on, letter));
+        if names.is_empty() {
+            Self::print_declarations(shell, |variable| {
+                (!has(true, 'x') || variable.exported) && (!has(true, 'r') || variable.readonly)
+            });
+            return Ok(());
+        }
+
+        let mut missing = None;
+        for arg in names {
+            let (name, value) = Self::name_and_value("declare", arg)?;
+            if has(true, 'p') {
+                match shell.variables().variable(name) {
+                    Some(variable) => Self::print_declaration(name, variable),
+                    None => missing = Some(name),
+                }
+                continue;
+            }
+
+            let in_builtin = |e: io::Error| io::Error::other(format!("declare: {}", e));
+            if shell.in_function() && !has(true, 'g') {
+                shell.declare_local(name, value).map_err(in_builtin)?;
+            } else if let Some(value) = value {
+                shell.variables_mut().set(name, value).map_err(in_builtin)?;
+            }
+            let variables = shell.variables_mut();
+            if has(true, 'x') {
+                variables.export(name, None).map_err(in_builtin)?;
+            } else if has(false, 'x') {
+                variables.unexport(name);
+            }
+            if has(true, 'r') {
+                variables.set_readonly(name, None).map_err(in_builtin)?;
+            } else if has(false, 'r') && variables.is_readonly(name) {
+                return Err(in_builtin(io::Error::other(format!("{}: readonly variable", name))));
+            }
+        }
+
+        match missing {
+            Some(name) => Err(io::Error::new(io::ErrorKind::NotFound, format!("declare: {}: not found", name))),
+            None => Ok(()),
+        }
+    }
+
+    // Leading options such as `-rx` or `+x`, as (`-` or not, letter) pairs, and the
+    // arguments after them; `--` ends the options
+    fn flags<'a>(command: &str, args: &'a [String], allowed: &str) -> io::Result<(Vec<Flag>, &'a [String])> {
+        let mut flags = Vec::new();
+        let mut rest = args;
+        while let Some((first, tail)) = rest.split_first() {
+            if first == "--" {
+                rest = tail;
+                break;
+            }
+            let Some(letters) = first.strip_prefix(['-', '+']).filter(|letters| !letters.is_empty()) else {
+                break;
+            };
+            for letter in letters.chars() {
+                if !allowed.contains(letter) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("{}: {}{}: invalid option", command, &first[..1], letter),
+                    ));
+                }
+                flags.push((first.starts_with('-'), letter));
+            }
+            rest = tail;
+        }
+        Ok((flags, rest))
+    }
+
+    // `name[=value]`, as given to export, readonly, declare and local
+    fn name_and_value<'a>(command: &str, arg: &'a str) -> io::Result<(&'a str, Option<String>)> {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_string())),
+            None => (arg, None),
+        };
+        if !is_name(name) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{}: `{}': not a valid identifier", command, arg),
+            ));
+        }
+        Ok((name, value))
+    }
+
+    // The variables `keep` selects, sorted, in the form `declare` reads back
+    fn print_declarations(shell: &Shell, keep: impl Fn(&Variable) -> bool) {
+        for (name, variable) in shell.variables().all() {
+            if keep(variable) {
+                Self::print_declaration(name, variable);
+            }
+        }
+    }
+
+    fn print_declaration(name: &str, variable: &Variable) {
+        match &variable.value {
+            Some(value) => println!("declare {} {}={}", variable.flags(), name, quote_value(value)),
+            None => println!("declare {} {}", variable.flags(), name),
+        }
+    }
+
     fn unset(args: &[String], shell: &mut Shell) -> io::Result<()> {
+        // unset -f name...: remove shell functions instead of variables
+        if args.first().map(String::as_str) == Some("-f") {
+            for name in &args[1..] {
+                shell.remove_function(name);
+            }
+            return Ok(());
+        }
+
         for arg in args {
-            shell.unset_env_var(arg);
-            env::remove_var(arg);
+            shell
+                .variables_mut()
+                .unset(arg)
+                .map_err(|e| io::Error::other(format!("unset: {}", e)))?;
         }
         Ok(())
     }
 
-    fn env(_args: &[String], _shell: &mut Shell) -> io::Result<()> {
-        // Get all environment variables and sort them
-        let mut vars: Vec<_> = env::vars().collect();
-        vars.sort_by(|a, b| a.0.cmp(&b.0));
-        
-        for (key, value) in vars {
-            println!("{}={}", key, value);
+    // The environment commands get: the exported variables
+    fn env(_args: &[String], shell: &mut Shell) -> io::Result<()> {
+        for (name, value) in shell.variables().environment() {
+            println!("{}={}", name, value);
         }
-        
         Ok(())
     }
 
-    fn type_command(args: &[String], _shell: &mut Shell) -> io::Result<()> {
+    fn type_command(args: &[String], shell: &mut Shell) -> io::Result<()> {
         if args.is_empty() {
             eprintln!("type: usage: type [-afptP] name [name ...]");
             return Ok(());
         }
 
+        let mut missing = None;
         for arg in args {
-            if Self::is_builtin(arg) {
+            if let Some(value) = shell.alias(arg) {
+                println!("{} is aliased to `{}'", arg, value);
+            } else if is_reserved_word(arg) {
+                println!("{} is a shell keyword", arg);
+            } else if shell.has_function(arg) {
+                println!("{} is a function", arg);
+            } else if Self::is_builtin(arg) {
                 println!("{} is a shell builtin", arg);
             } else {
                 // Check if it's in PATH
-                if let Some(path) = Self::find_in_path(arg) {
+                if let Some(path) = Self::find_in_path(arg, shell.variables().get("PATH").unwrap_or_default()) {
                     println!("{} is {}", arg, path);
                 } else {
-                    println!("{}: not found", arg);
+                    missing = Some(arg);
                 }
             }
         }
 
-        Ok(())
+        match missing {
+            Some(name) => Err(io::Error::new(io::ErrorKind::NotFound, format!("type: {}: not found", name))),
+            None => Ok(()),
+        }
     }
 
-    fn is_builtin(command: &str) -> bool {
-        matches!(command, "exit" | "cd" | "pwd" | "echo" | "export" | "unset" | "env" | "type" | "help" | "history")
+    pub fn is_builtin(command: &str) -> bool {
+        matches!(command, "exit" | "cd" | "pwd" | "echo" | "export" | "readonly" | "declare" | "unset" | "env" | "type" | "help" | "history" | "llm" | "route" | "shopt" | "set" | "break" | "continue" | "return" | "local" | "let" | "shift" | "alias" | "unalias" | "jobs" | "fg" | "bg" | "wait" | "kill" | "disown")
     }
 
-    fn find_in_path(command: &str) -> Option<String> {
-        if let Ok(path_var) = env::var("PATH") {
-            for path_dir in path_var.split(':') {
-                let full_path = format!("{}/{}", path_dir, command);
-                if std::path::Path::new(&full_path).exists() {
-                    return Some(full_path);
-                }
+    // The first file named `command` in the directories of `path`, a PATH value
+    pub fn find_in_path(command: &str, path: &str) -> Option<String> {
+        for path_dir in path.split(':').filter(|dir| !dir.is_empty()) {
+            let full_path = format!("{}/{}", path_dir, command);
+            if std::path::Path::new(&full_path).exists() {
+                return Some(full_path);
             }
         }
         None
@@ -228,25 +402,462 @@ impl Builtins {
         println!("  cd [dir]     - Change directory to dir (or home if no dir)");
         println!("  pwd          - Print current working directory");
         println!("  echo [args]  - Display arguments");
-        println!("  export VAR=value - Set environment variable");
-        println!("  unset VAR    - Unset environment variable");
-        println!("  env          - Display environment variables");
+        println!("  export [-n] [-p] VAR[=value] - Pass variables to commands (-n: stop); list them");
+        println!("  readonly [-p] VAR[=value] - Make variables readonly; list them");
+        println!("  declare [-gprx] [+x] VAR[=value] - Set variables and their attributes; show them");
+        println!("  unset VAR    - Unset a variable");
+        println!("  env          - Display the exported variables");
         println!("  type command - Display information about command type");
         println!("  help         - Display this help message");
         println!("  history      - Display command history");
+        println!("  llm [setting [value]] - Show or change LLM settings for this session");
+        println!("  route [auto|shell|ai|line] - Show or set how typed lines are routed");
+        println!("  shopt [-s|-u] [option...] - Show or set nullglob, failglob and dotglob");
+        println!("  set [-o|+o] [pipefail] - Show or set shell options");
+        println!("  set [--] [arg...] - Set the positional parameters $1, $2, ...");
+        println!("  shift [n]    - Drop the first n positional parameters (default 1)");
+        println!("  break [n]    - Leave the innermost (or n-th enclosing) loop");
+        println!("  continue [n] - Start the next iteration of the innermost (or n-th) loop");
+        println!("  return [n]   - Leave the running shell function with status n");
+        println!("  local VAR[=value] - Make a variable local to the running shell function");
+        println!("  let expression... - Evaluate arithmetic expressions; status 1 if the last is 0");
+        println!("  alias [name[=value]...] - Define or show aliases");
+        println!("  unalias [-a] name... - Remove aliases");
+        println!("  jobs [-l|-p] - List background and stopped jobs");
+        println!("  fg [%n]      - Continue a job in the foreground");
+        println!("  bg [%n]      - Continue a stopped job in the background");
+        println!("  wait [%n|pid...] - Wait for jobs to finish");
+        println!("  kill [-s sig|-sig] %n|pid... - Send a signal to jobs or processes (-l lists signals)");
+        println!("  disown [-a] [%n...] - Forget jobs, so they keep running after exit");
         println!();
         println!("Features:");
         println!("  - Command execution");
-        println!("  - I/O redirection (>, <, >>)");
-        println!("  - Pipes (|)");
-        println!("  - Background processes (&)");
-        println!("  - Variable expansion ($VAR, ${{VAR}})");
+        println!("  - I/O redirection (>, <, >>, 2>&1, &>, <<EOF, <<<)");
+        println!("  - Pipes (|), with builtins and functions as stages");
+        println!("  - Background jobs (&) and job control (Ctrl+Z, fg, bg)");
+        println!("  - Control flow (if, while, until, for, case)");
+        println!("  - Subshells ( ... ) and groups {{ ...; }}, with redirections and in pipes");
+        println!("  - Aliases, also loaded from ~/.aishrc");
+        println!("  - Shell functions (name() {{ ...; }}) with $1, $#, $@ and local variables");
+        println!("  - Shell and exported variables, with VAR=value before a command for its environment only");
+        println!("  - Variable expansion ($VAR, ${{VAR}}, ${{VAR:-default}}, ${{#VAR}}, ${{VAR%suffix}}, ...)");
+        println!("  - Arithmetic ($((expression)), (( expression )) and let)");
         println!("  - Command history (arrow keys)");
         println!("  - Tab completion");
 
         Ok(())
     }
 
+    // Show the LLM settings, or change one for the rest of the session:
+    // llm model NAME | max_tokens N | temperature T | stop SEQ... | timeout SECONDS
+    fn llm(args: &[String], shell: &mut Shell) -> io::Result<()> {
+        if args.is_empty() {
+            println!("provider: {}", shell.llm_description());
+            println!("{}", shell.llm_settings());
+            return Ok(());
+        }
+
+        shell
+            .llm_settings()
+            .set(&args[0], &args[1..])
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("llm: {}", e)))
+    }
+
+    // Show the routing mode, change it, or explain where a line would go:
+    // route | route auto|shell|ai | route LINE...
+    fn route(args: &[String], shell: &mut Shell) -> io::Result<()> {
+        if args.is_empty() {
+            println!("route mode: {}", shell.route_mode());
+            return Ok(());
+        }
+
+        if let [name] = args {
+            if let Some(mode) = RouteMode::parse(name) {
+                shell.set_route_mode(mode);
+                return Ok(());
+            }
+        }
+
+        let decision = shell.route(&args.join(" "));
+        let target = match decision.route {
+            Route::Shell => "shell",
+            Route::Llm => "ai",
+        };
+        println!("{}: {}", target, decision.reason);
+        Ok(())
+    }
+
+    // shopt [-s|-u] [name...]: show options, or set (-s) or unset (-u) them
+    fn shopt(args: &[String], shell: &mut Shell) -> io::Result<()> {
+        let (value, names) = match args.first().map(String::as_str) {
+            Some("-s") => (Some(true), &args[1..]),
+            Some("-u") => (Some(false), &args[1..]),
+            _ => (None, args),
+        };
+        let options = shell.glob_options_mut();
+
+        if let Some(value) = value {
+            for name in names {
+                options
+                    .set(name, value)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("shopt: {}", e)))?;
+            }
+            return Ok(());
+        }
+
+        let names: Vec<&str> = if names.is_empty() {
+            GlobOptions::NAMES.to_vec()
+        } else {
+            names.iter().map(String::as_str).collect()
+        };
+        for name in names {
+            let enabled = options.get(name).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("shopt: {}: invalid shell option name", name))
+            })?;
+            println!("{:<15}{}", name, if enabled { "on" } else { "off" });
+        }
+        Ok(())
+    }
+
+    // set -o NAME | set +o NAME: turn a shell option on or off; set -o alone lists
+    // the options, and set +o lists them as the commands that restore them.
+    // set -- ARGS | set ARGS: make ARGS the positional parameters $1...
+    fn set(args: &[String], shell: &mut Shell) -> io::Result<()> {
+        let pipefail = shell.pipefail();
+        match args {
+            [flag, parameters @ ..] if flag == "--" => shell.set_positional_parameters(parameters.to_vec()),
+            [first, ..] if !first.starts_with(['-', '+']) => shell.set_positional_parameters(args.to_vec()),
+            [] => println!("{:<15}{}", "pipefail", if pipefail { "on" } else { "off" }),
+            [flag] if flag == "-o" => println!("{:<15}{}", "pipefail", if pipefail { "on" } else { "off" }),
+            [flag] if flag == "+o" => println!("set {}o pipefail", if pipefail { '-' } else { '+' }),
+            [flag, name] if flag == "-o" || flag == "+o" => {
+                if name != "pipefail" {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("set: {}: invalid option name", name)));
+                }
+                shell.set_pipefail(flag == "-o");
+            }
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "set: usage: set [-o|+o] [option] | set [--] [arg...]")),
+        }
+        Ok(())
+    }
+
+    // shift [n]: drop the first n positional parameters, 1 by default, so that
+    // $n+1 becomes $1. With fewer than n it quietly fails, as in sh.
+    fn shift(args: &[String], shell: &mut Shell) -> io::Result<()> {
+        let count = match args.first() {
+            None => 1,
+            Some(arg) => arg.parse::<usize>().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("shift: {}: numeric argument required", arg))
+            })?,
+        };
+        if !shell.shift_positional_parameters(count) {
+            shell.set_builtin_status(1);
+        }
+        Ok(())
+    }
+
+    // break [n] | continue [n]: stop the body of the n-th enclosing for, while or
+    // until loop, leaving the loop (break) or going on with its next iteration
+    fn loop_control(name: &str, args: &[String], shell: &mut Shell) -> io::Result<()> {
+        let levels = match args.first() {
+            None => 1,
+            Some(arg) => match arg.parse::<usize>() {
+                Ok(levels) if levels > 0 => levels,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("{}: {}: loop count out of range", name, arg),
+                    ))
+                }
+            },
+        };
+
+        if shell.loop_depth() == 0 {
+            eprintln!("{}: only meaningful in a `for', `while', or `until' loop", name);
+            return Ok(());
+        }
+
+        // As in sh, a count beyond the number of loops stops the outermost one
+        let levels = levels.min(shell.loop_depth());
+        shell.set_loop_control(if name == "break" {
+            LoopControl::Break(levels)
+        } else {
+            LoopControl::Continue(levels)
+        });
+        Ok(())
+    }
+
+    // return [n]: leave the running shell function; without n its status is
+    // that of the last command
+    fn return_command(args: &[String], shell: &mut Shell) -> io::Result<()> {
+        let status = match args.first() {
+            None => shell.last_exit_status(),
+            Some(arg) => arg.parse::<i32>().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("return: {}: numeric argument required", arg))
+            })?,
+        };
+        shell.return_from_function(status & 0xff)
+    }
+
+    // local name[=value]...: variables that keep their value only until the
+    // running shell function returns
+    fn local(args: &[String], shell: &mut Shell) -> io::Result<()> {
+        if !shell.in_function() {
+            return Err(io::Error::other("local: can only be used in a function"));
+        }
+        for arg in args {
+            let (name, value) = Self::name_and_value("local", arg)?;
+            shell.declare_local(name, value).map_err(|e| io::Error::other(format!("local: {}", e)))?;
+        }
+        Ok(())
+    }
+
+    // let expression...: evaluate each argument; the status is 1 when the last
+    // one is 0, as with (( ))
+    fn let_command(args: &[String], shell: &mut Shell) -> io::Result<()> {
+        if args.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "let: expression expected"));
+        }
+        let mut value = 0;
+        for arg in args {
+            value = evaluate(arg, shell).map_err(|e| io::Error::other(format!("let: {}", e)))?;
+        }
+        shell.set_builtin_status(i32::from(value == 0));
+        Ok(())
+    }
+
+    // alias: list all aliases; alias name: show one; alias name=value: define one
+    fn alias(args: &[String], shell: &mut Shell) -> io::Result<()> {
+        if args.is_empty() {
+            for (name, value) in shell.aliases() {
+                println!("alias {}={}", name, Self::single_quoted(value));
+            }
+            return Ok(());
+        }
+
+        let mut missing = None;
+        for arg in args {
+            match arg.split_once('=') {
+                Some((name, value)) => {
+                    if name.is_empty() || name.contains(|c: char| c.is_whitespace() || "/$`\"'\\=".contains(c)) {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("alias: `{}': invalid alias name", name),
+                        ));
+                    }
+                    shell.set_alias(name.to_string(), value.to_string());
+                }
+                None => match shell.alias(arg) {
+                    Some(value) => println!("alias {}={}", arg, Self::single_quoted(value)),
+                    None => missing = Some(arg),
+                },
+            }
+        }
+
+        match missing {
+            Some(name) => Err(io::Error::new(io::ErrorKind::NotFound, format!("alias: {}: not found", name))),
+            None => Ok(()),
+        }
+    }
+
+    // unalias name...: remove aliases; unalias -a: remove them all
+    fn unalias(args: &[String], shell: &mut Shell) -> io::Result<()> {
+        if args.first().map(String::as_str) == Some("-a") {
+            shell.clear_aliases();
+            return Ok(());
+        }
+        for name in args {
+            if !shell.remove_alias(name) {
+                return Err(io::Error::new(io::ErrorKind::NotFound, format!("unalias: {}: not found", name)));
+            }
+        }
+        Ok(())
+    }
+
+    // jobs [-l|-p]: list the jobs with their state, with -l their process ids too,
+    // or with -p only the process ids
+    fn jobs(args: &[String], shell: &mut Shell) -> io::Result<()> {
+        let (long, pids_only) = match args.first().map(String::as_str) {
+            None => (false, false),
+            Some("-l") => (true, false),
+            Some("-p") => (false, true),
+            Some(arg) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("jobs: {}: invalid option", arg)))
+            }
+        };
+        let jobs = shell.jobs();
+        for job in jobs.iter() {
+            if pids_only {
+                if let Some(pid) = job.processes.first().and_then(|process| process.pid) {
+                    println!("{}", pid);
+                }
+            } else {
+                println!("{}", jobs.describe(job, long));
+            }
+        }
+        // Finished jobs have been reported now
+        for id in jobs.ids() {
+            if jobs.get(id).is_some_and(|job| job.is_done()) {
+                jobs.remove(id);
+            }
+        }
+        Ok(())
+    }
+
+    // The job a builtin's argument names, or the current job without one
+    fn job_id(name: &str, spec: Option<&String>, shell: &mut Shell) -> io::Result<usize> {
+        let jobs = shell.jobs();
+        let found = match spec {
+            Some(spec) => jobs.find(spec),
+            None => jobs.current().ok_or_else(|| "current: no such job".to_string()),
+        };
+        found.map_err(|e| io::Error::new(io::ErrorKind::NotFound, format!("{}: {}", name, e)))
+    }
+
+    // fg [%n] and bg [%n]: continue a job in the foreground or background
+    fn continue_job(name: &str, args: &[String], shell: &mut Shell) -> io::Result<()> {
+        if !shell.job_control() {
+            return Err(io::Error::other(format!("{}: no job control", name)));
+        }
+        let id = Self::job_id(name, args.first(), shell)?;
+        shell.continue_job(id, name == "fg")?;
+        if name == "fg" {
+            let status = shell.last_exit_status();
+            shell.set_builtin_status(status);
+        }
+        Ok(())
+    }
+
+    // wait [%n|pid...]: wait for the named jobs, or all of them; the status is
+    // that of the last one named, or 130 after Ctrl+C
+    fn wait(args: &[String], shell: &mut Shell) -> io::Result<()> {
+        match Self::wait_for_jobs(args, shell) {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+                println!();
+                shell.set_builtin_status(128 + libc::SIGINT);
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    fn wait_for_jobs(args: &[String], shell: &mut Shell) -> io::Result<()> {
+        if args.is_empty() {
+            for id in shell.jobs().ids() {
+                shell.wait_for_job(id)?;
+            }
+            return Ok(());
+        }
+
+        let mut status = 0;
+        for arg in args {
+            let id = if arg.starts_with('%') {
+                shell.jobs().find(arg).map_err(|e| eprintln!("wait: {}", e)).ok()
+            } else {
+                match arg.parse::<i32>() {
+                    Ok(pid) => {
+                        let id = shell.jobs().find_pid(Pid::from_raw(pid));
+                        if id.is_none() {
+                            eprintln!("wait: pid {} is not a child of this shell", pid);
+                        }
+                        id
+                    }
+                    Err(_) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("wait: `{}': not a pid or valid job spec", arg),
+                        ))
+                    }
+                }
+            };
+            status = match id {
+                Some(id) => shell.wait_for_job(id)?,
+                None => 127,
+            };
+        }
+        shell.set_builtin_status(status);
+        Ok(())
+    }
+
+    // kill [-s SIG | -SIG] %n|pid...: send a signal (TERM by default) to jobs or
+    // processes; kill -l lists the signal names
+    fn kill(args: &[String], shell: &mut Shell) -> io::Result<()> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+        let (signal, targets) = match args {
+            [flag, ..] if flag == "-l" => {
+                println!("{}", signal_names().join(" "));
+                return Ok(());
+            }
+            [flag, name, targets @ ..] if flag == "-s" => (
+                parse_signal(name).ok_or_else(|| invalid(format!("kill: {}: invalid signal specification", name)))?,
+                targets,
+            ),
+            [flag, targets @ ..] if flag.len() > 1 && flag.starts_with('-') => (
+                parse_signal(&flag[1..]).ok_or_else(|| invalid(format!("kill: {}: invalid signal specification", &flag[1..])))?,
+                targets,
+            ),
+            targets => (Signal::SIGTERM, targets),
+        };
+        if targets.is_empty() {
+            return Err(invalid("kill: usage: kill [-s sigspec | -sigspec] pid | jobspec ... or kill -l".to_string()));
+        }
+
+        let mut failed = false;
+        for target in targets {
+            let result = if target.starts_with('%') {
+                match shell.jobs().find(target) {
+                    Ok(id) => {
+                        let job = shell.jobs().get(id).expect("job was just found");
+                        // A stopped job only sees most signals once it runs again
+                        job.signal(signal).and_then(|_| {
+                            if job.is_stopped() && !matches!(signal, Signal::SIGKILL | Signal::SIGCONT) {
+                                job.signal(Signal::SIGCONT)
+                            } else {
+                                Ok(())
+                            }
+                        })
+                    }
+                    Err(e) => Err(io::Error::other(e)),
+                }
+            } else {
+                match target.parse::<i32>() {
+                    Ok(pid) => nix::sys::signal::kill(Pid::from_raw(pid), signal).map_err(io::Error::from),
+                    Err(_) => Err(invalid(format!("{}: arguments must be process or job IDs", target))),
+                }
+            };
+            if let Err(e) = result {
+                eprintln!("kill: {}: {}", target, e);
+                failed = true;
+            }
+        }
+        if failed {
+            shell.set_builtin_status(1);
+        }
+        Ok(())
+    }
+
+    // disown [-a] [%n...]: drop jobs from the table, so that they are neither
+    // reported nor hung up when the shell exits
+    fn disown(args: &[String], shell: &mut Shell) -> io::Result<()> {
+        let ids = match args.first().map(String::as_str) {
+            Some("-a") => shell.jobs().ids(),
+            None => vec![Self::job_id("disown", None, shell)?],
+            Some(_) => args
+                .iter()
+                .map(|spec| Self::job_id("disown", Some(spec), shell))
+                .collect::<io::Result<Vec<_>>>()?,
+        };
+        for id in ids {
+            shell.jobs().remove(id);
+        }
+        Ok(())
+    }
+
+    // Quote a value so that the shell reads it back unchanged
+    fn single_quoted(value: &str) -> String {
+        format!("'{}'", value.replace('\'', "'\\''"))
+    }
+
     fn history(_args: &[String], _shell: &mut Shell) -> io::Result<()> {
         println!("History functionality would be implemented here");
         println!("Use arrow keys to navigate through command history");
@@ -0,0 +1,253 @@
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::unistd::{close, dup2, pipe2, read, write};
+use std::io::{self, Write};
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How often the reader checks whether capturing has been stopped
+const POLL_INTERVAL_MS: i32 = 25;
+
+// Once capturing has stopped, output already waiting in the pipes is still
+// recorded, for at most this long: a chatty background job must not hold it up
+const DRAIN_LIMIT: Duration = Duration::from_millis(25);
+
+fn to_io_error(err: nix::Error) -> io::Error {
+    io::Error::from_raw_os_error(err as i32)
+}
+
+// One redirected descriptor: where it originally pointed and the pipe replacing it
+struct TeeFd {
+    target_fd: RawFd,
+    saved_fd: RawFd,
+    read_fd: RawFd,
+    write_fd: RawFd,
+}
+
+impl TeeFd {
+    fn redirect(target_fd: RawFd) -> io::Result<Self> {
+        let saved_fd = fcntl(target_fd, FcntlArg::F_DUPFD_CLOEXEC(10)).map_err(to_io_error)?;
+        let (read_fd, write_fd) = pipe2(OFlag::O_CLOEXEC).map_err(to_io_error)?;
+        dup2(write_fd, target_fd).map_err(to_io_error)?;
+
+        Ok(TeeFd {
+            target_fd,
+            saved_fd,
+            read_fd,
+            write_fd,
+        })
+    }
+
+    fn restore(&self) {
+        let _ = dup2(self.saved_fd, self.target_fd);
+        let _ = close(self.write_fd);
+    }
+}
+
+fn write_all(fd: RawFd, mut data: &[u8]) -> nix::Result<()> {
+    while !data.is_empty() {
+        match write(fd, data) {
+            Ok(n) => data = &data[n..],
+            Err(nix::Error::EINTR) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// What a capture recorded: the last characters written, up to its limit, and
+/// how many characters before them were dropped
+pub struct CapturedOutput {
+    pub text: String,
+    pub dropped_chars: usize,
+}
+
+// The tail of the recorded output. A character is at most 4 bytes, so keeping
+// 4 bytes per character of the limit is enough.
+struct Recording {
+    tail: Vec<u8>,
+    max_bytes: usize,
+    dropped_chars: usize,
+}
+
+impl Recording {
+    fn new(max_chars: usize) -> Self {
+        Recording { tail: Vec::new(), max_bytes: max_chars.saturating_mul(4), dropped_chars: 0 }
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        self.tail.extend_from_slice(data);
+        // Drop in large steps, so that a long stream is not moved for every chunk
+        if self.tail.len() > self.max_bytes.saturating_mul(2) {
+            let excess = self.tail.len() - self.max_bytes;
+            self.dropped_chars += self.tail[..excess].iter().filter(|b| !is_continuation_byte(**b)).count();
+            self.tail.drain(..excess);
+        }
+    }
+
+    fn finish(mut self) -> CapturedOutput {
+        // The start of a character cut in two was counted with the dropped ones
+        let partial = self.tail.iter().take_while(|b| is_continuation_byte(**b)).count();
+        self.tail.drain(..partial);
+        CapturedOutput { text: String::from_utf8_lossy(&self.tail).into_owned(), dropped_chars: self.dropped_chars }
+    }
+}
+
+fn is_continuation_byte(byte: u8) -> bool {
+    byte & 0xC0 == 0x80
+}
+
+// Forward everything arriving on the pipes to the original descriptors and
+// record it until capturing stops. Forwarding continues afterwards for
+// background processes that still hold the pipes open.
+fn tee_loop(tees: Vec<(RawFd, RawFd)>, stop: Arc<AtomicBool>, sender: mpsc::Sender<CapturedOutput>, mut recording: Recording) {
+    let mut sender = Some(sender);
+    let mut open: Vec<(RawFd, RawFd)> = tees;
+    let mut chunk = [0u8; 4096];
+    let mut drain_deadline = None;
+
+    while !open.is_empty() {
+        // Once stopped, take only what is already waiting, then hand over what
+        // was recorded
+        let draining = sender.is_some() && stop.load(Ordering::SeqCst);
+        if draining && Instant::now() >= *drain_deadline.get_or_insert_with(|| Instant::now() + DRAIN_LIMIT) {
+            hand_over(&mut sender, &mut recording);
+            continue;
+        }
+
+        let mut fds: Vec<PollFd> = open
+            .iter()
+            .map(|(read_fd, _)| PollFd::new(*read_fd, PollFlags::POLLIN))
+            .collect();
+
+        match poll(&mut fds, if draining { 0 } else { POLL_INTERVAL_MS }) {
+            Ok(0) => {
+                if draining {
+                    hand_over(&mut sender, &mut recording);
+                }
+                continue;
+            }
+            Ok(_) => {}
+            Err(nix::Error::EINTR) => continue,
+            Err(_) => break,
+        }
+
+        let ready: Vec<bool> = fds
+            .iter()
+            .map(|fd| fd.revents().is_some_and(|r| !r.is_empty()))
+            .collect();
+
+        let mut closed = Vec::new();
+        for (index, (read_fd, saved_fd)) in open.iter().enumerate() {
+            if !ready[index] {
+                continue;
+            }
+            match read(*read_fd, &mut chunk) {
+                Ok(0) => closed.push(index),
+                Ok(n) => {
+                    let _ = write_all(*saved_fd, &chunk[..n]);
+                    if sender.is_some() {
+                        recording.push(&chunk[..n]);
+                    }
+                }
+                Err(nix::Error::EINTR) => {}
+                Err(_) => closed.push(index),
+            }
+        }
+
+        for index in closed.into_iter().rev() {
+            let (read_fd, saved_fd) = open.remove(index);
+            let _ = close(read_fd);
+            let _ = close(saved_fd);
+        }
+    }
+
+    hand_over(&mut sender, &mut recording);
+}
+
+// Send what was recorded to `OutputCapture::finish`, once
+fn hand_over(sender: &mut Option<mpsc::Sender<CapturedOutput>>, recording: &mut Recording) {
+    if let Some(sender) = sender.take() {
+        let _ = sender.send(std::mem::replace(recording, Recording::new(0)).finish());
+    }
+}
+
+/// Captures stdout and stderr of the shell and every child it spawns,
+/// while still displaying the output as it is produced.
+pub struct OutputCapture {
+    tees: Vec<TeeFd>,
+    stop: Arc<AtomicBool>,
+    captured: Receiver<CapturedOutput>,
+}
+
+impl OutputCapture {
+    /// Start capturing, keeping at most the last `max_chars` characters
+    pub fn start(max_chars: usize) -> io::Result<Self> {
+        io::stdout().flush()?;
+        io::stderr().flush()?;
+
+        let mut tees: Vec<TeeFd> = Vec::new();
+        for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+            match TeeFd::redirect(fd) {
+                Ok(tee) => tees.push(tee),
+                Err(e) => {
+                    for tee in &tees {
+                        tee.restore();
+                        let _ = close(tee.read_fd);
+                        let _ = close(tee.saved_fd);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let (sender, captured) = mpsc::channel();
+        let pipes = tees.iter().map(|t| (t.read_fd, t.saved_fd)).collect();
+        let thread_stop = Arc::clone(&stop);
+        thread::spawn(move || tee_loop(pipes, thread_stop, sender, Recording::new(max_chars)));
+
+        Ok(OutputCapture {
+            tees,
+            stop,
+            captured,
+        })
+    }
+
+    /// Restores the original descriptors and returns what was written.
+    pub fn finish(self) -> io::Result<CapturedOutput> {
+        io::stdout().flush()?;
+        io::stderr().flush()?;
+
+        for tee in &self.tees {
+            tee.restore();
+        }
+        self.stop.store(true, Ordering::SeqCst);
+
+        Ok(self.captured.recv().unwrap_or(CapturedOutput { text: String::new(), dropped_chars: 0 }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recording_keeps_the_tail() {
+        let mut recording = Recording::new(4);
+        for _ in 0..10 {
+            recording.push("aé".as_bytes());
+        }
+        recording.push(b"end\n");
+        let output = recording.finish();
+        // At least the last 4 characters are kept, none of them cut in two, and
+        // every character is either kept or counted as dropped
+        assert!(output.text.ends_with("end\n"));
+        assert!(!output.text.contains('\u{FFFD}'));
+        assert_eq!(output.text.chars().count() + output.dropped_chars, 24);
+    }
+}
@@ -0,0 +1,325 @@
+use crate::context::ContentBlock;
+use crate::llm::{
+    replay_to_observer, ChatFuture, ChatRequest, LLMError, LLMProvider, LLMResponse, StreamObserver,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::fs;
+use std::sync::Mutex;
+
+// Lines of unchanged context shown around each difference of a replay mismatch
+const DIFF_CONTEXT_LINES: usize = 3;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct RecordedResponse {
+    content: Vec<ContentBlock>,
+    tokens_used: usize,
+    stop_reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Interaction {
+    request: Value,
+    response: RecordedResponse,
+}
+
+/// A recorded LLM session: every request sent through `LLMClient` and its response
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Cassette {
+    #[serde(default)]
+    provider: String,
+    interactions: Vec<Interaction>,
+}
+
+// The provider independent form of a request that is stored and compared. The
+// model is left out so that a session replays whatever backend is configured.
+fn request_to_json(request: &ChatRequest<'_>) -> Value {
+    let config = request.config;
+    json!({
+        "system": request.system,
+        "messages": request.messages.iter()
+            .map(|m| json!({"role": m.role, "content": m.content}))
+            .collect::<Vec<_>>(),
+        "tools": request.tools.iter()
+            .map(|t| json!({"name": t.name, "description": t.description, "input_schema": t.input_schema}))
+            .collect::<Vec<_>>(),
+        "max_tokens": config.max_tokens,
+        "temperature": config.temperature,
+        "stop_sequences": config.stop_sequences
+    })
+}
+
+/// Passes requests on to a real provider and writes every exchange to a cassette file
+pub struct RecordingProvider {
+    inner: Box<dyn LLMProvider>,
+    path: String,
+    cassette: Mutex<Cassette>,
+}
+
+impl RecordingProvider {
+    pub fn new(inner: Box<dyn LLMProvider>, path: &str) -> Self {
+        RecordingProvider {
+            inner,
+            path: path.to_string(),
+            cassette: Mutex::new(Cassette::default()),
+        }
+    }
+
+    // The cassette is rewritten after every exchange so that it survives `exit`
+    fn record(&self, request: &ChatRequest<'_>, response: &LLMResponse) {
+        let mut cassette = self.cassette.lock().unwrap();
+        cassette.provider = self.inner.describe(&request.config.model);
+        cassette.interactions.push(Interaction {
+            request: request_to_json(request),
+            response: RecordedResponse {
+                content: response.content.clone(),
+                tokens_used: response.tokens_used,
+                stop_reason: response.stop_reason.clone(),
+            },
+        });
+
+        let written = serde_json::to_string_pretty(&*cassette)
+            .map_err(|e| e.to_string())
+            .and_then(|json| fs::write(&self.path, json + "\n").map_err(|e| e.to_string()));
+        if let Err(e) = written {
+            println!("[SYS] Failed to write LLM recording {}: {}", self.path, e);
+        }
+    }
+}
+
+impl LLMProvider for RecordingProvider {
+    fn describe(&self, model: &str) -> String {
+        format!("{}, recording to {}", self.inner.describe(model), self.path)
+    }
+
+    fn chat<'a>(&'a self, request: &'a ChatRequest<'a>, observer: &'a mut dyn StreamObserver) -> ChatFuture<'a> {
+        Box::pin(async move {
+            let response = self.inner.chat(request, observer).await?;
+            self.record(request, &response);
+            Ok(response)
+        })
+    }
+}
+
+/// Serves the responses of a cassette without network access. Each request has to
+/// match the recorded one; a mismatch fails the request with a diff.
+pub struct ReplayProvider {
+    path: String,
+    recorded_with: String,
+    interactions: Mutex<VecDeque<Interaction>>,
+    total: usize,
+}
+
+impl ReplayProvider {
+    pub fn load(path: &str) -> Result<Self, LLMError> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| LLMError::InvalidConfig(format!("cannot read LLM recording {}: {}", path, e)))?;
+        let cassette: Cassette = serde_json::from_str(&content)
+            .map_err(|e| LLMError::InvalidConfig(format!("invalid LLM recording {}: {}", path, e)))?;
+
+        Ok(ReplayProvider {
+            path: path.to_string(),
+            recorded_with: cassette.provider,
+            total: cassette.interactions.len(),
+            interactions: Mutex::new(cassette.interactions.into()),
+        })
+    }
+
+    fn next_response(&self, request: &ChatRequest<'_>) -> Result<RecordedResponse, LLMError> {
+        let mut interactions = self.interactions.lock().unwrap();
+        let number = self.total - interactions.len() + 1;
+        let interaction = interactions.pop_front().ok_or_else(|| {
+            LLMError::RequestFailed(format!(
+                "recording {} has no response left for request {}",
+                self.path, number
+            ))
+        })?;
+
+        let actual = request_to_json(request);
+        if actual != interaction.request {
+            return Err(LLMError::RequestFailed(format!(
+                "request {} does not match recording {} (- recorded, + actual):\n{}",
+                number,
+                self.path,
+                json_diff(&interaction.request, &actual)
+            )));
+        }
+
+        Ok(interaction.response)
+    }
+}
+
+impl LLMProvider for ReplayProvider {
+    fn describe(&self, _model: &str) -> String {
+        let mut description = format!("replay of {} ({} responses", self.path, self.total);
+        if !self.recorded_with.is_empty() {
+            description.push_str(&format!(", recorded with {}", self.recorded_with));
+        }
+        description + ")"
+    }
+
+    fn chat<'a>(&'a self, request: &'a ChatRequest<'a>, observer: &'a mut dyn StreamObserver) -> ChatFuture<'a> {
+        Box::pin(async move {
+            let response = self.next_response(request)?;
+            replay_to_observer(&response.content, observer);
+            Ok(LLMResponse {
+                content: response.content,
+                tokens_used: response.tokens_used,
+                stop_reason: response.stop_reason,
+            })
+        })
+    }
+}
+
+impl Drop for ReplayProvider {
+    fn drop(&mut self) {
+        let left = self.interactions.lock().map(|interactions| interactions.len()).unwrap_or(0);
+        if left > 0 {
+            eprintln!("[SYS] Replay: {} of {} recorded responses were never requested", left, self.total);
+        }
+    }
+}
+
+// Line diff of two pretty-printed JSON values, showing only the changed regions
+fn json_diff(expected: &Value, actual: &Value) -> String {
+    let expected = serde_json::to_string_pretty(expected).unwrap_or_default();
+    let actual = serde_json::to_string_pretty(actual).unwrap_or_default();
+    let old: Vec<&str> = expected.lines().collect();
+    let new: Vec<&str> = actual.lines().collect();
+
+    // Longest common subsequence table, filled from the end
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines: Vec<(char, &str)> = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push((' ', old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            lines.push(('-', old[i]));
+            i += 1;
+        } else {
+            lines.push(('+', new[j]));
+            j += 1;
+        }
+    }
+
+    // Keep changed lines and their context, eliding the rest
+    let changed: Vec<usize> = (0..lines.len()).filter(|&index| lines[index].0 != ' ').collect();
+    let mut output = Vec::new();
+    let mut last_shown: Option<usize> = None;
+    for (index, (marker, line)) in lines.iter().enumerate() {
+        let near_change = changed
+            .iter()
+            .any(|&c| index + DIFF_CONTEXT_LINES >= c && index <= c + DIFF_CONTEXT_LINES);
+        if !near_change {
+            continue;
+        }
+        if last_shown.is_some_and(|last| index > last + 1) || (last_shown.is_none() && index > 0) {
+            output.push("  ...".to_string());
+        }
+        output.push(format!("{} {}", marker, line));
+        last_shown = Some(index);
+    }
+    if last_shown.is_some_and(|last| last + 1 < lines.len()) {
+        output.push("  ...".to_string());
+    }
+
+    output.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::Message;
+    use crate::llm::tests::RecordingObserver;
+    use crate::llm::GenerationConfig;
+
+    // Answers every request with the same text
+    struct FixedProvider;
+
+    impl LLMProvider for FixedProvider {
+        fn describe(&self, _model: &str) -> String {
+            "fixed".to_string()
+        }
+
+        fn chat<'a>(&'a self, _request: &'a ChatRequest<'a>, observer: &'a mut dyn StreamObserver) -> ChatFuture<'a> {
+            Box::pin(async move {
+                observer.text_delta("recorded answer");
+                Ok(LLMResponse {
+                    content: vec![ContentBlock::Text { text: "recorded answer".to_string() }],
+                    tokens_used: 7,
+                    stop_reason: Some("end_turn".to_string()),
+                })
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_record_then_replay() {
+        let path = std::env::temp_dir().join(format!("aish-cassette-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let history = vec![Message::user("What is aish?".to_string())];
+        let request = ChatRequest { system: "be brief".to_string(), messages: &history, tools: &[], config: &GenerationConfig::with_model("fixed") };
+
+        let recorder = RecordingProvider::new(Box::new(FixedProvider), path);
+        recorder.chat(&request, &mut RecordingObserver::default()).await.unwrap();
+
+        let replay = ReplayProvider::load(path).unwrap();
+        let mut observer = RecordingObserver::default();
+        let response = replay.chat(&request, &mut observer).await.unwrap();
+
+        assert_eq!(observer.events, vec!["text:recorded answer".to_string()]);
+        assert_eq!(response.tokens_used, 7);
+        assert_eq!(response.stop_reason.as_deref(), Some("end_turn"));
+        assert!(replay.describe("any").contains("recorded with fixed"));
+
+        // The cassette is used up now
+        let result = replay.chat(&request, &mut observer).await;
+        assert!(matches!(result, Err(LLMError::RequestFailed(message)) if message.contains("no response left for request 2")));
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replay_mismatch_shows_diff() {
+        let path = std::env::temp_dir().join(format!("aish-cassette-mismatch-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let recorded = vec![Message::user("What is aish?".to_string())];
+        let request = ChatRequest { system: "be brief".to_string(), messages: &recorded, tools: &[], config: &GenerationConfig::with_model("fixed") };
+        RecordingProvider::new(Box::new(FixedProvider), path)
+            .chat(&request, &mut RecordingObserver::default())
+            .await
+            .unwrap();
+
+        let edited = vec![Message::user("What is bash?".to_string())];
+        let request = ChatRequest { system: "be brief".to_string(), messages: &edited, tools: &[], config: &GenerationConfig::with_model("fixed") };
+        let replay = ReplayProvider::load(path).unwrap();
+        let result = replay.chat(&request, &mut RecordingObserver::default()).await;
+
+        let Err(LLMError::RequestFailed(message)) = result else {
+            panic!("replay should fail on a different request");
+        };
+        assert!(message.starts_with("request 1 does not match recording"));
+        let changed: Vec<&str> = message.lines().filter(|line| line.starts_with(['-', '+'])).collect();
+        assert_eq!(changed, vec![
+            "-           \"text\": \"What is aish?\",",
+            "+           \"text\": \"What is bash?\",",
+        ]);
+        assert!(!message.contains("\"system\""));
+
+        fs::remove_file(path).unwrap();
+    }
+}
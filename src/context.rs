@@ -1,4 +1,4 @@
-use crate::llm::LLMClient;
+use crate::llm::{GenerationConfig, LLMClient, TerminalRenderer};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs;
@@ -8,10 +8,38 @@ use std::process::Command;
 // Token counting constants
 const MAX_CONTEXT_TOKENS: usize = 200_000; // 200K token limit
 
+// Longest code block output kept in context; the tail is usually the most relevant part
+pub const MAX_BLOCK_OUTPUT_CHARS: usize = 8_000;
+
+// How often an answer cut off by max_tokens is continued, and how it is asked for
+const MAX_CONTINUATIONS: usize = 3;
+const CONTINUE_PROMPT: &str =
+    "Your previous answer was cut off by the max_tokens limit. Continue exactly where it stopped, without repeating anything.";
+
+/// One block of message content, mirroring the Messages API content blocks
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentBlock {
+    Text {
+        text: String,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: serde_json::Value,
+    },
+    ToolResult {
+        tool_use_id: String,
+        content: String,
+        #[serde(default)]
+        is_error: bool,
+    },
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
     pub role: String, // "user", "assistant", or "system"
-    pub content: String,
+    pub content: Vec<ContentBlock>,
     pub tokens_used: Option<usize>, // Track tokens used for this message (mainly for assistant messages)
 }
 
@@ -19,7 +47,7 @@ impl Message {
     pub fn user(content: String) -> Self {
         Message {
             role: "user".to_string(),
-            content,
+            content: vec![ContentBlock::Text { text: content }],
             tokens_used: None,
         }
     }
@@ -27,12 +55,13 @@ impl Message {
     pub fn assistant(content: String) -> Self {
         Message {
             role: "assistant".to_string(),
-            content,
+            content: vec![ContentBlock::Text { text: content }],
             tokens_used: None,
         }
     }
 
-    pub fn assistant_with_tokens(content: String, tokens: usize) -> Self {
+    // Assistant turn as returned by the model, including any tool_use blocks
+    pub fn assistant_with_blocks(content: Vec<ContentBlock>, tokens: usize) -> Self {
         Message {
             role: "assistant".to_string(),
             content,
@@ -40,13 +69,46 @@ impl Message {
         }
     }
 
+    // User turn answering every tool_use of the previous assistant turn
+    pub fn tool_results(results: Vec<ContentBlock>) -> Self {
+        Message {
+            role: "user".to_string(),
+            content: results,
+            tokens_used: None,
+        }
+    }
+
     pub fn system(content: String) -> Self {
         Message {
             role: "system".to_string(),
-            content,
+            content: vec![ContentBlock::Text { text: content }],
             tokens_used: None,
         }
     }
+
+    // Concatenated text blocks, ignoring tool calls and results
+    pub fn text(&self) -> String {
+        self.content
+            .iter()
+            .filter_map(|block| match block {
+                ContentBlock::Text { text } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    pub fn tool_uses(&self) -> Vec<(&str, &str, &serde_json::Value)> {
+        self.content
+            .iter()
+            .filter_map(|block| match block {
+                ContentBlock::ToolUse { id, name, input } => {
+                    Some((id.as_str(), name.as_str(), input))
+                }
+                _ => None,
+            })
+            .collect()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -117,7 +179,8 @@ impl ConversationHistory {
 pub struct ContextManager {
     global_history: ConversationHistory,
     function_histories: HashMap<String, ConversationHistory>,
-    current_function: Option<String>,
+    // Active function calls, innermost last; each call gets a fresh history
+    call_stack: Vec<(String, ConversationHistory)>,
 }
 
 impl ContextManager {
@@ -125,28 +188,21 @@ impl ContextManager {
         ContextManager {
             global_history: ConversationHistory::new(),
             function_histories: HashMap::new(),
-            current_function: None,
+            call_stack: Vec::new(),
         }
     }
 
     pub fn get_current_history(&self) -> &ConversationHistory {
-        if let Some(func_name) = &self.current_function {
-            self.function_histories
-                .get(func_name)
-                .unwrap_or(&self.global_history)
-        } else {
-            &self.global_history
+        match self.call_stack.last() {
+            Some((_, history)) => history,
+            None => &self.global_history,
         }
     }
 
     pub fn get_current_history_mut(&mut self) -> &mut ConversationHistory {
-        if let Some(func_name) = &self.current_function {
-            let func_name = func_name.clone();
-            self.function_histories
-                .entry(func_name)
-                .or_insert_with(ConversationHistory::new)
-        } else {
-            &mut self.global_history
+        match self.call_stack.last_mut() {
+            Some((_, history)) => history,
+            None => &mut self.global_history,
         }
     }
 
@@ -159,9 +215,8 @@ impl ContextManager {
             .add_assistant_message(content);
     }
 
-    pub fn add_assistant_message_with_tokens(&mut self, content: String, tokens: usize) {
-        self.get_current_history_mut()
-            .add_message(Message::assistant_with_tokens(content, tokens));
+    pub fn add_message(&mut self, message: Message) {
+        self.get_current_history_mut().add_message(message);
     }
 
     pub fn add_system_message(&mut self, content: String) {
@@ -173,14 +228,14 @@ impl ContextManager {
     }
 
     pub fn enter_function(&mut self, function_name: String) {
-        self.current_function = Some(function_name.clone());
-        self.function_histories
-            .entry(function_name)
-            .or_insert_with(ConversationHistory::new);
+        self.call_stack.push((function_name, ConversationHistory::new()));
     }
 
+    // Leave the innermost function; its history stays available for inspection
     pub fn exit_function(&mut self) {
-        self.current_function = None;
+        if let Some((function_name, history)) = self.call_stack.pop() {
+            self.function_histories.insert(function_name, history);
+        }
     }
 
     pub fn get_global_history(&self) -> &ConversationHistory {
@@ -200,6 +255,7 @@ pub enum LLMAction {
 pub struct LLMActionProcessor {
     context_manager: ContextManager,
     llm_client: LLMClient,
+    context_cleared: bool,
 }
 
 impl LLMActionProcessor {
@@ -207,6 +263,7 @@ impl LLMActionProcessor {
         LLMActionProcessor {
             context_manager: ContextManager::new(),
             llm_client: LLMClient::new(),
+            context_cleared: false,
         }
     }
 
@@ -219,91 +276,126 @@ impl LLMActionProcessor {
         }
     }
 
-    // Execute a paragraph in agentic style - LLM can perform multiple sequential actions
+    // Execute a paragraph in agentic style - LLM can perform multiple sequential actions.
+    // Progress is printed as it happens; the final answer text is returned.
     async fn execute_agentic_paragraph(&mut self, content: &str) -> io::Result<String> {
-        let mut all_results = Vec::new();
+        let mut answer = String::new();
+        let mut renderer = TerminalRenderer::new();
         let max_iterations = 5; // Prevent infinite loops
+        let mut iteration = 0;
+        let mut continuations = 0;
+        let mut continuing = false;
 
         // Add the user's request to conversation history
         self.context_manager.add_user_message(content.to_string());
 
-        for iteration in 0..max_iterations {
+        loop {
             let current_history = self
                 .context_manager
                 .get_current_history()
                 .get_messages()
                 .clone();
 
-            // Add iteration info for debugging
-            if iteration > 0 {
-                all_results.push(format!("[SYS] Agentic iteration {}", iteration + 1));
+            // Add iteration info for debugging; continuations do not count as iterations
+            let is_continuation = std::mem::take(&mut continuing);
+            if !is_continuation {
+                iteration += 1;
+                if iteration > 1 {
+                    println!("[SYS] Agentic iteration {}", iteration);
+                }
             }
 
-            match self
+            let result = self
                 .llm_client
-                .process_with_tools_and_history(&current_history)
-                .await
-            {
-                Ok((response, tool_calls, tokens_used)) => {
-                    // Add LLM response to conversation history with token count
-                    if !response.trim().is_empty() {
-                        self.context_manager
-                            .add_assistant_message_with_tokens(response.clone(), tokens_used);
-                        // Format each line with [LLM] prefix for display
-                        let display_response = response.lines()
-                            .map(|line| format!("[LLM] {}", line))
-                            .collect::<Vec<_>>()
-                            .join("\n");
-                        all_results.push(display_response);
+                .process_with_tools_and_history(&current_history, &mut renderer)
+                .await;
+            renderer.finish_line();
+
+            match result {
+                Ok(response) => {
+                    let truncated = response.stop_reason.as_deref() == Some("max_tokens");
+                    let assistant_message =
+                        Message::assistant_with_blocks(response.content, response.tokens_used);
+                    let text = assistant_message.text();
+                    let tool_uses: Vec<(String, String, serde_json::Value)> = assistant_message
+                        .tool_uses()
+                        .into_iter()
+                        .map(|(id, name, input)| (id.to_string(), name.to_string(), input.clone()))
+                        .collect();
+
+                    // Keep the complete assistant turn (text and tool_use blocks) in the history
+                    if !assistant_message.content.is_empty() {
+                        self.context_manager.add_message(assistant_message);
                     }
 
-                    // Execute tool calls and add results to context as user messages
+                    if is_continuation {
+                        answer.push_str(&text);
+                    } else if !text.trim().is_empty() {
+                        answer = text;
+                    }
+
+                    // If no tools were called, the LLM is done unless it ran out of tokens
+                    if tool_uses.is_empty() {
+                        let max_tokens = self.llm_client.config().max_tokens;
+                        if truncated && continuations < MAX_CONTINUATIONS {
+                            continuations += 1;
+                            println!(
+                                "[SYS] Response stopped at max_tokens ({}), continuing ({}/{})",
+                                max_tokens, continuations, MAX_CONTINUATIONS
+                            );
+                            self.context_manager.add_user_message(CONTINUE_PROMPT.to_string());
+                            continuing = true;
+                            continue;
+                        }
+                        if truncated {
+                            println!(
+                                "[SYS] Response stopped at max_tokens ({}); raise it with `llm max_tokens N`",
+                                max_tokens
+                            );
+                        }
+                        break;
+                    }
+
+                    // Answer every tool_use of this turn, in order, in a single user message
+                    self.context_cleared = false;
                     let mut tool_results = Vec::new();
-                    for (tool_name, input) in &tool_calls {
-                        match self.execute_tool_call(tool_name, input).await {
+                    for (id, tool_name, input) in &tool_uses {
+                        let (content, is_error) = match self.execute_tool_call(tool_name, input).await {
                             Ok(tool_result) => {
-                                // Add tool result as user message with tool_result content block
-                                let tool_result_message = serde_json::json!({
-                                    "type": "tool_result",
-                                    "tool_use_id": format!("{}_result", tool_name),
-                                    "content": tool_result
-                                });
-
-                                self.context_manager
-                                    .add_user_message(tool_result_message.to_string());
-                                all_results.push(format!("[TOOL] {}: {}", tool_name, tool_result));
-                                tool_results.push(format!("Tool {} executed", tool_name));
+                                println!("[TOOL] {}: {}", tool_name, tool_result);
+                                let failed = tool_result.get("success") == Some(&serde_json::Value::Bool(false));
+                                (tool_result.to_string(), failed)
                             }
                             Err(e) => {
-                                let error_msg = format!("[SYS] Tool execution error: {}", e);
-                                all_results.push(error_msg.clone());
-                                tool_results.push(format!("Tool {} failed: {}", tool_name, e));
+                                println!("[SYS] Tool execution error: {}", e);
+                                (e.to_string(), true)
                             }
-                        }
+                        };
+                        tool_results.push(ContentBlock::ToolResult {
+                            tool_use_id: id.clone(),
+                            content,
+                            is_error,
+                        });
                     }
 
-                    // If no tools were called, the LLM is done
-                    if tool_calls.is_empty() {
+                    // A cleared context no longer holds the tool_use blocks these results answer
+                    if self.context_cleared {
                         break;
                     }
+                    self.context_manager.add_message(Message::tool_results(tool_results));
 
-                    // Let the LLM continue only if there were successful tool executions
-                    // and we haven't reached iteration limit
-                    let has_successful_tools = tool_results.iter().any(|r| !r.contains("failed:"));
-
-                    if !has_successful_tools || iteration >= max_iterations - 1 {
-                        // Stop if all tools failed or we've reached iteration limit
+                    if iteration >= max_iterations {
                         break;
                     }
                 }
                 Err(e) => {
-                    all_results.push(format!("[SYS] LLM processing failed: {}", e));
+                    println!("[SYS] LLM processing failed: {}", e);
                     break;
                 }
             }
         }
 
-        Ok(all_results.join("\n"))
+        Ok(answer)
     }
 
     // Simple direct tool functions
@@ -344,6 +436,7 @@ impl LLMActionProcessor {
 
     fn clear_context(&mut self) -> serde_json::Value {
         self.context_manager.clear_context();
+        self.context_cleared = true;
         serde_json::json!({
             "success": true,
             "message": "Context cleared"
@@ -408,14 +501,89 @@ impl LLMActionProcessor {
         Ok(result)
     }
 
-    pub fn enter_function(&mut self, function_name: String) {
-        self.context_manager.enter_function(function_name);
+    // Make the result of an executed code block visible to the following paragraphs.
+    // `dropped_chars` came before `output` and were not kept by the capture.
+    pub fn add_code_block_result(&mut self, code: &str, output: &str, dropped_chars: usize, exit_status: i32) {
+        let char_count = output.chars().count();
+        let truncated = (char_count + dropped_chars).saturating_sub(MAX_BLOCK_OUTPUT_CHARS);
+        let output = if truncated > 0 {
+            let tail: String = output.chars().skip(char_count.saturating_sub(MAX_BLOCK_OUTPUT_CHARS)).collect();
+            format!("[... {} characters truncated ...]\n{}", truncated, tail)
+        } else {
+            output.to_string()
+        };
+
+        self.context_manager.add_system_message(format!(
+            "Executed shell code block:\n```\n{}\n```\nExit status: {}\nOutput:\n{}",
+            code.trim_end(),
+            exit_status,
+            if output.trim().is_empty() { "(no output)" } else { output.trim_end() }
+        ));
+    }
+
+    // Tell the LLM the arguments a script was run with, which its paragraphs may
+    // refer to as $1, $2, ...
+    pub fn set_script_arguments(&mut self, script: &str, arguments: &[String]) {
+        if arguments.is_empty() {
+            return;
+        }
+        let arguments = arguments
+            .iter()
+            .enumerate()
+            .map(|(index, value)| format!("${} = {}", index + 1, value))
+            .collect::<Vec<_>>()
+            .join("\n");
+        self.context_manager.add_system_message(format!(
+            "The script '{}' was run with these arguments:\n{}",
+            script, arguments
+        ));
+    }
+
+    // Start a function call with its own conversation, seeded with its arguments
+    pub fn enter_function(&mut self, function_name: String, bindings: &[(String, String)]) {
+        self.context_manager.enter_function(function_name.clone());
+
+        if !bindings.is_empty() {
+            let arguments = bindings
+                .iter()
+                .map(|(param, value)| format!("{} = {}", param, value))
+                .collect::<Vec<_>>()
+                .join("\n");
+            self.context_manager.add_system_message(format!(
+                "You are executing the function '{}' with these arguments:\n{}",
+                function_name, arguments
+            ));
+        }
     }
 
     pub fn exit_function(&mut self) {
         self.context_manager.exit_function();
     }
 
+    // The most recent answer given by the LLM in the current context
+    pub fn last_answer(&self) -> Option<String> {
+        let messages = self.context_manager.get_current_history().get_messages();
+        let mut index = messages
+            .iter()
+            .rposition(|message| message.role == "assistant" && !message.text().trim().is_empty())?;
+        let mut answer = messages[index].text();
+
+        // Join the pieces of an answer that was continued after hitting max_tokens
+        while index >= 2 && messages[index - 1].text() == CONTINUE_PROMPT && messages[index - 2].role == "assistant" {
+            answer = messages[index - 2].text() + &answer;
+            index -= 2;
+        }
+        Some(answer)
+    }
+
+    pub fn llm_description(&self) -> String {
+        self.llm_client.describe()
+    }
+
+    pub fn generation_config_mut(&mut self) -> &mut GenerationConfig {
+        self.llm_client.config_mut()
+    }
+
     pub fn get_context_info(&self) -> String {
         let current = self.context_manager.get_current_history();
         let global = self.context_manager.get_global_history();
@@ -0,0 +1,969 @@
+use crate::arith::evaluate;
+use crate::glob::{glob, is_glob_pattern, pattern_matches, GlobOptions};
+use crate::parser::scan_quoted;
+use std::fmt;
+use std::io;
+
+// Field separators used when IFS is not set
+const DEFAULT_IFS: &str = " \t\n";
+
+/// What word expansion needs from the shell
+pub trait Expander {
+    /// Value of `$name`, including special parameters such as `?`
+    fn variable(&self, name: &str) -> Option<String>;
+
+    /// Output of a command substitution, with trailing newlines removed
+    fn command_output(&mut self, command: &str) -> String;
+
+    /// Options for pathname expansion
+    fn glob_options(&self) -> GlobOptions {
+        GlobOptions::default()
+    }
+
+    /// `$1`, `$2`, ...: a quoted `"$@"` expands to one field for each of them
+    fn positional_parameters(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// `${name:=word}` sets an unset or empty variable
+    fn assign_variable(&mut self, name: &str, _value: &str) -> io::Result<()> {
+        Err(parameter_error(format!("${}: cannot assign in this way", name)))
+    }
+}
+
+/// An expansion that cannot go on, such as `${name:?}` with name unset or a bad
+/// substitution. It fails the command, and a shell that is not interactive stops.
+#[derive(Debug)]
+pub struct ParameterError(String);
+
+impl fmt::Display for ParameterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ParameterError {}
+
+pub fn parameter_error(message: String) -> io::Error {
+    io::Error::other(ParameterError(message))
+}
+
+pub fn is_parameter_error(error: &io::Error) -> bool {
+    error.get_ref().is_some_and(|inner| inner.is::<ParameterError>())
+}
+
+// What `$*` joins the parameters with: the first character of IFS, a space when
+// IFS is unset and nothing when it is empty
+pub fn star_separator(ifs: Option<&str>) -> &str {
+    match ifs {
+        None => " ",
+        Some(ifs) => ifs.char_indices().nth(1).map_or(ifs, |(end, _)| &ifs[..end]),
+    }
+}
+
+// Expand a word as typed into the fields it produces, in the order sh does:
+// brace expansion; `$NAME`, `${NAME}` and its operators, `$0`, `$1`, `$?`, `$#`,
+// `$@`, `$*`, `$$`, `$!`, `$(command)`, `` `command` `` and `$((expression))`;
+// splitting of unquoted expansion results on IFS; pathname expansion of unquoted
+// `*`, `?` and `[...]`; and removal of quotes and backslashes. Single quotes keep
+// everything literal; inside double quotes expansions happen but are not split.
+pub fn expand_word(word: &str, expander: &mut dyn Expander) -> io::Result<Vec<String>> {
+    let ifs = expander.variable("IFS").unwrap_or_else(|| DEFAULT_IFS.to_string());
+    let options = expander.glob_options();
+    let mut result = Vec::new();
+
+    for word in expand_braces(word) {
+        let mut fields = Fields { ifs: Some(ifs.clone()), ..Fields::default() };
+        expand_into(&word, expander, &mut fields)?;
+
+        for field in fields.finish() {
+            if !is_glob_pattern(&field.pattern) {
+                result.push(field.text);
+                continue;
+            }
+            let matches = glob(&field.pattern, &options);
+            if !matches.is_empty() {
+                result.extend(matches);
+            } else if options.failglob {
+                return Err(io::Error::new(io::ErrorKind::NotFound, format!("no match: {}", field.text)));
+            } else if !options.nullglob {
+                result.push(field.text);
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+// Expand a word where no field splitting or pathname expansion happens, such as
+// a redirection target
+pub fn expand_string(word: &str, expander: &mut dyn Expander) -> io::Result<String> {
+    let mut fields = Fields::default();
+    expand_into(word, expander, &mut fields)?;
+    Ok(fields.finish().into_iter().map(|field| field.text).collect::<Vec<_>>().join(" "))
+}
+
+// Expand the text of a here-document: parameters and command substitutions are
+// expanded as inside double quotes, but quote characters are ordinary. A backslash
+// only escapes `$`, `` ` ``, itself and a newline, which it removes.
+pub fn expand_here_document(body: &str, expander: &mut dyn Expander) -> io::Result<String> {
+    let chars: Vec<char> = body.chars().collect();
+    let mut text = String::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => match chars.get(i + 1) {
+                Some('\n') => {
+                    i += 2;
+                    continue;
+                }
+                Some(&next @ ('$' | '`' | '\\')) => {
+                    text.push(next);
+                    i += 2;
+                    continue;
+                }
+                _ => text.push('\\'),
+            },
+            '$' | '`' => {
+                let (value, end) = expand_dollar(&chars, i, expander)?;
+                text.push_str(value.as_deref().unwrap_or("$"));
+                i = end;
+                continue;
+            }
+            ch => text.push(ch),
+        }
+        i += 1;
+    }
+
+    Ok(text)
+}
+
+// Expand a word used as a pattern, such as a `case` pattern: expansions happen
+// without splitting, and quoted characters are escaped so they match themselves
+pub fn expand_pattern(word: &str, expander: &mut dyn Expander) -> io::Result<String> {
+    let mut fields = Fields::default();
+    expand_into(word, expander, &mut fields)?;
+    Ok(fields.finish().into_iter().map(|field| field.pattern).collect::<Vec<_>>().join(" "))
+}
+
+// One field of an expanded word: its text, and the same text as a glob pattern in
+// which quoted characters are escaped so that they only match themselves
+#[derive(Default)]
+struct Field {
+    text: String,
+    pattern: String,
+}
+
+// The fields of a word being expanded
+#[derive(Default)]
+struct Fields {
+    done: Vec<Field>,
+    current: Field,
+    // A quoted empty string still makes a field, so track that one was started
+    started: bool,
+    // Separators for unquoted expansion results; None disables splitting
+    ifs: Option<String>,
+    // The last field was ended by IFS whitespace, which a following non-whitespace
+    // separator belongs to
+    after_whitespace: bool,
+}
+
+impl Fields {
+    fn push(&mut self, ch: char, quoted: bool) {
+        self.current.text.push(ch);
+        if quoted && matches!(ch, '*' | '?' | '[' | ']' | '\\') {
+            self.current.pattern.push('\\');
+        }
+        self.current.pattern.push(ch);
+        self.started = true;
+        self.after_whitespace = false;
+    }
+
+    fn push_str(&mut self, text: &str) {
+        for ch in text.chars() {
+            self.push(ch, true);
+        }
+        self.started = true;
+    }
+
+    // Text coming from an unquoted expansion, which is split into fields. IFS
+    // whitespace is trimmed and collapses; every other IFS character ends a
+    // field, even an empty one, so `a::b` gives `a`, `` and `b` with IFS=:
+    fn push_unquoted(&mut self, text: &str) {
+        let Some(ifs) = self.ifs.clone() else {
+            text.chars().for_each(|ch| self.push(ch, false));
+            return;
+        };
+        for ch in text.chars() {
+            if !ifs.contains(ch) {
+                self.push(ch, false);
+            } else if matches!(ch, ' ' | '\t' | '\n') {
+                if self.started {
+                    self.done.push(std::mem::take(&mut self.current));
+                    self.started = false;
+                    self.after_whitespace = true;
+                }
+            } else {
+                if self.started || !self.after_whitespace {
+                    self.done.push(std::mem::take(&mut self.current));
+                    self.started = false;
+                }
+                self.after_whitespace = false;
+            }
+        }
+    }
+
+    fn finish(mut self) -> Vec<Field> {
+        if self.started || !self.current.text.is_empty() {
+            self.done.push(self.current);
+        }
+        self.done
+    }
+}
+
+// Brace expansion on the word as typed: `a{b,c}d` gives `abd acd`, `{1..3}` gives
+// `1 2 3` and `{a..e..2}` gives `a c e`. Quoted or escaped braces, `${...}` and
+// braces without a comma or range (such as `{}`) stay as they are.
+pub fn expand_braces(word: &str) -> Vec<String> {
+    let chars: Vec<char> = word.chars().collect();
+    let mut i = 0;
+
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 1,
+            '\'' | '"' | '`' => {
+                i = scan_quoted(&chars, i).unwrap_or(chars.len());
+                continue;
+            }
+            '$' if matches!(chars.get(i + 1), Some('(') | Some('{')) => {
+                i = scan_quoted(&chars, i).unwrap_or(chars.len());
+                continue;
+            }
+            '{' => {
+                if let Some((close, alternatives)) = brace_alternatives(&chars, i) {
+                    let prefix: String = chars[..i].iter().collect();
+                    let suffix: String = chars[close + 1..].iter().collect();
+                    return alternatives
+                        .iter()
+                        .flat_map(|alternative| expand_braces(&format!("{}{}{}", prefix, alternative, suffix)))
+                        .collect();
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+
+    vec![word.to_string()]
+}
+
+// The closing brace and the alternatives of the brace expression at `chars[open]`
+fn brace_alternatives(chars: &[char], open: usize) -> Option<(usize, Vec<String>)> {
+    let mut depth = 0;
+    let mut commas = Vec::new();
+    let mut i = open + 1;
+
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 1,
+            '\'' | '"' | '`' => {
+                i = scan_quoted(chars, i).ok()?;
+                continue;
+            }
+            '{' => depth += 1,
+            '}' if depth > 0 => depth -= 1,
+            '}' => {
+                let inner: String = chars[open + 1..i].iter().collect();
+                if commas.is_empty() {
+                    return brace_sequence(&inner).map(|sequence| (i, sequence));
+                }
+                let mut alternatives = Vec::new();
+                let mut start = open + 1;
+                for &comma in commas.iter().chain(std::iter::once(&i)) {
+                    alternatives.push(chars[start..comma].iter().collect());
+                    start = comma + 1;
+                }
+                return Some((i, alternatives));
+            }
+            ',' if depth == 0 => commas.push(i),
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+// `{1..10}`, `{10..1..3}`, `{01..10}` (zero padded) and `{a..z}`
+fn brace_sequence(inner: &str) -> Option<Vec<String>> {
+    let parts: Vec<&str> = inner.split("..").collect();
+    let (start, end, step) = match parts.as_slice() {
+        [start, end] => (*start, *end, 1),
+        [start, end, step] => (*start, *end, i64::try_from(step.parse::<i64>().ok()?.unsigned_abs().max(1)).ok()?),
+        _ => return None,
+    };
+
+    if let (Ok(first), Ok(last)) = (start.parse::<i64>(), end.parse::<i64>()) {
+        let padded = |s: &str| s.trim_start_matches('-').len() > 1 && s.trim_start_matches('-').starts_with('0');
+        let width = if padded(start) || padded(end) { start.len().max(end.len()) } else { 0 };
+        let step = if first <= last { step } else { -step };
+        let mut values = Vec::new();
+        let mut value = first;
+        while (step > 0 && value <= last) || (step < 0 && value >= last) {
+            values.push(format!("{:0width$}", value, width = width));
+            // The sequence ends where the next value would not fit
+            match value.checked_add(step) {
+                Some(next) => value = next,
+                None => break,
+            }
+        }
+        return Some(values);
+    }
+
+    let mut first = start.chars();
+    let mut last = end.chars();
+    match (first.next(), first.next(), last.next(), last.next()) {
+        (Some(first), None, Some(last), None) if first.is_ascii_alphabetic() && last.is_ascii_alphabetic() => {
+            let (low, high) = (first as u8, last as u8);
+            let letters: Vec<u8> = if low <= high { (low..=high).collect() } else { (high..=low).rev().collect() };
+            // The result is read again as part of the word, so characters between
+            // the letters, such as the backtick in `{a..Z}`, are escaped
+            let escape = |c: u8| {
+                let c = c as char;
+                if c.is_ascii_alphanumeric() { c.to_string() } else { format!("\\{}", c) }
+            };
+            Some(letters.into_iter().step_by(step as usize).map(escape).collect())
+        }
+        _ => None,
+    }
+}
+
+fn expand_into(word: &str, expander: &mut dyn Expander, fields: &mut Fields) -> io::Result<()> {
+    let chars: Vec<char> = word.chars().collect();
+    let mut in_double_quotes = false;
+    // A `"$@"` without parameters makes no field, unlike `""`
+    let mut empty_quoted_at = false;
+    let mut i = 0;
+
+    while i < chars.len() {
+        let ch = chars[i];
+        match ch {
+            '$' if in_double_quotes && chars.get(i + 1) == Some(&'@') => {
+                let parameters = expander.positional_parameters();
+                empty_quoted_at |= parameters.is_empty();
+                for (index, parameter) in parameters.iter().enumerate() {
+                    if index > 0 {
+                        fields.done.push(std::mem::take(&mut fields.current));
+                    }
+                    fields.push_str(parameter);
+                }
+                i += 2;
+                continue;
+            }
+            '\'' if !in_double_quotes => {
+                let end = scan_quoted(&chars, i).unwrap_or(chars.len());
+                let literal: String = chars[i + 1..end.max(i + 1)].iter().collect();
+                fields.push_str(literal.strip_suffix('\'').unwrap_or(&literal));
+                i = end;
+                continue;
+            }
+            '"' => {
+                if in_double_quotes && !empty_quoted_at {
+                    fields.started = true;
+                }
+                in_double_quotes = !in_double_quotes;
+                empty_quoted_at = false;
+            }
+            '\\' => match chars.get(i + 1) {
+                Some(&next) if !in_double_quotes || matches!(next, '$' | '`' | '"' | '\\') => {
+                    fields.push(next, true);
+                    i += 2;
+                    continue;
+                }
+                // Other backslashes inside double quotes are kept
+                _ => fields.push(ch, true),
+            },
+            '$' | '`' => {
+                let (value, end) = expand_dollar(&chars, i, expander)?;
+                match value {
+                    Some(value) if in_double_quotes => fields.push_str(&value),
+                    Some(value) => fields.push_unquoted(&value),
+                    None => fields.push(ch, in_double_quotes),
+                }
+                i = end;
+                continue;
+            }
+            _ => fields.push(ch, in_double_quotes),
+        }
+        i += 1;
+    }
+    Ok(())
+}
+
+// Expand the `$...` or backtick expression at `chars[start]`. Returns its value
+// (None when the `$` is literal) and where the expression ends.
+fn expand_dollar(chars: &[char], start: usize, expander: &mut dyn Expander) -> io::Result<(Option<String>, usize)> {
+    if chars[start] == '`' {
+        let end = scan_quoted(chars, start).unwrap_or(chars.len());
+        let body: String = chars[start + 1..end.max(start + 1)].iter().collect();
+        let body = body.strip_suffix('`').unwrap_or(&body);
+        // Inside backticks a backslash only escapes $, ` and itself
+        let command = body.replace("\\$", "$").replace("\\`", "`").replace("\\\\", "\\");
+        return Ok((Some(expander.command_output(&command)), end));
+    }
+
+    Ok(match chars.get(start + 1) {
+        Some('(') => {
+            let end = scan_quoted(chars, start).unwrap_or(chars.len());
+            if is_arithmetic(chars, start, end) {
+                let expression: String = chars[start + 3..end - 2].iter().collect();
+                return Ok((Some(arithmetic(&expression, expander)?.to_string()), end));
+            }
+            let command: String = chars[start + 2..end.saturating_sub(1).max(start + 2)].iter().collect();
+            (Some(expander.command_output(&command)), end)
+        }
+        Some('{') => {
+            let end = scan_quoted(chars, start).unwrap_or(chars.len());
+            let inner: String = chars[start + 2..end.saturating_sub(1).max(start + 2)].iter().collect();
+            (Some(expand_parameter(&inner, expander)?), end)
+        }
+        Some(&c @ ('?' | '#' | '@' | '*' | '$' | '!')) => (Some(expander.variable(&c.to_string()).unwrap_or_default()), start + 2),
+        // `$10` is `$1` followed by a 0; `${10}` is the tenth parameter
+        Some(&c) if c.is_ascii_digit() => (Some(expander.variable(&c.to_string()).unwrap_or_default()), start + 2),
+        Some(&c) if c.is_alphanumeric() || c == '_' => {
+            let end = chars[start + 1..]
+                .iter()
+                .position(|&c| !(c.is_alphanumeric() || c == '_'))
+                .map_or(chars.len(), |offset| start + 1 + offset);
+            let name: String = chars[start + 1..end].iter().collect();
+            (Some(expander.variable(&name).unwrap_or_default()), end)
+        }
+        _ => (None, start + 1),
+    })
+}
+
+// Is the `$(...)` from `start` to `end` an arithmetic expansion, `$((...))`? Its
+// inner parentheses must pair up with each other, unlike in `$((a) && (b))`.
+fn is_arithmetic(chars: &[char], start: usize, end: usize) -> bool {
+    if end < start + 5 || chars[start + 2] != '(' || chars[end - 2] != ')' {
+        return false;
+    }
+    let mut depth = 0;
+    for (i, &c) in chars.iter().enumerate().take(end - 1).skip(start + 2) {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            _ => {}
+        }
+        if depth == 0 {
+            return i == end - 2;
+        }
+    }
+    false
+}
+
+// The value of an arithmetic expression, after expanding parameters and command
+// substitutions in it as inside double quotes
+fn arithmetic(expression: &str, expander: &mut dyn Expander) -> io::Result<i64> {
+    let expression = expand_string(expression, expander)?;
+    evaluate(&expression, expander).map_err(|e| parameter_error(e.to_string()))
+}
+
+// Expand `${...}`, given the text between the braces:
+//   name               the value
+//   #name              its length in characters (`${#@}`: the number of parameters)
+//   name:-word         word if name is unset or empty; without the colon, only if unset
+//   name:=word         the same, also assigning word to name
+//   name:?message      an error if name is unset or empty
+//   name:+word         word if name is set and not empty, else nothing
+//   name#pattern       without the shortest prefix matching pattern; ## the longest
+//   name%pattern       without the shortest matching suffix; %% the longest
+//   name/pattern/word  the longest match of pattern replaced by word: `//` replaces
+//                      all of them, `/#` one at the start and `/%` one at the end
+//   name:offset:length a substring; a negative offset counts from the end, and
+//                      both are arithmetic expressions
+//   name^pattern       the first character in upper case; ^^ all of them, and
+//                      `,` and `,,` lower case; pattern limits the characters changed
+fn expand_parameter(inner: &str, expander: &mut dyn Expander) -> io::Result<String> {
+    let bad_substitution = || parameter_error(format!("${{{}}}: bad substitution", inner));
+
+    if let Some(name) = inner.strip_prefix('#').filter(|name| !name.is_empty()) {
+        if parameter_name_length(name) == name.len() {
+            return Ok(match name {
+                "@" | "*" => expander.positional_parameters().len(),
+                _ => expander.variable(name).unwrap_or_default().chars().count(),
+            }
+            .to_string());
+        }
+    }
+
+    let name_length = parameter_name_length(inner);
+    if name_length == 0 {
+        return Err(bad_substitution());
+    }
+    let (name, operation) = inner.split_at(name_length);
+    let value = expander.variable(name);
+
+    // With a colon, an empty value counts as unset
+    let colon = operation.starts_with(':') && matches!(operation[1..].chars().next(), Some('-' | '=' | '?' | '+'));
+    let test = if colon { &operation[1..] } else { operation };
+    let set = value.as_ref().is_some_and(|value| !(colon && value.is_empty()));
+    if let Some(op @ ('-' | '=' | '?' | '+')) = test.chars().next() {
+        let word = &test[1..];
+        return match (op, value) {
+            ('+', _) if set => expand_string(word, expander),
+            ('+', _) => Ok(String::new()),
+            (_, Some(value)) if set => Ok(value),
+            ('-', _) => expand_string(word, expander),
+            ('=', _) => {
+                let value = expand_string(word, expander)?;
+                expander.assign_variable(name, &value)?;
+                Ok(value)
+            }
+            _ => {
+                let message = match word {
+                    "" if colon => "parameter null or not set".to_string(),
+                    "" => "parameter not set".to_string(),
+                    word => expand_string(word, expander)?,
+                };
+                Err(parameter_error(format!("{}: {}", name, message)))
+            }
+        };
+    }
+
+    let value = value.unwrap_or_default();
+    if operation.is_empty() {
+        return Ok(value);
+    }
+    let operand = |prefix: &str| operation[prefix.len()..].to_string();
+    match operation {
+        _ if operation.starts_with("##") => Ok(remove_prefix(&value, &expand_pattern(&operand("##"), expander)?, true)),
+        _ if operation.starts_with('#') => Ok(remove_prefix(&value, &expand_pattern(&operand("#"), expander)?, false)),
+        _ if operation.starts_with("%%") => Ok(remove_suffix(&value, &expand_pattern(&operand("%%"), expander)?, true)),
+        _ if operation.starts_with('%') => Ok(remove_suffix(&value, &expand_pattern(&operand("%"), expander)?, false)),
+        _ if operation.starts_with('/') => {
+            let (mode, rest) = match operation[1..].chars().next() {
+                Some('/') => (Replace::All, &operation[2..]),
+                Some('#') => (Replace::Prefix, &operation[2..]),
+                Some('%') => (Replace::Suffix, &operation[2..]),
+                _ => (Replace::First, &operation[1..]),
+            };
+            let (pattern, replacement) = split_replacement(rest);
+            let pattern = expand_pattern(&pattern, expander)?;
+            let replacement = expand_string(&replacement, expander)?;
+            Ok(replace_matches(&value, &pattern, &replacement, mode))
+        }
+        _ if operation.starts_with('^') || operation.starts_with(',') => {
+            let upper = operation.starts_with('^');
+            let all = operation[1..].starts_with(&operation[..1]);
+            let pattern = expand_pattern(&operation[if all { 2 } else { 1 }..], expander)?;
+            Ok(change_case(&value, &pattern, upper, all))
+        }
+        _ if operation.starts_with(':') => {
+            let (offset, length) = match operation[1..].split_once(':') {
+                Some((offset, length)) => (offset, Some(length)),
+                None => (&operation[1..], None),
+            };
+            let offset = arithmetic(offset, expander)?;
+            let length_value = length.map(|length| arithmetic(length, expander)).transpose()?;
+            let negative_length = || parameter_error(format!("{}: substring expression < 0", length.unwrap_or_default().trim()));
+            match name {
+                "@" | "*" => {
+                    // $0 comes first, so that `${@:1}` starts at $1
+                    let mut parameters = vec![expander.variable("0").unwrap_or_default()];
+                    parameters.extend(expander.positional_parameters());
+                    let ifs = expander.variable("IFS");
+                    let separator = if name == "*" { star_separator(ifs.as_deref()) } else { " " };
+                    slice(&parameters, offset, length_value)
+                        .map(|parameters| parameters.join(separator))
+                        .ok_or_else(negative_length)
+                }
+                _ => {
+                    let chars: Vec<char> = value.chars().collect();
+                    slice(&chars, offset, length_value).map(|chars| chars.iter().collect()).ok_or_else(negative_length)
+                }
+            }
+        }
+        _ => Err(bad_substitution()),
+    }
+}
+
+// Length in bytes of the parameter name `text` starts with: a variable name, a
+// positional parameter or a special parameter; 0 when there is none
+fn parameter_name_length(text: &str) -> usize {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(c) if c.is_alphabetic() || c == '_' => {
+            text.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(text.len())
+        }
+        Some(c) if c.is_ascii_digit() => text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len()),
+        Some('?' | '#' | '@' | '*' | '$' | '!' | '-') => 1,
+        _ => 0,
+    }
+}
+
+// Byte offsets of the character boundaries of `text`, its end included
+fn boundaries(text: &str) -> Vec<usize> {
+    text.char_indices().map(|(index, _)| index).chain(std::iter::once(text.len())).collect()
+}
+
+fn remove_prefix(value: &str, pattern: &str, longest: bool) -> String {
+    let matches = |&&end: &&usize| pattern_matches(pattern, &value[..end]);
+    let bounds = boundaries(value);
+    let end = if longest { bounds.iter().rev().find(matches) } else { bounds.iter().find(matches) };
+    end.map_or_else(|| value.to_string(), |&end| value[end..].to_string())
+}
+
+fn remove_suffix(value: &str, pattern: &str, longest: bool) -> String {
+    let matches = |&&start: &&usize| pattern_matches(pattern, &value[start..]);
+    let bounds = boundaries(value);
+    let start = if longest { bounds.iter().find(matches) } else { bounds.iter().rev().find(matches) };
+    start.map_or_else(|| value.to_string(), |&start| value[..start].to_string())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Replace {
+    First,
+    All,
+    Prefix,
+    Suffix,
+}
+
+// `pattern/replacement` split at the first `/` that is not quoted or escaped
+fn split_replacement(text: &str) -> (String, String) {
+    let chars: Vec<char> = text.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 1,
+            '\'' | '"' | '`' => {
+                i = scan_quoted(&chars, i).unwrap_or(chars.len());
+                continue;
+            }
+            '$' if matches!(chars.get(i + 1), Some('(') | Some('{')) => {
+                i = scan_quoted(&chars, i).unwrap_or(chars.len());
+                continue;
+            }
+            '/' => return (chars[..i].iter().collect(), chars[i + 1..].iter().collect()),
+            _ => {}
+        }
+        i += 1;
+    }
+    (text.to_string(), String::new())
+}
+
+// Replace the longest matches of a pattern, as `${name/pattern/word}` does
+fn replace_matches(value: &str, pattern: &str, replacement: &str, mode: Replace) -> String {
+    let bounds = boundaries(value);
+    match mode {
+        Replace::Prefix => match bounds.iter().rev().find(|&&end| pattern_matches(pattern, &value[..end])) {
+            Some(&end) => format!("{}{}", replacement, &value[end..]),
+            None => value.to_string(),
+        },
+        Replace::Suffix => match bounds.iter().find(|&&start| pattern_matches(pattern, &value[start..])) {
+            Some(&start) => format!("{}{}", &value[..start], replacement),
+            None => value.to_string(),
+        },
+        Replace::First | Replace::All => {
+            if pattern.is_empty() {
+                return value.to_string();
+            }
+            let mut result = String::new();
+            let mut index = 0;
+            while index < bounds.len() - 1 {
+                let start = bounds[index];
+                let found = bounds[index + 1..]
+                    .iter()
+                    .rposition(|&end| pattern_matches(pattern, &value[start..end]))
+                    .map(|offset| index + 1 + offset);
+                match found {
+                    Some(end_index) => {
+                        result.push_str(replacement);
+                        index = end_index;
+                        if mode == Replace::First {
+                            break;
+                        }
+                    }
+                    None => {
+                        result.push_str(&value[start..bounds[index + 1]]);
+                        index += 1;
+                    }
+                }
+            }
+            result.push_str(&value[bounds[index]..]);
+            result
+        }
+    }
+}
+
+fn change_case(value: &str, pattern: &str, upper: bool, all: bool) -> String {
+    value
+        .chars()
+        .enumerate()
+        .map(|(index, c)| {
+            let selected = (all || index == 0) && (pattern.is_empty() || pattern_matches(pattern, &c.to_string()));
+            match (selected, upper) {
+                (false, _) => c.to_string(),
+                (true, true) => c.to_uppercase().to_string(),
+                (true, false) => c.to_lowercase().to_string(),
+            }
+        })
+        .collect()
+}
+
+// The `offset:length` slice of a list of characters or parameters; None when a
+// negative length would end before the offset
+fn slice<T: Clone>(items: &[T], offset: i64, length: Option<i64>) -> Option<Vec<T>> {
+    let count = items.len() as i64;
+    let start = if offset < 0 { count.saturating_add(offset) } else { offset.min(count) };
+    // As in bash, a negative offset before the first item gives nothing
+    if start < 0 {
+        return Some(Vec::new());
+    }
+    let end = match length {
+        None => count,
+        Some(length) if length < 0 => count.saturating_add(length),
+        Some(length) => start.saturating_add(length).min(count),
+    };
+    (end >= start).then(|| items[start as usize..end as usize].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Variables from a fixed list; substitutions echo their command back
+    struct Fixed {
+        commands: Vec<String>,
+    }
+
+    impl Expander for Fixed {
+        fn variable(&self, name: &str) -> Option<String> {
+            match name {
+                "HOME" => Some("/home/ada".to_string()),
+                "?" => Some("1".to_string()),
+                "FILES" => Some(" a.txt  b.txt\n".to_string()),
+                "1" => Some("one".to_string()),
+                "#" => Some("2".to_string()),
+                "@" | "*" => Some("one two words".to_string()),
+                _ => None,
+            }
+        }
+
+        fn command_output(&mut self, command: &str) -> String {
+            self.commands.push(command.to_string());
+            match command {
+                "ls" => "Cargo.toml\nsrc".to_string(),
+                other => format!("<{}>", other),
+            }
+        }
+    }
+
+    // Positional parameters for the tests that need them
+    struct WithParameters(Vec<&'static str>);
+
+    impl Expander for WithParameters {
+        fn variable(&self, name: &str) -> Option<String> {
+            match name {
+                "@" | "*" => Some(self.0.join(" ")),
+                _ => None,
+            }
+        }
+
+        fn command_output(&mut self, _command: &str) -> String {
+            String::new()
+        }
+
+        fn positional_parameters(&self) -> Vec<String> {
+            self.0.iter().map(|p| p.to_string()).collect()
+        }
+    }
+
+    // Variables that `${name:=word}` can set
+    struct Variables(std::collections::HashMap<String, String>);
+
+    impl Expander for Variables {
+        fn variable(&self, name: &str) -> Option<String> {
+            self.0.get(name).cloned()
+        }
+
+        fn command_output(&mut self, command: &str) -> String {
+            format!("<{}>", command)
+        }
+
+        fn positional_parameters(&self) -> Vec<String> {
+            vec!["a".to_string(), "b".to_string(), "c".to_string()]
+        }
+
+        fn assign_variable(&mut self, name: &str, value: &str) -> io::Result<()> {
+            self.0.insert(name.to_string(), value.to_string());
+            Ok(())
+        }
+    }
+
+    fn expand(word: &str) -> Vec<String> {
+        expand_word(word, &mut Fixed { commands: Vec::new() }).unwrap()
+    }
+
+    #[test]
+    fn test_variables_and_quotes() {
+        assert_eq!(expand("$HOME/src"), vec!["/home/ada/src"]);
+        assert_eq!(expand("status=$?"), vec!["status=1"]);
+        assert_eq!(expand("\"${HOME} and $MISSING\""), vec!["/home/ada and "]);
+        assert_eq!(expand("'$HOME'"), vec!["$HOME"]);
+        assert_eq!(expand("a\\ b\\$HOME"), vec!["a b$HOME"]);
+        assert_eq!(expand("\"say \\\"hi\\\" \\n\""), vec!["say \"hi\" \\n"]);
+        assert_eq!(expand("cost:$"), vec!["cost:$"]);
+        assert_eq!(expand("$MISSING"), Vec::<String>::new());
+        assert_eq!(expand("\"\""), vec![""]);
+    }
+
+    #[test]
+    fn test_command_substitution_and_splitting() {
+        assert_eq!(expand("$(ls)"), vec!["Cargo.toml", "src"]);
+        assert_eq!(expand("\"$(ls)\""), vec!["Cargo.toml\nsrc"]);
+        assert_eq!(expand("x$FILES"), vec!["x", "a.txt", "b.txt"]);
+        assert_eq!(expand("`ls`"), vec!["Cargo.toml", "src"]);
+
+        let mut fixed = Fixed { commands: Vec::new() };
+        assert_eq!(expand_word("$(echo \"$(pwd)\" ')')", &mut fixed).unwrap(), vec!["<echo", "\"$(pwd)\"", "')'>"]);
+        assert_eq!(fixed.commands, vec!["echo \"$(pwd)\" ')'"]);
+        assert_eq!(expand_word("`echo \\`pwd\\``", &mut fixed).unwrap(), vec!["<echo", "`pwd`>"]);
+
+        assert_eq!(expand_string("$FILES", &mut fixed).unwrap(), " a.txt  b.txt\n");
+    }
+
+    #[test]
+    fn test_splitting_on_other_separators() {
+        let values = [("IFS", ": "), ("PATHS", "a::b"), ("EDGES", ":a:"), ("MIXED", " a : b  c")];
+        let mut variables = Variables(values.into_iter().map(|(name, value)| (name.to_string(), value.to_string())).collect());
+        let mut expand = |word: &str| expand_word(word, &mut variables).unwrap();
+
+        // Only IFS whitespace collapses; `:` ends a field even when it is empty
+        assert_eq!(expand("$PATHS"), vec!["a", "", "b"]);
+        assert_eq!(expand("$EDGES"), vec!["", "a"]);
+        assert_eq!(expand("$MIXED"), vec!["a", "b", "c"]);
+        assert_eq!(expand("x$EDGES\"y\""), vec!["x", "a", "y"]);
+    }
+
+    #[test]
+    fn test_positional_parameters() {
+        assert_eq!(expand("$1:$#:$10"), vec!["one:2:one0"]);
+        assert_eq!(expand("${1}"), vec!["one"]);
+
+        let mut parameters = WithParameters(vec!["a b", "c"]);
+        assert_eq!(expand_word("\"$@\"", &mut parameters).unwrap(), vec!["a b", "c"]);
+        assert_eq!(expand_word("\"x$@y\"", &mut parameters).unwrap(), vec!["xa b", "cy"]);
+        assert_eq!(expand_word("$@", &mut parameters).unwrap(), vec!["a", "b", "c"]);
+        assert_eq!(expand_word("\"$*\"", &mut parameters).unwrap(), vec!["a b c"]);
+        assert_eq!(expand_word("\"$@\"", &mut WithParameters(vec![])).unwrap(), Vec::<String>::new());
+        assert_eq!(expand_word("\"\"", &mut WithParameters(vec![])).unwrap(), vec![""]);
+    }
+
+    #[test]
+    fn test_parameter_operators() {
+        let mut variables = Variables(
+            [("FILE", "/usr/src/archive.tar.gz"), ("EMPTY", ""), ("NAME", "ada lovelace"), ("0", "aish")]
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        );
+        let mut expand = |word: &str| expand_string(word, &mut variables).unwrap();
+
+        assert_eq!(expand("${MISSING:-a $FILE}"), "a /usr/src/archive.tar.gz");
+        assert_eq!(expand("${EMPTY:-x}/${EMPTY-x}/${MISSING-x}"), "x//x");
+        assert_eq!(expand("${EMPTY:+x}/${EMPTY+x}/${FILE:+set}"), "/x/set");
+        assert_eq!(expand("${#FILE} ${#EMPTY} ${#@}"), "23 0 3");
+        assert_eq!(expand("${FILE#*/} ${FILE##*/}"), "usr/src/archive.tar.gz archive.tar.gz");
+        assert_eq!(expand("${FILE%.*} ${FILE%%.*}"), "/usr/src/archive.tar /usr/src/archive");
+        assert_eq!(expand("${FILE/a/A} ${FILE//a/A}"), "/usr/src/Archive.tar.gz /usr/src/Archive.tAr.gz");
+        assert_eq!(expand("${FILE/#\\/usr/~} ${FILE/%gz/bz2} ${FILE//[.\\/]}"), "~/src/archive.tar.gz /usr/src/archive.tar.bz2 usrsrcarchivetargz");
+        assert_eq!(expand("${NAME^} ${NAME^^} ${NAME^^[al]}"), "Ada lovelace ADA LOVELACE AdA LoveLAce");
+        assert_eq!(expand("${FILE:5:3} ${FILE:9} ${FILE: -2} ${FILE:1:-3}"), "src archive.tar.gz gz usr/src/archive.tar");
+        assert_eq!(expand("${@:2} ${@:0:2} ${@: -1}"), "b c aish a c");
+        assert_eq!(expand("${FILE:1:9223372036854775807}"), "usr/src/archive.tar.gz");
+        assert_eq!(expand("[${FILE: -9223372036854775807:2}] [${FILE: -30}] [${@: -5}]"), "[] [] []");
+        assert_eq!(expand("${MISSING:-${FILE##*.}}"), "gz");
+
+        assert_eq!(expand("${NEW:=value} $NEW ${NEW:=other}"), "value value value");
+        assert_eq!(expand("${EMPTY=kept}/${EMPTY:=filled}/$EMPTY"), "/filled/filled");
+    }
+
+    #[test]
+    fn test_parameter_errors() {
+        let mut variables = Variables([("EMPTY".to_string(), String::new())].into_iter().collect());
+        let mut error = |word: &str| {
+            let error = expand_word(word, &mut variables).unwrap_err();
+            assert!(is_parameter_error(&error));
+            error.to_string()
+        };
+
+        assert_eq!(error("${MISSING:?is required}"), "MISSING: is required");
+        assert_eq!(error("${EMPTY:?}"), "EMPTY: parameter null or not set");
+        assert_eq!(error("${MISSING?}"), "MISSING: parameter not set");
+        assert_eq!(error("${EMPTY!x}"), "${EMPTY!x}: bad substitution");
+        assert_eq!(error("${}"), "${}: bad substitution");
+        assert_eq!(error("${EMPTY:1/0}"), "1/0: division by 0 (error token is \"0\")");
+        assert_eq!(error("${EMPTY:0:-1}"), "-1: substring expression < 0");
+        assert_eq!(expand_word("${EMPTY?}", &mut variables).unwrap(), Vec::<String>::new());
+        // Without assign_variable, `:=` cannot set anything
+        assert!(expand_word("${X:=1}", &mut Fixed { commands: Vec::new() }).is_err());
+    }
+
+    #[test]
+    fn test_arithmetic_expansion() {
+        let mut variables = Variables([("n".to_string(), "4".to_string())].into_iter().collect());
+        let mut expand = |word: &str| expand_string(word, &mut variables).unwrap();
+
+        assert_eq!(expand("$((1 + 2 * 3))"), "7");
+        assert_eq!(expand("\"$(( n * ${#n} + 1 ))\""), "5");
+        assert_eq!(expand("$((m = n ** 2)) $m"), "16 16");
+        assert_eq!(expand("$((n > 3 ? (n - 1) : 0))"), "3");
+        // Parentheses that do not pair up inside make a command substitution
+        assert_eq!(expand("$((echo a) && (echo b))"), "<(echo a) && (echo b)>");
+        assert_eq!(expand("${m:n - 3:1+0}"), "6");
+    }
+
+    #[test]
+    fn test_here_document_expansion() {
+        let mut expander = Fixed { commands: Vec::new() };
+        assert_eq!(
+            expand_here_document("home: \"$HOME\" '$1'\n\\$HOME \\t $(ls)\nlong \\\nline\n", &mut expander).unwrap(),
+            "home: \"/home/ada\" 'one'\n$HOME \\t Cargo.toml\nsrc\nlong line\n"
+        );
+    }
+
+    #[test]
+    fn test_brace_expansion() {
+        assert_eq!(expand_braces("file.{rs,toml}"), vec!["file.rs", "file.toml"]);
+        assert_eq!(expand_braces("{a,b{1,2}}x"), vec!["ax", "b1x", "b2x"]);
+        assert_eq!(expand_braces("{1..3}{a,b}"), vec!["1a", "1b", "2a", "2b", "3a", "3b"]);
+        assert_eq!(expand_braces("{3..1}"), vec!["3", "2", "1"]);
+        assert_eq!(expand_braces("{01..10..3}"), vec!["01", "04", "07", "10"]);
+        assert_eq!(expand_braces("{a..e..2}"), vec!["a", "c", "e"]);
+        assert_eq!(expand_braces("{a..Z}"), vec!["a", "\\`", "\\_", "\\^", "\\]", "\\\\", "\\[", "Z"]);
+        assert_eq!(expand("{a..Z}"), vec!["a", "`", "_", "^", "]", "\\", "[", "Z"]);
+        assert_eq!(expand_braces("{1..3..9223372036854775807}"), vec!["1"]);
+        assert_eq!(expand_braces("{9223372036854775806..9223372036854775807}"), vec!["9223372036854775806", "9223372036854775807"]);
+        assert_eq!(expand_braces("{1..2..-9223372036854775808}"), vec!["{1..2..-9223372036854775808}"]);
+        assert_eq!(expand_braces("{}"), vec!["{}"]);
+        assert_eq!(expand_braces("{x}"), vec!["{x}"]);
+        assert_eq!(expand_braces("${HOME}"), vec!["${HOME}"]);
+        assert_eq!(expand_braces("'{a,b}'"), vec!["'{a,b}'"]);
+        assert_eq!(expand_braces("\\{a,b}"), vec!["\\{a,b}"]);
+        assert_eq!(expand_braces("{'a,b',c}"), vec!["'a,b'", "c"]);
+    }
+
+    #[test]
+    fn test_quoted_glob_characters_stay_literal() {
+        assert_eq!(expand("'*.rs'"), vec!["*.rs"]);
+        assert_eq!(expand("\"*\""), vec!["*"]);
+        assert_eq!(expand("\\*.none"), vec!["*.none"]);
+        // A pattern without matches is kept as typed
+        assert_eq!(expand("*.none-such-extension"), vec!["*.none-such-extension"]);
+        assert_eq!(expand("\"src\"/*.none"), vec!["src/*.none"]);
+
+        let mut fixed = Fixed { commands: Vec::new() };
+        assert_eq!(expand_pattern("'*'$HOME[ab]*", &mut fixed).unwrap(), "\\*/home/ada[ab]*");
+    }
+}
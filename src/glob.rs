@@ -0,0 +1,284 @@
+use std::fs;
+use std::path::Path;
+
+/// Shell options that change pathname expansion, set with `shopt -s NAME`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct GlobOptions {
+    /// A pattern without matches expands to nothing instead of itself
+    pub nullglob: bool,
+    /// A pattern without matches is an error and the command does not run
+    pub failglob: bool,
+    /// `*` and `?` also match names starting with a dot
+    pub dotglob: bool,
+}
+
+impl GlobOptions {
+    pub const NAMES: [&'static str; 3] = ["dotglob", "failglob", "nullglob"];
+
+    pub fn get(&self, name: &str) -> Option<bool> {
+        match name {
+            "nullglob" => Some(self.nullglob),
+            "failglob" => Some(self.failglob),
+            "dotglob" => Some(self.dotglob),
+            _ => None,
+        }
+    }
+
+    pub fn set(&mut self, name: &str, value: bool) -> Result<(), String> {
+        let option = match name {
+            "nullglob" => &mut self.nullglob,
+            "failglob" => &mut self.failglob,
+            "dotglob" => &mut self.dotglob,
+            _ => return Err(format!("{}: invalid shell option name", name)),
+        };
+        *option = value;
+        Ok(())
+    }
+}
+
+// Does the pattern contain an unescaped `*`, `?` or a complete `[...]` class?
+pub fn is_glob_pattern(pattern: &str) -> bool {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 1,
+            '*' | '?' => return true,
+            '[' if match_class(&chars, i, ' ').is_some() => return true,
+            _ => {}
+        }
+        i += 1;
+    }
+    false
+}
+
+// Remove the backslashes that protect literal characters in a pattern
+pub fn unescape(pattern: &str) -> String {
+    let mut result = String::new();
+    let mut chars = pattern.chars();
+    while let Some(ch) = chars.next() {
+        if ch == '\\' {
+            result.extend(chars.next());
+        } else {
+            result.push(ch);
+        }
+    }
+    result
+}
+
+// Match a whole string against a shell pattern (`*`, `?`, `[...]`, `\x`)
+pub fn pattern_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    matches_at(&pattern, 0, &text, 0)
+}
+
+fn matches_at(pattern: &[char], mut p: usize, text: &[char], mut t: usize) -> bool {
+    // Position to retry from after the last `*`: (pattern index, text index)
+    let mut star: Option<(usize, usize)> = None;
+
+    loop {
+        if p < pattern.len() {
+            let step = match pattern[p] {
+                '*' => {
+                    star = Some((p + 1, t));
+                    p += 1;
+                    continue;
+                }
+                '?' if t < text.len() => Some(1),
+                '[' if t < text.len() => match match_class(pattern, p, text[t]) {
+                    Some((true, end)) => Some(end - p),
+                    Some((false, _)) => None,
+                    // An unclosed bracket is an ordinary character
+                    None => (text[t] == '[').then_some(1),
+                },
+                '\\' if p + 1 < pattern.len() => {
+                    (t < text.len() && text[t] == pattern[p + 1]).then_some(2)
+                }
+                ch if t < text.len() && text[t] == ch => Some(1),
+                _ => None,
+            };
+            if let Some(length) = step {
+                p += length;
+                t += 1;
+                continue;
+            }
+        } else if t == text.len() {
+            return true;
+        }
+
+        // Mismatch: let the last `*` swallow one more character
+        match star {
+            Some((star_p, star_t)) if star_t < text.len() => {
+                star = Some((star_p, star_t + 1));
+                p = star_p;
+                t = star_t + 1;
+            }
+            _ => return false,
+        }
+    }
+}
+
+// Match `ch` against the bracket expression at `pattern[start]`. Returns whether it
+// matched and the index after the closing `]`, or None when the bracket is not closed.
+fn match_class(pattern: &[char], start: usize, ch: char) -> Option<(bool, usize)> {
+    let mut i = start + 1;
+    let negated = matches!(pattern.get(i), Some('!') | Some('^'));
+    if negated {
+        i += 1;
+    }
+
+    let mut matched = false;
+    let mut first = true;
+    while i < pattern.len() {
+        let c = pattern[i];
+        if c == ']' && !first {
+            return Some((matched != negated, i + 1));
+        }
+        first = false;
+
+        // [:alpha:] and friends
+        if c == '[' && pattern.get(i + 1) == Some(&':') {
+            let rest: String = pattern[i + 2..].iter().collect();
+            if let Some(end) = rest.find(":]") {
+                matched |= class_contains(&rest[..end], ch);
+                i += 2 + rest[..end].chars().count() + 2;
+                continue;
+            }
+        }
+
+        let (low, next) = if c == '\\' && i + 1 < pattern.len() {
+            (pattern[i + 1], i + 2)
+        } else {
+            (c, i + 1)
+        };
+        if pattern.get(next) == Some(&'-') && pattern.get(next + 1).is_some_and(|&high| high != ']') {
+            let high = pattern[next + 1];
+            matched |= low <= ch && ch <= high;
+            i = next + 2;
+        } else {
+            matched |= low == ch;
+            i = next;
+        }
+    }
+    None
+}
+
+fn class_contains(class: &str, ch: char) -> bool {
+    match class {
+        "alpha" => ch.is_alphabetic(),
+        "digit" => ch.is_ascii_digit(),
+        "alnum" => ch.is_alphanumeric(),
+        "upper" => ch.is_uppercase(),
+        "lower" => ch.is_lowercase(),
+        "space" => ch.is_whitespace(),
+        "blank" => ch == ' ' || ch == '\t',
+        "punct" => ch.is_ascii_punctuation(),
+        "xdigit" => ch.is_ascii_hexdigit(),
+        "cntrl" => ch.is_control(),
+        "print" => !ch.is_control(),
+        "graph" => !ch.is_control() && !ch.is_whitespace(),
+        _ => false,
+    }
+}
+
+// Pathnames matching a pattern, sorted. Each `/`-separated component is matched
+// against the entries of the directories found so far; names starting with a dot
+// only match a pattern that starts with a literal dot, unless dotglob is set.
+pub fn glob(pattern: &str, options: &GlobOptions) -> Vec<String> {
+    let dirs_only = pattern.ends_with('/');
+    let components: Vec<&str> = pattern.split('/').filter(|c| !c.is_empty()).collect();
+    let mut paths = vec![if pattern.starts_with('/') { "/".to_string() } else { String::new() }];
+
+    for (index, component) in components.iter().enumerate() {
+        let last = index + 1 == components.len();
+        let mut next = Vec::new();
+
+        for base in &paths {
+            if !is_glob_pattern(component) {
+                next.push(format!("{}{}", base, unescape(component)));
+                continue;
+            }
+
+            let dir = if base.is_empty() { "." } else { base.as_str() };
+            let Ok(entries) = fs::read_dir(dir) else {
+                continue;
+            };
+            let hidden_allowed = options.dotglob || component.starts_with('.') || component.starts_with("\\.");
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if name.starts_with('.') && !hidden_allowed {
+                    continue;
+                }
+                if pattern_matches(component, &name) {
+                    next.push(format!("{}{}", base, name));
+                }
+            }
+        }
+
+        paths = next
+            .into_iter()
+            .filter(|path| !last || dirs_only || Path::new(path).symlink_metadata().is_ok())
+            .filter(|path| (last && !dirs_only) || Path::new(path).is_dir())
+            .map(|path| if last { path } else { path + "/" })
+            .collect();
+    }
+
+    if dirs_only {
+        paths = paths.into_iter().map(|path| path + "/").collect();
+    }
+    paths.sort();
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pattern_matching() {
+        assert!(pattern_matches("*.rs", "main.rs"));
+        assert!(!pattern_matches("*.rs", "main.rs.bak"));
+        assert!(pattern_matches("a*b*c", "aXXbYYbc"));
+        assert!(pattern_matches("file?.txt", "file1.txt"));
+        assert!(pattern_matches("[a-c]x", "bx"));
+        assert!(!pattern_matches("[!a-c]x", "bx"));
+        assert!(pattern_matches("[]]", "]"));
+        assert!(pattern_matches("[[:digit:]][[:upper:]]", "7Q"));
+        assert!(pattern_matches("\\*", "*"));
+        assert!(!pattern_matches("\\*", "x"));
+        assert!(pattern_matches("[", "["));
+
+        assert!(is_glob_pattern("*.tmp"));
+        assert!(is_glob_pattern("[ab]"));
+        assert!(!is_glob_pattern("["));
+        assert!(!is_glob_pattern("\\*.tmp"));
+    }
+
+    #[test]
+    fn test_glob_directory_tree() {
+        let root = std::env::temp_dir().join(format!("aish-glob-{}", std::process::id()));
+        for path in ["src/main.rs", "src/shell.rs", "src/notes.txt", "docs/a.md", ".hidden.rs", "top.rs"] {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        let root_str = root.to_str().unwrap();
+        let relative = |paths: Vec<String>| -> Vec<String> {
+            paths.iter().map(|path| path.strip_prefix(root_str).unwrap().to_string()).collect()
+        };
+        let options = GlobOptions::default();
+
+        assert_eq!(relative(glob(&format!("{}/*.rs", root_str), &options)), vec!["/top.rs"]);
+        assert_eq!(relative(glob(&format!("{}/.*.rs", root_str), &options)), vec!["/.hidden.rs"]);
+        assert_eq!(relative(glob(&format!("{}/*/*.rs", root_str), &options)), vec!["/src/main.rs", "/src/shell.rs"]);
+        assert_eq!(relative(glob(&format!("{}/*/", root_str), &options)), vec!["/docs/", "/src/"]);
+        assert_eq!(relative(glob(&format!("{}/src/[mn]*", root_str), &options)), vec!["/src/main.rs", "/src/notes.txt"]);
+        assert!(glob(&format!("{}/*.none", root_str), &options).is_empty());
+
+        let dotglob = GlobOptions { dotglob: true, ..GlobOptions::default() };
+        assert_eq!(relative(glob(&format!("{}/*.rs", root_str), &dotglob)), vec!["/.hidden.rs", "/top.rs"]);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
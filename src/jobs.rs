@@ -0,0 +1,485 @@
+use nix::errno::Errno;
+use nix::sys::signal::{kill, killpg, signal, SigHandler, Signal};
+use nix::sys::termios::{tcgetattr, tcsetattr, SetArg, Termios};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{getpgrp, setpgid, tcgetpgrp, tcsetpgrp, Pid};
+use std::io;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Set by the SIGCHLD handler; the job table is checked when it is set
+static CHILD_CHANGED: AtomicBool = AtomicBool::new(false);
+
+// Set by the SIGINT handler installed by `catch_interrupts`
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// Signals that the terminal sends to the foreground job: an interactive shell
+// ignores them, and its jobs get the default actions back
+const JOB_CONTROL_SIGNALS: [Signal; 4] = [Signal::SIGQUIT, Signal::SIGTSTP, Signal::SIGTTIN, Signal::SIGTTOU];
+
+/// What a process of a job is doing, as far as the shell has heard
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProcessState {
+    Running,
+    Stopped(Signal),
+    Exited(i32),
+    Killed(Signal),
+}
+
+#[derive(Debug)]
+pub struct Process {
+    // None for a stage that could not be started
+    pub pid: Option<Pid>,
+    pub state: ProcessState,
+}
+
+/// A pipeline or command started by the shell, in the foreground or with `&`
+#[derive(Debug)]
+pub struct Job {
+    pub id: usize,
+    // The process group of a job started with job control, which gets its signals
+    // as one; without job control the processes are signalled one by one
+    pub pgid: Option<Pid>,
+    pub processes: Vec<Process>,
+    pub command: String,
+    // Terminal modes of a job stopped in the foreground, given back by `fg`
+    pub modes: Option<Termios>,
+    // The last change of state has been reported to the user
+    pub notified: bool,
+}
+
+impl Job {
+    pub fn is_done(&self) -> bool {
+        self.processes
+            .iter()
+            .all(|process| matches!(process.state, ProcessState::Exited(_) | ProcessState::Killed(_)))
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        !self.is_done() && self.processes.iter().all(|process| process.state != ProcessState::Running)
+    }
+
+    // The status of the last process, or with pipefail of the last one that failed
+    pub fn status(&self, pipefail: bool) -> i32 {
+        let mut statuses = self.processes.iter().rev().map(|process| match process.state {
+            ProcessState::Running => 0,
+            ProcessState::Exited(code) => code,
+            ProcessState::Stopped(signal) | ProcessState::Killed(signal) => 128 + signal as i32,
+        });
+        if pipefail {
+            statuses.find(|status| *status != 0).unwrap_or(0)
+        } else {
+            statuses.next().unwrap_or(0)
+        }
+    }
+
+    pub fn last_pid(&self) -> Option<Pid> {
+        self.processes.iter().rev().find_map(|process| process.pid)
+    }
+
+    fn running_pids(&self) -> impl Iterator<Item = Pid> + '_ {
+        self.processes.iter().filter(|process| !matches!(process.state, ProcessState::Exited(_) | ProcessState::Killed(_))).filter_map(|process| process.pid)
+    }
+
+    // Record what waitpid reported for one of the job's processes; false when the
+    // process is not part of this job
+    pub fn record(&mut self, status: WaitStatus) -> bool {
+        let (pid, state) = match status {
+            WaitStatus::Exited(pid, code) => (pid, ProcessState::Exited(code)),
+            WaitStatus::Signaled(pid, signal, _) => (pid, ProcessState::Killed(signal)),
+            WaitStatus::Stopped(pid, signal) => (pid, ProcessState::Stopped(signal)),
+            WaitStatus::Continued(pid) => (pid, ProcessState::Running),
+            _ => return false,
+        };
+        match self.processes.iter_mut().find(|process| process.pid == Some(pid)) {
+            Some(process) => {
+                process.state = state;
+                self.notified = false;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Wait for the processes still running; `until_stopped` also returns when the
+    // job stops, as a foreground job does on Ctrl+Z
+    pub fn wait(&mut self, until_stopped: bool) -> io::Result<()> {
+        let flags = until_stopped.then_some(WaitPidFlag::WUNTRACED);
+        while !(self.is_done() || until_stopped && self.is_stopped()) {
+            let Some(pid) = self.processes.iter().find(|process| process.state == ProcessState::Running).and_then(|process| process.pid) else {
+                break;
+            };
+            // With a process group, whichever process changes first is reported
+            let target = match self.pgid {
+                Some(pgid) if until_stopped => Pid::from_raw(-pgid.as_raw()),
+                _ => pid,
+            };
+            match waitpid(target, flags) {
+                Ok(status) => {
+                    self.record(status);
+                }
+                Err(Errno::EINTR) if INTERRUPTED.load(Ordering::SeqCst) => {
+                    return Err(io::Error::from(io::ErrorKind::Interrupted))
+                }
+                Err(Errno::EINTR) => {}
+                // Already collected elsewhere: nothing more can be learned about it
+                Err(Errno::ECHILD) => self.forget(pid),
+                Err(e) => return Err(io::Error::from(e)),
+            }
+        }
+        Ok(())
+    }
+
+    fn forget(&mut self, pid: Pid) {
+        for process in self.processes.iter_mut().filter(|process| process.pid == Some(pid)) {
+            process.state = ProcessState::Exited(0);
+        }
+    }
+
+    // Send a signal to the whole job
+    pub fn signal(&self, signal: Signal) -> io::Result<()> {
+        match self.pgid {
+            Some(pgid) => killpg(pgid, signal)?,
+            None => {
+                for pid in self.running_pids() {
+                    kill(pid, signal)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Let a stopped job go on
+    pub fn resume(&mut self) -> io::Result<()> {
+        for process in self.processes.iter_mut().filter(|process| matches!(process.state, ProcessState::Stopped(_))) {
+            process.state = ProcessState::Running;
+        }
+        self.signal(Signal::SIGCONT)
+    }
+
+    // "Running", "Stopped", "Done", "Exit 2", "Terminated", ... as in `jobs`
+    pub fn state_description(&self) -> String {
+        let last = self.processes.last().map(|process| process.state);
+        if !self.is_done() {
+            return if self.is_stopped() { "Stopped" } else { "Running" }.to_string();
+        }
+        match last {
+            Some(ProcessState::Killed(signal)) => signal_description(signal).to_string(),
+            Some(ProcessState::Exited(code)) if code != 0 => format!("Exit {}", code),
+            _ => "Done".to_string(),
+        }
+    }
+}
+
+/// The shell's jobs, numbered from 1 in the order they started
+#[derive(Debug, Default)]
+pub struct JobTable {
+    jobs: Vec<Job>,
+    // Job ids from least to most recently started or stopped: the last one is the
+    // current job (`%+`) and the one before it the previous job (`%-`)
+    recent: Vec<usize>,
+}
+
+impl JobTable {
+    pub fn add(&mut self, pgid: Option<Pid>, processes: Vec<Process>, command: String) -> usize {
+        let id = self.jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1;
+        self.jobs.push(Job { id, pgid, processes, command, modes: None, notified: false });
+        self.make_current(id);
+        id
+    }
+
+    pub fn get(&self, id: usize) -> Option<&Job> {
+        self.jobs.iter().find(|job| job.id == id)
+    }
+
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut Job> {
+        self.jobs.iter_mut().find(|job| job.id == id)
+    }
+
+    pub fn remove(&mut self, id: usize) -> Option<Job> {
+        self.recent.retain(|recent| *recent != id);
+        let index = self.jobs.iter().position(|job| job.id == id)?;
+        Some(self.jobs.remove(index))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Job> {
+        self.jobs.iter()
+    }
+
+    pub fn ids(&self) -> Vec<usize> {
+        self.jobs.iter().map(|job| job.id).collect()
+    }
+
+    pub fn make_current(&mut self, id: usize) {
+        self.recent.retain(|recent| *recent != id);
+        self.recent.push(id);
+    }
+
+    pub fn current(&self) -> Option<usize> {
+        self.recent.last().copied()
+    }
+
+    // The job a job spec names: `%n`, `%+` (or `%%` or `%`), `%-`, or `%text` for
+    // the job whose command starts with text; `n` alone is a job number as well
+    pub fn find(&self, spec: &str) -> Result<usize, String> {
+        let name = spec.strip_prefix('%').unwrap_or(spec);
+        let found = match name {
+            "" | "%" | "+" => self.current(),
+            "-" => self.recent.iter().rev().nth(1).copied().or(self.current()),
+            _ => match name.parse::<usize>() {
+                Ok(id) => self.get(id).map(|job| job.id),
+                Err(_) => {
+                    let mut matching = self.jobs.iter().filter(|job| job.command.starts_with(name));
+                    match (matching.next(), matching.next()) {
+                        (Some(job), None) => Some(job.id),
+                        (Some(_), Some(_)) => return Err(format!("{}: ambiguous job spec", spec)),
+                        _ => None,
+                    }
+                }
+            },
+        };
+        found.ok_or_else(|| format!("{}: no such job", spec))
+    }
+
+    // The job a process belongs to
+    pub fn find_pid(&self, pid: Pid) -> Option<usize> {
+        self.jobs.iter().find(|job| job.processes.iter().any(|process| process.pid == Some(pid))).map(|job| job.id)
+    }
+
+    // `[1]+  Running                 sleep 10 &`; with `long`, the process ids too
+    pub fn describe(&self, job: &Job, long: bool) -> String {
+        let marker = if Some(job.id) == self.current() {
+            '+'
+        } else if self.recent.iter().rev().nth(1) == Some(&job.id) {
+            '-'
+        } else {
+            ' '
+        };
+        let state = job.state_description();
+        let background = if state == "Running" { " &" } else { "" };
+        if long {
+            let pids: Vec<String> = job.processes.iter().filter_map(|process| process.pid).map(|pid| pid.to_string()).collect();
+            format!("[{}]{} {} {:<24}{}{}", job.id, marker, pids.join(" "), state, job.command, background)
+        } else {
+            format!("[{}]{}  {:<24}{}{}", job.id, marker, state, job.command, background)
+        }
+    }
+
+    // Collect changes of the jobs' processes without waiting, after a SIGCHLD
+    pub fn poll(&mut self) {
+        if !CHILD_CHANGED.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        let flags = WaitPidFlag::WNOHANG | WaitPidFlag::WUNTRACED | WaitPidFlag::WCONTINUED;
+        for job in &mut self.jobs {
+            let pids: Vec<Pid> = job.processes.iter().filter(|process| !matches!(process.state, ProcessState::Exited(_) | ProcessState::Killed(_))).filter_map(|process| process.pid).collect();
+            for pid in pids {
+                match waitpid(pid, Some(flags)) {
+                    Ok(WaitStatus::StillAlive) => {}
+                    Ok(status) => {
+                        job.record(status);
+                    }
+                    Err(Errno::ECHILD) => job.forget(pid),
+                    Err(_) => {}
+                }
+            }
+        }
+    }
+
+    // Jobs that finished or stopped since they were last reported, to tell the user
+    // about before the next prompt; finished ones leave the table
+    pub fn take_notifications(&mut self) -> Vec<String> {
+        let mut lines = Vec::new();
+        for id in self.ids() {
+            let Some(job) = self.get(id) else { continue };
+            if job.notified || !(job.is_done() || job.is_stopped()) {
+                continue;
+            }
+            lines.push(self.describe(job, false));
+            if job.is_done() {
+                self.remove(id);
+            } else if let Some(job) = self.get_mut(id) {
+                job.notified = true;
+            }
+        }
+        lines
+    }
+}
+
+extern "C" fn note_child_changed(_: libc::c_int) {
+    CHILD_CHANGED.store(true, Ordering::SeqCst);
+}
+
+// Note when background jobs finish or stop, so they are reported at the next prompt
+pub fn watch_children() -> io::Result<()> {
+    let action = nix::sys::signal::SigAction::new(
+        SigHandler::Handler(note_child_changed),
+        nix::sys::signal::SaFlags::SA_RESTART,
+        nix::sys::signal::SigSet::empty(),
+    );
+    // SAFETY: the handler only stores to an atomic
+    unsafe { nix::sys::signal::sigaction(Signal::SIGCHLD, &action) }?;
+    Ok(())
+}
+
+extern "C" fn note_interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+// Run `f` with Ctrl+C making Job::wait give up instead of ending the shell, as
+// for the `wait` builtin: the terminal sends SIGINT to the shell while no job is
+// in the foreground
+pub fn catch_interrupts<T>(f: impl FnOnce() -> T) -> io::Result<T> {
+    INTERRUPTED.store(false, Ordering::SeqCst);
+    // No SA_RESTART, so that a waitpid in progress returns
+    let action = nix::sys::signal::SigAction::new(
+        SigHandler::Handler(note_interrupt),
+        nix::sys::signal::SaFlags::empty(),
+        nix::sys::signal::SigSet::empty(),
+    );
+    // SAFETY: the handler only stores to an atomic
+    let previous = unsafe { nix::sys::signal::sigaction(Signal::SIGINT, &action) }?;
+    let result = f();
+    // SAFETY: puts back what was there before
+    unsafe { nix::sys::signal::sigaction(Signal::SIGINT, &previous) }?;
+    INTERRUPTED.store(false, Ordering::SeqCst);
+    Ok(result)
+}
+
+/// The terminal state an interactive shell with job control keeps
+pub struct Terminal {
+    pub shell_pgid: Pid,
+    pub modes: Termios,
+}
+
+// Set up job control when standard input is a terminal: wait to be in the
+// foreground, ignore the keyboard's stop signals (they are for the foreground
+// job), and move the shell to a process group of its own that owns the terminal
+pub fn take_terminal() -> io::Result<Option<Terminal>> {
+    if unsafe { libc::isatty(libc::STDIN_FILENO) } == 0 {
+        return Ok(None);
+    }
+    while tcgetpgrp(libc::STDIN_FILENO)? != getpgrp() {
+        killpg(getpgrp(), Signal::SIGTTIN)?;
+    }
+    for signal_number in JOB_CONTROL_SIGNALS {
+        // SAFETY: ignoring a signal installs no handler
+        unsafe { signal(signal_number, SigHandler::SigIgn) }?;
+    }
+    // A session leader is in its own group already
+    let _ = setpgid(Pid::from_raw(0), Pid::from_raw(0));
+    let shell_pgid = getpgrp();
+    tcsetpgrp(libc::STDIN_FILENO, shell_pgid)?;
+    Ok(Some(Terminal { shell_pgid, modes: tcgetattr(libc::STDIN_FILENO)? }))
+}
+
+// Give the terminal to a process group, restoring its saved modes
+pub fn give_terminal(pgid: Pid, modes: Option<&Termios>) {
+    let _ = tcsetpgrp(libc::STDIN_FILENO, pgid);
+    if let Some(modes) = modes {
+        let _ = tcsetattr(libc::STDIN_FILENO, SetArg::TCSADRAIN, modes);
+    }
+}
+
+pub fn terminal_modes() -> Option<Termios> {
+    tcgetattr(libc::STDIN_FILENO).ok()
+}
+
+// In a new process of a job: join the job's process group (a new one led by this
+// process when `pgid` is None), take the terminal if the job is in the
+// foreground, and restore the signals the shell ignores. Only async-signal-safe
+// calls, as this also runs between fork and exec.
+pub fn enter_job(pgid: Option<Pid>, foreground: bool) {
+    // SAFETY: plain system calls on this process
+    unsafe {
+        libc::setpgid(0, pgid.map_or(0, Pid::as_raw));
+        if foreground {
+            libc::tcsetpgrp(libc::STDIN_FILENO, libc::getpgrp());
+        }
+        for signal_number in JOB_CONTROL_SIGNALS {
+            libc::signal(signal_number as libc::c_int, libc::SIG_DFL);
+        }
+        libc::signal(libc::SIGCHLD, libc::SIG_DFL);
+    }
+}
+
+// A signal by name (`TERM`, `SIGTERM`, any case) or number
+pub fn parse_signal(name: &str) -> Option<Signal> {
+    if let Ok(number) = name.parse::<i32>() {
+        return Signal::try_from(number).ok();
+    }
+    let name = name.to_ascii_uppercase();
+    let name = if name.starts_with("SIG") { name } else { format!("SIG{}", name) };
+    Signal::from_str(&name).ok()
+}
+
+// Names for `kill -l`, without the SIG prefix
+pub fn signal_names() -> Vec<&'static str> {
+    Signal::iterator().map(|signal| signal.as_str().trim_start_matches("SIG")).collect()
+}
+
+fn signal_description(signal: Signal) -> &'static str {
+    match signal {
+        Signal::SIGTERM => "Terminated",
+        Signal::SIGKILL => "Killed",
+        Signal::SIGINT => "Interrupt",
+        Signal::SIGHUP => "Hangup",
+        Signal::SIGQUIT => "Quit",
+        Signal::SIGPIPE => "Broken pipe",
+        Signal::SIGSEGV => "Segmentation fault",
+        Signal::SIGABRT => "Aborted",
+        other => other.as_str(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job_table() -> JobTable {
+        let mut jobs = JobTable::default();
+        for (command, state) in [
+            ("sleep 100", ProcessState::Running),
+            ("vim notes", ProcessState::Stopped(Signal::SIGTSTP)),
+            ("make", ProcessState::Exited(2)),
+        ] {
+            let process = Process { pid: Some(Pid::from_raw(1)), state };
+            jobs.add(None, vec![process], command.to_string());
+        }
+        jobs
+    }
+
+    #[test]
+    fn test_job_specs() {
+        let jobs = job_table();
+        assert_eq!(jobs.find("%1"), Ok(1));
+        assert_eq!(jobs.find("2"), Ok(2));
+        assert_eq!(jobs.find("%%"), Ok(3));
+        assert_eq!(jobs.find("%-"), Ok(2));
+        assert_eq!(jobs.find("%vim"), Ok(2));
+        assert_eq!(jobs.find("%4"), Err("%4: no such job".to_string()));
+
+        let lines: Vec<String> = jobs.iter().map(|job| jobs.describe(job, false)).collect();
+        assert_eq!(lines, vec![
+            "[1]   Running                 sleep 100 &",
+            "[2]-  Stopped                 vim notes",
+            "[3]+  Exit 2                  make",
+        ]);
+    }
+
+    #[test]
+    fn test_job_status_and_signals() {
+        let processes = vec![
+            Process { pid: None, state: ProcessState::Exited(127) },
+            Process { pid: Some(Pid::from_raw(1)), state: ProcessState::Exited(0) },
+        ];
+        let job = Job { id: 1, pgid: None, processes, command: "nope | true".to_string(), modes: None, notified: false };
+        assert_eq!(job.status(false), 0);
+        assert_eq!(job.status(true), 127);
+
+        assert_eq!(parse_signal("term"), Some(Signal::SIGTERM));
+        assert_eq!(parse_signal("SIGKILL"), Some(Signal::SIGKILL));
+        assert_eq!(parse_signal("9"), Some(Signal::SIGKILL));
+        assert_eq!(parse_signal("nope"), None);
+    }
+}
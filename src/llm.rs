@@ -1,13 +1,21 @@
-use reqwest::Client;
-use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use crate::anthropic::AnthropicProvider;
+use crate::cassette::{RecordingProvider, ReplayProvider};
+use crate::context::{ContentBlock, Message};
+use crate::mock::ScriptedProvider;
+use crate::openai::OpenAIProvider;
+use serde_json::{json, Value};
 use std::env;
 use std::error::Error;
 use std::fmt;
+use std::future::Future;
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::time::Duration;
 
 #[derive(Debug)]
 pub enum LLMError {
-    ApiKeyMissing,
+    ApiKeyMissing(String),
+    InvalidConfig(String),
     RequestFailed(String),
     ParseError(String),
     NetworkError(reqwest::Error),
@@ -16,7 +24,8 @@ pub enum LLMError {
 impl fmt::Display for LLMError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            LLMError::ApiKeyMissing => write!(f, "ANTHROPIC_API_KEY not found in environment"),
+            LLMError::ApiKeyMissing(var) => write!(f, "{} not found in environment", var),
+            LLMError::InvalidConfig(msg) => write!(f, "Invalid LLM configuration: {}", msg),
             LLMError::RequestFailed(msg) => write!(f, "API request failed: {}", msg),
             LLMError::ParseError(msg) => write!(f, "Failed to parse response: {}", msg),
             LLMError::NetworkError(e) => write!(f, "Network error: {}", e),
@@ -32,911 +41,647 @@ impl From<reqwest::Error> for LLMError {
     }
 }
 
-#[derive(Serialize)]
-struct AnthropicRequest {
-    model: String,
-    max_tokens: u32,
-    messages: Vec<Message>,
-    system: Option<String>,
-    tools: Option<Vec<Tool>>,
+/// A tool the model may call, described independently of any provider's wire format
+#[derive(Debug, Clone)]
+pub struct ToolDefinition {
+    pub name: String,
+    pub description: String,
+    pub input_schema: Value,
 }
 
-#[derive(Serialize)]
-struct Tool {
-    name: String,
-    description: String,
-    input_schema: Value,
+pub const DEFAULT_MAX_TOKENS: u32 = 4096;
+
+/// Generation parameters sent with every request. They start from the environment
+/// (`AISH_LLM_MAX_TOKENS`, `AISH_LLM_TEMPERATURE`, `AISH_LLM_STOP` with comma
+/// separated sequences, `AISH_LLM_TIMEOUT` in seconds) and can be changed with
+/// command line flags and the `llm` builtin.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GenerationConfig {
+    pub model: String,
+    pub max_tokens: u32,
+    pub temperature: Option<f64>,
+    pub stop_sequences: Vec<String>,
+    pub timeout: Option<Duration>,
 }
 
-#[derive(Serialize, Deserialize)]
-struct Message {
-    role: String,
-    content: String,
+impl GenerationConfig {
+    pub const SETTINGS: [&'static str; 5] = ["model", "max_tokens", "temperature", "stop", "timeout"];
+
+    pub fn with_model(model: &str) -> Self {
+        GenerationConfig {
+            model: model.to_string(),
+            max_tokens: DEFAULT_MAX_TOKENS,
+            temperature: None,
+            stop_sequences: Vec::new(),
+            timeout: None,
+        }
+    }
+
+    fn from_lookup(model: &str, var: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let mut config = Self::with_model(model);
+        for setting in Self::SETTINGS.iter().filter(|s| **s != "model") {
+            if let Some(value) = var(&format!("AISH_LLM_{}", setting.to_uppercase())) {
+                let values: Vec<String> = if *setting == "stop" {
+                    value.split(',').map(str::to_string).collect()
+                } else {
+                    vec![value]
+                };
+                config.set(setting, &values)?;
+            }
+        }
+        Ok(config)
+    }
+
+    /// Changes one setting; `default` (or no value) restores the default where there is one
+    pub fn set(&mut self, setting: &str, values: &[String]) -> Result<(), String> {
+        let value = values.first().map(|v| v.trim()).unwrap_or("default");
+        let reset = value == "default" || value == "none";
+
+        match setting {
+            "model" if !reset && !value.is_empty() => self.model = value.to_string(),
+            "model" => return Err("model: a model name is required".to_string()),
+            "max_tokens" if reset => self.max_tokens = DEFAULT_MAX_TOKENS,
+            "max_tokens" => {
+                self.max_tokens = value.parse().ok().filter(|n| *n > 0)
+                    .ok_or_else(|| format!("max_tokens: '{}' is not a positive number", value))?;
+            }
+            "temperature" if reset => self.temperature = None,
+            "temperature" => {
+                let temperature: f64 = value.parse()
+                    .map_err(|_| format!("temperature: '{}' is not a number", value))?;
+                if !(0.0..=2.0).contains(&temperature) {
+                    return Err(format!("temperature: {} is outside 0.0-2.0", temperature));
+                }
+                self.temperature = Some(temperature);
+            }
+            "stop" if reset => self.stop_sequences.clear(),
+            "stop" => self.stop_sequences = values.iter().filter(|v| !v.is_empty()).cloned().collect(),
+            "timeout" if reset || value == "0" => self.timeout = None,
+            "timeout" => {
+                let seconds: u64 = value.parse()
+                    .map_err(|_| format!("timeout: '{}' is not a number of seconds", value))?;
+                self.timeout = Some(Duration::from_secs(seconds));
+            }
+            _ => {
+                return Err(format!(
+                    "unknown setting '{}' (expected one of: {})",
+                    setting,
+                    Self::SETTINGS.join(", ")
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for GenerationConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "model: {}", self.model)?;
+        writeln!(f, "max_tokens: {}", self.max_tokens)?;
+        match self.temperature {
+            Some(temperature) => writeln!(f, "temperature: {}", temperature)?,
+            None => writeln!(f, "temperature: default")?,
+        }
+        if self.stop_sequences.is_empty() {
+            writeln!(f, "stop: none")?;
+        } else {
+            writeln!(f, "stop: {:?}", self.stop_sequences)?;
+        }
+        match self.timeout {
+            Some(timeout) => write!(f, "timeout: {}s", timeout.as_secs()),
+            None => write!(f, "timeout: none"),
+        }
+    }
 }
 
-#[derive(Deserialize)]
-struct AnthropicResponse {
-    content: Vec<ContentBlock>,
-    usage: Option<Usage>,
+/// Everything a provider needs to produce the next assistant turn
+pub struct ChatRequest<'a> {
+    pub system: String,
+    pub messages: &'a [Message],
+    pub tools: &'a [ToolDefinition],
+    pub config: &'a GenerationConfig,
 }
 
-#[derive(Deserialize)]
-struct Usage {
-    input_tokens: usize,
-    output_tokens: usize,
+/// One assistant turn: its content blocks (text and tool_use) and the tokens it cost.
+/// Providers report `stop_reason` with the Anthropic names: `end_turn`, `tool_use`, `max_tokens`.
+pub struct LLMResponse {
+    pub content: Vec<ContentBlock>,
+    pub tokens_used: usize,
+    pub stop_reason: Option<String>,
 }
 
-#[derive(Deserialize)]
-struct ContentBlock {
-    #[serde(rename = "type")]
-    content_type: String,
-    text: Option<String>,
-    name: Option<String>,
-    input: Option<Value>,
+pub type ChatFuture<'a> = Pin<Box<dyn Future<Output = Result<LLMResponse, LLMError>> + 'a>>;
+
+/// A chat backend: sends the conversation and tool definitions, streams the answer
+/// to the observer and reports the resulting content blocks and token usage
+pub trait LLMProvider {
+    /// Shown at startup, e.g. `anthropic (model: claude-3-5-sonnet-20241022)`
+    fn describe(&self, model: &str) -> String;
+
+    fn chat<'a>(&'a self, request: &'a ChatRequest<'a>, observer: &'a mut dyn StreamObserver) -> ChatFuture<'a>;
 }
 
-pub struct AnthropicClient {
-    client: Client,
-    api_key: String,
-    model: String,
+/// Receives a streamed response as it arrives
+pub trait StreamObserver {
+    fn text_delta(&mut self, text: &str);
+    fn tool_use_start(&mut self, name: &str);
 }
 
-// Model constants for easy switching
-pub const CLAUDE_3_5_SONNET: &str = "claude-3-5-sonnet-20241022";
-pub const CLAUDE_3_HAIKU: &str = "claude-3-haiku-20240307";
-pub const CLAUDE_3_OPUS: &str = "claude-3-opus-20240229";
+/// Prints streamed text with the `[LLM]` prefix at the start of every line
+pub struct TerminalRenderer {
+    at_line_start: bool,
+}
 
-// Default model
-const DEFAULT_MODEL: &str = CLAUDE_3_5_SONNET;
+impl TerminalRenderer {
+    pub fn new() -> Self {
+        TerminalRenderer { at_line_start: true }
+    }
 
-impl AnthropicClient {
-    pub fn new() -> Result<Self, LLMError> {
-        dotenv::dotenv().ok(); // Load .env file, ignore if it doesn't exist
-        
-        let api_key = env::var("ANTHROPIC_API_KEY")
-            .map_err(|_| LLMError::ApiKeyMissing)?;
-        
-        let model = env::var("ANTHROPIC_MODEL")
-            .unwrap_or_else(|_| DEFAULT_MODEL.to_string());
-
-        Ok(AnthropicClient {
-            client: Client::new(),
-            api_key,
-            model,
-        })
+    // End a partially printed line so that following output starts cleanly
+    pub fn finish_line(&mut self) {
+        if !self.at_line_start {
+            println!();
+            self.at_line_start = true;
+        }
     }
-    
-    pub fn with_model(model: &str) -> Result<Self, LLMError> {
-        dotenv::dotenv().ok();
-        
-        let api_key = env::var("ANTHROPIC_API_KEY")
-            .map_err(|_| LLMError::ApiKeyMissing)?;
-        
-        Ok(AnthropicClient {
-            client: Client::new(),
-            api_key,
-            model: model.to_string(),
-        })
+}
+
+impl StreamObserver for TerminalRenderer {
+    fn text_delta(&mut self, text: &str) {
+        let mut output = String::new();
+        for ch in text.chars() {
+            if self.at_line_start {
+                output.push_str("[LLM] ");
+            }
+            output.push(ch);
+            self.at_line_start = ch == '\n';
+        }
+        print!("{}", output);
+        let _ = io::stdout().flush();
     }
 
-    pub async fn analyze_context(&self, context: &str, prompt: &str) -> Result<String, LLMError> {
-        let system_prompt = format!(
-            "You are an AI assistant helping with shell script analysis and automation. \
-             You have access to the following context:\n\n{}\n\n\
-             Provide clear, concise responses focused on the specific request. \
-             When analyzing files or configurations, highlight key information and potential issues.",
-            context
-        );
-
-        let request = AnthropicRequest {
-            model: self.model.clone(),
-            max_tokens: 1000,
-            messages: vec![Message {
-                role: "user".to_string(),
-                content: prompt.to_string(),
-            }],
-            system: Some(system_prompt),
-            tools: None,
-        };
+    fn tool_use_start(&mut self, name: &str) {
+        self.finish_line();
+        println!("[TOOL] Calling {}...", name);
+    }
+}
 
-        let response = self
-            .client
-            .post("https://api.anthropic.com/v1/messages")
-            .header("Content-Type", "application/json")
-            .header("X-API-Key", &self.api_key)
-            .header("anthropic-version", "2023-06-01")
-            .json(&request)
-            .send()
-            .await?;
-
-        if !response.status().is_success() {
-            let error_text = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
-            return Err(LLMError::RequestFailed(error_text));
+// Show a complete response the way a streamed one would be observed
+pub fn replay_to_observer(content: &[ContentBlock], observer: &mut dyn StreamObserver) {
+    for block in content {
+        match block {
+            ContentBlock::Text { text } => observer.text_delta(text),
+            ContentBlock::ToolUse { name, .. } => observer.tool_use_start(name),
+            ContentBlock::ToolResult { .. } => {}
         }
+    }
+}
 
-        let anthropic_response: AnthropicResponse = response
-            .json()
-            .await
-            .map_err(|e| LLMError::ParseError(e.to_string()))?;
+// A single server-sent event
+#[derive(Debug, Clone, PartialEq)]
+pub struct SseEvent {
+    pub event: String,
+    pub data: String,
+}
+
+// Splits a byte stream into server-sent events; chunks may end anywhere,
+// including in the middle of a line or a UTF-8 sequence
+#[derive(Default)]
+pub struct SseDecoder {
+    buffer: Vec<u8>,
+    event: String,
+    data: Vec<String>,
+}
 
-        if let Some(content) = anthropic_response.content.first() {
-            if let Some(ref text) = content.text {
-                Ok(text.clone())
-            } else {
-                Err(LLMError::ParseError("No text in content block".to_string()))
+impl SseDecoder {
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        self.buffer.extend_from_slice(chunk);
+        let mut events = Vec::new();
+
+        while let Some(newline) = self.buffer.iter().position(|&b| b == b'\n') {
+            let raw_line: Vec<u8> = self.buffer.drain(..=newline).collect();
+            let line = String::from_utf8_lossy(&raw_line);
+            let line = line.trim_end_matches(['\n', '\r']);
+
+            if line.is_empty() {
+                if !self.data.is_empty() || !self.event.is_empty() {
+                    events.push(SseEvent {
+                        event: std::mem::take(&mut self.event),
+                        data: std::mem::take(&mut self.data).join("\n"),
+                    });
+                }
+            } else if let Some(value) = line.strip_prefix("event:") {
+                self.event = value.trim_start().to_string();
+            } else if let Some(value) = line.strip_prefix("data:") {
+                self.data.push(value.strip_prefix(' ').unwrap_or(value).to_string());
             }
-        } else {
-            Err(LLMError::ParseError("No content in response".to_string()))
+            // Comments (":...") and unknown fields are ignored
         }
-    }
 
-    pub async fn summarize_context(&self, context: &str, request: &str) -> Result<String, LLMError> {
-        let prompt = format!(
-            "Based on the context provided, please summarize: {}\n\n\
-             Focus on the most important points and actionable insights.",
-            request
-        );
-        
-        self.analyze_context(context, &prompt).await
+        events
     }
+}
 
+// Where streamed bytes come from: the HTTP response, or a recording in tests
+pub trait ChunkSource {
+    async fn next_chunk(&mut self) -> Result<Option<Vec<u8>>, LLMError>;
+}
 
-    pub async fn process_general_request(&self, context: &str, request: &str) -> Result<String, LLMError> {
-        let prompt = if context.trim().is_empty() {
-            format!("Please help with: {}", request)
-        } else {
-            format!(
-                "Based on the provided context, please help with: {}\n\n\
-                 Use the context information to provide a more informed response.",
-                request
-            )
-        };
-        
-        self.analyze_context(context, &prompt).await
+impl ChunkSource for reqwest::Response {
+    async fn next_chunk(&mut self) -> Result<Option<Vec<u8>>, LLMError> {
+        Ok(self.chunk().await?.map(|bytes| bytes.to_vec()))
     }
+}
 
-    pub async fn analyze_with_message_history(&self, messages: &Vec<crate::context::Message>, request: &str) -> Result<String, LLMError> {
-        // Convert our Message type to Anthropic's Message type
-        let anthropic_messages: Vec<Message> = messages.iter()
-            .filter(|m| m.role != "system") // Anthropic handles system messages separately
-            .map(|m| Message {
-                role: m.role.clone(),
-                content: m.content.clone(),
-            })
-            .collect();
+// Feed every server-sent event of a stream to the handler, in order
+pub async fn read_sse<S: ChunkSource>(
+    source: &mut S,
+    mut handle: impl FnMut(&SseEvent) -> Result<(), LLMError>,
+) -> Result<(), LLMError> {
+    let mut decoder = SseDecoder::default();
 
-        // Collect system messages for system prompt
-        let system_messages: Vec<String> = messages.iter()
-            .filter(|m| m.role == "system")
-            .map(|m| m.content.clone())
-            .collect();
+    while let Some(chunk) = source.next_chunk().await? {
+        for event in decoder.push(&chunk) {
+            handle(&event)?;
+        }
+    }
+    // A final event without its trailing blank line
+    for event in decoder.push(b"\n\n") {
+        handle(&event)?;
+    }
 
-        let system_prompt = if system_messages.is_empty() {
-            format!(
-                "You are an AI assistant helping with shell script analysis and automation. \
-                 Analyze the conversation history and respond to: {}",
-                request
-            )
-        } else {
-            format!(
-                "You are an AI assistant helping with shell script analysis and automation. \
-                 Context from previous actions:\n{}\n\n\
-                 Based on the conversation history, respond to: {}",
-                system_messages.join("\n\n"),
-                request
-            )
-        };
+    Ok(())
+}
 
-        let mut api_messages = anthropic_messages;
-        api_messages.push(Message {
-            role: "user".to_string(),
-            content: request.to_string(),
-        });
-
-        let request = AnthropicRequest {
-            model: self.model.clone(),
-            max_tokens: 1000,
-            messages: api_messages,
-            system: Some(system_prompt),
-            tools: None,
-        };
+// Fail with the server's message when a request was rejected
+pub async fn check_status(response: reqwest::Response) -> Result<reqwest::Response, LLMError> {
+    if response.status().is_success() {
+        return Ok(response);
+    }
+    let error_text = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
+    Err(LLMError::RequestFailed(error_text))
+}
 
-        let response = self
-            .client
-            .post("https://api.anthropic.com/v1/messages")
-            .header("Content-Type", "application/json")
-            .header("X-API-Key", &self.api_key)
-            .header("anthropic-version", "2023-06-01")
-            .json(&request)
-            .send()
-            .await?;
-
-        if !response.status().is_success() {
-            let error_text = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
-            return Err(LLMError::RequestFailed(error_text));
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProviderKind {
+    Anthropic,
+    OpenAI,
+    Local,
+    Mock,
+}
+
+impl ProviderKind {
+    fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "anthropic" | "claude" => Some(ProviderKind::Anthropic),
+            "openai" | "openai-compatible" => Some(ProviderKind::OpenAI),
+            "local" | "llama.cpp" | "llamacpp" | "ollama" => Some(ProviderKind::Local),
+            "mock" | "none" => Some(ProviderKind::Mock),
+            _ => None,
         }
+    }
+}
 
-        let anthropic_response: AnthropicResponse = response
-            .json()
-            .await
-            .map_err(|e| LLMError::ParseError(e.to_string()))?;
+/// Backend selection, read from the environment (and `.env`):
+///
+/// - `AISH_LLM_PROVIDER`: `anthropic`, `openai`, `local` or `mock`. When unset,
+///   the mock is used if `AISH_MOCK_SCRIPT` is set, then Anthropic if
+///   `ANTHROPIC_API_KEY` is set, then OpenAI if `OPENAI_API_KEY` is set, and
+///   the plain mock otherwise.
+/// - `AISH_LLM_BASE_URL`: API root, e.g. `http://localhost:11434/v1` for Ollama
+/// - `AISH_LLM_MODEL`, falling back to `ANTHROPIC_MODEL` / `OPENAI_MODEL`
+/// - `AISH_LLM_API_KEY`, falling back to `ANTHROPIC_API_KEY` / `OPENAI_API_KEY`
+/// - `AISH_MOCK_SCRIPT`: fixture with the replies of the mock (see `mock.rs`)
+/// - `AISH_LLM_RECORD`: write every request and response to this cassette file
+/// - `AISH_LLM_REPLAY`: answer from a recorded cassette instead of any backend
+#[derive(Debug, Clone)]
+pub struct ProviderConfig {
+    pub kind: ProviderKind,
+    pub base_url: String,
+    pub model: String,
+    pub api_key: Option<String>,
+    pub mock_script: Option<String>,
+    pub record: Option<String>,
+    pub replay: Option<String>,
+}
 
-        if let Some(content) = anthropic_response.content.first() {
-            if let Some(ref text) = content.text {
-                Ok(text.clone())
-            } else {
-                Err(LLMError::ParseError("No text in content block".to_string()))
-            }
-        } else {
-            Err(LLMError::ParseError("No content in response".to_string()))
-        }
+impl ProviderConfig {
+    pub fn from_env() -> Result<Self, LLMError> {
+        dotenv::dotenv().ok(); // Load .env file, ignore if it doesn't exist
+        Self::from_lookup(|name| env::var(name).ok().filter(|value| !value.is_empty()))
     }
 
-    pub async fn summarize_with_message_history(&self, messages: &Vec<crate::context::Message>, request: &str) -> Result<String, LLMError> {
-        let prompt = format!(
-            "Based on the conversation history, please summarize: {}\n\n\
-             Focus on the most important points and actionable insights.",
-            request
-        );
-        
-        self.analyze_with_message_history(messages, &prompt).await
-    }
-
-    pub async fn process_with_tools(&self, context: &str, request: &str, processor: &mut crate::context::LLMActionProcessor) -> Result<String, LLMError> {
-        let tools = self.get_available_tools();
-        
-        let system_prompt = format!(
-            "You are an AI assistant helping with shell automation and file operations. \
-             You have access to these tools:
-             - read_file: Read a file into context
-             - clear_context: Clear the current context
-             - analyze: Analyze content in context  
-             - summarize: Summarize content
-             - explain: Explain something
-             - add_to_context: Add information to context
-             
-             Current context: {}
-             
-             Use tools when the user requests file operations, analysis, or context management. \
-             Respond naturally and call tools as needed.",
-            if context.trim().is_empty() { "No context loaded" } else { context }
-        );
-
-        let request_with_tools = AnthropicRequest {
-            model: self.model.clone(),
-            max_tokens: 1000,
-            messages: vec![Message {
-                role: "user".to_string(),
-                content: request.to_string(),
-            }],
-            system: Some(system_prompt),
-            tools: Some(tools),
+    fn from_lookup(var: impl Fn(&str) -> Option<String>) -> Result<Self, LLMError> {
+        let kind = match var("AISH_LLM_PROVIDER") {
+            Some(name) => ProviderKind::parse(&name)
+                .ok_or_else(|| LLMError::InvalidConfig(format!("unknown provider '{}'", name)))?,
+            None if var("AISH_MOCK_SCRIPT").is_some() => ProviderKind::Mock,
+            None if var("ANTHROPIC_API_KEY").is_some() => ProviderKind::Anthropic,
+            None if var("OPENAI_API_KEY").is_some() => ProviderKind::OpenAI,
+            None => ProviderKind::Mock,
         };
 
-        let response = self
-            .client
-            .post("https://api.anthropic.com/v1/messages")
-            .header("Content-Type", "application/json")
-            .header("X-API-Key", &self.api_key)
-            .header("anthropic-version", "2023-06-01")
-            .json(&request_with_tools)
-            .send()
-            .await?;
-
-        if !response.status().is_success() {
-            let error_text = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
-            return Err(LLMError::RequestFailed(error_text));
-        }
+        // Provider specific variables and defaults
+        let (prefix, default_url, default_model) = match kind {
+            ProviderKind::Anthropic => ("ANTHROPIC", crate::anthropic::DEFAULT_BASE_URL, crate::anthropic::DEFAULT_MODEL),
+            ProviderKind::OpenAI => ("OPENAI", crate::openai::DEFAULT_BASE_URL, crate::openai::DEFAULT_MODEL),
+            ProviderKind::Local => ("OPENAI", crate::openai::DEFAULT_LOCAL_BASE_URL, crate::openai::DEFAULT_LOCAL_MODEL),
+            ProviderKind::Mock => ("MOCK", "", "mock"),
+        };
+        let setting = |name: &str| {
+            var(&format!("AISH_LLM_{}", name)).or_else(|| var(&format!("{}_{}", prefix, name)))
+        };
 
-        let anthropic_response: AnthropicResponse = response
-            .json()
-            .await
-            .map_err(|e| LLMError::ParseError(e.to_string()))?;
-
-        let mut results = Vec::new();
-        
-        for content_block in &anthropic_response.content {
-            match content_block.content_type.as_str() {
-                "text" => {
-                    if let Some(ref text) = content_block.text {
-                        results.push(format!("🤖 {}", text));
-                    }
-                },
-                "tool_use" => {
-                    if let (Some(name), Some(input)) = (&content_block.name, &content_block.input) {
-                        match self.execute_tool(name, input, processor).await {
-                            Ok(tool_result) => results.push(tool_result),
-                            Err(e) => results.push(format!("Tool execution error: {}", e)),
-                        }
-                    }
-                },
-                _ => {}
-            }
+        let api_key = setting("API_KEY");
+        let replay = var("AISH_LLM_REPLAY");
+        // Local servers usually accept any key, hosted APIs never do; a replay needs none
+        if api_key.is_none() && replay.is_none() && matches!(kind, ProviderKind::Anthropic | ProviderKind::OpenAI) {
+            return Err(LLMError::ApiKeyMissing(format!("{}_API_KEY", prefix)));
         }
 
-        if results.is_empty() {
-            Ok("🤖 Processed request".to_string())
-        } else {
-            Ok(results.join("\n"))
-        }
+        Ok(ProviderConfig {
+            kind,
+            base_url: setting("BASE_URL")
+                .unwrap_or_else(|| default_url.to_string())
+                .trim_end_matches('/')
+                .to_string(),
+            model: setting("MODEL").unwrap_or_else(|| default_model.to_string()),
+            api_key,
+            mock_script: var("AISH_MOCK_SCRIPT"),
+            record: var("AISH_LLM_RECORD"),
+            replay,
+        })
     }
 
-    fn get_available_tools(&self) -> Vec<Tool> {
-        use serde_json::json;
-        
-        vec![
-            Tool {
-                name: "read_file".to_string(),
-                description: "Read a file into the context for analysis".to_string(),
-                input_schema: json!({
-                    "type": "object",
-                    "properties": {
-                        "filename": {
-                            "type": "string", 
-                            "description": "Path to the file to read"
-                        }
-                    },
-                    "required": ["filename"]
-                }),
-            },
-            Tool {
-                name: "clear_context".to_string(),
-                description: "Clear the current context".to_string(),
-                input_schema: json!({
-                    "type": "object",
-                    "properties": {}
-                }),
-            },
-            Tool {
-                name: "analyze".to_string(),
-                description: "Analyze content in the current context".to_string(),
-                input_schema: json!({
-                    "type": "object",
-                    "properties": {
-                        "content": {
-                            "type": "string",
-                            "description": "What to analyze"
-                        }
-                    },
-                    "required": ["content"]
-                }),
-            },
-            Tool {
-                name: "add_to_context".to_string(),
-                description: "Add information to the current context".to_string(),
-                input_schema: json!({
-                    "type": "object",
-                    "properties": {
-                        "content": {
-                            "type": "string",
-                            "description": "Content to add to context"
-                        }
-                    },
-                    "required": ["content"]
-                }),
+    pub fn build_provider(self) -> Result<Box<dyn LLMProvider>, LLMError> {
+        if let Some(ref path) = self.replay {
+            return Ok(Box::new(ReplayProvider::load(path)?));
+        }
+
+        let record = self.record.clone();
+        let provider: Box<dyn LLMProvider> = match self.kind {
+            ProviderKind::Anthropic => Box::new(AnthropicProvider::new(self)),
+            ProviderKind::OpenAI | ProviderKind::Local => Box::new(OpenAIProvider::new(self)),
+            ProviderKind::Mock => match self.mock_script {
+                Some(ref path) => Box::new(ScriptedProvider::load(path)?),
+                None => Box::new(MockProvider),
             },
-        ]
-    }
+        };
 
-    async fn execute_tool(&self, tool_name: &str, input: &Value, processor: &mut crate::context::LLMActionProcessor) -> Result<String, LLMError> {
-        // This method is deprecated - tools are now handled directly in the processor
-        Err(LLMError::ParseError("Tool execution moved to processor".to_string()))
+        Ok(match record {
+            Some(path) => Box::new(RecordingProvider::new(provider, &path)),
+            None => provider,
+        })
     }
 }
 
-// Unified LLM client wrapper that handles both real and mock clients
-pub struct LLMClient {
-    client_type: ClientType,
-    anthropic_client: Option<AnthropicClient>,
+// Offline stand-in used when no backend is configured
+struct MockProvider;
+
+impl LLMProvider for MockProvider {
+    fn describe(&self, _model: &str) -> String {
+        "mock (set AISH_LLM_PROVIDER or ANTHROPIC_API_KEY to use a real model)".to_string()
+    }
+
+    fn chat<'a>(&'a self, _request: &'a ChatRequest<'a>, observer: &'a mut dyn StreamObserver) -> ChatFuture<'a> {
+        Box::pin(async move {
+            let text = "[Mock] Processed message history".to_string();
+            observer.text_delta(&text);
+            Ok(LLMResponse {
+                content: vec![ContentBlock::Text { text }],
+                tokens_used: 0, // Mock returns 0 tokens
+                stop_reason: Some("end_turn".to_string()),
+            })
+        })
+    }
 }
 
-enum ClientType {
-    Anthropic,
-    Mock,
+// Unified LLM client wrapper over the configured provider
+pub struct LLMClient {
+    provider: Box<dyn LLMProvider>,
+    tools: Vec<ToolDefinition>,
+    config: GenerationConfig,
 }
 
 impl LLMClient {
     pub fn new() -> Self {
-        Self::with_model(None)
-    }
-    
-    pub fn with_model(model: Option<&str>) -> Self {
-        dotenv::dotenv().ok();
-        
-        if let Ok(_) = env::var("ANTHROPIC_API_KEY") {
-            let client_result = match model {
-                Some(m) => AnthropicClient::with_model(m),
-                None => AnthropicClient::new(),
-            };
-            
-            match client_result {
-                Ok(client) => {
-                    let model_name = &client.model;
-                    println!("[SYS] Anthropic LLM integration enabled (model: {})", model_name);
-                    LLMClient {
-                        client_type: ClientType::Anthropic,
-                        anthropic_client: Some(client),
-                    }
-                }
-                Err(e) => {
-                    println!("[SYS] Anthropic client initialization failed: {}", e);
-                    println!("[SYS] Falling back to mock client");
-                    LLMClient {
-                        client_type: ClientType::Mock,
-                        anthropic_client: None,
-                    }
-                }
-            }
-        } else {
-            println!("[SYS] ANTHROPIC_API_KEY not found, using mock LLM client");
-            LLMClient {
-                client_type: ClientType::Mock,
-                anthropic_client: None,
+        let provider_config = ProviderConfig::from_env();
+        let model = provider_config.as_ref().map(|c| c.model.clone()).unwrap_or_else(|_| "mock".to_string());
+
+        let provider = match provider_config.and_then(ProviderConfig::build_provider) {
+            Ok(provider) => provider,
+            Err(e) => {
+                println!("[SYS] LLM provider initialization failed: {}", e);
+                println!("[SYS] Falling back to mock client");
+                Box::new(MockProvider)
             }
+        };
+        let config = GenerationConfig::from_lookup(&model, |name| env::var(name).ok().filter(|v| !v.is_empty()))
+            .unwrap_or_else(|e| {
+                println!("[SYS] Ignoring LLM settings from the environment: {}", e);
+                GenerationConfig::with_model(&model)
+            });
+
+        LLMClient {
+            provider,
+            tools: agent_tools(),
+            config,
         }
     }
 
-    pub async fn analyze_context(&self, context: &str, content: &str) -> Result<String, LLMError> {
-        match self.client_type {
-            ClientType::Anthropic => {
-                if let Some(ref client) = self.anthropic_client {
-                    match client.analyze_context(context, content).await {
-                        Ok(response) => {
-                            Ok(response)
-                        },
-                        Err(e) => Ok(format!("[SYS] Analysis failed: {}", e)),
-                    }
-                } else {
-                    Ok("[SYS] No Anthropic client available".to_string())
-                }
-            }
-            ClientType::Mock => {
-                let prefixed_response = content.lines()
-                    .map(|line| format!("[Mock Analysis] {}", line))
-                    .collect::<Vec<_>>()
-                    .join("\n");
-                Ok(prefixed_response)
-            }
-        }
+    pub fn describe(&self) -> String {
+        self.provider.describe(&self.config.model)
     }
 
-    pub async fn summarize_context(&self, context: &str, content: &str) -> Result<String, LLMError> {
-        match self.client_type {
-            ClientType::Anthropic => {
-                if let Some(ref client) = self.anthropic_client {
-                    match client.summarize_context(context, content).await {
-                        Ok(response) => {
-                            Ok(response)
-                        },
-                        Err(e) => Ok(format!("[SYS] Summarization failed: {}", e)),
-                    }
-                } else {
-                    Ok("[SYS] No Anthropic client available".to_string())
-                }
-            }
-            ClientType::Mock => {
-                let prefixed_response = content.lines()
-                    .map(|line| format!("[Mock Summary] {}", line))
-                    .collect::<Vec<_>>()
-                    .join("\n");
-                Ok(prefixed_response)
-            }
-        }
+    pub fn config(&self) -> &GenerationConfig {
+        &self.config
     }
 
+    pub fn config_mut(&mut self) -> &mut GenerationConfig {
+        &mut self.config
+    }
 
-    pub async fn analyze_with_history(&self, messages: &Vec<crate::context::Message>, content: &str) -> Result<String, LLMError> {
-        match self.client_type {
-            ClientType::Anthropic => {
-                if let Some(ref client) = self.anthropic_client {
-                    match client.analyze_with_message_history(messages, content).await {
-                        Ok(response) => {
-                            Ok(response)
-                        },
-                        Err(e) => Ok(format!("[SYS] Analysis failed: {}", e)),
-                    }
-                } else {
-                    Ok("[SYS] No Anthropic client available".to_string())
-                }
-            }
-            ClientType::Mock => {
-                let prefixed_response = content.lines()
-                    .map(|line| format!("[Mock Analysis] {}", line))
-                    .collect::<Vec<_>>()
-                    .join("\n");
-                Ok(prefixed_response)
-            }
-        }
+    pub async fn process_with_tools_and_history(&self, messages: &[Message], observer: &mut dyn StreamObserver) -> Result<LLMResponse, LLMError> {
+        let request = ChatRequest {
+            system: system_prompt(messages),
+            messages,
+            tools: &self.tools,
+            config: &self.config,
+        };
+
+        self.provider.chat(&request, observer).await
     }
+}
 
-    pub async fn summarize_with_history(&self, messages: &Vec<crate::context::Message>, content: &str) -> Result<String, LLMError> {
-        match self.client_type {
-            ClientType::Anthropic => {
-                if let Some(ref client) = self.anthropic_client {
-                    match client.summarize_with_message_history(messages, content).await {
-                        Ok(response) => {
-                            Ok(response)
-                        },
-                        Err(e) => Ok(format!("[SYS] Summarization failed: {}", e)),
-                    }
-                } else {
-                    Ok("[SYS] No Anthropic client available".to_string())
-                }
-            }
-            ClientType::Mock => {
-                let prefixed_response = content.lines()
-                    .map(|line| format!("[Mock Summary] {}", line))
-                    .collect::<Vec<_>>()
-                    .join("\n");
-                Ok(prefixed_response)
-            }
-        }
+// System messages of the history are sent through the system prompt
+fn system_prompt(messages: &[Message]) -> String {
+    let system_messages: Vec<String> = messages.iter()
+        .filter(|m| m.role == "system")
+        .map(|m| m.text())
+        .collect();
+
+    let context_summary = if system_messages.is_empty() {
+        "No context loaded".to_string()
+    } else {
+        format!("CONTEXT LOADED: {}", system_messages.join("\n"))
+    };
+
+    format!(
+        "You are an AI assistant helping with shell automation and file operations. \
+         You operate in AGENTIC mode - you can perform multiple sequential actions to complete complex tasks.\n\n\
+         Available tools:\n\
+         - read_file: Read files into context for analysis\n\
+         - clear_context: Clear current context\n\
+         - add_to_context: Add information to context\n\
+         - execute_command: Execute shell commands and get their output\n\n\
+         IMPORTANT INSTRUCTIONS:\n\
+         1. When given a task, think about what information you need to complete it\n\
+         2. Use tools to gather information, then analyze and provide insights\n\
+         3. If you need multiple steps, use tools in sequence (each tool call triggers a follow-up)\n\
+         4. If there is no further action needed after using a tool, do NOT respond - stay silent\n\
+         5. Be proactive - if a task requires reading files, analysis, or context building, do it automatically\n\
+         6. ALWAYS UTILIZE CONTEXT: If context is loaded, use it to answer questions directly\n\n\
+         {}",
+        context_summary
+    )
+}
+
+// The tools handled by LLMActionProcessor
+fn agent_tools() -> Vec<ToolDefinition> {
+    let tool = |name: &str, description: &str, input_schema: Value| ToolDefinition {
+        name: name.to_string(),
+        description: description.to_string(),
+        input_schema,
+    };
+
+    vec![
+        tool("read_file", "Read a file into the context for analysis", json!({
+            "type": "object",
+            "properties": {
+                "filename": {"type": "string", "description": "Path to the file to read"}
+            },
+            "required": ["filename"]
+        })),
+        tool("clear_context", "Clear the current context", json!({
+            "type": "object",
+            "properties": {}
+        })),
+        tool("add_to_context", "Add information to the current context", json!({
+            "type": "object",
+            "properties": {
+                "content": {"type": "string", "description": "Content to add to context"}
+            },
+            "required": ["content"]
+        })),
+        tool("execute_command", "Execute a shell command and return its output", json!({
+            "type": "object",
+            "properties": {
+                "command": {"type": "string", "description": "Shell command to execute"}
+            },
+            "required": ["command"]
+        })),
+    ]
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    // Test double replaying a recorded SSE stream in small chunks, so that events,
+    // lines and UTF-8 sequences get split across chunk boundaries
+    pub struct ReplayStream {
+        pub data: Vec<u8>,
+        pub chunk_size: usize,
     }
 
-    pub async fn process_with_tools_and_history(&self, messages: &Vec<crate::context::Message>) -> Result<(String, Vec<(String, serde_json::Value)>, usize), LLMError> {
-        match self.client_type {
-            ClientType::Anthropic => {
-                self.process_with_anthropic_tools_and_history(messages).await
-            }
-            ClientType::Mock => {
-                let response = "[Mock] Processed message history".to_string();
-                Ok((response, Vec::new(), 0)) // Mock returns 0 tokens
+    impl ChunkSource for ReplayStream {
+        async fn next_chunk(&mut self) -> Result<Option<Vec<u8>>, LLMError> {
+            if self.data.is_empty() {
+                return Ok(None);
             }
+            let size = self.chunk_size.min(self.data.len());
+            Ok(Some(self.data.drain(..size).collect()))
         }
     }
 
-    pub async fn process_with_tools(&self, context: &str, content: &str) -> Result<(String, Vec<(String, serde_json::Value)>, usize), LLMError> {
-        match self.client_type {
-            ClientType::Anthropic => {
-                self.process_with_anthropic_tools(context, content).await
-            }
-            ClientType::Mock => {
-                let prefixed_response = content.lines()
-                    .map(|line| format!("[Mock] {}", line))
-                    .collect::<Vec<_>>()
-                    .join("\n");
-                Ok((prefixed_response, Vec::new(), 0)) // Mock returns 0 tokens
-            }
-        }
+    #[derive(Default)]
+    pub struct RecordingObserver {
+        pub events: Vec<String>,
     }
 
-    async fn process_with_anthropic_tools_and_history(&self, messages: &Vec<crate::context::Message>) -> Result<(String, Vec<(String, serde_json::Value)>, usize), LLMError> {
-        use serde::Deserialize;
-        
-        #[derive(Deserialize)]
-        struct ToolResponse {
-            content: Vec<ToolContentBlock>,
-            usage: Option<ToolUsage>,
+    impl StreamObserver for RecordingObserver {
+        fn text_delta(&mut self, text: &str) {
+            self.events.push(format!("text:{}", text));
         }
-        
-        #[derive(Deserialize)]
-        struct ToolUsage {
-            input_tokens: usize,
-            output_tokens: usize,
-        }
-        
-        #[derive(Deserialize)]
-        struct ToolContentBlock {
-            #[serde(rename = "type")]
-            content_type: String,
-            text: Option<String>,
-            name: Option<String>,
-            input: Option<Value>,
+
+        fn tool_use_start(&mut self, name: &str) {
+            self.events.push(format!("tool:{}", name));
         }
+    }
 
-        let tools = vec![
-            serde_json::json!({
-                "name": "read_file",
-                "description": "Read a file into the context for analysis",
-                "input_schema": {
-                    "type": "object",
-                    "properties": {
-                        "filename": {"type": "string", "description": "Path to the file to read"}
-                    },
-                    "required": ["filename"]
-                }
-            }),
-            serde_json::json!({
-                "name": "clear_context", 
-                "description": "Clear the current context",
-                "input_schema": {
-                    "type": "object",
-                    "properties": {}
-                }
-            }),
-            serde_json::json!({
-                "name": "add_to_context",
-                "description": "Add information to the current context", 
-                "input_schema": {
-                    "type": "object",
-                    "properties": {
-                        "content": {"type": "string", "description": "Content to add to context"}
-                    },
-                    "required": ["content"]
-                }
-            }),
-            serde_json::json!({
-                "name": "execute_command",
-                "description": "Execute a shell command and return its output",
-                "input_schema": {
-                    "type": "object",
-                    "properties": {
-                        "command": {"type": "string", "description": "Shell command to execute"}
-                    },
-                    "required": ["command"]
-                }
-            })
-        ];
-        
-        // Convert our Message type to the API format
-        let final_messages: Vec<serde_json::Value> = messages.iter()
-            .filter(|m| m.role != "system")
-            .map(|m| serde_json::json!({
-                "role": m.role,
-                "content": m.content
-            }))
-            .collect();
-        
-        // Collect system messages for system prompt
-        let system_messages: Vec<String> = messages.iter()
-            .filter(|m| m.role == "system")
-            .map(|m| m.content.clone())
+    fn config(vars: &[(&str, &str)]) -> Result<ProviderConfig, LLMError> {
+        let vars: HashMap<String, String> = vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
             .collect();
+        ProviderConfig::from_lookup(|name| vars.get(name).cloned())
+    }
 
-        let context_summary = if system_messages.is_empty() {
-            "No context loaded".to_string()
-        } else {
-            format!("CONTEXT LOADED: {}", system_messages.join("\n"))
-        };
-        
-        let system_prompt = format!(
-            "You are an AI assistant helping with shell automation and file operations. \
-             You operate in AGENTIC mode - you can perform multiple sequential actions to complete complex tasks.\n\n\
-             Available tools:\n\
-             - read_file: Read files into context for analysis\n\
-             - clear_context: Clear current context\n\
-             - add_to_context: Add information to context\n\
-             - execute_command: Execute shell commands and get their output\n\n\
-             IMPORTANT INSTRUCTIONS:\n\
-             1. When given a task, think about what information you need to complete it\n\
-             2. Use tools to gather information, then analyze and provide insights\n\
-             3. If you need multiple steps, use tools in sequence (each tool call triggers a follow-up)\n\
-             4. If there is no further action needed after using a tool, do NOT respond - stay silent\n\
-             5. Be proactive - if a task requires reading files, analysis, or context building, do it automatically\n\
-             6. ALWAYS UTILIZE CONTEXT: If context is loaded, use it to answer questions directly\n\n\
-             {}", 
-            context_summary
-        );
-        
-        let request = serde_json::json!({
-            "model": DEFAULT_MODEL,
-            "max_tokens": 1000,
-            "messages": final_messages,
-            "system": system_prompt,
-            "tools": tools
-        });
-        
-        let response = reqwest::Client::new()
-            .post("https://api.anthropic.com/v1/messages")
-            .header("Content-Type", "application/json")
-            .header("X-API-Key", env::var("ANTHROPIC_API_KEY").unwrap_or_default())
-            .header("anthropic-version", "2023-06-01")
-            .json(&request)
-            .send()
-            .await
-            .map_err(|e| LLMError::NetworkError(e))?;
-            
-        if !response.status().is_success() {
-            let error_text = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
-            return Err(LLMError::RequestFailed(error_text));
-        }
-        
-        let tool_response: ToolResponse = response
-            .json()
-            .await
-            .map_err(|e| LLMError::ParseError(e.to_string()))?;
-        
-        let mut results = Vec::new();
-        let mut tool_calls = Vec::new();
-        
-        for content_block in &tool_response.content {
-            match content_block.content_type.as_str() {
-                "text" => {
-                    if let Some(ref text) = content_block.text {
-                        results.push(text.clone());
-                    }
-                },
-                "tool_use" => {
-                    if let (Some(name), Some(input)) = (&content_block.name, &content_block.input) {
-                        tool_calls.push((name.clone(), input.clone()));
-                    }
-                },
-                _ => {}
-            }
-        }
-        
-        let response_text = if results.is_empty() {
-"Processed request".to_string()
-        } else {
-            results.join("\n")
-        };
-        
-        // Extract token usage
-        let total_tokens = if let Some(usage) = &tool_response.usage {
-            usage.input_tokens + usage.output_tokens
-        } else {
-            0
-        };
-        
-        Ok((response_text, tool_calls, total_tokens))
+    #[test]
+    fn test_provider_selection() {
+        let default = config(&[]).unwrap();
+        assert_eq!(default.kind, ProviderKind::Mock);
+
+        let scripted = config(&[("AISH_MOCK_SCRIPT", "replies.json"), ("ANTHROPIC_API_KEY", "sk-ant")]).unwrap();
+        assert_eq!(scripted.kind, ProviderKind::Mock);
+        assert_eq!(scripted.mock_script.as_deref(), Some("replies.json"));
+
+        let anthropic = config(&[("ANTHROPIC_API_KEY", "sk-ant"), ("ANTHROPIC_MODEL", "claude-x")]).unwrap();
+        assert_eq!(anthropic.kind, ProviderKind::Anthropic);
+        assert_eq!(anthropic.model, "claude-x");
+        assert_eq!(anthropic.base_url, crate::anthropic::DEFAULT_BASE_URL);
+
+        let local = config(&[
+            ("AISH_LLM_PROVIDER", "ollama"),
+            ("AISH_LLM_BASE_URL", "http://localhost:11434/v1/"),
+            ("AISH_LLM_MODEL", "llama3.1"),
+        ]).unwrap();
+        assert_eq!(local.kind, ProviderKind::Local);
+        assert_eq!(local.base_url, "http://localhost:11434/v1");
+        assert_eq!(local.model, "llama3.1");
+        assert_eq!(local.api_key, None);
+
+        let openai = config(&[("AISH_LLM_PROVIDER", "openai"), ("AISH_LLM_API_KEY", "sk-1"), ("ANTHROPIC_API_KEY", "sk-ant")]).unwrap();
+        assert_eq!(openai.kind, ProviderKind::OpenAI);
+        assert_eq!(openai.api_key.as_deref(), Some("sk-1"));
+
+        assert!(matches!(config(&[("AISH_LLM_PROVIDER", "openai")]), Err(LLMError::ApiKeyMissing(var)) if var == "OPENAI_API_KEY"));
+        assert!(config(&[("AISH_LLM_PROVIDER", "openai"), ("AISH_LLM_REPLAY", "session.json")]).is_ok());
+        assert!(matches!(config(&[("AISH_LLM_PROVIDER", "gpt")]), Err(LLMError::InvalidConfig(_))));
     }
 
-    async fn process_with_anthropic_tools(&self, context: &str, content: &str) -> Result<(String, Vec<(String, serde_json::Value)>, usize), LLMError> {
-        use serde::Deserialize;
-        
-        #[derive(Deserialize)]
-        struct ToolResponse {
-            content: Vec<ToolContentBlock>,
-            usage: Option<ToolUsage>,
-        }
-        
-        #[derive(Deserialize)]
-        struct ToolUsage {
-            input_tokens: usize,
-            output_tokens: usize,
-        }
-        
-        #[derive(Deserialize)]
-        struct ToolContentBlock {
-            #[serde(rename = "type")]
-            content_type: String,
-            text: Option<String>,
-            name: Option<String>,
-            input: Option<Value>,
-        }
+    #[test]
+    fn test_generation_settings() {
+        let vars: HashMap<&str, &str> = [("AISH_LLM_MAX_TOKENS", "8192"), ("AISH_LLM_STOP", "###,END")].into();
+        let mut config = GenerationConfig::from_lookup("claude-x", |name| vars.get(name).map(|v| v.to_string())).unwrap();
+        assert_eq!(config.max_tokens, 8192);
+        assert_eq!(config.stop_sequences, vec!["###".to_string(), "END".to_string()]);
 
-        let tools = vec![
-            serde_json::json!({
-                "name": "read_file",
-                "description": "Read a file into the context for analysis",
-                "input_schema": {
-                    "type": "object",
-                    "properties": {
-                        "filename": {"type": "string", "description": "Path to the file to read"}
-                    },
-                    "required": ["filename"]
-                }
-            }),
-            serde_json::json!({
-                "name": "clear_context", 
-                "description": "Clear the current context",
-                "input_schema": {
-                    "type": "object",
-                    "properties": {}
-                }
-            }),
-            serde_json::json!({
-                "name": "add_to_context",
-                "description": "Add information to the current context", 
-                "input_schema": {
-                    "type": "object",
-                    "properties": {
-                        "content": {"type": "string", "description": "Content to add to context"}
-                    },
-                    "required": ["content"]
-                }
-            }),
-            serde_json::json!({
-                "name": "execute_command",
-                "description": "Execute a shell command and return its output",
-                "input_schema": {
-                    "type": "object",
-                    "properties": {
-                        "command": {"type": "string", "description": "Shell command to execute"}
-                    },
-                    "required": ["command"]
-                }
-            })
-        ];
-        
-        let context_summary = if context.trim().is_empty() { 
-            "No context loaded".to_string() 
-        } else { 
-            format!("CONTEXT LOADED ({} chars): {}", context.len(), context)
-        };
-        
-        let system_prompt = format!(
-            "You are an AI assistant helping with shell automation and file operations. \
-             You operate in AGENTIC mode - you can perform multiple sequential actions to complete complex tasks.\n\n\
-             Available tools:\n\
-             - read_file: Read files into context for analysis\n\
-             - clear_context: Clear current context\n\
-             - add_to_context: Add information to context\n\
-             - execute_command: Execute shell commands and get their output\n\n\
-             IMPORTANT INSTRUCTIONS:\n\
-             1. When given a task, think about what information you need to complete it\n\
-             2. Use tools to gather information, then analyze and provide insights\n\
-             3. If you need multiple steps, use tools in sequence (each tool call triggers a follow-up)\n\
-             4. If there is no further action needed after using a tool, do NOT respond - stay silent\n\
-             5. Be proactive - if a task requires reading files, analysis, or context building, do it automatically\n\
-             6. ALWAYS UTILIZE CONTEXT: If context is loaded, use it to answer questions directly\n\n\
-             {}",
-            context_summary
-        );
-        
-        
-        let request = serde_json::json!({
-            "model": DEFAULT_MODEL,
-            "max_tokens": 1000,
-            "messages": [{
-                "role": "user",
-                "content": content
-            }],
-            "system": system_prompt,
-            "tools": tools
-        });
-        
-        let response = reqwest::Client::new()
-            .post("https://api.anthropic.com/v1/messages")
-            .header("Content-Type", "application/json")
-            .header("X-API-Key", env::var("ANTHROPIC_API_KEY").unwrap_or_default())
-            .header("anthropic-version", "2023-06-01")
-            .json(&request)
-            .send()
-            .await
-            .map_err(|e| LLMError::NetworkError(e))?;
-            
-        if !response.status().is_success() {
-            let error_text = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
-            return Err(LLMError::RequestFailed(error_text));
-        }
-        
-        let tool_response: ToolResponse = response
-            .json()
-            .await
-            .map_err(|e| LLMError::ParseError(e.to_string()))?;
-        
-        let mut results = Vec::new();
-        let mut tool_calls = Vec::new();
-        
-        for content_block in &tool_response.content {
-            match content_block.content_type.as_str() {
-                "text" => {
-                    if let Some(ref text) = content_block.text {
-                        results.push(text.clone());
-                    }
-                },
-                "tool_use" => {
-                    if let (Some(name), Some(input)) = (&content_block.name, &content_block.input) {
-                        tool_calls.push((name.clone(), input.clone()));
-                    }
-                },
-                _ => {}
-            }
-        }
-        
-        let response_text = if results.is_empty() {
-"Processed request".to_string()
-        } else {
-            results.join("\n")
+        let set = |config: &mut GenerationConfig, setting: &str, values: &[&str]| {
+            config.set(setting, &values.iter().map(|v| v.to_string()).collect::<Vec<_>>())
         };
-        
-        // Extract token usage
-        let total_tokens = if let Some(usage) = &tool_response.usage {
-            usage.input_tokens + usage.output_tokens
-        } else {
-            0
-        };
-        
-        Ok((response_text, tool_calls, total_tokens))
+        set(&mut config, "temperature", &["0.2"]).unwrap();
+        set(&mut config, "timeout", &["30"]).unwrap();
+        set(&mut config, "max_tokens", &["default"]).unwrap();
+        set(&mut config, "stop", &[]).unwrap();
+        assert_eq!(config.temperature, Some(0.2));
+        assert_eq!(config.timeout, Some(Duration::from_secs(30)));
+        assert_eq!(config.max_tokens, DEFAULT_MAX_TOKENS);
+        assert!(config.stop_sequences.is_empty());
+
+        assert!(set(&mut config, "temperature", &["hot"]).is_err());
+        assert!(set(&mut config, "max_tokens", &["0"]).is_err());
+        assert!(set(&mut config, "top_k", &["5"]).unwrap_err().starts_with("unknown setting 'top_k'"));
+        assert_eq!(config.to_string(), "model: claude-x\nmax_tokens: 4096\ntemperature: 0.2\nstop: none\ntimeout: 30s");
     }
 }
-
-// Utility function to check if Anthropic integration is available
-pub fn is_anthropic_available() -> bool {
-    dotenv::dotenv().ok();
-    env::var("ANTHROPIC_API_KEY").is_ok()
-}
-
-// Internal mock client (hidden from main logic)
-struct MockLLMClient;
\ No newline at end of file
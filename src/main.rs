@@ -1,13 +1,27 @@
+use std::env;
 use std::io;
 use clap::{Arg, ArgAction, Command as ClapCommand};
 
 mod shell;
 mod parser;
+mod expand;
+mod arith;
+mod variables;
+mod glob;
+mod jobs;
+mod redirect;
 mod builtins;
+mod capture;
 mod markdown;
 mod context;
 mod llm;
+mod anthropic;
+mod openai;
+mod mock;
+mod cassette;
+mod router;
 
+use llm::GenerationConfig;
 use shell::Shell;
 
 #[tokio::main]
@@ -23,24 +37,153 @@ async fn main() -> io::Result<()> {
                 .help("Execute the given command string")
                 .action(ArgAction::Set)
         )
+        .arg(
+            Arg::new("mock-llm")
+                .long("mock-llm")
+                .value_name("SCRIPT")
+                .help("Answer LLM requests with the scripted replies of a JSON fixture")
+                .action(ArgAction::Set)
+        )
+        .arg(
+            Arg::new("record")
+                .long("record")
+                .value_name("CASSETTE")
+                .help("Record every LLM request and response to a JSON cassette")
+                .conflicts_with("replay")
+                .action(ArgAction::Set)
+        )
+        .arg(
+            Arg::new("replay")
+                .long("replay")
+                .value_name("CASSETTE")
+                .help("Answer LLM requests from a recorded cassette, without network access")
+                .conflicts_with("mock-llm")
+                .action(ArgAction::Set)
+        )
+        .arg(
+            Arg::new("model")
+                .long("model")
+                .value_name("MODEL")
+                .help("LLM model to use")
+                .action(ArgAction::Set)
+        )
+        .arg(
+            Arg::new("max_tokens")
+                .long("max-tokens")
+                .value_name("N")
+                .help("Maximum number of tokens per LLM response")
+                .action(ArgAction::Set)
+        )
+        .arg(
+            Arg::new("temperature")
+                .long("temperature")
+                .value_name("T")
+                .help("LLM sampling temperature (0.0-2.0)")
+                .action(ArgAction::Set)
+        )
+        .arg(
+            Arg::new("stop")
+                .long("stop")
+                .value_name("SEQUENCE")
+                .help("Stop sequence for LLM responses (may be repeated)")
+                .action(ArgAction::Append)
+        )
+        .arg(
+            Arg::new("timeout")
+                .long("timeout")
+                .value_name("SECONDS")
+                .help("Timeout for each LLM request")
+                .action(ArgAction::Set)
+        )
+        .arg(
+            Arg::new("rcfile")
+                .long("rcfile")
+                .value_name("FILE")
+                .help("Run FILE instead of ~/.aishrc when an interactive session starts")
+                .action(ArgAction::Set)
+        )
+        .arg(
+            Arg::new("norc")
+                .long("norc")
+                .help("Do not run a startup file in an interactive session")
+                .action(ArgAction::SetTrue)
+        )
         .arg(
             Arg::new("file")
                 .value_name("FILE")
                 .help("Execute commands from the given file")
                 .action(ArgAction::Set)
         )
+        .arg(
+            Arg::new("args")
+                .value_name("ARGS")
+                .help("Positional parameters $1, $2, ... for the file or command")
+                .num_args(0..)
+                .trailing_var_arg(true)
+                .allow_hyphen_values(true)
+                .action(ArgAction::Append)
+        )
         .get_matches();
 
+    // Provider selection is read from the environment when the shell starts
+    if let Some(script) = matches.get_one::<String>("mock-llm") {
+        env::set_var("AISH_LLM_PROVIDER", "mock");
+        env::set_var("AISH_MOCK_SCRIPT", script);
+    }
+    if let Some(cassette) = matches.get_one::<String>("record") {
+        env::set_var("AISH_LLM_RECORD", cassette);
+    }
+    if let Some(cassette) = matches.get_one::<String>("replay") {
+        env::set_var("AISH_LLM_REPLAY", cassette);
+    }
+
     let mut shell = Shell::new();
 
+    // Generation settings given on the command line override the environment
+    for setting in GenerationConfig::SETTINGS {
+        if let Some(values) = matches.get_many::<String>(setting) {
+            let values: Vec<String> = values.cloned().collect();
+            shell
+                .llm_settings()
+                .set(setting, &values)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        }
+    }
+    println!("[SYS] LLM provider: {}", shell.llm_description());
+
+    let args: Vec<String> = matches.get_many::<String>("args").map(|args| args.cloned().collect()).unwrap_or_default();
+
     if let Some(command) = matches.get_one::<String>("command") {
-        // Execute command string mode (-c flag)
-        shell.run_command(command).await
+        // Execute command string mode (-c flag), exiting with the command's status.
+        // As in sh, the first word after the command is $0 and the rest are $1...
+        if let Some(name) = matches.get_one::<String>("file") {
+            shell.set_script_parameters(name, args);
+        }
+        shell.run_command(command).await?;
+        let status = shell.last_exit_status();
+        drop(shell);
+        std::process::exit(status)
     } else if let Some(filename) = matches.get_one::<String>("file") {
-        // Execute file mode
-        shell.run_file(filename).await
+        // Execute file mode, exiting with the status of the script's last command;
+        // a script stopped by an error such as `${VAR:?}` fails
+        shell.set_script_parameters(filename, args);
+        shell.run_file(filename).await?;
+        let status = shell.last_exit_status();
+        drop(shell);
+        std::process::exit(status)
     } else {
-        // Interactive mode (default)
+        // Interactive mode (default), after the startup file with the user's aliases
+        if !matches.get_flag("norc") {
+            let default_rc = env::var("HOME").ok().map(|home| format!("{}/.aishrc", home));
+            match matches.get_one::<String>("rcfile") {
+                Some(rcfile) => shell.load_rc_file(rcfile).await?,
+                None => {
+                    if let Some(rcfile) = default_rc.filter(|path| std::path::Path::new(path).is_file()) {
+                        shell.load_rc_file(&rcfile).await?;
+                    }
+                }
+            }
+        }
         shell.run_interactive().await
     }
 }
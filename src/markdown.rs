@@ -1,6 +1,6 @@
 use pulldown_cmark::{Parser, Event, Tag, CodeBlockKind, HeadingLevel};
 use std::io;
-use crate::context::{LLMAction, LLMActionProcessor};
+use crate::context::LLMAction;
 use regex::Regex;
 
 #[derive(Debug, Clone)]
@@ -12,17 +12,55 @@ pub enum MarkdownElement {
     FunctionDeclaration(String, Vec<String>, Vec<MarkdownElement>), // name, params, body
 }
 
+impl MarkdownElement {
+    pub fn as_function(&self) -> Option<AishFunction> {
+        match self {
+            MarkdownElement::FunctionDeclaration(name, params, body) => Some(AishFunction {
+                name: name.clone(),
+                params: params.clone(),
+                body: body.clone(),
+            }),
+            _ => None,
+        }
+    }
+}
+
 pub struct MarkdownScript {
     pub elements: Vec<MarkdownElement>,
 }
 
+/// Reusable unit declared with `func name(params) { ... }` in an .aish script
+#[derive(Debug, Clone)]
+pub struct AishFunction {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: Vec<MarkdownElement>,
+}
+
+// A piece of the source document: either plain markdown or a complete function declaration
+enum Segment {
+    Markdown(String),
+    Function(MarkdownElement),
+}
+
 impl MarkdownScript {
     pub fn parse(content: &str) -> Result<Self, io::Error> {
-        // First, handle function declarations
-        let (content, functions) = Self::extract_functions(content)?;
-        
+        // Function declarations are cut out first so that their bodies are not
+        // parsed as regular markdown, but they keep their place in the document
+        let mut elements = Vec::new();
+        for segment in Self::extract_functions(content)? {
+            match segment {
+                Segment::Markdown(text) => elements.extend(Self::parse_markdown(&text)),
+                Segment::Function(function) => elements.push(function),
+            }
+        }
+
+        Ok(MarkdownScript { elements })
+    }
+
+    fn parse_markdown(content: &str) -> Vec<MarkdownElement> {
         let mut elements = Vec::new();
-        let parser = Parser::new(&content);
+        let parser = Parser::new(content);
         
         let mut current_paragraph = String::new();
         let mut in_code_block = false;
@@ -159,41 +197,81 @@ impl MarkdownScript {
             elements.push(MarkdownElement::Paragraph(current_paragraph.trim().to_string()));
         }
         
-        // Add function declarations
-        elements.extend(functions);
-        
-        Ok(MarkdownScript { elements })
+        elements
     }
 
-    fn extract_functions(content: &str) -> Result<(String, Vec<MarkdownElement>), io::Error> {
-        let func_regex = Regex::new(r"func\s+(\w+)\s*\(([^)]*)\)\s*\{")
+    fn extract_functions(content: &str) -> Result<Vec<Segment>, io::Error> {
+        let func_regex = Regex::new(r"^\s*func\s+(\w+)\s*\(([^)]*)\)\s*\{\s*$")
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("Regex error: {}", e)))?;
-        
-        let mut functions = Vec::new();
-        let mut remaining_content = content.to_string();
-        
-        // For now, we'll parse function declarations but not extract their bodies
-        // This is a simplified implementation
-        for captures in func_regex.captures_iter(content) {
-            let func_name = captures[1].to_string();
-            let params_str = &captures[2];
-            let params: Vec<String> = if params_str.trim().is_empty() {
-                Vec::new()
-            } else {
-                params_str.split(',').map(|s| s.trim().to_string()).collect()
+
+        let mut segments = Vec::new();
+        let mut markdown = String::new();
+        let mut in_fence = false;
+        let mut lines = content.lines();
+
+        while let Some(line) = lines.next() {
+            if is_fence(line) {
+                in_fence = !in_fence;
+            }
+
+            let captures = match func_regex.captures(line) {
+                Some(captures) if !in_fence => captures,
+                _ => {
+                    markdown.push_str(line);
+                    markdown.push('\n');
+                    continue;
+                }
             };
-            
-            // For now, empty function body - in a complete implementation,
-            // we would parse the function body content
-            functions.push(MarkdownElement::FunctionDeclaration(func_name, params, Vec::new()));
+
+            let func_name = captures[1].to_string();
+            let params = parse_parameter_list(&captures[2]);
+
+            // Collect the body up to the matching closing brace; braces inside
+            // code blocks belong to the shell code, nested declarations nest
+            let mut body = Vec::new();
+            let mut depth = 1;
+            let mut body_in_fence = false;
+            for body_line in lines.by_ref() {
+                if is_fence(body_line) {
+                    body_in_fence = !body_in_fence;
+                } else if !body_in_fence {
+                    if func_regex.is_match(body_line) {
+                        depth += 1;
+                    } else if body_line.trim() == "}" {
+                        depth -= 1;
+                        if depth == 0 {
+                            break;
+                        }
+                    }
+                }
+                body.push(body_line);
+            }
+
+            if depth != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("Unterminated function declaration: func {}", func_name),
+                ));
+            }
+
+            let body = Self::parse(&dedent(&body))?.elements;
+
+            if !markdown.trim().is_empty() {
+                segments.push(Segment::Markdown(std::mem::take(&mut markdown)));
+            }
+            markdown.clear();
+            segments.push(Segment::Function(MarkdownElement::FunctionDeclaration(
+                func_name, params, body,
+            )));
         }
-        
-        // Remove function declarations from content for now
-        remaining_content = func_regex.replace_all(&remaining_content, "").to_string();
-        
-        Ok((remaining_content, functions))
+
+        if !markdown.trim().is_empty() {
+            segments.push(Segment::Markdown(markdown));
+        }
+
+        Ok(segments)
     }
-    
+
     pub fn get_llm_actions(&self) -> Vec<LLMAction> {
         let mut actions = Vec::new();
         
@@ -214,50 +292,12 @@ impl MarkdownScript {
         actions
     }
     
-    pub fn get_executable_blocks(&self) -> Vec<(Option<String>, String)> {
-        let mut executable_blocks = Vec::new();
-        
-        for element in &self.elements {
-            match element {
-                MarkdownElement::CodeBlock(lang, code) => {
-                    // Consider blocks executable if they have no language specified,
-                    // or if they're marked as shell/bash/sh/aish
-                    let is_executable = match lang {
-                        None => true, // No language specified - assume shell
-                        Some(l) => {
-                            let lang_lower = l.to_lowercase();
-                            matches!(lang_lower.as_str(), 
-                                   "shell" | "bash" | "sh" | "aish" | "zsh" | "fish" | ""
-                            )
-                        }
-                    };
-                    
-                    if is_executable && !code.trim().is_empty() {
-                        executable_blocks.push((lang.clone(), code.clone()));
-                    }
-                }
-                _ => {} // Other elements are not executable
-            }
-        }
-        
-        executable_blocks
-    }
-
-    pub fn get_functions(&self) -> Vec<&MarkdownElement> {
+    pub fn get_functions(&self) -> Vec<AishFunction> {
         self.elements.iter()
-            .filter(|e| matches!(e, MarkdownElement::FunctionDeclaration(_, _, _)))
+            .filter_map(|e| e.as_function())
             .collect()
     }
 
-    pub fn get_headers(&self) -> Vec<(u8, &String)> {
-        self.elements.iter()
-            .filter_map(|e| match e {
-                MarkdownElement::Header(level, text) => Some((*level, text)),
-                _ => None,
-            })
-            .collect()
-    }
-    
     // DEPRECATED: Manual parsing removed - LLM now handles all tool decisions via function calling
     // fn parse_paragraph_to_action(text: &str) -> LLMAction {
     //     // All paragraphs now go to LLM as Comment actions
@@ -269,6 +309,120 @@ impl MarkdownScript {
     // }
 }
 
+fn is_fence(line: &str) -> bool {
+    let trimmed = line.trim_start();
+    trimmed.starts_with("```") || trimmed.starts_with("~~~")
+}
+
+fn parse_parameter_list(params: &str) -> Vec<String> {
+    params
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+// Remove the indentation shared by all non-blank lines so that indented function
+// bodies are not mistaken for indented code blocks. Only spaces and tabs count as
+// indentation, so that other whitespace such as U+3000 stays part of the text.
+fn dedent(lines: &[&str]) -> String {
+    let indentation = |line: &str| line.len() - line.trim_start_matches([' ', '\t']).len();
+    let indent = lines
+        .iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| indentation(line))
+        .min()
+        .unwrap_or(0);
+
+    lines
+        .iter()
+        .map(|line| &line[indentation(line).min(indent)..])
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub fn is_function_declaration(line: &str) -> bool {
+    Regex::new(r"^\s*func\s+\w+\s*\(")
+        .map(|re| re.is_match(line))
+        .unwrap_or(false)
+}
+
+/// Recognize a call written as `name(arg, "quoted arg", $var, other(arg))`,
+/// returning the function name and the raw (unexpanded) arguments.
+pub fn parse_function_call(line: &str) -> Option<(String, Vec<String>)> {
+    let call_regex = Regex::new(r"^\s*([A-Za-z_]\w*)\s*\((.*)\)\s*$").ok()?;
+    let captures = call_regex.captures(line)?;
+
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut quote = None;
+    let mut depth = 0;
+    for ch in captures[2].chars() {
+        match (quote, ch) {
+            (None, '"') | (None, '\'') => {
+                quote = Some(ch);
+                current.push(ch);
+            }
+            (Some(q), _) if ch == q => {
+                quote = None;
+                current.push(ch);
+            }
+            (None, '(') => {
+                depth += 1;
+                current.push(ch);
+            }
+            (None, ')') => {
+                // More closing than opening parentheses: not a single call
+                if depth == 0 {
+                    return None;
+                }
+                depth -= 1;
+                current.push(ch);
+            }
+            (None, ',') if depth == 0 => {
+                args.push(current.trim().to_string());
+                current.clear();
+            }
+            _ => current.push(ch),
+        }
+    }
+
+    if quote.is_some() || depth != 0 {
+        return None;
+    }
+    if !current.trim().is_empty() || !args.is_empty() {
+        args.push(current.trim().to_string());
+    }
+
+    Some((captures[1].to_string(), args))
+}
+
+/// Recognize `variable = name(args)`, returning the variable and the call expression.
+pub fn parse_call_assignment(line: &str) -> Option<(String, String)> {
+    let assignment_regex = Regex::new(r"^\s*([A-Za-z_]\w*)\s*=\s*(.+)$").ok()?;
+    let captures = assignment_regex.captures(line)?;
+    let expression = captures[2].trim().to_string();
+
+    parse_function_call(&expression)?;
+    Some((captures[1].to_string(), expression))
+}
+
+// Consider blocks executable if they have no language specified,
+// or if they're marked as shell/bash/sh/aish
+pub fn is_executable_block(lang: &Option<String>, code: &str) -> bool {
+    let is_shell = match lang {
+        None => true, // No language specified - assume shell
+        Some(l) => {
+            let lang_lower = l.to_lowercase();
+            matches!(lang_lower.as_str(),
+                   "shell" | "bash" | "sh" | "aish" | "zsh" | "fish" | ""
+            )
+        }
+    };
+
+    is_shell && !code.trim().is_empty()
+}
+
 pub fn is_markdown_file(filename: &str) -> bool {
     filename.ends_with(".md") || filename.ends_with(".markdown") || filename.ends_with(".aish")
 }
@@ -300,12 +454,33 @@ Summarize what we've learned so far.
         
         let script = MarkdownScript::parse(content).unwrap();
         let actions = script.get_llm_actions();
-        let executable = script.get_executable_blocks();
+        let executable: Vec<_> = script.elements.iter()
+            .filter(|e| matches!(e, MarkdownElement::CodeBlock(lang, code) if is_executable_block(lang, code)))
+            .collect();
         
         assert_eq!(executable.len(), 2);
         assert!(actions.len() >= 3); // Header, read file, clear/analyze, summarize
     }
 
+    #[test]
+    fn test_elements_keep_document_order() {
+        let content = r#"
+Create the file.
+
+```bash
+echo "data" > out.txt
+```
+
+Now analyze it.
+"#;
+
+        let script = MarkdownScript::parse(content).unwrap();
+
+        assert!(matches!(&script.elements[0], MarkdownElement::Paragraph(text) if text == "Create the file."));
+        assert!(matches!(&script.elements[1], MarkdownElement::CodeBlock(Some(lang), _) if lang == "bash"));
+        assert!(matches!(&script.elements[2], MarkdownElement::Paragraph(text) if text == "Now analyze it."));
+    }
+
     #[test]
     fn test_function_parsing() {
         let content = r#"
@@ -322,5 +497,65 @@ func deploy(environment) {
         let functions = script.get_functions();
         
         assert_eq!(functions.len(), 1);
+        assert_eq!(functions[0].name, "deploy");
+        assert_eq!(functions[0].params, vec!["environment"]);
+        assert!(matches!(&functions[0].body[0], MarkdownElement::Paragraph(text) if text == "Check if environment is valid."));
+        assert!(matches!(&functions[0].body[1], MarkdownElement::CodeBlock(_, code) if code.contains("$environment")));
+    }
+
+    #[test]
+    fn test_function_keeps_position_and_braces_in_code() {
+        let content = r#"
+Before.
+
+func build() {
+    ```bash
+    echo "${TARGET}"
+    if true; then { echo ok; }; fi
+    }
+    ```
+}
+
+After.
+"#;
+
+        let script = MarkdownScript::parse(content).unwrap();
+
+        assert_eq!(script.elements.len(), 3);
+        assert!(matches!(&script.elements[1], MarkdownElement::FunctionDeclaration(name, _, body) if name == "build" && body.len() == 1));
+        assert!(matches!(&script.elements[2], MarkdownElement::Paragraph(text) if text == "After."));
+        assert!(MarkdownScript::parse("func open() {\nText\n").is_err());
+    }
+
+    #[test]
+    fn test_dedent() {
+        assert_eq!(dedent(&["    a", "      b", "", "  ", "    c"]), "a\n  b\n\n\nc");
+        // A line indented with other whitespace keeps it
+        assert_eq!(dedent(&["  a", "\u{3000}b", "\u{3000}"]), "  a\n\u{3000}b\n\u{3000}");
+        assert_eq!(dedent(&["\t  a", "\t  \u{3000}b"]), "a\n\u{3000}b");
+    }
+
+    #[test]
+    fn test_parse_function_call() {
+        assert_eq!(
+            parse_function_call(r#"deploy("production", 'a, b', $REGION)"#),
+            Some(("deploy".to_string(), vec![r#""production""#.to_string(), "'a, b'".to_string(), "$REGION".to_string()]))
+        );
+        assert_eq!(parse_function_call("status()"), Some(("status".to_string(), vec![])));
+        assert_eq!(parse_function_call("echo hello"), None);
+    }
+
+    #[test]
+    fn test_nested_calls_and_assignment() {
+        assert_eq!(
+            parse_function_call("report(summarize(dir), \"(x)\")"),
+            Some(("report".to_string(), vec!["summarize(dir)".to_string(), "\"(x)\"".to_string()]))
+        );
+        assert_eq!(parse_function_call("f(a) + g(b)"), None);
+        assert_eq!(
+            parse_call_assignment("summary = test_agent($dir)"),
+            Some(("summary".to_string(), "test_agent($dir)".to_string()))
+        );
+        assert_eq!(parse_call_assignment("summary = plain text"), None);
     }
 }
\ No newline at end of file
@@ -0,0 +1,240 @@
+use crate::context::{ContentBlock, Message};
+use crate::llm::{replay_to_observer, ChatFuture, ChatRequest, LLMError, LLMProvider, LLMResponse, StreamObserver};
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::fs;
+use std::sync::Mutex;
+
+/// Checks a scripted reply makes on the request it answers; every field is optional
+/// and names a substring that has to appear
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Expectation {
+    /// Text of the latest user message
+    pub user: Option<String>,
+    /// One of the tool results sent with the latest message
+    pub tool_result: Option<String>,
+    /// Whether the latest tool result was reported as an error
+    pub is_error: Option<bool>,
+    /// The system prompt, which carries the loaded context
+    pub system: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScriptedReply {
+    #[serde(default)]
+    pub expect: Expectation,
+    pub content: Vec<ContentBlock>,
+    #[serde(default)]
+    pub tokens: usize,
+    /// Defaults to `tool_use` when the reply calls a tool and `end_turn` otherwise
+    pub stop_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MockScript {
+    replies: Vec<ScriptedReply>,
+}
+
+/// Offline provider answering requests with the replies of a fixture file, in order:
+///
+/// ```json
+/// {"replies": [
+///   {"expect": {"user": "Read ./src/main.rs"},
+///    "content": [{"type": "tool_use", "id": "toolu_1", "name": "read_file", "input": {"filename": "./src/main.rs"}}]},
+///   {"expect": {"tool_result": "mod shell;"},
+///    "content": [{"type": "text", "text": "It is the entry point."}], "tokens": 120}
+/// ]}
+/// ```
+pub struct ScriptedProvider {
+    path: String,
+    replies: Mutex<VecDeque<ScriptedReply>>,
+    total: usize,
+}
+
+impl ScriptedProvider {
+    pub fn load(path: &str) -> Result<Self, LLMError> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| LLMError::InvalidConfig(format!("cannot read mock script {}: {}", path, e)))?;
+        Self::parse(path, &content)
+    }
+
+    fn parse(path: &str, content: &str) -> Result<Self, LLMError> {
+        let script: MockScript = serde_json::from_str(content)
+            .map_err(|e| LLMError::InvalidConfig(format!("invalid mock script {}: {}", path, e)))?;
+
+        Ok(ScriptedProvider {
+            path: path.to_string(),
+            total: script.replies.len(),
+            replies: Mutex::new(script.replies.into()),
+        })
+    }
+
+    fn next_reply(&self, request: &ChatRequest<'_>) -> Result<ScriptedReply, LLMError> {
+        let mut replies = self.replies.lock().unwrap();
+        let number = self.total - replies.len() + 1;
+        let reply = replies.pop_front().ok_or_else(|| {
+            LLMError::RequestFailed(format!(
+                "mock script {} has no reply left for request {}",
+                self.path, number
+            ))
+        })?;
+
+        reply.expect.check(request).map_err(|mismatch| {
+            LLMError::RequestFailed(format!("mock request {}: {}", number, mismatch))
+        })?;
+        Ok(reply)
+    }
+}
+
+impl Expectation {
+    fn check(&self, request: &ChatRequest<'_>) -> Result<(), String> {
+        let conversation: Vec<&Message> = request.messages.iter().filter(|m| m.role != "system").collect();
+        let last = conversation.last();
+
+        if let Some(ref expected) = self.user {
+            let user_text = conversation
+                .iter()
+                .rev()
+                .find(|m| m.role == "user" && !m.text().is_empty())
+                .map(|m| m.text())
+                .unwrap_or_default();
+            expect_contains("user message", &user_text, expected)?;
+        }
+
+        let tool_results: Vec<(&str, bool)> = last
+            .map(|m| {
+                m.content
+                    .iter()
+                    .filter_map(|block| match block {
+                        ContentBlock::ToolResult { content, is_error, .. } => Some((content.as_str(), *is_error)),
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        if let Some(ref expected) = self.tool_result {
+            if !tool_results.iter().any(|(content, _)| content.contains(expected.as_str())) {
+                return Err(format!(
+                    "expected a tool result containing {:?}, got {:?}",
+                    expected,
+                    tool_results.iter().map(|(content, _)| *content).collect::<Vec<_>>()
+                ));
+            }
+        }
+
+        if let Some(expected) = self.is_error {
+            match tool_results.last() {
+                Some((_, is_error)) if *is_error == expected => {}
+                Some((content, _)) => {
+                    return Err(format!("expected is_error {} for tool result {:?}", expected, content));
+                }
+                None => return Err("expected a tool result, got none".to_string()),
+            }
+        }
+
+        if let Some(ref expected) = self.system {
+            expect_contains("system prompt", &request.system, expected)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn expect_contains(what: &str, actual: &str, expected: &str) -> Result<(), String> {
+    if actual.contains(expected) {
+        Ok(())
+    } else {
+        Err(format!("expected {} containing {:?}, got {:?}", what, expected, actual))
+    }
+}
+
+impl LLMProvider for ScriptedProvider {
+    fn describe(&self, _model: &str) -> String {
+        format!("mock (script: {}, {} replies)", self.path, self.total)
+    }
+
+    fn chat<'a>(&'a self, request: &'a ChatRequest<'a>, observer: &'a mut dyn StreamObserver) -> ChatFuture<'a> {
+        Box::pin(async move {
+            let reply = self.next_reply(request)?;
+
+            replay_to_observer(&reply.content, observer);
+
+            let calls_tool = reply.content.iter().any(|block| matches!(block, ContentBlock::ToolUse { .. }));
+            let stop_reason = reply.stop_reason.unwrap_or_else(|| {
+                if calls_tool { "tool_use" } else { "end_turn" }.to_string()
+            });
+
+            Ok(LLMResponse {
+                content: reply.content,
+                tokens_used: reply.tokens,
+                stop_reason: Some(stop_reason),
+            })
+        })
+    }
+}
+
+impl Drop for ScriptedProvider {
+    // A reply that was never requested means the script ran differently than expected
+    fn drop(&mut self) {
+        let left = self.replies.lock().map(|replies| replies.len()).unwrap_or(0);
+        if left > 0 {
+            eprintln!("[SYS] Mock LLM: {} of {} scripted replies were never requested", left, self.total);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::tests::RecordingObserver;
+    use crate::llm::GenerationConfig;
+
+    const SCRIPT: &str = r#"{"replies": [
+        {"expect": {"user": "Read the manifest"},
+         "content": [{"type": "tool_use", "id": "toolu_1", "name": "read_file", "input": {"filename": "Cargo.toml"}}]},
+        {"expect": {"tool_result": "[package]", "is_error": false},
+         "content": [{"type": "text", "text": "A Rust package."}], "tokens": 42}
+    ]}"#;
+
+    #[tokio::test]
+    async fn test_scripted_replies_in_order() {
+        let provider = ScriptedProvider::parse("inline", SCRIPT).unwrap();
+        let mut observer = RecordingObserver::default();
+
+        let mut history = vec![Message::user("Read the manifest".to_string())];
+        let request = ChatRequest { system: String::new(), messages: &history, tools: &[], config: &GenerationConfig::with_model("mock") };
+        let first = provider.chat(&request, &mut observer).await.unwrap();
+        assert_eq!(first.stop_reason.as_deref(), Some("tool_use"));
+
+        history.push(Message::assistant_with_blocks(first.content, 0));
+        history.push(Message::tool_results(vec![ContentBlock::ToolResult {
+            tool_use_id: "toolu_1".to_string(),
+            content: "[package]\nname = \"aish\"".to_string(),
+            is_error: false,
+        }]));
+        let request = ChatRequest { system: String::new(), messages: &history, tools: &[], config: &GenerationConfig::with_model("mock") };
+        let second = provider.chat(&request, &mut observer).await.unwrap();
+
+        assert_eq!(second.tokens_used, 42);
+        assert_eq!(second.stop_reason.as_deref(), Some("end_turn"));
+        assert_eq!(observer.events, vec!["tool:read_file".to_string(), "text:A Rust package.".to_string()]);
+
+        let result = provider.chat(&request, &mut observer).await;
+        assert!(matches!(result, Err(LLMError::RequestFailed(message)) if message.contains("no reply left for request 3")));
+    }
+
+    #[tokio::test]
+    async fn test_unexpected_request_fails() {
+        let provider = ScriptedProvider::parse("inline", SCRIPT).unwrap();
+        let history = vec![Message::user("Something else".to_string())];
+        let request = ChatRequest { system: String::new(), messages: &history, tools: &[], config: &GenerationConfig::with_model("mock") };
+
+        let result = provider.chat(&request, &mut RecordingObserver::default()).await;
+
+        assert!(matches!(result, Err(LLMError::RequestFailed(message)) if message.starts_with("mock request 1: expected user message")));
+    }
+}
@@ -0,0 +1,343 @@
+use crate::context::{ContentBlock, Message};
+use crate::llm::{
+    check_status, read_sse, ChatFuture, ChatRequest, ChunkSource, LLMError, LLMProvider, LLMResponse,
+    ProviderConfig, SseEvent, StreamObserver, ToolDefinition,
+};
+use reqwest::Client;
+use serde_json::{json, Value};
+
+pub const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+pub const DEFAULT_MODEL: &str = "gpt-4o-mini";
+
+// llama.cpp's server listens here; Ollama uses http://localhost:11434/v1
+pub const DEFAULT_LOCAL_BASE_URL: &str = "http://localhost:8080/v1";
+pub const DEFAULT_LOCAL_MODEL: &str = "local-model";
+
+/// Backend for the OpenAI chat-completions schema, spoken by OpenAI itself and
+/// by local model servers such as llama.cpp, Ollama or vLLM
+pub struct OpenAIProvider {
+    client: Client,
+    base_url: String,
+    api_key: Option<String>,
+    local: bool,
+}
+
+impl OpenAIProvider {
+    pub fn new(config: ProviderConfig) -> Self {
+        OpenAIProvider {
+            client: Client::new(),
+            local: config.kind == crate::llm::ProviderKind::Local,
+            base_url: config.base_url,
+            api_key: config.api_key,
+        }
+    }
+
+    async fn send(&self, request: &ChatRequest<'_>, observer: &mut dyn StreamObserver) -> Result<LLMResponse, LLMError> {
+        let config = request.config;
+        let mut body = json!({
+            "model": config.model,
+            "max_tokens": config.max_tokens,
+            "messages": to_api_messages(&request.system, request.messages),
+            "tools": to_api_tools(request.tools),
+            "stream": true,
+            "stream_options": {"include_usage": true}
+        });
+        if let Some(temperature) = config.temperature {
+            body["temperature"] = json!(temperature);
+        }
+        if !config.stop_sequences.is_empty() {
+            body["stop"] = json!(config.stop_sequences);
+        }
+
+        let mut builder = self.client
+            .post(format!("{}/chat/completions", self.base_url))
+            .header("Content-Type", "application/json")
+            .json(&body);
+        if let Some(ref api_key) = self.api_key {
+            builder = builder.bearer_auth(api_key);
+        }
+        if let Some(timeout) = config.timeout {
+            builder = builder.timeout(timeout);
+        }
+        let mut response = check_status(builder.send().await?).await?;
+
+        read_event_stream(&mut response, observer).await
+    }
+}
+
+impl LLMProvider for OpenAIProvider {
+    fn describe(&self, model: &str) -> String {
+        let name = if self.local { "local" } else { "openai" };
+        format!("{} (model: {}, url: {})", name, model, self.base_url)
+    }
+
+    fn chat<'a>(&'a self, request: &'a ChatRequest<'a>, observer: &'a mut dyn StreamObserver) -> ChatFuture<'a> {
+        Box::pin(self.send(request, observer))
+    }
+}
+
+fn to_api_tools(tools: &[ToolDefinition]) -> Vec<Value> {
+    tools.iter()
+        .map(|tool| json!({
+            "type": "function",
+            "function": {
+                "name": tool.name,
+                "description": tool.description,
+                "parameters": tool.input_schema
+            }
+        }))
+        .collect()
+}
+
+// Convert conversation history into chat-completions messages. Tool calls become
+// `tool_calls` on the assistant message and every tool result becomes its own
+// `tool` message, placed before any text of the same user turn.
+fn to_api_messages(system: &str, messages: &[Message]) -> Vec<Value> {
+    let mut api_messages = vec![json!({"role": "system", "content": system})];
+
+    for message in messages.iter().filter(|m| m.role != "system") {
+        let mut text = Vec::new();
+        let mut tool_calls = Vec::new();
+
+        for block in &message.content {
+            match block {
+                ContentBlock::Text { text: fragment } => text.push(fragment.as_str()),
+                ContentBlock::ToolUse { id, name, input } => tool_calls.push(json!({
+                    "id": id,
+                    "type": "function",
+                    "function": {"name": name, "arguments": input.to_string()}
+                })),
+                ContentBlock::ToolResult { tool_use_id, content, .. } => api_messages.push(json!({
+                    "role": "tool",
+                    "tool_call_id": tool_use_id,
+                    "content": content
+                })),
+            }
+        }
+
+        if message.role == "assistant" {
+            let mut api_message = json!({"role": "assistant", "content": text.join("\n")});
+            if !tool_calls.is_empty() {
+                api_message["tool_calls"] = Value::Array(tool_calls);
+            }
+            api_messages.push(api_message);
+        } else if !text.is_empty() {
+            api_messages.push(json!({"role": message.role, "content": text.join("\n")}));
+        }
+    }
+
+    api_messages
+}
+
+// A tool call being assembled from stream deltas, keyed by its index
+struct PendingToolCall {
+    index: u64,
+    id: String,
+    name: String,
+    arguments: String,
+}
+
+// Builds the final response from chat-completions stream chunks
+#[derive(Default)]
+struct StreamAccumulator {
+    text: String,
+    tool_calls: Vec<PendingToolCall>,
+    tokens_used: usize,
+    finish_reason: Option<String>,
+    done: bool,
+}
+
+impl StreamAccumulator {
+    fn handle(&mut self, event: &SseEvent, observer: &mut dyn StreamObserver) -> Result<(), LLMError> {
+        let payload = event.data.trim();
+        if payload.is_empty() || self.done {
+            return Ok(());
+        }
+        if payload == "[DONE]" {
+            self.done = true;
+            return Ok(());
+        }
+        let data: Value = serde_json::from_str(payload)
+            .map_err(|e| LLMError::ParseError(format!("invalid stream chunk: {}", e)))?;
+
+        if let Some(error) = data.get("error") {
+            return Err(LLMError::RequestFailed(error["message"]
+                .as_str()
+                .unwrap_or(payload)
+                .to_string()));
+        }
+
+        if let Some(usage) = data.get("usage").filter(|usage| !usage.is_null()) {
+            let prompt = usage["prompt_tokens"].as_u64().unwrap_or(0);
+            let completion = usage["completion_tokens"].as_u64().unwrap_or(0);
+            self.tokens_used = (prompt + completion) as usize;
+        }
+
+        let Some(choice) = data["choices"].get(0) else {
+            return Ok(()); // The usage chunk has no choices
+        };
+        let delta = &choice["delta"];
+
+        if let Some(fragment) = delta["content"].as_str() {
+            if !fragment.is_empty() {
+                observer.text_delta(fragment);
+                self.text.push_str(fragment);
+            }
+        }
+
+        for call in delta["tool_calls"].as_array().into_iter().flatten() {
+            let index = call["index"].as_u64().unwrap_or(self.tool_calls.len() as u64);
+            if !self.tool_calls.iter().any(|pending| pending.index == index) {
+                self.tool_calls.push(PendingToolCall {
+                    index,
+                    id: String::new(),
+                    name: String::new(),
+                    arguments: String::new(),
+                });
+            }
+            let pending = self.tool_calls.iter_mut().find(|pending| pending.index == index).unwrap();
+
+            if let Some(id) = call["id"].as_str() {
+                pending.id.push_str(id);
+            }
+            if let Some(name) = call["function"]["name"].as_str() {
+                if pending.name.is_empty() && !name.is_empty() {
+                    observer.tool_use_start(name);
+                }
+                pending.name.push_str(name);
+            }
+            if let Some(arguments) = call["function"]["arguments"].as_str() {
+                pending.arguments.push_str(arguments);
+            }
+        }
+
+        if let Some(reason) = choice["finish_reason"].as_str() {
+            self.finish_reason = Some(reason.to_string());
+        }
+
+        Ok(())
+    }
+
+    fn finish(self) -> Result<LLMResponse, LLMError> {
+        let mut content = Vec::new();
+        if !self.text.is_empty() {
+            content.push(ContentBlock::Text { text: self.text });
+        }
+
+        let truncated = self.finish_reason.as_deref() == Some("length");
+        let has_tool_calls = !self.tool_calls.is_empty();
+        for call in self.tool_calls {
+            // A tool call cut off by max_tokens has incomplete arguments; drop it
+            if truncated && serde_json::from_str::<Value>(&call.arguments).is_err() {
+                continue;
+            }
+            let input = if call.arguments.trim().is_empty() {
+                json!({})
+            } else {
+                serde_json::from_str(&call.arguments).map_err(|e| {
+                    LLMError::ParseError(format!("invalid input for tool {}: {}", call.name, e))
+                })?
+            };
+            // Some local servers leave out the call id
+            let id = if call.id.is_empty() { format!("call_{}", call.index) } else { call.id };
+            content.push(ContentBlock::ToolUse { id, name: call.name, input });
+        }
+
+        // Report the stop reason with the names the rest of the shell uses
+        let stop_reason = match self.finish_reason.as_deref() {
+            Some("tool_calls") | Some("function_call") => Some("tool_use".to_string()),
+            Some("length") => Some("max_tokens".to_string()),
+            Some(_) if has_tool_calls => Some("tool_use".to_string()),
+            Some(_) => Some("end_turn".to_string()),
+            None => None,
+        };
+
+        Ok(LLMResponse {
+            content,
+            tokens_used: self.tokens_used,
+            stop_reason,
+        })
+    }
+}
+
+async fn read_event_stream<S: ChunkSource>(source: &mut S, observer: &mut dyn StreamObserver) -> Result<LLMResponse, LLMError> {
+    let mut accumulator = StreamAccumulator::default();
+    read_sse(source, |event| accumulator.handle(event, observer)).await?;
+    accumulator.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::tests::{RecordingObserver, ReplayStream};
+
+    #[test]
+    fn test_tool_calls_and_results_translation() {
+        let history = vec![
+            Message::user("Read the file".to_string()),
+            Message::system("context note".to_string()),
+            Message::assistant_with_blocks(
+                vec![
+                    ContentBlock::Text { text: "Reading it.".to_string() },
+                    ContentBlock::ToolUse { id: "call_1".to_string(), name: "read_file".to_string(), input: json!({"filename": "a"}) },
+                ],
+                10,
+            ),
+            Message::tool_results(vec![
+                ContentBlock::ToolResult { tool_use_id: "call_1".to_string(), content: "A".to_string(), is_error: false },
+            ]),
+        ];
+
+        let api_messages = to_api_messages("be helpful", &history);
+
+        assert_eq!(api_messages, vec![
+            json!({"role": "system", "content": "be helpful"}),
+            json!({"role": "user", "content": "Read the file"}),
+            json!({
+                "role": "assistant",
+                "content": "Reading it.",
+                "tool_calls": [{"id": "call_1", "type": "function", "function": {"name": "read_file", "arguments": "{\"filename\":\"a\"}"}}]
+            }),
+            json!({"role": "tool", "tool_call_id": "call_1", "content": "A"}),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_replay_recorded_stream() {
+        let recording = include_bytes!("../tests/fixtures/openai_stream.sse");
+
+        for chunk_size in [1, 5, recording.len()] {
+            let mut stream = ReplayStream { data: recording.to_vec(), chunk_size };
+            let mut observer = RecordingObserver::default();
+
+            let response = read_event_stream(&mut stream, &mut observer).await.unwrap();
+
+            assert_eq!(observer.events, vec![
+                "text:Let me check ".to_string(),
+                "text:the manifest — ☕".to_string(),
+                "tool:read_file".to_string(),
+            ]);
+            assert_eq!(response.content, vec![
+                ContentBlock::Text { text: "Let me check the manifest — ☕".to_string() },
+                ContentBlock::ToolUse {
+                    id: "call_Qz8fJ2kLr1".to_string(),
+                    name: "read_file".to_string(),
+                    input: json!({"filename": "Cargo.toml"}),
+                },
+            ]);
+            assert_eq!(response.tokens_used, 301 + 27);
+            assert_eq!(response.stop_reason.as_deref(), Some("tool_use"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_length_finish_reason() {
+        let recording = b"data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"partial\"},\"finish_reason\":\"length\"}]}\n\ndata: [DONE]\n\n";
+        let mut stream = ReplayStream { data: recording.to_vec(), chunk_size: 9 };
+
+        let response = read_event_stream(&mut stream, &mut RecordingObserver::default()).await.unwrap();
+
+        assert_eq!(response.content, vec![ContentBlock::Text { text: "partial".to_string() }]);
+        assert_eq!(response.stop_reason.as_deref(), Some("max_tokens"));
+    }
+}
@@ -1,29 +1,207 @@
+use std::collections::HashMap;
 use std::fmt;
 
 #[derive(Debug, Clone)]
 pub enum CommandLine {
     Simple(SimpleCommand),
-    Pipeline(Vec<SimpleCommand>),
-    Background(SimpleCommand),
+    // `a | b | c`: any kind of command can be a stage
+    Pipeline(Vec<CommandLine>),
+    // `command &`: runs as a background job while the shell goes on
+    Background(Box<CommandLine>),
+    // `left && right`: right runs only when left succeeds
+    And(Box<CommandLine>, Box<CommandLine>),
+    // `left || right`: right runs only when left fails
+    Or(Box<CommandLine>, Box<CommandLine>),
+    // Commands separated by `;`, `&` or newlines, run one after the other
+    List(Vec<CommandLine>),
+    // `if c1; then b1; elif c2; then b2; else b3; fi`: the (condition, body) pairs in order
+    If {
+        branches: Vec<(CommandLine, CommandLine)>,
+        else_branch: Option<Box<CommandLine>>,
+    },
+    // `while c; do b; done`, or `until c; do b; done` when `until` is set
+    While {
+        condition: Box<CommandLine>,
+        body: Box<CommandLine>,
+        until: bool,
+    },
+    // `for name in words; do b; done`; without `in`, words is None
+    For {
+        variable: String,
+        words: Option<Vec<String>>,
+        body: Box<CommandLine>,
+    },
+    // `case word in pattern|pattern) b;; ... esac`
+    Case {
+        word: String,
+        arms: Vec<CaseArm>,
+    },
+    // `name() { body; }`: defines a shell function, run later by name
+    FunctionDef {
+        name: String,
+        body: Box<CommandLine>,
+    },
+    // `( list )`: runs in a forked copy of the shell, so changes stay inside
+    Subshell(Box<CommandLine>),
+    // `{ list; }`: runs in the shell itself, as one command for redirections and pipes
+    Group(Box<CommandLine>),
+    // `(( expression ))`: succeeds when the arithmetic expression is not zero
+    Arithmetic(String),
+    // A compound command with redirections, such as `while ...; done < file`
+    Redirected {
+        command: Box<CommandLine>,
+        redirections: Vec<Redirection>,
+    },
+}
+
+impl CommandLine {
+    // The command name that runs first, as typed; the keyword for compound commands
+    pub fn first_word(&self) -> Option<&str> {
+        match self {
+            CommandLine::Simple(cmd) => cmd.args.first().map(String::as_str),
+            CommandLine::Pipeline(commands) => commands.first()?.first_word(),
+            CommandLine::Background(command) | CommandLine::Subshell(command) => command.first_word(),
+            CommandLine::Redirected { command, .. } => command.first_word(),
+            CommandLine::And(left, _) | CommandLine::Or(left, _) => left.first_word(),
+            CommandLine::List(items) => items.first()?.first_word(),
+            CommandLine::If { .. } => Some("if"),
+            CommandLine::While { until: false, .. } => Some("while"),
+            CommandLine::While { until: true, .. } => Some("until"),
+            CommandLine::For { .. } => Some("for"),
+            CommandLine::Case { .. } => Some("case"),
+            CommandLine::Group(_) => Some("{"),
+            CommandLine::Arithmetic(_) => Some("(("),
+            CommandLine::FunctionDef { .. } => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CaseArm {
+    pub patterns: Vec<String>,
+    // None for an empty arm such as `*) ;;`
+    pub body: Option<CommandLine>,
 }
 
 #[derive(Debug, Clone)]
 pub struct SimpleCommand {
+    // `name=value` words before the command name, with the values as typed
+    pub assignments: Vec<(String, String)>,
     pub args: Vec<String>,
     pub redirections: Vec<Redirection>,
 }
 
+// Commands are shown the way they could be typed, on one line, as in `jobs`
+impl fmt::Display for CommandLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandLine::Simple(cmd) => write!(f, "{}", cmd),
+            CommandLine::Pipeline(commands) => {
+                let stages: Vec<String> = commands.iter().map(CommandLine::to_string).collect();
+                write!(f, "{}", stages.join(" | "))
+            }
+            CommandLine::Background(command) => write!(f, "{} &", command),
+            CommandLine::And(left, right) => write!(f, "{} && {}", left, right),
+            CommandLine::Or(left, right) => write!(f, "{} || {}", left, right),
+            CommandLine::List(items) => {
+                let items: Vec<String> = items.iter().map(CommandLine::to_string).collect();
+                write!(f, "{}", items.join("; "))
+            }
+            CommandLine::If { branches, else_branch } => {
+                for (index, (condition, body)) in branches.iter().enumerate() {
+                    let keyword = if index == 0 { "if" } else { "elif" };
+                    write!(f, "{} {}; then {}; ", keyword, condition, body)?;
+                }
+                if let Some(else_branch) = else_branch {
+                    write!(f, "else {}; ", else_branch)?;
+                }
+                write!(f, "fi")
+            }
+            CommandLine::While { condition, body, until } => {
+                write!(f, "{} {}; do {}; done", if *until { "until" } else { "while" }, condition, body)
+            }
+            CommandLine::For { variable, words, body } => {
+                write!(f, "for {}", variable)?;
+                if let Some(words) = words {
+                    write!(f, " in {}", words.join(" "))?;
+                }
+                write!(f, "; do {}; done", body)
+            }
+            CommandLine::Case { word, arms } => {
+                write!(f, "case {} in", word)?;
+                for arm in arms {
+                    write!(f, " {})", arm.patterns.join("|"))?;
+                    if let Some(body) = &arm.body {
+                        write!(f, " {}", body)?;
+                    }
+                    write!(f, ";;")?;
+                }
+                write!(f, " esac")
+            }
+            CommandLine::FunctionDef { name, body } => write!(f, "{}() {{ {}; }}", name, body),
+            CommandLine::Subshell(body) => write!(f, "({})", body),
+            CommandLine::Group(body) => write!(f, "{{ {}; }}", body),
+            CommandLine::Arithmetic(expression) => write!(f, "(({}))", expression),
+            CommandLine::Redirected { command, redirections } => {
+                write!(f, "{}", command)?;
+                for redirection in redirections {
+                    write!(f, " {}", redirection)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Display for SimpleCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut words: Vec<String> = self.assignments.iter().map(|(name, value)| format!("{}={}", name, value)).collect();
+        words.extend(self.args.iter().cloned());
+        words.extend(self.redirections.iter().map(Redirection::to_string));
+        write!(f, "{}", words.join(" "))
+    }
+}
+
+impl fmt::Display for Redirection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (default_fd, operator) = match self.redir_type {
+            RedirectionType::Input => (0, "<"),
+            RedirectionType::Output => (1, ">"),
+            RedirectionType::Append => (1, ">>"),
+            RedirectionType::ReadWrite => (0, "<>"),
+            RedirectionType::Duplicate if self.fd == 0 => (0, "<&"),
+            RedirectionType::Duplicate => (1, ">&"),
+            // The document itself would not fit on the line
+            RedirectionType::HereDoc { .. } => return write!(f, "<<..."),
+            RedirectionType::HereString => (0, "<<<"),
+        };
+        if self.fd != default_fd {
+            write!(f, "{}", self.fd)?;
+        }
+        write!(f, "{}{}", operator, self.target)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Redirection {
+    // The descriptor being redirected: 0 for `<` and 1 for `>` unless a number precedes the operator
+    pub fd: i32,
     pub redir_type: RedirectionType,
-    pub filename: String,
+    // A file name, or for `Duplicate` the descriptor to copy, or `-` to close `fd`
+    pub target: String,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum RedirectionType {
-    Input,   // <
-    Output,  // >
-    Append,  // >>
+    Input,      // [n]<
+    Output,     // [n]> and [n]>|
+    Append,     // [n]>>
+    ReadWrite,  // [n]<>
+    Duplicate,  // [n]>&m, [n]<&m and [n]>&-
+    // [n]<<word and [n]<<-word: the target is the text of the document, expanded
+    // like a double-quoted string unless the delimiter word was quoted
+    HereDoc { expand: bool },
+    HereString, // [n]<<< word
 }
 
 #[derive(Debug)]
@@ -32,6 +210,25 @@ pub enum ParseError {
     MissingFilename,
     EmptyCommand,
     InvalidSyntax(String),
+    UnterminatedQuote(char),
+    UnterminatedSubstitution,
+    UnterminatedParameterExpansion,
+    // The input stops inside a construct; the text says what was still expected
+    Incomplete(String),
+}
+
+impl ParseError {
+    // Could more lines of input complete the command? Scripts and the prompt keep
+    // reading while this is the case.
+    pub fn is_incomplete(&self) -> bool {
+        matches!(
+            self,
+            ParseError::Incomplete(_)
+                | ParseError::UnterminatedQuote(_)
+                | ParseError::UnterminatedSubstitution
+                | ParseError::UnterminatedParameterExpansion
+        )
+    }
 }
 
 impl fmt::Display for ParseError {
@@ -41,15 +238,104 @@ impl fmt::Display for ParseError {
             ParseError::MissingFilename => write!(f, "Missing filename for redirection"),
             ParseError::EmptyCommand => write!(f, "Empty command"),
             ParseError::InvalidSyntax(msg) => write!(f, "Invalid syntax: {}", msg),
+            ParseError::UnterminatedQuote(quote) => write!(f, "Unterminated quote: {}", quote),
+            ParseError::UnterminatedSubstitution => write!(f, "Unterminated command substitution: $("),
+            ParseError::UnterminatedParameterExpansion => write!(f, "Unterminated parameter expansion: ${{"),
+            ParseError::Incomplete(expected) => write!(f, "Unexpected end of input, expected {}", expected),
         }
     }
 }
 
 impl std::error::Error for ParseError {}
 
+// Operators recognized by the tokenizer, longest first
+const OPERATORS: &[&str] = &[
+    "<<<", "<<-", "&>>", "<<", "&&", "||", ";;", "&>", ">>", ">&", "<&", "<>", ">|", "|", "&", ";", "<", ">", "(", ")", "\n",
+];
+
+// Operators that redirect the command they follow, with the descriptor each
+// applies to when no number is given
+fn redirection_operator(op: &str) -> Option<(i32, RedirectionType)> {
+    match op {
+        "<" => Some((0, RedirectionType::Input)),
+        ">" | ">|" | "&>" => Some((1, RedirectionType::Output)),
+        ">>" | "&>>" => Some((1, RedirectionType::Append)),
+        "<>" => Some((0, RedirectionType::ReadWrite)),
+        "<&" => Some((0, RedirectionType::Duplicate)),
+        "<<" | "<<-" => Some((0, RedirectionType::HereDoc { expand: true })),
+        "<<<" => Some((0, RedirectionType::HereString)),
+        ">&" => Some((1, RedirectionType::Duplicate)),
+        _ => None,
+    }
+}
+
+// Words with a meaning of their own where a command name is expected
+const RESERVED_WORDS: &[&str] = &[
+    "if", "then", "elif", "else", "fi", "while", "until", "do", "done", "for", "in", "case", "esac", "{", "}",
+];
+
+pub fn is_reserved_word(word: &str) -> bool {
+    RESERVED_WORDS.contains(&word)
+}
+
+// A variable name: letters, digits and underscores, not starting with a digit
+pub fn is_name(word: &str) -> bool {
+    !word.is_empty()
+        && !word.starts_with(|c: char| c.is_ascii_digit())
+        && word.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+// A word such as `name=value`, split into the name and the value; the name must
+// be unquoted
+fn assignment(word: &str) -> Option<(String, String)> {
+    let (name, value) = word.split_once('=')?;
+    is_name(name).then(|| (name.to_string(), value.to_string()))
+}
+
+// A name that `name() { ...; }` can define: a variable name, also allowing `-`, `.` and `:`
+pub fn is_function_name(word: &str) -> bool {
+    !word.is_empty()
+        && !is_reserved_word(word)
+        && word.chars().all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.' | ':'))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    // A word as typed, quotes and backslashes included; see `expand_word`
+    Word(String),
+    // The digits right before a redirection operator, as in `2>`
+    IoNumber(i32),
+    // The delimiter word after `<<`, with the lines of the document that followed
+    // the command
+    HereDoc { delimiter: String, body: String, expand: bool },
+    // The expression of `(( expression ))`
+    Arithmetic(String),
+    Operator(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "{}", word),
+            Token::IoNumber(fd) => write!(f, "{}", fd),
+            Token::HereDoc { delimiter, .. } => write!(f, "{}", delimiter),
+            Token::Arithmetic(expression) => write!(f, "(({}))", expression),
+            Token::Operator("\n") => write!(f, "newline"),
+            Token::Operator(op) => write!(f, "{}", op),
+        }
+    }
+}
+
 pub struct Parser {
-    tokens: Vec<String>,
+    tokens: Vec<Token>,
     position: usize,
+    aliases: HashMap<String, String>,
+    // Aliases being expanded, each with the end of the tokens its value produced;
+    // a word in that range does not expand the same alias again
+    active_aliases: Vec<(String, usize)>,
+    // A word after an alias whose value ends in a blank, which is checked for an
+    // alias as well
+    alias_next: Option<usize>,
 }
 
 impl Parser {
@@ -57,224 +343,998 @@ impl Parser {
         Parser {
             tokens: Vec::new(),
             position: 0,
+            aliases: HashMap::new(),
+            active_aliases: Vec::new(),
+            alias_next: None,
         }
     }
 
+    // Parse a whole command line or script fragment, which may span several lines
     pub fn parse(&mut self, input: &str) -> Result<CommandLine, ParseError> {
-        self.tokens = self.tokenize(input);
+        self.parse_with_aliases(input, &HashMap::new())
+    }
+
+    // Parse with aliases expanded: an unquoted command name with an alias is
+    // replaced by the alias value, as if it had been typed
+    pub fn parse_with_aliases(&mut self, input: &str, aliases: &HashMap<String, String>) -> Result<CommandLine, ParseError> {
+        self.tokens = self.tokenize(input)?;
         self.position = 0;
+        self.aliases = aliases.clone();
+        self.active_aliases.clear();
+        self.alias_next = None;
 
-        if self.tokens.is_empty() {
+        self.skip_newlines();
+        if self.position >= self.tokens.len() {
             return Err(ParseError::EmptyCommand);
         }
 
-        // Check for background execution
-        let is_background = self.tokens.last() == Some(&"&".to_string());
-        if is_background {
-            self.tokens.pop();
-        }
-
-        // Check for pipeline
-        if self.tokens.contains(&"|".to_string()) {
-            if is_background {
-                return Err(ParseError::InvalidSyntax("Background pipelines not supported".to_string()));
-            }
-            return self.parse_pipeline();
-        }
-
-        // Parse simple command
-        let simple_command = self.parse_simple_command()?;
-        
-        if is_background {
-            Ok(CommandLine::Background(simple_command))
-        } else {
-            Ok(CommandLine::Simple(simple_command))
+        let command_line = self.parse_list(&[])?;
+        match self.tokens.get(self.position) {
+            Some(token) => Err(ParseError::UnexpectedToken(token.to_string())),
+            None => Ok(command_line),
         }
     }
 
-    fn tokenize(&self, input: &str) -> Vec<String> {
+    fn tokenize(&self, input: &str) -> Result<Vec<Token>, ParseError> {
+        let chars: Vec<char> = input.chars().collect();
         let mut tokens = Vec::new();
         let mut current_token = String::new();
-        let mut in_quotes = false;
-        let mut quote_char = ' ';
-        let mut chars = input.chars().peekable();
+        let mut in_word = false;
+        // Here-documents whose body starts after the next newline: the position of
+        // the delimiter token, and whether leading tabs are stripped (`<<-`)
+        let mut here_docs: Vec<(usize, bool)> = Vec::new();
+        let mut i = 0;
 
-        while let Some(ch) = chars.next() {
+        while i < chars.len() {
+            let ch = chars[i];
             match ch {
-                '"' | '\'' if !in_quotes => {
-                    in_quotes = true;
-                    quote_char = ch;
+                '"' | '\'' | '`' | '$' if ch != '$' || matches!(chars.get(i + 1), Some('(') | Some('{')) => {
+                    // Quotes and substitutions are kept whole, operators inside them included
+                    let end = scan_quoted(&chars, i)?;
+                    current_token.extend(&chars[i..end]);
+                    in_word = true;
+                    i = end;
+                    continue;
                 }
-                '"' | '\'' if in_quotes && ch == quote_char => {
-                    in_quotes = false;
-                    quote_char = ' ';
+                '\\' => {
+                    match chars.get(i + 1) {
+                        // A backslash at the end of a line joins it with the next one
+                        Some('\n') => {}
+                        Some(&next) => {
+                            current_token.push('\\');
+                            current_token.push(next);
+                            in_word = true;
+                        }
+                        None => return Err(ParseError::Incomplete("a line after \\".to_string())),
+                    }
+                    i += 2;
+                    continue;
                 }
-                ' ' | '\t' if !in_quotes => {
-                    if !current_token.is_empty() {
-                        tokens.push(current_token.clone());
-                        current_token.clear();
+                '#' if !in_word => {
+                    // A comment runs to the end of the line
+                    while i < chars.len() && chars[i] != '\n' {
+                        i += 1;
                     }
+                    continue;
                 }
-                '|' | '&' | '<' | '>' if !in_quotes => {
-                    if !current_token.is_empty() {
-                        tokens.push(current_token.clone());
-                        current_token.clear();
+                ' ' | '\t' => {
+                    if in_word {
+                        tokens.push(Token::Word(std::mem::take(&mut current_token)));
+                        in_word = false;
                     }
-                    
-                    // Handle >> redirection
-                    if ch == '>' && chars.peek() == Some(&'>') {
-                        chars.next();
-                        tokens.push(">>".to_string());
+                }
+                '(' if !in_word && chars.get(i + 1) == Some(&'(') => {
+                    // `((` starts an arithmetic command when a `))` closes it;
+                    // otherwise it is two subshells, as in `((cd src); ls)`
+                    if let Some(end) = scan_arithmetic(&chars, i)? {
+                        tokens.push(Token::Arithmetic(chars[i + 2..end - 2].iter().collect()));
+                        i = end;
                     } else {
-                        tokens.push(ch.to_string());
+                        tokens.push(Token::Operator("("));
+                        i += 1;
                     }
+                    continue;
                 }
-                '\\' if !in_quotes => {
-                    // Handle escape sequences
-                    if let Some(next_ch) = chars.next() {
-                        current_token.push(next_ch);
+                '|' | '&' | ';' | '<' | '>' | '(' | ')' | '\n' => {
+                    if in_word {
+                        let word = std::mem::take(&mut current_token);
+                        let io_number = match ch {
+                            '<' | '>' if word.chars().all(|c| c.is_ascii_digit()) => word.parse().ok(),
+                            _ => None,
+                        };
+                        tokens.push(io_number.map_or(Token::Word(word), Token::IoNumber));
+                        in_word = false;
                     }
+
+                    let next: String = chars[i..].iter().take(3).collect();
+                    let operator = OPERATORS
+                        .iter()
+                        .find(|op| next.starts_with(**op))
+                        .copied()
+                        .unwrap_or_default();
+                    tokens.push(Token::Operator(operator));
+                    i += operator.chars().count();
+                    match operator {
+                        "<<" | "<<-" => here_docs.push((tokens.len(), operator == "<<-")),
+                        "\n" => {
+                            for (index, strip_tabs) in here_docs.drain(..) {
+                                i = read_here_doc(&chars, i, &mut tokens, index, strip_tabs)?;
+                            }
+                        }
+                        _ => {}
+                    }
+                    continue;
                 }
                 _ => {
                     current_token.push(ch);
+                    in_word = true;
                 }
             }
+            i += 1;
         }
 
-        if !current_token.is_empty() {
-            tokens.push(current_token);
+        if in_word {
+            tokens.push(Token::Word(current_token));
+        }
+        if let Some(&(index, _)) = here_docs.first() {
+            if let Some(Token::Word(word)) = tokens.get(index) {
+                return Err(ParseError::Incomplete(format!("here-document delimited by {}", here_doc_delimiter(word).0)));
+            }
         }
 
-        tokens
+        Ok(tokens)
     }
 
-    fn parse_pipeline(&mut self) -> Result<CommandLine, ParseError> {
-        let mut commands = Vec::new();
-        let mut current_args = Vec::new();
+    fn peek_operator(&self) -> Option<&'static str> {
+        match self.tokens.get(self.position) {
+            Some(Token::Operator(op)) => Some(op),
+            _ => None,
+        }
+    }
 
-        for token in &self.tokens {
-            if token == "|" {
-                if current_args.is_empty() {
-                    return Err(ParseError::InvalidSyntax("Empty command in pipeline".to_string()));
+    fn peek_word(&self) -> Option<&str> {
+        match self.tokens.get(self.position) {
+            Some(Token::Word(word)) => Some(word),
+            _ => None,
+        }
+    }
+
+    // Expand the alias named by the word at the current position, then any alias
+    // its value starts with. An alias is not expanded again inside its own value.
+    fn expand_aliases(&mut self) -> Result<(), ParseError> {
+        loop {
+            let position = self.position;
+            let Some(Token::Word(word)) = self.tokens.get(position) else {
+                return Ok(());
+            };
+            let expanding = self.active_aliases.iter().any(|(name, end)| name == word && position < *end);
+            let Some(value) = self.aliases.get(word).filter(|_| !expanding).cloned() else {
+                return Ok(());
+            };
+            let name = word.clone();
+
+            let replacement = self.tokenize(&value)?;
+            let added = replacement.len();
+            self.tokens.splice(position..position + 1, replacement);
+
+            // Positions after the alias moved by the length of its value
+            let shift = |index: &mut usize| {
+                if *index > position {
+                    *index = *index + added - 1;
                 }
-                commands.push(SimpleCommand {
-                    args: current_args.clone(),
-                    redirections: Vec::new(), // Redirections in pipelines are complex, simplified for now
-                });
-                current_args.clear();
-            } else {
-                current_args.push(token.clone());
+            };
+            self.active_aliases.iter_mut().for_each(|(_, end)| shift(end));
+            if let Some(next) = self.alias_next.as_mut() {
+                shift(next);
+            }
+            self.active_aliases.push((name, position + added));
+            if value.ends_with([' ', '\t']) {
+                self.alias_next = Some(position + added);
             }
         }
+    }
 
-        if current_args.is_empty() {
-            return Err(ParseError::InvalidSyntax("Pipeline ends with |".to_string()));
+    fn skip_newlines(&mut self) {
+        while self.peek_operator() == Some("\n") {
+            self.position += 1;
         }
+    }
 
-        commands.push(SimpleCommand {
-            args: current_args,
-            redirections: Vec::new(),
-        });
-
-        Ok(CommandLine::Pipeline(commands))
+    // Consume a keyword that must come next, such as the `fi` closing an `if`
+    fn expect_word(&mut self, keyword: &str) -> Result<(), ParseError> {
+        match self.tokens.get(self.position) {
+            Some(Token::Word(word)) if word == keyword => {
+                self.position += 1;
+                Ok(())
+            }
+            Some(token) => Err(ParseError::UnexpectedToken(token.to_string())),
+            None => Err(ParseError::Incomplete(keyword.to_string())),
+        }
     }
 
-    fn parse_simple_command(&mut self) -> Result<SimpleCommand, ParseError> {
-        let mut args = Vec::new();
-        let mut redirections = Vec::new();
+    // list: and_or ((';' | '&' | newline) and_or)* [';' | '&' | newline]
+    // The list ends at the end of input, before a token it cannot use, or where a
+    // command would start with one of `terminators` (`fi` in an `if` body, say).
+    fn parse_list(&mut self, terminators: &[&str]) -> Result<CommandLine, ParseError> {
+        let mut items = Vec::new();
 
-        while self.position < self.tokens.len() {
-            let token = &self.tokens[self.position];
+        loop {
+            self.skip_newlines();
+            match self.tokens.get(self.position) {
+                None => break,
+                Some(token) if terminators.contains(&token.to_string().as_str()) => break,
+                _ => {}
+            }
 
-            match token.as_str() {
-                "<" => {
-                    self.position += 1;
-                    if self.position >= self.tokens.len() {
-                        return Err(ParseError::MissingFilename);
-                    }
-                    redirections.push(Redirection {
-                        redir_type: RedirectionType::Input,
-                        filename: self.tokens[self.position].clone(),
-                    });
-                }
-                ">" => {
+            let item = self.parse_and_or()?;
+            match self.peek_operator() {
+                Some(";") | Some("\n") => {
                     self.position += 1;
-                    if self.position >= self.tokens.len() {
-                        return Err(ParseError::MissingFilename);
-                    }
-                    redirections.push(Redirection {
-                        redir_type: RedirectionType::Output,
-                        filename: self.tokens[self.position].clone(),
-                    });
+                    items.push(item);
                 }
-                ">>" => {
+                Some("&") => {
                     self.position += 1;
-                    if self.position >= self.tokens.len() {
-                        return Err(ParseError::MissingFilename);
-                    }
-                    redirections.push(Redirection {
-                        redir_type: RedirectionType::Append,
-                        filename: self.tokens[self.position].clone(),
-                    });
+                    items.push(CommandLine::Background(Box::new(item)));
                 }
                 _ => {
-                    args.push(self.expand_variables(token));
+                    items.push(item);
+                    break;
                 }
             }
+        }
+
+        if items.is_empty() {
+            return Err(match (self.tokens.get(self.position), terminators.first()) {
+                (Some(token), _) => ParseError::UnexpectedToken(token.to_string()),
+                (None, Some(terminator)) => ParseError::Incomplete(format!("commands before {}", terminator)),
+                (None, None) => ParseError::EmptyCommand,
+            });
+        }
+
+        if items.len() == 1 {
+            Ok(items.pop().unwrap())
+        } else {
+            Ok(CommandLine::List(items))
+        }
+    }
+
+    // and_or: pipeline (('&&' | '||') newline* pipeline)*, grouped from the left
+    fn parse_and_or(&mut self) -> Result<CommandLine, ParseError> {
+        let mut left = self.parse_pipeline()?;
+
+        while let Some(op @ ("&&" | "||")) = self.peek_operator() {
+            self.position += 1;
+            self.skip_newlines();
+            if self.position >= self.tokens.len() {
+                return Err(ParseError::Incomplete(format!("a command after {}", op)));
+            }
+            let right = self.parse_pipeline()?;
+            left = if op == "&&" {
+                CommandLine::And(Box::new(left), Box::new(right))
+            } else {
+                CommandLine::Or(Box::new(left), Box::new(right))
+            };
+        }
+
+        Ok(left)
+    }
+
+    fn parse_pipeline(&mut self) -> Result<CommandLine, ParseError> {
+        let first = self.parse_command()?;
+        if self.peek_operator() != Some("|") {
+            return Ok(first);
+        }
+
+        let mut commands = vec![first];
+        while self.peek_operator() == Some("|") {
             self.position += 1;
+            self.skip_newlines();
+            if self.position >= self.tokens.len() {
+                return Err(ParseError::Incomplete("a command after |".to_string()));
+            }
+            commands.push(self.parse_command()?);
         }
 
-        if args.is_empty() {
-            return Err(ParseError::EmptyCommand);
+        Ok(CommandLine::Pipeline(commands))
+    }
+
+    // command: compound_command redirection* | function_definition | simple_command
+    fn parse_command(&mut self) -> Result<CommandLine, ParseError> {
+        let defines_function = matches!(
+            self.tokens.get(self.position..self.position + 3),
+            Some([Token::Word(name), Token::Operator("("), Token::Operator(")")]) if is_function_name(name)
+        );
+        if defines_function {
+            return self.parse_function_definition();
         }
+        self.expand_aliases()?;
 
-        Ok(SimpleCommand { args, redirections })
+        if let Some(Token::Arithmetic(expression)) = self.tokens.get(self.position) {
+            let command = CommandLine::Arithmetic(expression.clone());
+            self.position += 1;
+            return self.parse_redirections_after(command);
+        }
+        let command = match (self.peek_word(), self.peek_operator()) {
+            (Some("if"), _) => self.parse_if()?,
+            (Some("while"), _) | (Some("until"), _) => self.parse_while()?,
+            (Some("for"), _) => self.parse_for()?,
+            (Some("case"), _) => self.parse_case()?,
+            (Some("{"), _) => self.parse_group()?,
+            (_, Some("(")) => self.parse_subshell()?,
+            (Some(word), _) if is_reserved_word(word) => return Err(ParseError::UnexpectedToken(word.to_string())),
+            _ => return Ok(CommandLine::Simple(self.parse_simple_command()?)),
+        };
+        self.parse_redirections_after(command)
     }
 
-    fn expand_variables(&self, token: &str) -> String {
-        let mut result = String::new();
-        let mut chars = token.chars().peekable();
+    // Redirections after a compound command apply to all of it
+    fn parse_redirections_after(&mut self, command: CommandLine) -> Result<CommandLine, ParseError> {
+        let mut redirections = Vec::new();
+        while self.parse_redirection(&mut redirections)? {}
+        if redirections.is_empty() {
+            Ok(command)
+        } else {
+            Ok(CommandLine::Redirected { command: Box::new(command), redirections })
+        }
+    }
 
-        while let Some(ch) = chars.next() {
-            if ch == '$' {
-                if chars.peek() == Some(&'{') {
-                    chars.next(); // consume '{'
-                    let mut var_name = String::new();
-                    
-                    while let Some(ch) = chars.next() {
-                        if ch == '}' {
-                            break;
-                        }
-                        var_name.push(ch);
-                    }
-                    
-                    if let Ok(value) = std::env::var(&var_name) {
-                        result.push_str(&value);
-                    }
-                } else {
-                    // Simple variable expansion $VAR
-                    let mut var_name = String::new();
-                    while let Some(&ch) = chars.peek() {
-                        if ch.is_alphanumeric() || ch == '_' {
-                            var_name.push(chars.next().unwrap());
-                        } else {
-                            break;
-                        }
+    // '(' list ')'
+    fn parse_subshell(&mut self) -> Result<CommandLine, ParseError> {
+        self.position += 1;
+        let body = self.parse_list(&[")"])?;
+        match self.tokens.get(self.position) {
+            Some(Token::Operator(")")) => self.position += 1,
+            Some(token) => return Err(ParseError::UnexpectedToken(token.to_string())),
+            None => return Err(ParseError::Incomplete(")".to_string())),
+        }
+        Ok(CommandLine::Subshell(Box::new(body)))
+    }
+
+    // '{' list '}'; the `}` is only a keyword where a command could start
+    fn parse_group(&mut self) -> Result<CommandLine, ParseError> {
+        self.position += 1;
+        let body = self.parse_list(&["}"])?;
+        self.expect_word("}")?;
+        Ok(CommandLine::Group(Box::new(body)))
+    }
+
+    // name '(' ')' newline* ('{' list '}' | compound_command)
+    fn parse_function_definition(&mut self) -> Result<CommandLine, ParseError> {
+        let name = self.peek_word().unwrap_or_default().to_string();
+        self.position += 3;
+        self.skip_newlines();
+
+        let body = match (self.peek_word(), self.peek_operator()) {
+            (Some("{"), _) => {
+                self.position += 1;
+                let body = self.parse_list(&["}"])?;
+                self.expect_word("}")?;
+                // `f() { ...; } > log` redirects every call
+                self.parse_redirections_after(body)?
+            }
+            (Some("if") | Some("while") | Some("until") | Some("for") | Some("case"), _) | (_, Some("(")) => {
+                self.parse_command()?
+            }
+            (Some(word), _) => return Err(ParseError::UnexpectedToken(word.to_string())),
+            (None, _) => {
+                return Err(match self.tokens.get(self.position) {
+                    Some(token) => ParseError::UnexpectedToken(token.to_string()),
+                    None => ParseError::Incomplete(format!("the body of {}()", name)),
+                })
+            }
+        };
+
+        Ok(CommandLine::FunctionDef { name, body: Box::new(body) })
+    }
+
+    // if list then list (elif list then list)* [else list] fi
+    fn parse_if(&mut self) -> Result<CommandLine, ParseError> {
+        self.position += 1;
+        let mut branches = Vec::new();
+
+        loop {
+            let condition = self.parse_list(&["then"])?;
+            self.expect_word("then")?;
+            let body = self.parse_list(&["elif", "else", "fi"])?;
+            branches.push((condition, body));
+
+            if self.peek_word() != Some("elif") {
+                break;
+            }
+            self.position += 1;
+        }
+
+        let else_branch = if self.peek_word() == Some("else") {
+            self.position += 1;
+            Some(Box::new(self.parse_list(&["fi"])?))
+        } else {
+            None
+        };
+        self.expect_word("fi")?;
+
+        Ok(CommandLine::If { branches, else_branch })
+    }
+
+    // (while | until) list do list done
+    fn parse_while(&mut self) -> Result<CommandLine, ParseError> {
+        let until = self.peek_word() == Some("until");
+        self.position += 1;
+
+        let condition = self.parse_list(&["do"])?;
+        self.expect_word("do")?;
+        let body = self.parse_list(&["done"])?;
+        self.expect_word("done")?;
+
+        Ok(CommandLine::While {
+            condition: Box::new(condition),
+            body: Box::new(body),
+            until,
+        })
+    }
+
+    // for name [in word*] (';' | newline) do list done
+    fn parse_for(&mut self) -> Result<CommandLine, ParseError> {
+        self.position += 1;
+        let variable = match self.tokens.get(self.position) {
+            Some(Token::Word(name)) if is_name(name) => name.clone(),
+            Some(token) => return Err(ParseError::InvalidSyntax(format!("`{}': not a valid identifier", token))),
+            None => return Err(ParseError::Incomplete("a variable name after for".to_string())),
+        };
+        self.position += 1;
+
+        if self.peek_operator() == Some(";") {
+            self.position += 1;
+        }
+        self.skip_newlines();
+
+        let words = if self.peek_word() == Some("in") {
+            self.position += 1;
+            let mut words = Vec::new();
+            while let Some(word) = self.peek_word() {
+                words.push(word.to_string());
+                self.position += 1;
+            }
+            match self.peek_operator() {
+                Some(";") | Some("\n") => self.position += 1,
+                Some(op) => return Err(ParseError::UnexpectedToken(op.to_string())),
+                None => return Err(ParseError::Incomplete("do".to_string())),
+            }
+            self.skip_newlines();
+            Some(words)
+        } else {
+            None
+        };
+
+        self.expect_word("do")?;
+        let body = self.parse_list(&["done"])?;
+        self.expect_word("done")?;
+
+        Ok(CommandLine::For {
+            variable,
+            words,
+            body: Box::new(body),
+        })
+    }
+
+    // case word in ([(] pattern ('|' pattern)* ')' [list] [';;'])* esac
+    fn parse_case(&mut self) -> Result<CommandLine, ParseError> {
+        self.position += 1;
+        let word = match self.tokens.get(self.position) {
+            Some(Token::Word(word)) => word.clone(),
+            Some(token) => return Err(ParseError::UnexpectedToken(token.to_string())),
+            None => return Err(ParseError::Incomplete("a word after case".to_string())),
+        };
+        self.position += 1;
+        self.skip_newlines();
+        self.expect_word("in")?;
+
+        let mut arms = Vec::new();
+        loop {
+            self.skip_newlines();
+            match self.peek_word() {
+                Some("esac") => {
+                    self.position += 1;
+                    break;
+                }
+                None if self.position >= self.tokens.len() => {
+                    return Err(ParseError::Incomplete("esac".to_string()));
+                }
+                _ => {}
+            }
+
+            if self.peek_operator() == Some("(") {
+                self.position += 1;
+            }
+            let mut patterns = Vec::new();
+            loop {
+                match self.tokens.get(self.position) {
+                    Some(Token::Word(pattern)) => patterns.push(pattern.clone()),
+                    Some(token) => return Err(ParseError::UnexpectedToken(token.to_string())),
+                    None => return Err(ParseError::Incomplete(")".to_string())),
+                }
+                self.position += 1;
+                match self.tokens.get(self.position) {
+                    Some(Token::Operator("|")) => self.position += 1,
+                    Some(Token::Operator(")")) => {
+                        self.position += 1;
+                        break;
                     }
-                    
-                    if !var_name.is_empty() {
-                        if let Ok(value) = std::env::var(&var_name) {
-                            result.push_str(&value);
-                        }
-                    } else {
-                        result.push('$');
+                    Some(token) => return Err(ParseError::UnexpectedToken(token.to_string())),
+                    None => return Err(ParseError::Incomplete(")".to_string())),
+                }
+            }
+
+            self.skip_newlines();
+            let body = match self.tokens.get(self.position) {
+                Some(Token::Operator(";;")) => None,
+                Some(Token::Word(word)) if word == "esac" => None,
+                _ => Some(self.parse_list(&[";;", "esac"])?),
+            };
+            arms.push(CaseArm { patterns, body });
+
+            match self.tokens.get(self.position) {
+                Some(Token::Operator(";;")) => self.position += 1,
+                Some(Token::Word(word)) if word == "esac" => {}
+                Some(token) => return Err(ParseError::UnexpectedToken(token.to_string())),
+                None => return Err(ParseError::Incomplete("esac".to_string())),
+            }
+        }
+
+        Ok(CommandLine::Case { word, arms })
+    }
+
+    fn parse_simple_command(&mut self) -> Result<SimpleCommand, ParseError> {
+        let mut assignments = Vec::new();
+        let mut args = Vec::new();
+        let mut redirections = Vec::new();
+
+        while self.position < self.tokens.len() {
+            if self.alias_next == Some(self.position) {
+                self.alias_next = None;
+                self.expand_aliases()?;
+            }
+            if let Some(Token::Word(word)) = self.tokens.get(self.position) {
+                self.position += 1;
+                // Until the command name, `name=value` is an assignment, and the
+                // word after one can be an alias
+                match assignment(word).filter(|_| args.is_empty()) {
+                    Some(assignment) => {
+                        assignments.push(assignment);
+                        self.alias_next = Some(self.position);
                     }
+                    None => args.push(word.clone()),
                 }
-            } else {
-                result.push(ch);
+            } else if !self.parse_redirection(&mut redirections)? {
+                break;
             }
         }
 
-        result
+        if assignments.is_empty() && args.is_empty() && redirections.is_empty() {
+            return Err(match self.peek_operator() {
+                Some(op) => ParseError::UnexpectedToken(op.to_string()),
+                None => ParseError::EmptyCommand,
+            });
+        }
+
+        Ok(SimpleCommand { assignments, args, redirections })
     }
-}
\ No newline at end of file
+
+    // One redirection at the current position, such as `2>&1` or `<<EOF`, added to
+    // `redirections`; false when there is none
+    fn parse_redirection(&mut self, redirections: &mut Vec<Redirection>) -> Result<bool, ParseError> {
+        let (io_number, op_position) = match self.tokens.get(self.position) {
+            Some(Token::IoNumber(fd)) => (Some(*fd), self.position + 1),
+            _ => (None, self.position),
+        };
+        let Some(Token::Operator(op)) = self.tokens.get(op_position) else {
+            return Ok(false);
+        };
+        let Some((default_fd, redir_type)) = redirection_operator(op) else {
+            return Ok(false);
+        };
+        let both = op.starts_with('&');
+
+        self.position = op_position + 1;
+        let (redir_type, target) = match (redir_type, self.tokens.get(self.position)) {
+            (RedirectionType::HereDoc { .. }, Some(Token::HereDoc { body, expand, .. })) => {
+                (RedirectionType::HereDoc { expand: *expand }, body.clone())
+            }
+            (RedirectionType::HereDoc { .. }, _) => return Err(ParseError::MissingFilename),
+            (redir_type, Some(Token::Word(target))) => (redir_type, target.clone()),
+            _ => return Err(ParseError::MissingFilename),
+        };
+        self.position += 1;
+
+        redirections.push(Redirection {
+            fd: io_number.unwrap_or(default_fd),
+            redir_type,
+            target,
+        });
+        // `&> file` is `> file 2>&1`
+        if both {
+            redirections.push(Redirection {
+                fd: 2,
+                redir_type: RedirectionType::Duplicate,
+                target: "1".to_string(),
+            });
+        }
+        Ok(true)
+    }
+}
+
+// Read the here-document that starts at `chars[start]`, just after a newline, into
+// the delimiter token at `tokens[index]`; returns where the input goes on after
+// the delimiter line
+fn read_here_doc(chars: &[char], start: usize, tokens: &mut [Token], index: usize, strip_tabs: bool) -> Result<usize, ParseError> {
+    // Without a delimiter word the parser reports the missing word
+    let Some(Token::Word(word)) = tokens.get(index) else {
+        return Ok(start);
+    };
+    let (delimiter, quoted) = here_doc_delimiter(word);
+    let mut body = String::new();
+    let mut i = start;
+
+    loop {
+        if i >= chars.len() {
+            return Err(ParseError::Incomplete(format!("here-document delimited by {}", delimiter)));
+        }
+        let end = chars[i..].iter().position(|&c| c == '\n').map_or(chars.len(), |offset| i + offset);
+        let mut line: String = chars[i..end].iter().collect();
+        if strip_tabs {
+            line = line.trim_start_matches('\t').to_string();
+        }
+        i = (end + 1).min(chars.len());
+        if line == delimiter {
+            break;
+        }
+        body.push_str(&line);
+        body.push('\n');
+    }
+
+    tokens[index] = Token::HereDoc { delimiter, body, expand: !quoted };
+    Ok(i)
+}
+
+// The delimiter a here-document ends at, with quotes removed, and whether any part
+// of it was quoted, which keeps the document from being expanded
+fn here_doc_delimiter(word: &str) -> (String, bool) {
+    let mut delimiter = String::new();
+    let mut chars = word.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\'' | '"' => {}
+            '\\' => delimiter.extend(chars.next()),
+            _ => delimiter.push(ch),
+        }
+    }
+    let quoted = word.contains(['\'', '"', '\\']);
+    (delimiter, quoted)
+}
+
+// End (exclusive) of the `(( expression ))` starting at `chars[start]`, or None
+// when the parenthesis after the first one is not closed by the `))`
+fn scan_arithmetic(chars: &[char], start: usize) -> Result<Option<usize>, ParseError> {
+    let mut depth = 0;
+    let mut i = start + 1;
+    while i < chars.len() {
+        match chars[i] {
+            '"' | '\'' | '`' => {
+                i = scan_quoted(chars, i)?;
+                continue;
+            }
+            '$' if matches!(chars.get(i + 1), Some('(') | Some('{')) => {
+                i = scan_quoted(chars, i)?;
+                continue;
+            }
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok((chars.get(i + 1) == Some(&')')).then_some(i + 2));
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    Ok(None)
+}
+
+// End (exclusive) of the quoted string, command substitution or parameter
+// expansion starting at `chars[start]`: a quote, a backtick, or the `$` of `$(` or
+// `${`. Substitutions nest, and may contain quotes and parentheses of their own.
+pub fn scan_quoted(chars: &[char], start: usize) -> Result<usize, ParseError> {
+    let open = chars[start];
+    // `${` is closed by `}`, `$(` by its matching `)`
+    let braced = open == '$' && chars.get(start + 1) == Some(&'{');
+    let mut i = if open == '$' { start + 2 } else { start + 1 };
+    let mut depth = 0;
+
+    while i < chars.len() {
+        let ch = chars[i];
+        match (open, ch) {
+            ('\'', '\'') | ('"', '"') | ('`', '`') => return Ok(i + 1),
+            ('\'', _) => {}
+            (_, '\\') => i += 1,
+            ('`', _) => {}
+            (_, '$') if matches!(chars.get(i + 1), Some('(') | Some('{')) => {
+                i = scan_quoted(chars, i)?;
+                continue;
+            }
+            (_, '`') => {
+                i = scan_quoted(chars, i)?;
+                continue;
+            }
+            ('$', '\'' | '"') => {
+                i = scan_quoted(chars, i)?;
+                continue;
+            }
+            ('$', '}') if braced => return Ok(i + 1),
+            ('$', '(') if !braced => depth += 1,
+            ('$', ')') if !braced && depth == 0 => return Ok(i + 1),
+            ('$', ')') if !braced => depth -= 1,
+            _ => {}
+        }
+        i += 1;
+    }
+
+    Err(if braced {
+        ParseError::UnterminatedParameterExpansion
+    } else if open == '$' {
+        ParseError::UnterminatedSubstitution
+    } else {
+        ParseError::UnterminatedQuote(open)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(command_line: &CommandLine) -> Vec<String> {
+        match command_line {
+            CommandLine::Simple(cmd) => cmd.args.clone(),
+            other => panic!("expected a simple command, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_lists_and_conditionals() {
+        let command_line = Parser::new().parse("make && ./deploy || notify; echo done;").unwrap();
+
+        let CommandLine::List(items) = command_line else {
+            panic!("expected a list");
+        };
+        assert_eq!(items.len(), 2);
+        let CommandLine::Or(left, right) = &items[0] else {
+            panic!("expected ||, got {:?}", items[0]);
+        };
+        assert!(matches!(left.as_ref(), CommandLine::And(..)));
+        assert_eq!(words(right), vec!["notify"]);
+        assert_eq!(words(&items[1]), vec!["echo", "done"]);
+
+        assert!(matches!(Parser::new().parse("sleep 1 & echo hi"), Ok(CommandLine::List(items)) if matches!(items[0], CommandLine::Background(_))));
+        let Ok(CommandLine::Background(job)) = Parser::new().parse("make 2>&1 | tee log &") else {
+            panic!("expected a background pipeline");
+        };
+        assert_eq!(job.to_string(), "make 2>&1 | tee log");
+        assert!(matches!(Parser::new().parse("a && b & c"), Ok(CommandLine::List(items)) if matches!(&items[0], CommandLine::Background(job) if matches!(**job, CommandLine::And(..)))));
+        assert!(matches!(Parser::new().parse("echo 'a;b' \\&\\& c"), Ok(CommandLine::Simple(_))));
+        assert!(matches!(Parser::new().parse("&& ls"), Err(ParseError::UnexpectedToken(op)) if op == "&&"));
+        assert!(matches!(Parser::new().parse("ls ;; ls"), Err(ParseError::UnexpectedToken(op)) if op == ";;"));
+    }
+
+    #[test]
+    fn test_compound_commands() {
+        let script = "if test -f a; then\n  echo a\nelif false; then echo b\nelse\n  echo c # comment\nfi";
+        let Ok(CommandLine::If { branches, else_branch }) = Parser::new().parse(script) else {
+            panic!("expected an if command");
+        };
+        assert_eq!(branches.len(), 2);
+        assert_eq!(words(&branches[0].0), vec!["test", "-f", "a"]);
+        assert_eq!(words(&branches[1].1), vec!["echo", "b"]);
+        assert_eq!(words(else_branch.as_deref().unwrap()), vec!["echo", "c"]);
+
+        let Ok(CommandLine::For { variable, words: Some(items), body }) =
+            Parser::new().parse("for f in *.rs 'a b'\ndo\n  echo $f\ndone")
+        else {
+            panic!("expected a for loop");
+        };
+        assert_eq!(variable, "f");
+        assert_eq!(items, vec!["*.rs", "'a b'"]);
+        assert_eq!(words(&body), vec!["echo", "$f"]);
+
+        let Ok(CommandLine::Case { word, arms }) =
+            Parser::new().parse("case $x in\n  a|b) echo ab;;\n  (*) ;;\nesac")
+        else {
+            panic!("expected a case command");
+        };
+        assert_eq!(word, "$x");
+        assert_eq!(arms[0].patterns, vec!["a", "b"]);
+        assert!(arms[1].body.is_none());
+
+        assert!(matches!(
+            Parser::new().parse("until false; do break; done && echo fi"),
+            Ok(CommandLine::And(left, _)) if matches!(*left, CommandLine::While { until: true, .. })
+        ));
+        assert!(matches!(Parser::new().parse("echo if then"), Ok(CommandLine::Simple(_))));
+        assert!(matches!(Parser::new().parse("fi"), Err(ParseError::UnexpectedToken(word)) if word == "fi"));
+        assert!(matches!(Parser::new().parse("if true; then fi"), Err(ParseError::UnexpectedToken(word)) if word == "fi"));
+    }
+
+    #[test]
+    fn test_subshells_and_groups() {
+        let Ok(CommandLine::Subshell(body)) = Parser::new().parse("(cd build && make)") else {
+            panic!("expected a subshell");
+        };
+        assert!(matches!(*body, CommandLine::And(..)));
+
+        let Ok(CommandLine::Redirected { command, redirections }) = Parser::new().parse("{ echo a; echo b; } > out 2>&1") else {
+            panic!("expected a redirected group");
+        };
+        assert!(matches!(&*command, CommandLine::Group(body) if matches!(**body, CommandLine::List(_))));
+        assert_eq!(redirections.len(), 2);
+        assert_eq!(command.to_string(), "{ echo a; echo b; }");
+
+        let Ok(CommandLine::Pipeline(stages)) = Parser::new().parse("(echo b; echo a) | sort | { cat; }") else {
+            panic!("expected a pipeline");
+        };
+        assert!(matches!(stages[0], CommandLine::Subshell(_)));
+        assert!(matches!(stages[2], CommandLine::Group(_)));
+        assert!(matches!(
+            Parser::new().parse("while true; do echo; done < in"),
+            Ok(CommandLine::Redirected { command, .. }) if matches!(*command, CommandLine::While { .. })
+        ));
+        assert!(matches!(
+            Parser::new().parse("log() { echo \"$@\"; } >> log.txt"),
+            Ok(CommandLine::FunctionDef { body, .. }) if matches!(*body, CommandLine::Redirected { .. })
+        ));
+
+        // `}` only closes a group where a command could start
+        assert!(matches!(Parser::new().parse("{ echo }"), Err(e) if e.is_incomplete()));
+        assert!(matches!(Parser::new().parse("(echo a"), Err(e) if e.is_incomplete()));
+
+        // `((` is an arithmetic command only when `))` closes it
+        assert!(matches!(Parser::new().parse("(( x = (1 + 2) * 3 ))"), Ok(CommandLine::Arithmetic(e)) if e == " x = (1 + 2) * 3 "));
+        assert!(matches!(
+            Parser::new().parse("((x > 1)) && echo big"),
+            Ok(CommandLine::And(left, _)) if matches!(*left, CommandLine::Arithmetic(_))
+        ));
+        assert!(matches!(Parser::new().parse("((cd src); ls)"), Ok(CommandLine::Subshell(body)) if matches!(*body, CommandLine::List(_))));
+        assert!(matches!(Parser::new().parse("echo $(( (1) + (2) ))"), Ok(CommandLine::Simple(cmd)) if cmd.args[1] == "$(( (1) + (2) ))"));
+        assert!(matches!(Parser::new().parse("( )"), Err(ParseError::UnexpectedToken(token)) if token == ")"));
+        assert!(matches!(Parser::new().parse("echo (a)"), Err(ParseError::UnexpectedToken(token)) if token == "("));
+    }
+
+    #[test]
+    fn test_alias_expansion() {
+        let aliases: HashMap<String, String> = [
+            ("ll", "ls -l"),
+            ("ls", "ls --color"),
+            ("gs", "git status; echo done"),
+            ("sudo", "sudo "),
+            ("loop", "loop2"),
+            ("loop2", "loop"),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+        let parse = |input: &str| Parser::new().parse_with_aliases(input, &aliases).unwrap();
+
+        assert_eq!(words(&parse("ll src")), vec!["ls", "--color", "-l", "src"]);
+        assert!(matches!(parse("gs"), CommandLine::List(items) if words(&items[1]) == vec!["echo", "done"]));
+        assert_eq!(words(&parse("sudo ll")), vec!["sudo", "ls", "--color", "-l"]);
+        assert_eq!(words(&parse("echo ll")), vec!["echo", "ll"]);
+        assert_eq!(words(&parse("'ll'")), vec!["'ll'"]);
+        assert_eq!(words(&parse("loop")), vec!["loop"]);
+        assert!(matches!(parse("true && ll | wc"), CommandLine::And(_, right) if matches!(&*right, CommandLine::Pipeline(cmds) if words(&cmds[0])[0] == "ls")));
+        assert_eq!(words(&parse("LANG=C ll")), vec!["ls", "--color", "-l"]);
+    }
+
+    #[test]
+    fn test_assignments() {
+        let Ok(CommandLine::Simple(cmd)) = Parser::new().parse("A=1 B=\"$x y\" env C=3 > out") else {
+            panic!("expected a simple command");
+        };
+        assert_eq!(cmd.assignments, vec![("A".to_string(), "1".to_string()), ("B".to_string(), "\"$x y\"".to_string())]);
+        assert_eq!(cmd.args, vec!["env", "C=3"]);
+        assert_eq!(cmd.to_string(), "A=1 B=\"$x y\" env C=3 >out");
+
+        let Ok(CommandLine::Simple(cmd)) = Parser::new().parse("EMPTY= '1A=x' =y") else {
+            panic!("expected a simple command");
+        };
+        assert_eq!(cmd.assignments, vec![("EMPTY".to_string(), String::new())]);
+        assert_eq!(cmd.args, vec!["'1A=x'", "=y"]);
+        assert_eq!(Parser::new().parse("x=1 y=2").unwrap().first_word(), None);
+    }
+
+    #[test]
+    fn test_function_definitions() {
+        let Ok(CommandLine::FunctionDef { name, body }) = Parser::new().parse("greet() {\n  echo \"hi $1\"\n  return 0\n}") else {
+            panic!("expected a function definition");
+        };
+        assert_eq!(name, "greet");
+        assert!(matches!(*body, CommandLine::List(ref items) if items.len() == 2));
+
+        assert!(matches!(
+            Parser::new().parse("is-dir(){ test -d \"$1\"; }; is-dir src"),
+            Ok(CommandLine::List(items)) if matches!(&items[0], CommandLine::FunctionDef { name, .. } if name == "is-dir")
+        ));
+        assert!(matches!(Parser::new().parse("loop() for x in a; do echo $x; done"), Ok(CommandLine::FunctionDef { .. })));
+        assert!(Parser::new().parse("greet() {").unwrap_err().is_incomplete());
+        assert!(Parser::new().parse("greet() { echo hi }").unwrap_err().is_incomplete());
+        assert!(matches!(Parser::new().parse("greet() echo hi"), Err(ParseError::UnexpectedToken(word)) if word == "echo"));
+    }
+
+    #[test]
+    fn test_redirections() {
+        let redirections = |input: &str| match Parser::new().parse(input) {
+            Ok(CommandLine::Simple(cmd)) => cmd
+                .redirections
+                .iter()
+                .map(|redir| (redir.fd, redir.redir_type.clone(), redir.target.clone()))
+                .collect::<Vec<_>>(),
+            other => panic!("expected a simple command, got {:?}", other),
+        };
+
+        assert_eq!(redirections("make 2> err.log >>out.log"), vec![
+            (2, RedirectionType::Output, "err.log".to_string()),
+            (1, RedirectionType::Append, "out.log".to_string()),
+        ]);
+        assert_eq!(redirections("make > log 2>&1 3<&-"), vec![
+            (1, RedirectionType::Output, "log".to_string()),
+            (2, RedirectionType::Duplicate, "1".to_string()),
+            (3, RedirectionType::Duplicate, "-".to_string()),
+        ]);
+        assert_eq!(redirections("make &> log"), vec![
+            (1, RedirectionType::Output, "log".to_string()),
+            (2, RedirectionType::Duplicate, "1".to_string()),
+        ]);
+        assert_eq!(redirections("cat 0<>rw <&3 >|out"), vec![
+            (0, RedirectionType::ReadWrite, "rw".to_string()),
+            (0, RedirectionType::Duplicate, "3".to_string()),
+            (1, RedirectionType::Output, "out".to_string()),
+        ]);
+        // Only digits right before the operator name a descriptor
+        assert_eq!(words(&Parser::new().parse("echo 2 >f a2>g '2'>h").unwrap()), vec!["echo", "2", "a2", "'2'"]);
+        assert!(matches!(Parser::new().parse("echo 2>"), Err(ParseError::MissingFilename)));
+    }
+
+    #[test]
+    fn test_here_documents() {
+        let Ok(CommandLine::List(items)) = Parser::new().parse("cat <<EOF > out; cat <<-'END'\nhi $USER\nEOF\n\tliteral $USER\n\tEND\necho next") else {
+            panic!("expected a list");
+        };
+        let redirections: Vec<_> = items[..2]
+            .iter()
+            .map(|item| match item {
+                CommandLine::Simple(cmd) => (cmd.redirections[0].redir_type.clone(), cmd.redirections[0].target.clone()),
+                other => panic!("expected a simple command, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(redirections, vec![
+            (RedirectionType::HereDoc { expand: true }, "hi $USER\n".to_string()),
+            (RedirectionType::HereDoc { expand: false }, "literal $USER\n".to_string()),
+        ]);
+        assert_eq!(words(&items[2]), vec!["echo", "next"]);
+
+        assert!(matches!(
+            Parser::new().parse("tr a-z A-Z <<< \"$x y\""),
+            Ok(CommandLine::Simple(cmd)) if cmd.redirections[0].redir_type == RedirectionType::HereString && cmd.redirections[0].target == "\"$x y\""
+        ));
+        for input in ["cat <<EOF", "cat <<EOF\nbody", "cat <<EOF\nbody\nEOF2"] {
+            assert!(Parser::new().parse(input).unwrap_err().is_incomplete(), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_incomplete_input() {
+        for input in ["if true; then", "while true\ndo echo", "for x in a b", "case a in", "ls &&", "ls |", "echo \"a", "echo a \\", "echo ${x:-a"] {
+            let result = Parser::new().parse(input);
+            assert!(matches!(&result, Err(e) if e.is_incomplete()), "{:?}: {:?}", input, result);
+        }
+        assert!(matches!(Parser::new().parse("echo a \\\nb"), Ok(CommandLine::Simple(cmd)) if cmd.args == vec!["echo", "a", "b"]));
+        assert!(matches!(Parser::new().parse("echo ${x:-a b} ${y/}/x}"), Ok(CommandLine::Simple(cmd)) if cmd.args == vec!["echo", "${x:-a b}", "${y/}/x}"]));
+        assert!(!Parser::new().parse("if true; then echo; done").unwrap_err().is_incomplete());
+    }
+}
@@ -0,0 +1,261 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::parser::{Redirection, RedirectionType};
+
+// Files opened for redirections, and the shell's own descriptors while a builtin
+// runs redirected, are kept at or above this number, out of the way of the
+// descriptors commands use
+const FIRST_SPARE_FD: RawFd = 10;
+
+// Descriptors above the standard ones set up in the shell by the redirections of
+// an enclosing command, as in `{ echo hi >&3; } 3>log`; commands inside may use them
+static SHELL_DESCRIPTORS: Mutex<Vec<RawFd>> = Mutex::new(Vec::new());
+
+// One step of a redirection, done in order: `2>&1 >file` and `>file 2>&1` differ
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FdAction {
+    // Make `fd` a copy of `source`
+    Duplicate { source: RawFd, fd: RawFd },
+    Close(RawFd),
+}
+
+impl FdAction {
+    fn fd(&self) -> RawFd {
+        match *self {
+            FdAction::Duplicate { fd, .. } | FdAction::Close(fd) => fd,
+        }
+    }
+
+    // Only dup2 and close, so this is safe between fork and exec. Closing a
+    // descriptor that is not open is not an error.
+    fn apply(&self) -> io::Result<()> {
+        // SAFETY: plain descriptor operations, no memory is involved
+        let result = unsafe {
+            match *self {
+                FdAction::Duplicate { source, fd } => libc::dup2(source, fd),
+                FdAction::Close(fd) => {
+                    libc::close(fd);
+                    0
+                }
+            }
+        };
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// The redirections of one command with their files open, ready to apply either
+/// to a command about to be spawned or to the shell itself
+pub struct Redirections {
+    actions: Vec<FdAction>,
+    // Held open until the command has started or the builtin has finished
+    _files: Vec<OwnedFd>,
+}
+
+impl Redirections {
+    /// Open the files named by expanded redirections, so that a missing file or a
+    /// bad descriptor is reported before anything runs
+    pub fn open(redirections: &[Redirection]) -> io::Result<Self> {
+        let mut actions = Vec::new();
+        let mut files = Vec::new();
+
+        for redir in redirections {
+            let action = match redir.redir_type {
+                RedirectionType::Duplicate => match redir.target.as_str() {
+                    "-" => FdAction::Close(redir.fd),
+                    target => {
+                        let source = target
+                            .parse::<RawFd>()
+                            .ok()
+                            .filter(|fd| *fd >= 0)
+                            .ok_or_else(|| io::Error::other(format!("{}: ambiguous redirect", target)))?;
+                        if !is_open(source, &actions) {
+                            return Err(io::Error::other(format!("{}: Bad file descriptor", source)));
+                        }
+                        FdAction::Duplicate { source, fd: redir.fd }
+                    }
+                },
+                RedirectionType::HereDoc { .. } | RedirectionType::HereString => {
+                    let file = spare_descriptor(here_document_file(&redir.target)?)?;
+                    let action = FdAction::Duplicate { source: file.as_raw_fd(), fd: redir.fd };
+                    files.push(file);
+                    action
+                }
+                _ => {
+                    let file = open_file(redir)
+                        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", redir.target, e)))?;
+                    let file = spare_descriptor(file)?;
+                    let action = FdAction::Duplicate { source: file.as_raw_fd(), fd: redir.fd };
+                    files.push(file);
+                    action
+                }
+            };
+            actions.push(action);
+        }
+
+        Ok(Redirections { actions, _files: files })
+    }
+
+    /// Apply the redirections in the child, after its standard streams are set up,
+    /// so that they override pipes: `cmd 2>&1 | less` sends errors down the pipe
+    pub fn apply_on_spawn(&self, command: &mut Command) {
+        if self.actions.is_empty() {
+            return;
+        }
+        let actions = self.actions.clone();
+        // SAFETY: the closure only calls dup2 and close, which are async-signal-safe
+        unsafe {
+            command.pre_exec(move || actions.iter().try_for_each(FdAction::apply));
+        }
+    }
+
+    /// Apply the redirections to the shell's own descriptors, for builtins and
+    /// functions; they are restored when the result is dropped
+    pub fn apply_to_shell(&self) -> io::Result<SavedDescriptors> {
+        io::stdout().flush()?;
+        let mut shell_descriptors = SHELL_DESCRIPTORS.lock().unwrap_or_else(|e| e.into_inner());
+        let mut saved = SavedDescriptors { fds: Vec::new(), shell_descriptors: shell_descriptors.clone() };
+        for action in &self.actions {
+            let fd = action.fd();
+            if !saved.fds.iter().any(|(saved_fd, _)| *saved_fd == fd) {
+                // SAFETY: F_DUPFD_CLOEXEC only creates a new descriptor
+                let copy = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, FIRST_SPARE_FD) };
+                saved.fds.push((fd, (copy >= 0).then_some(copy)));
+            }
+            action.apply()?;
+            shell_descriptors.retain(|open| *open != fd);
+            if matches!(action, FdAction::Duplicate { .. }) && fd > 2 {
+                shell_descriptors.push(fd);
+            }
+        }
+        Ok(saved)
+    }
+}
+
+/// The shell's descriptors as they were before `Redirections::apply_to_shell`
+pub struct SavedDescriptors {
+    // Copies of the descriptors changed, `None` for one that was not open
+    fds: Vec<(RawFd, Option<RawFd>)>,
+    shell_descriptors: Vec<RawFd>,
+}
+
+impl Drop for SavedDescriptors {
+    fn drop(&mut self) {
+        let _ = io::stdout().flush();
+        *SHELL_DESCRIPTORS.lock().unwrap_or_else(|e| e.into_inner()) = std::mem::take(&mut self.shell_descriptors);
+        for (fd, copy) in self.fds.drain(..).rev() {
+            // SAFETY: the copies were made by apply_to_shell and are owned here
+            unsafe {
+                match copy {
+                    Some(copy) => {
+                        libc::dup2(copy, fd);
+                        libc::close(copy);
+                    }
+                    None => {
+                        libc::close(fd);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn open_file(redir: &Redirection) -> io::Result<File> {
+    match redir.redir_type {
+        RedirectionType::Input => File::open(&redir.target),
+        RedirectionType::Output => File::create(&redir.target),
+        RedirectionType::Append => OpenOptions::new().create(true).append(true).open(&redir.target),
+        RedirectionType::ReadWrite => OpenOptions::new().create(true).truncate(false).read(true).write(true).open(&redir.target),
+        _ => unreachable!("only file redirections open files"),
+    }
+}
+
+// The text of a here-document or here-string in an unnamed temporary file, read
+// from the start; a pipe could not hold a long document without a writer
+fn here_document_file(text: &str) -> io::Result<File> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "aish-here-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+    std::fs::remove_file(&path)?;
+    file.write_all(text.as_bytes())?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(file)
+}
+
+// Move a newly opened file to a descriptor of its own above the ones commands use,
+// closed on exec, so that `3>a 4>b` cannot clash with where the files landed
+fn spare_descriptor(file: File) -> io::Result<OwnedFd> {
+    // SAFETY: F_DUPFD_CLOEXEC only creates a new descriptor, owned by the result
+    let fd = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_DUPFD_CLOEXEC, FIRST_SPARE_FD) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+// Will `fd` be open once the earlier actions have run? Other than the standard
+// streams and those an enclosing command redirected, the descriptors open in the
+// shell are its own and not for commands.
+fn is_open(fd: RawFd, earlier: &[FdAction]) -> bool {
+    match earlier.iter().rev().find(|action| action.fd() == fd) {
+        Some(FdAction::Duplicate { .. }) => true,
+        Some(FdAction::Close(_)) => false,
+        None => {
+            let visible = fd <= 2 || SHELL_DESCRIPTORS.lock().unwrap_or_else(|e| e.into_inner()).contains(&fd);
+            // SAFETY: F_GETFD only reads the descriptor flags
+            visible && (unsafe { libc::fcntl(fd, libc::F_GETFD) }) >= 0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn redirection(fd: i32, redir_type: RedirectionType, target: &str) -> Redirection {
+        Redirection { fd, redir_type, target: target.to_string() }
+    }
+
+    #[test]
+    fn test_redirections_apply_in_order() {
+        let path = std::env::temp_dir().join(format!("aish-redirect-{}", std::process::id()));
+        let path_str = path.to_str().unwrap();
+
+        // 40>file 41>&40 40>&-: 41 ends up on the file and 40 is closed
+        let redirections = Redirections::open(&[
+            redirection(40, RedirectionType::Output, path_str),
+            redirection(41, RedirectionType::Duplicate, "40"),
+            redirection(40, RedirectionType::Duplicate, "-"),
+        ])
+        .unwrap();
+        let saved = redirections.apply_to_shell().unwrap();
+        assert_eq!(unsafe { libc::fcntl(40, libc::F_GETFD) }, -1);
+        assert_eq!(unsafe { libc::write(41, b"hi".as_ptr().cast(), 2) }, 2);
+        drop(saved);
+        assert_eq!(unsafe { libc::fcntl(41, libc::F_GETFD) }, -1);
+
+        let mut contents = String::new();
+        File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "hi");
+        std::fs::remove_file(&path).unwrap();
+
+        let error = Redirections::open(&[redirection(1, RedirectionType::Duplicate, "42")]).err().unwrap();
+        assert_eq!(error.to_string(), "42: Bad file descriptor");
+        let error = Redirections::open(&[redirection(1, RedirectionType::Duplicate, "out.txt")]).err().unwrap();
+        assert_eq!(error.to_string(), "out.txt: ambiguous redirect");
+    }
+}
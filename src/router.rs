@@ -0,0 +1,194 @@
+use crate::parser::{is_function_name, is_reserved_word, CommandLine, Parser};
+use std::fmt;
+
+// Characters that make a line shell syntax rather than a plain question
+const SHELL_OPERATORS: &[char] = &['|', '&', '<', '>', ';', '$', '`'];
+
+/// Where a line typed at the interactive prompt is sent
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Route {
+    Shell,
+    Llm,
+}
+
+/// How lines without a `!` or `?` prefix are routed for the rest of the session
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RouteMode {
+    Auto,
+    Shell,
+    Llm,
+}
+
+impl RouteMode {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "auto" => Some(RouteMode::Auto),
+            "shell" | "sh" => Some(RouteMode::Shell),
+            "ai" | "llm" => Some(RouteMode::Llm),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for RouteMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RouteMode::Auto => write!(f, "auto"),
+            RouteMode::Shell => write!(f, "shell"),
+            RouteMode::Llm => write!(f, "ai"),
+        }
+    }
+}
+
+/// The routing of one line: where it goes, the text to run and why
+#[derive(Debug, PartialEq)]
+pub struct Decision {
+    pub route: Route,
+    pub line: String,
+    pub reason: String,
+}
+
+impl Decision {
+    fn new(route: Route, line: &str, reason: impl Into<String>) -> Self {
+        Decision {
+            route,
+            line: line.trim().to_string(),
+            reason: reason.into(),
+        }
+    }
+}
+
+/// Decide whether a line is a shell command or prose for the LLM. `command_kind`
+/// describes a command name the shell can run (builtin, function, executable) or
+/// returns None when there is no such command.
+pub fn classify(line: &str, mode: RouteMode, command_kind: impl Fn(&str) -> Option<String>) -> Decision {
+    let line = line.trim();
+
+    // Explicit prefixes win over everything else
+    if let Some(command) = line.strip_prefix('!') {
+        return Decision::new(Route::Shell, command, "! prefix");
+    }
+    if let Some(question) = line.strip_prefix('?') {
+        return Decision::new(Route::Llm, question, "? prefix");
+    }
+
+    // The route builtin stays reachable so that the mode can always be switched back
+    if line.split_whitespace().next() == Some("route") {
+        return Decision::new(Route::Shell, line, "route is a shell builtin");
+    }
+
+    match mode {
+        RouteMode::Shell => return Decision::new(Route::Shell, line, "route mode is shell"),
+        RouteMode::Llm => return Decision::new(Route::Llm, line, "route mode is ai"),
+        RouteMode::Auto => {}
+    }
+
+    let command_line = match Parser::new().parse(line) {
+        Ok(command_line) => command_line,
+        // The first line of a command that continues on the next ones, such as
+        // `for f in *.rs; do` or `(cd build`, goes to the shell when it starts like a command
+        Err(e) if e.is_incomplete() => {
+            let first = line.split_whitespace().next().unwrap_or_default().trim_start_matches('(');
+            return match command_kind(first) {
+                _ if is_reserved_word(first) => Decision::new(Route::Shell, line, format!("{} is a shell keyword", first)),
+                _ if starts_function_definition(line) => Decision::new(Route::Shell, line, "a function definition"),
+                Some(kind) => Decision::new(Route::Shell, line, format!("{} is {}", first, kind)),
+                None => Decision::new(Route::Llm, line, format!("not a command line ({})", e)),
+            };
+        }
+        Err(e) => return Decision::new(Route::Llm, line, format!("not a command line ({})", e)),
+    };
+    if let CommandLine::FunctionDef { .. } = command_line {
+        return Decision::new(Route::Shell, line, "a function definition");
+    }
+    // A line of only assignments or redirections, such as `FOO=1` or `> out.txt`,
+    // is still a command line
+    let Some(first) = command_line.first_word() else {
+        let reason = match command_line {
+            CommandLine::Simple(cmd) if !cmd.assignments.is_empty() => "a variable assignment",
+            _ => "a redirection",
+        };
+        return Decision::new(Route::Shell, line, reason);
+    };
+    if is_reserved_word(first) || first == "((" {
+        return Decision::new(Route::Shell, line, format!("{} is a shell keyword", first));
+    }
+
+    // Commands such as `which` or `time` are also English words
+    let words = line.split_whitespace().count();
+    if line.ends_with('?') && words >= 3 && !line.contains(SHELL_OPERATORS) {
+        return Decision::new(Route::Llm, line, "reads like a question");
+    }
+
+    match command_kind(first) {
+        Some(kind) => Decision::new(Route::Shell, line, format!("{} is {}", first, kind)),
+        None => Decision::new(Route::Llm, line, format!("{} is not a command", first)),
+    }
+}
+
+// `name() {` or `name () {`, the first line of a function definition
+fn starts_function_definition(line: &str) -> bool {
+    let compact: String = line.split_whitespace().collect();
+    compact
+        .split_once("()")
+        .is_some_and(|(name, _)| is_function_name(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kind(name: &str) -> Option<String> {
+        match name {
+            "cd" => Some("a shell builtin".to_string()),
+            "ls" | "which" => Some(format!("/usr/bin/{}", name)),
+            _ => None,
+        }
+    }
+
+    fn route(line: &str) -> (Route, String) {
+        let decision = classify(line, RouteMode::Auto, kind);
+        (decision.route, decision.line)
+    }
+
+    #[test]
+    fn test_commands_run_in_the_shell() {
+        assert_eq!(route("ls -la"), (Route::Shell, "ls -la".to_string()));
+        assert_eq!(route("cd /tmp"), (Route::Shell, "cd /tmp".to_string()));
+        assert_eq!(route("ls | grep rs > out.txt"), (Route::Shell, "ls | grep rs > out.txt".to_string()));
+        assert_eq!(route("which cargo"), (Route::Shell, "which cargo".to_string()));
+        assert_eq!(route("for f in *.rs; do").0, Route::Shell);
+        assert_eq!(route("if ls; then cd /tmp; fi").0, Route::Shell);
+        assert_eq!(route("ls 'first").0, Route::Shell);
+        assert_eq!(route("greet() {").0, Route::Shell);
+        assert_eq!(route("greet () { echo hi; }").0, Route::Shell);
+        assert_eq!(route("(cd /tmp && ls)").0, Route::Shell);
+        assert_eq!(route("(cd /tmp").0, Route::Shell);
+        assert_eq!(route("{ ls; cd /tmp; } > out.txt").0, Route::Shell);
+        assert_eq!(route("(( count++ ))").0, Route::Shell);
+        assert_eq!(route("((count > 3)) && echo done").0, Route::Shell);
+        assert_eq!(route("LANG=C ls").0, Route::Shell);
+        assert_eq!(classify("name=world", RouteMode::Auto, kind).reason, "a variable assignment");
+    }
+
+    #[test]
+    fn test_prose_goes_to_the_llm() {
+        assert_eq!(route("explain the last error").0, Route::Llm);
+        assert_eq!(route("what's in this directory").0, Route::Llm);
+        assert_eq!(route("which file is the largest here?").0, Route::Llm);
+        assert_eq!(
+            classify("what's up", RouteMode::Auto, kind).reason,
+            "not a command line (Unterminated quote: ')"
+        );
+    }
+
+    #[test]
+    fn test_prefixes_and_modes_override() {
+        assert_eq!(route("!make test"), (Route::Shell, "make test".to_string()));
+        assert_eq!(route("? ls -la"), (Route::Llm, "ls -la".to_string()));
+        assert_eq!(classify("ls", RouteMode::Llm, kind).route, Route::Llm);
+        assert_eq!(classify("explain this", RouteMode::Shell, kind).route, Route::Shell);
+        assert_eq!(classify("?explain this", RouteMode::Shell, kind).route, Route::Llm);
+        assert_eq!(classify("route auto", RouteMode::Llm, kind).route, Route::Shell);
+    }
+}
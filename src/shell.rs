@@ -1,57 +1,151 @@
 use rustyline::Editor;
+use regex::Regex;
 use std::collections::HashMap;
 use std::env;
 use std::fs::File;
-use std::io::{self, BufRead, BufReader};
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::process::CommandExt;
 use std::process::{Child, Command, Stdio};
-// use nix::sys::signal::{self, Signal};
-// use nix::unistd::{self, Pid};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::fcntl::OFlag;
+use nix::sys::signal::Signal;
+use nix::unistd::{close, dup2, fork, pipe, pipe2, setpgid, ForkResult, Pid};
 
 use crate::builtins::Builtins;
-use crate::context::LLMActionProcessor;
-use crate::markdown::{is_markdown_file, MarkdownScript};
-use crate::parser::{CommandLine, Parser, RedirectionType, SimpleCommand};
+use crate::capture::OutputCapture;
+use crate::context::{LLMAction, LLMActionProcessor, MAX_BLOCK_OUTPUT_CHARS};
+use crate::llm::GenerationConfig;
+use crate::markdown::{
+    is_executable_block, is_function_declaration, is_markdown_file, parse_call_assignment,
+    parse_function_call, AishFunction, MarkdownElement, MarkdownScript,
+};
+use crate::arith::evaluate;
+use crate::expand::{
+    expand_here_document, expand_pattern, expand_string, expand_word, is_parameter_error, parameter_error, star_separator,
+    Expander,
+};
+use crate::glob::{pattern_matches, GlobOptions};
+use crate::jobs::{catch_interrupts, enter_job, give_terminal, take_terminal, terminal_modes, watch_children, Job, JobTable, Process, ProcessState, Terminal};
+use crate::parser::{is_name, CaseArm, CommandLine, Parser, Redirection, RedirectionType, SimpleCommand};
+use crate::redirect::Redirections;
+use crate::router::{self, Decision, Route, RouteMode};
+use crate::variables::{Variable, Variables};
+
+/// A pending `break n` or `continue n`, counted in enclosing loops
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoopControl {
+    Break(usize),
+    Continue(usize),
+}
+
+// One process of a job: a command with its words expanded, or a command line run
+// by a forked copy of the shell
+enum JobStage {
+    Command(SimpleCommand),
+    Shell(CommandLine),
+}
+
+// Deepest nesting of shell or .aish function calls, to stop runaway recursion
+const MAX_FUNCTION_DEPTH: usize = 200;
 
 pub struct Shell {
     editor: Editor<()>,
-    env_vars: HashMap<String, String>,
-    background_jobs: Vec<Child>,
+    variables: Variables,
+    jobs: JobTable,
+    // A status other than 0 set by a builtin that succeeded, as `wait` and `fg` do
+    builtin_status: Option<i32>,
+    // The terminal of an interactive session with job control; None in scripts
+    // and in forked copies of the shell
+    terminal: Option<Terminal>,
     exit_requested: bool,
+    // Reading commands from the user, who sees errors and goes on, rather than
+    // running a script
+    interactive: bool,
+    last_exit_status: i32,
+    // Status of the last command substitution in the words being expanded, which
+    // a command of only assignments gives as its own
+    last_substitution_status: Option<i32>,
     parser: Parser,
     builtins: Builtins,
     llm_processor: LLMActionProcessor,
+    aish_functions: HashMap<String, AishFunction>,
+    // Value set by `return` inside the running .aish function; stops its body
+    aish_return: Option<String>,
+    aish_call_depth: usize,
+    // Parameters of the running .aish function, replaced in its paragraphs
+    aish_bindings: Vec<(String, String)>,
+    route_mode: RouteMode,
+    glob_options: GlobOptions,
+    // Number of loops being run, and a `break`/`continue` on its way out of them
+    loop_depth: usize,
+    loop_control: Option<LoopControl>,
+    // Shell functions defined with `name() { ...; }`
+    functions: HashMap<String, CommandLine>,
+    // `$1`, `$2`, ... of the script, or of the running function
+    positional: Vec<String>,
+    // `$0`: the script being run, or the shell's own name
+    script_name: String,
+    // `$$`: the shell's process id, which forked copies of it keep
+    pid: u32,
+    // `$!`: the last process of the most recent background job
+    last_background_pid: Option<Pid>,
+    // For each running function call, the variables made `local` in it with the
+    // values to restore when it returns
+    call_stack: Vec<Vec<(String, Option<Variable>)>>,
+    // Status given to `return` in the running function; stops its body
+    function_return: Option<i32>,
+    // Aliases defined with `alias name=value`, expanded when a line is parsed
+    aliases: HashMap<String, String>,
+    // `set -o pipefail`: a pipeline fails when any of its stages does
+    pipefail: bool,
 }
 
 impl Shell {
     pub fn new() -> Self {
-        let mut env_vars = HashMap::new();
-
-        // Initialize with system environment variables
-        for (key, value) in env::vars() {
-            env_vars.insert(key, value);
-        }
+        let mut variables = Variables::from_environment();
 
         // Set default PATH if not present
-        if !env_vars.contains_key("PATH") {
-            env_vars.insert(
-                "PATH".to_string(),
-                "/usr/local/bin:/usr/bin:/bin".to_string(),
-            );
+        if variables.get("PATH").is_none() {
+            let _ = variables.export("PATH", Some("/usr/local/bin:/usr/bin:/bin".to_string()));
         }
 
-        // Set default PS1 prompt
-        if !env_vars.contains_key("PS1") {
-            env_vars.insert("PS1".to_string(), "aish$ ".to_string());
+        // Set default PS1 prompt, a shell variable that commands do not see
+        if variables.get("PS1").is_none() {
+            let _ = variables.set("PS1", "aish$ ".to_string());
         }
 
         Shell {
             editor: Editor::new().expect("Failed to create readline editor"),
-            env_vars,
-            background_jobs: Vec::new(),
+            variables,
+            last_substitution_status: None,
+            jobs: JobTable::default(),
+            builtin_status: None,
+            terminal: None,
             exit_requested: false,
+            interactive: false,
+            last_exit_status: 0,
             parser: Parser::new(),
             builtins: Builtins::new(),
             llm_processor: LLMActionProcessor::new(),
+            aish_functions: HashMap::new(),
+            aish_return: None,
+            aish_call_depth: 0,
+            aish_bindings: Vec::new(),
+            route_mode: RouteMode::Auto,
+            glob_options: GlobOptions::default(),
+            loop_depth: 0,
+            loop_control: None,
+            functions: HashMap::new(),
+            positional: Vec::new(),
+            script_name: "aish".to_string(),
+            pid: std::process::id(),
+            last_background_pid: None,
+            call_stack: Vec::new(),
+            function_return: None,
+            aliases: HashMap::new(),
+            pipefail: false,
         }
     }
 
@@ -61,13 +155,15 @@ impl Shell {
 
     pub async fn run_interactive(&mut self) -> io::Result<()> {
         self.setup_signal_handlers()?;
+        self.interactive = true;
+        self.terminal = take_terminal()?;
 
         println!("Welcome to aish - AI-Enhanced Shell");
         println!("Type 'exit' or use Ctrl+D to quit");
-        println!("This shell uses natural language commands");
+        println!("Commands run in the shell and prose goes to the AI; start a line with ! or ? to choose");
 
         while !self.exit_requested {
-            self.cleanup_background_jobs();
+            self.notify_jobs();
 
             let prompt = self.get_prompt();
 
@@ -105,13 +201,23 @@ impl Shell {
         Ok(())
     }
 
+    // Run a -c command string; its status is left in `last_exit_status`
     pub async fn run_command(&mut self, command: &str) -> io::Result<()> {
         self.setup_signal_handlers()?;
-        self.execute_line(command)?;
+        if let Err(e) = self.execute_line(command) {
+            eprintln!("aish: {}", e);
+            if self.last_exit_status == 0 {
+                self.last_exit_status = 2;
+            }
+        }
         self.cleanup_all_jobs();
         Ok(())
     }
 
+    pub fn last_exit_status(&self) -> i32 {
+        self.last_exit_status
+    }
+
     pub async fn run_file(&mut self, filename: &str) -> io::Result<()> {
         self.setup_signal_handlers()?;
 
@@ -122,6 +228,13 @@ impl Shell {
         }
     }
 
+    // Run the startup file of an interactive session, such as ~/.aishrc, where
+    // aliases and functions are usually defined
+    pub async fn load_rc_file(&mut self, filename: &str) -> io::Result<()> {
+        self.interactive = true;
+        self.run_shell_script(filename).await
+    }
+
     async fn run_markdown_file(&mut self, filename: &str) -> io::Result<()> {
         let content = std::fs::read_to_string(filename)
             .map_err(|e| io::Error::new(e.kind(), format!("aish: {}: {}", filename, e)))?;
@@ -134,191 +247,450 @@ impl Shell {
         })?;
 
         println!("[SYS] Executing intelligent markdown script: {}", filename);
+        self.llm_processor.set_script_arguments(filename, &self.positional);
         println!("[SYS] {}", self.llm_processor.get_context_info());
-
-        // Display headers as labels/comments (non-actionable)
-        let headers = script.get_headers();
-        if !headers.is_empty() {
-            println!("[SYS] Found {} section header(s):", headers.len());
-            for (level, text) in &headers {
-                let indent = "  ".repeat((*level as usize).saturating_sub(1));
-                println!("[SYS]   {}{} {}", indent, "#".repeat(*level as usize), text);
-            }
-        }
         println!();
 
-        // Process LLM actions (paragraphs and headers)
-        let llm_actions = script.get_llm_actions();
-        for (action_index, action) in llm_actions.iter().enumerate() {
-            if self.exit_requested {
+        self.execute_markdown_elements(&script.elements, filename).await;
+
+        self.cleanup_all_jobs();
+        println!("\n[SYS] Script execution completed");
+        println!("[SYS] Final {}", self.llm_processor.get_context_info());
+        Ok(())
+    }
+
+    // Walk the document in order so paragraphs and code blocks run the way they read
+    async fn execute_markdown_elements(&mut self, elements: &[MarkdownElement], filename: &str) {
+        let mut block_index = 0;
+
+        for element in elements {
+            if self.exit_requested || self.aish_return.is_some() {
                 break;
             }
 
-            // Print the paragraph content with token usage
-            let token_usage = self.llm_processor.get_token_usage();
-            match action {
-                crate::context::LLMAction::Comment { content } => {
-                    println!("[CMD] {} {}", token_usage, content);
+            match element {
+                MarkdownElement::Header(level, text) => {
+                    // Headers are non-actionable comments/labels
+                    println!("[SYS] {} {}", "#".repeat(*level as usize), text);
                 }
-            }
-
-            match self.llm_processor.process_action(action.clone()).await {
-                Ok(result) => {
-                    println!("{}", result);
-                    // Show updated token count after processing
-                    let updated_tokens = self.llm_processor.get_token_usage();
-                    println!("[SYS] Paragraph complete: {}", updated_tokens);
+                MarkdownElement::Paragraph(text) => {
+                    self.execute_markdown_paragraph(text).await;
                 }
-                Err(e) => {
-                    eprintln!("LLM Action Error: {}", e);
+                MarkdownElement::CodeBlock(lang, code) => {
+                    if is_executable_block(lang, code) {
+                        block_index += 1;
+                        self.execute_markdown_code_block(lang, code, block_index, filename);
+                    }
                 }
+                MarkdownElement::FunctionDeclaration(..) => {
+                    if let Some(function) = element.as_function() {
+                        self.define_aish_function(function);
+                    }
+                }
+                MarkdownElement::InlineCode(_) => {}
             }
         }
+    }
 
-        // Execute shell code blocks
-        let executable_blocks = script.get_executable_blocks();
-        for (block_index, (lang, code)) in executable_blocks.iter().enumerate() {
-            if self.exit_requested {
-                break;
-            }
+    async fn execute_markdown_paragraph(&mut self, text: &str) {
+        let mut bindings = self.aish_bindings.clone();
+        bindings.extend(self.positional_bindings());
+        let text = &substitute_parameters(text, &bindings);
+        let action = LLMAction::Comment {
+            content: text.to_string(),
+        };
 
-            self.cleanup_background_jobs();
+        // Print the paragraph content with token usage
+        let token_usage = self.llm_processor.get_token_usage();
+        println!("[CMD] {} {}", token_usage, text);
 
-            let lang_display = lang.as_deref().unwrap_or("shell");
-            println!(
-                "\n[CMD] Executing {} block {} ---",
-                lang_display,
-                block_index + 1
-            );
+        // The answer is streamed to the terminal while it is generated
+        match self.llm_processor.process_action(action).await {
+            Ok(_) => {
+                // Show updated token count after processing
+                let updated_tokens = self.llm_processor.get_token_usage();
+                println!("[SYS] Paragraph complete: {}", updated_tokens);
+            }
+            Err(e) => {
+                eprintln!("LLM Action Error: {}", e);
+            }
+        }
+    }
 
-            // Execute each line in the code block
-            for (line_num, line) in code.lines().enumerate() {
-                let line = line.trim();
+    fn execute_markdown_code_block(
+        &mut self,
+        lang: &Option<String>,
+        code: &str,
+        block_index: usize,
+        filename: &str,
+    ) {
+        let lang_display = lang.as_deref().unwrap_or("shell");
+        println!("\n[CMD] Executing {} block {} ---", lang_display, block_index);
+
+        // Tee the block's output so following paragraphs can reason about it
+        let capture = match OutputCapture::start(MAX_BLOCK_OUTPUT_CHARS) {
+            Ok(capture) => Some(capture),
+            Err(e) => {
+                eprintln!("[SYS] Unable to capture block output: {}", e);
+                None
+            }
+        };
 
-                // Skip empty lines and comments
-                if line.is_empty() || line.starts_with('#') {
-                    continue;
-                }
+        self.last_exit_status = 0;
 
-                if self.exit_requested {
-                    break;
-                }
+        // Execute each command in the code block; compound commands span several lines
+        for (line_num, command) in complete_commands(code) {
+            if self.exit_requested || self.aish_return.is_some() {
+                break;
+            }
 
-                println!("$ {}", line);
+            println!("$ {}", command.replace('\n', "\n> "));
+
+            let result = match return_expression(&command) {
+                Some(expression) if self.aish_call_depth > 0 => self.execute_return(expression),
+                _ => self.execute_line(&command),
+            };
+
+            if let Err(e) = result {
+                eprintln!(
+                    "aish: {}: block {}:{}: {}",
+                    filename,
+                    block_index,
+                    line_num,
+                    e
+                );
+                self.last_exit_status = 1;
+                // Continue execution even if a command fails
+            }
+        }
 
-                if let Err(e) = self.execute_line(line) {
-                    eprintln!(
-                        "aish: {}: block {}:{}: {}",
-                        filename,
-                        block_index + 1,
-                        line_num + 1,
-                        e
-                    );
-                    // Continue execution even if a command fails
+        if let Some(capture) = capture {
+            match capture.finish() {
+                Ok(output) => {
+                    self.llm_processor
+                        .add_code_block_result(code, &output.text, output.dropped_chars, self.last_exit_status);
                 }
+                Err(e) => eprintln!("[SYS] Unable to capture block output: {}", e),
             }
         }
+    }
 
-        // Handle function declarations
-        let functions = script.get_functions();
-        if !functions.is_empty() {
-            println!("\n[SYS] Found {} function declaration(s)", functions.len());
-            for func in functions {
-                if let crate::markdown::MarkdownElement::FunctionDeclaration(name, params, _) = func
-                {
-                    println!("  func {}({})", name, params.join(", "));
-                }
+    async fn run_shell_script(&mut self, filename: &str) -> io::Result<()> {
+        let script = std::fs::read_to_string(filename)
+            .map_err(|e| io::Error::new(e.kind(), format!("aish: {}: {}", filename, e)))?;
+
+        for (line_number, command) in complete_commands(&script) {
+            if self.exit_requested {
+                break;
+            }
+
+            if let Err(e) = self.execute_line(&command) {
+                eprintln!("aish: {}:{}: {}", filename, line_number, e);
+                // Continue execution even if a command fails
             }
         }
 
         self.cleanup_all_jobs();
-        println!("\n[SYS] Script execution completed");
-        println!("[SYS] Final {}", self.llm_processor.get_context_info());
         Ok(())
     }
 
-    async fn run_shell_script(&mut self, filename: &str) -> io::Result<()> {
-        let file = File::open(filename)
-            .map_err(|e| io::Error::new(e.kind(), format!("aish: {}: {}", filename, e)))?;
+    // What `$0`, `$1`... stand for in paragraphs of a script given arguments; none
+    // without them, so that a price such as $5 in the prose stays as written
+    fn positional_bindings(&self) -> Vec<(String, String)> {
+        if self.positional.is_empty() {
+            return Vec::new();
+        }
+        let mut bindings: Vec<(String, String)> = self
+            .positional
+            .iter()
+            .enumerate()
+            .map(|(index, value)| ((index + 1).to_string(), value.clone()))
+            .collect();
+        bindings.push(("0".to_string(), self.script_name.clone()));
+        bindings.push(("#".to_string(), self.positional.len().to_string()));
+        for name in ["@", "*"] {
+            bindings.push((name.to_string(), self.positional.join(" ")));
+        }
+        bindings
+    }
 
-        let reader = BufReader::new(file);
-        let mut line_number = 0;
+    fn define_aish_function(&mut self, function: AishFunction) {
+        println!(
+            "[SYS] Defined function: func {}({})",
+            function.name,
+            function.params.join(", ")
+        );
+        self.aish_functions.insert(function.name.clone(), function);
+    }
 
-        for line_result in reader.lines() {
-            line_number += 1;
+    // Does this line invoke a declared .aish function, either as `name(args)`,
+    // `var = name(args)` or `name args`?
+    fn is_aish_function_call(&self, line: &str) -> bool {
+        if let Some((_, expression)) = parse_call_assignment(line) {
+            return self.is_aish_function_call(&expression);
+        }
+        if let Some((name, _)) = parse_function_call(line) {
+            return self.aish_functions.contains_key(&name);
+        }
+        line.split_whitespace()
+            .next()
+            .is_some_and(|word| self.aish_functions.contains_key(word))
+    }
 
-            if self.exit_requested {
-                break;
-            }
+    // Run a function body in its own conversation, with parameters bound as variables.
+    // The result is the value of an explicit `return`, or else the final LLM answer.
+    async fn call_aish_function(&mut self, name: &str, args: Vec<String>) -> io::Result<String> {
+        let function = self.aish_functions.get(name).cloned().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("{}: function not found", name))
+        })?;
 
-            self.cleanup_background_jobs();
+        if args.len() != function.params.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{}: expected {} argument(s), got {}",
+                    name,
+                    function.params.len(),
+                    args.len()
+                ),
+            ));
+        }
 
-            let line = line_result?;
-            let line = line.trim();
+        if self.aish_call_depth >= MAX_FUNCTION_DEPTH {
+            return Err(io::Error::other(format!(
+                "{}: maximum function nesting level exceeded ({})",
+                name, MAX_FUNCTION_DEPTH
+            )));
+        }
 
-            // Skip empty lines and comments
-            if line.is_empty() || line.starts_with('#') {
-                continue;
+        println!("[SYS] Calling func {}({})", name, args.join(", "));
+
+        let bindings: Vec<(String, String)> =
+            function.params.iter().cloned().zip(args).collect();
+
+        // Parameters are visible to code blocks as variables for the duration of the call
+        let saved: Vec<(String, Option<Variable>)> = bindings
+            .iter()
+            .map(|(param, _)| (param.clone(), self.variables.save(param)))
+            .collect();
+        for (param, value) in &bindings {
+            if let Err(e) = self.variables.export(param, Some(value.clone())) {
+                self.restore_variables(saved);
+                return Err(e);
             }
+        }
 
-            if let Err(e) = self.execute_line(line) {
-                eprintln!("aish: {}:{}: {}", filename, line_number, e);
-                // Continue execution even if a command fails
+        // ...and to paragraphs through substitution and the function's context
+        self.llm_processor.enter_function(name.to_string(), &bindings);
+        let caller_bindings = std::mem::replace(&mut self.aish_bindings, bindings);
+        self.aish_call_depth += 1;
+        self.execute_markdown_elements(&function.body, &format!("{}()", name)).await;
+        self.aish_call_depth -= 1;
+        self.aish_bindings = caller_bindings;
+
+        let value = match self.aish_return.take() {
+            Some(value) => value,
+            None => self.llm_processor.last_answer().unwrap_or_default(),
+        };
+        self.llm_processor.exit_function();
+
+        self.restore_variables(saved);
+
+        println!("[SYS] func {} finished", name);
+        Ok(value)
+    }
+
+    // `return value` inside a function body: evaluate the value and stop the body
+    fn execute_return(&mut self, expression: &str) -> io::Result<()> {
+        let value = if expression.is_empty() {
+            String::new()
+        } else {
+            self.evaluate_call_argument(expression)?
+        };
+        self.aish_return = Some(value);
+        Ok(())
+    }
+
+    // Evaluate `name(args)`, calling nested functions in the arguments first
+    fn evaluate_function_call(&mut self, expression: &str) -> io::Result<String> {
+        let (name, raw_args) = parse_function_call(expression).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid function call: {}", expression),
+            )
+        })?;
+
+        let mut args = Vec::new();
+        for arg in &raw_args {
+            args.push(self.evaluate_call_argument(arg)?);
+        }
+
+        block_on(self.call_aish_function(&name, args))
+    }
+
+    fn evaluate_call_argument(&mut self, arg: &str) -> io::Result<String> {
+        if let Some((name, _)) = parse_function_call(arg) {
+            if self.aish_functions.contains_key(&name) {
+                return self.evaluate_function_call(arg);
             }
         }
 
-        self.cleanup_all_jobs();
+        expand_string(arg, self).inspect_err(|e| self.expansion_failed(e))
+    }
+
+    // Read the remaining lines of a function typed at the prompt and register it
+    fn define_functions_interactive(&mut self, first_line: &str) -> io::Result<()> {
+        let mut source = format!("{}\n", first_line);
+
+        let script = loop {
+            match MarkdownScript::parse(&source) {
+                Ok(script) => break script,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    match self.editor.readline("> ") {
+                        Ok(line) => {
+                            source.push_str(&line);
+                            source.push('\n');
+                        }
+                        Err(_) => return Err(e),
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        };
+
+        self.editor.add_history_entry(source.trim_end());
+        for function in script.get_functions() {
+            self.define_aish_function(function);
+        }
         Ok(())
     }
 
     // Interactive mode with AI support - uses same parsing as .aish files
     async fn execute_line_interactive(&mut self, line: &str) -> io::Result<()> {
-        // Create a simple markdown document with just this line as a paragraph
-        let markdown_content = format!("{}\n", line);
+        // Function declarations span several lines; keep reading until the body is closed
+        if is_function_declaration(line) {
+            return self.define_functions_interactive(line);
+        }
 
-        // Parse using the same markdown parser as .aish files
-        let script = match crate::markdown::MarkdownScript::parse(&markdown_content) {
-            Ok(script) => script,
-            Err(_) => {
-                // If markdown parsing fails, treat as traditional shell command
-                return self.execute_line(line);
-            }
-        };
+        // Calls to declared functions run locally instead of going to the LLM
+        if self.is_aish_function_call(line) {
+            return self.execute_line(line);
+        }
 
-        // Get LLM actions using the same logic as .aish files
+        let decision = self.route(line);
+        if decision.line.is_empty() {
+            return Ok(());
+        }
+        if decision.route == Route::Shell {
+            return match self.read_continuation_lines(&decision.line) {
+                Some(command) => self.execute_line(&command),
+                None => Ok(()),
+            };
+        }
+
+        // Parse using the same markdown parser as .aish files, so `# note` stays a comment
+        let script = MarkdownScript::parse(&format!("{}\n", decision.line))?;
         let llm_actions = script.get_llm_actions();
 
+        // Only prose reaches the model; say why so a misrouted command can be rerun with !
         if !llm_actions.is_empty() {
-            // Process as AI command using same logic as .aish files
-            for action in llm_actions {
-                let token_usage = self.llm_processor.get_token_usage();
-                match &action {
-                    crate::context::LLMAction::Comment { content } => {
-                        println!("[SYS] {} {}", token_usage, content);
-                    }
+            println!("[SYS] Asking AI: {} (start the line with ! to run it in the shell)", decision.reason);
+        }
+        for action in llm_actions {
+            let token_usage = self.llm_processor.get_token_usage();
+            match &action {
+                LLMAction::Comment { content } => {
+                    println!("[SYS] {} {}", token_usage, content);
                 }
+            }
 
-                match self.llm_processor.process_action(action).await {
-                    Ok(result) => {
-                        println!("{}", result);
-                        let updated_tokens = self.llm_processor.get_token_usage();
-                        println!("[SYS] Complete: {}", updated_tokens);
-                    }
-                    Err(e) => {
-                        eprintln!("[SYS] Error: {}", e);
-                    }
+            match self.llm_processor.process_action(action).await {
+                Ok(_) => {
+                    let updated_tokens = self.llm_processor.get_token_usage();
+                    println!("[SYS] Complete: {}", updated_tokens);
+                }
+                Err(e) => {
+                    eprintln!("[SYS] Error: {}", e);
                 }
             }
-            Ok(())
-        } else {
-            // No LLM actions, execute as traditional shell command
-            self.execute_line(line)
         }
+        Ok(())
+    }
+
+    // Keep reading lines at the `> ` prompt while a typed command is incomplete, as
+    // after `for f in *.rs; do`. None when input ends or Ctrl+C abandons the command.
+    fn read_continuation_lines(&mut self, first_line: &str) -> Option<String> {
+        let mut source = first_line.to_string();
+        while needs_more_input(&source) {
+            match self.editor.readline("> ") {
+                Ok(line) => {
+                    source.push('\n');
+                    source.push_str(&line);
+                }
+                Err(_) => return None,
+            }
+        }
+        if source != first_line {
+            self.editor.add_history_entry(&source);
+        }
+        Some(source)
+    }
+
+    // Decide whether an interactive line runs in the shell or goes to the LLM
+    pub fn route(&self, line: &str) -> Decision {
+        router::classify(line, self.route_mode, |name| {
+            if let Some(value) = self.aliases.get(name) {
+                Some(format!("an alias for `{}`", value))
+            } else if self.aish_functions.contains_key(name) {
+                Some("an aish function".to_string())
+            } else if self.functions.contains_key(name) {
+                Some("a shell function".to_string())
+            } else if Builtins::is_builtin(name) {
+                Some("a shell builtin".to_string())
+            } else if name.contains('/') {
+                std::path::Path::new(name).is_file().then(|| "a file".to_string())
+            } else {
+                Builtins::find_in_path(name, self.variables.get("PATH").unwrap_or_default())
+            }
+        })
+    }
+
+    pub fn route_mode(&self) -> RouteMode {
+        self.route_mode
+    }
+
+    pub fn set_route_mode(&mut self, mode: RouteMode) {
+        self.route_mode = mode;
+    }
+
+    pub fn pipefail(&self) -> bool {
+        self.pipefail
+    }
+
+    pub fn set_pipefail(&mut self, enabled: bool) {
+        self.pipefail = enabled;
+    }
+
+    pub fn glob_options_mut(&mut self) -> &mut GlobOptions {
+        &mut self.glob_options
     }
 
     // Traditional shell command execution (synchronous)
     fn execute_line(&mut self, line: &str) -> io::Result<()> {
-        match self.parser.parse(line) {
+        if let Some((variable, expression)) = parse_call_assignment(line) {
+            if self.is_aish_function_call(&expression) {
+                let value = self.evaluate_function_call(&expression)?;
+                return self.variables.set(&variable, value);
+            }
+        }
+
+        if let Some((name, _)) = parse_function_call(line) {
+            if self.aish_functions.contains_key(&name) {
+                return self.evaluate_function_call(line).map(|_| ());
+            }
+        }
+
+        match self.parser.parse_with_aliases(line, &self.aliases) {
             Ok(command_line) => self.execute_command_line(command_line),
             Err(e) => Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
@@ -327,193 +699,1026 @@ impl Shell {
         }
     }
 
+    pub fn loop_depth(&self) -> usize {
+        self.loop_depth
+    }
+
+    pub fn set_loop_control(&mut self, control: LoopControl) {
+        self.loop_control = Some(control);
+    }
+
+    // Should the rest of a list be skipped? Set by `exit`, `return`, or a
+    // `break`/`continue` leaving the current loop body
+    fn interrupted(&self) -> bool {
+        self.exit_requested
+            || self.aish_return.is_some()
+            || self.loop_control.is_some()
+            || self.function_return.is_some()
+    }
+
     fn get_prompt(&self) -> String {
-        self.env_vars
-            .get("PS1")
-            .unwrap_or(&"aish$ ".to_string())
-            .clone()
+        self.variables.get("PS1").unwrap_or("aish$ ").to_string()
     }
 
     fn execute_command_line(&mut self, command_line: CommandLine) -> io::Result<()> {
         match command_line {
-            CommandLine::Simple(cmd) => self.execute_simple_command(cmd, false),
+            CommandLine::Simple(cmd) => self.execute_simple_command(cmd),
             CommandLine::Pipeline(commands) => self.execute_pipeline(commands),
-            CommandLine::Background(cmd) => self.execute_simple_command(cmd, true),
+            CommandLine::Background(command) => self.execute_background(*command),
+            CommandLine::And(left, right) => {
+                if self.execute_for_status(*left) == 0 && !self.interrupted() {
+                    self.execute_command_line(*right)
+                } else {
+                    Ok(())
+                }
+            }
+            CommandLine::Or(left, right) => {
+                if self.execute_for_status(*left) != 0 && !self.interrupted() {
+                    self.execute_command_line(*right)
+                } else {
+                    Ok(())
+                }
+            }
+            CommandLine::List(mut items) => {
+                let last = items.pop();
+                for item in items {
+                    self.execute_for_status(item);
+                    if self.interrupted() {
+                        return Ok(());
+                    }
+                }
+                match last {
+                    Some(last) => self.execute_command_line(last),
+                    None => Ok(()),
+                }
+            }
+            CommandLine::If { branches, else_branch } => {
+                for (condition, body) in branches {
+                    let status = self.execute_for_status(condition);
+                    if self.interrupted() {
+                        return Ok(());
+                    }
+                    if status == 0 {
+                        return self.execute_command_line(body);
+                    }
+                }
+                match else_branch {
+                    Some(body) => self.execute_command_line(*body),
+                    None => {
+                        self.last_exit_status = 0;
+                        Ok(())
+                    }
+                }
+            }
+            CommandLine::While { condition, body, until } => {
+                self.execute_while(*condition, *body, until);
+                Ok(())
+            }
+            CommandLine::For { variable, words, body } => self.execute_for(variable, words, *body),
+            CommandLine::Case { word, arms } => self.execute_case(&word, arms),
+            CommandLine::FunctionDef { name, body } => {
+                self.functions.insert(name, *body);
+                self.last_exit_status = 0;
+                Ok(())
+            }
+            CommandLine::Subshell(body) => {
+                let text = format!("({})", body);
+                self.run_job(vec![JobStage::Shell(*body)], text, false)
+            }
+            CommandLine::Group(body) => self.execute_command_line(*body),
+            CommandLine::Arithmetic(expression) => self.execute_arithmetic(&expression),
+            CommandLine::Redirected { command, redirections } => self.execute_redirected(*command, redirections),
         }
     }
 
-    fn execute_simple_command(&mut self, cmd: SimpleCommand, background: bool) -> io::Result<()> {
-        if cmd.args.is_empty() {
-            return Ok(());
+    // (( expression )): status 0 when the value is not zero, 1 when it is zero or
+    // the expression is wrong
+    fn execute_arithmetic(&mut self, expression: &str) -> io::Result<()> {
+        let expression = expand_string(expression, self).inspect_err(|e| self.expansion_failed(e))?;
+        let value = evaluate(&expression, self).map_err(|e| {
+            self.last_exit_status = 1;
+            io::Error::other(format!("((: {}", e))
+        })?;
+        self.last_exit_status = i32::from(value == 0);
+        Ok(())
+    }
+
+    // Run a compound command with its redirections applied to the shell while it
+    // runs, so that every command inside shares them
+    fn execute_redirected(&mut self, command: CommandLine, redirections: Vec<Redirection>) -> io::Result<()> {
+        let redirections = self.expand_redirections(redirections)?;
+        let redirections = Redirections::open(&redirections).inspect_err(|_| self.last_exit_status = 1)?;
+        let saved = redirections.apply_to_shell().inspect_err(|_| self.last_exit_status = 1)?;
+        // As for builtins, an error goes where the command's stderr does
+        self.execute_for_status(command);
+        drop(saved);
+        Ok(())
+    }
+
+    // The status of a loop is that of the last body run, or 0 if none ran
+    fn execute_while(&mut self, condition: CommandLine, body: CommandLine, until: bool) {
+        let mut status = 0;
+        self.loop_depth += 1;
+        loop {
+            let succeeded = self.execute_for_status(condition.clone()) == 0;
+            if self.interrupted() {
+                self.next_iteration();
+                break;
+            }
+            if succeeded == until {
+                break;
+            }
+            status = self.execute_for_status(body.clone());
+            if !self.next_iteration() {
+                break;
+            }
         }
+        self.loop_depth -= 1;
+        self.last_exit_status = status;
+    }
 
-        let command_name = &cmd.args[0];
+    fn execute_for(&mut self, variable: String, words: Option<Vec<String>>, body: CommandLine) -> io::Result<()> {
+        // Without `in`, the loop runs over the positional parameters
+        let words = words.unwrap_or_else(|| vec!["\"$@\"".to_string()]);
+        let mut values = Vec::new();
+        for word in words {
+            values.extend(expand_word(&word, self).inspect_err(|e| self.expansion_failed(e))?);
+        }
 
-        // Check if it's a builtin command
-        if let Some(result) = self.builtins.execute(command_name, &cmd.args[1..]) {
-            match result(&mut *self) {
-                Ok(_) => return Ok(()),
-                Err(e) => return Err(e),
+        self.last_exit_status = 0;
+        self.loop_depth += 1;
+        let mut result = Ok(());
+        for value in values {
+            if let Err(e) = self.variables.set(&variable, value) {
+                self.last_exit_status = 1;
+                result = Err(e);
+                break;
+            }
+            self.execute_for_status(body.clone());
+            if !self.next_iteration() {
+                break;
+            }
+        }
+        self.loop_depth -= 1;
+        result
+    }
+
+    // After a loop body: take a `break`/`continue` aimed at this loop, pass one aimed
+    // further out on to the enclosing loop, and say whether to run another iteration
+    fn next_iteration(&mut self) -> bool {
+        match self.loop_control.take() {
+            None => !self.interrupted(),
+            Some(LoopControl::Continue(1)) => true,
+            Some(LoopControl::Break(1)) => false,
+            Some(LoopControl::Break(levels)) => {
+                self.loop_control = Some(LoopControl::Break(levels - 1));
+                false
+            }
+            Some(LoopControl::Continue(levels)) => {
+                self.loop_control = Some(LoopControl::Continue(levels - 1));
+                false
             }
         }
+    }
 
-        // Execute external command
-        self.execute_external_command(cmd, background)
+    // Run the first arm with a pattern matching the word; patterns are expanded
+    // but their quoted characters match literally
+    fn execute_case(&mut self, word: &str, arms: Vec<CaseArm>) -> io::Result<()> {
+        let subject = expand_string(word, self).inspect_err(|e| self.expansion_failed(e))?;
+        for arm in arms {
+            let mut matched = false;
+            for pattern in &arm.patterns {
+                let pattern = expand_pattern(pattern, self).inspect_err(|e| self.expansion_failed(e))?;
+                if pattern_matches(&pattern, &subject) {
+                    matched = true;
+                    break;
+                }
+            }
+            if matched {
+                return match arm.body {
+                    Some(body) => self.execute_command_line(body),
+                    None => {
+                        self.last_exit_status = 0;
+                        Ok(())
+                    }
+                };
+            }
+        }
+        self.last_exit_status = 0;
+        Ok(())
     }
 
-    fn execute_external_command(&mut self, cmd: SimpleCommand, background: bool) -> io::Result<()> {
-        let mut command = Command::new(&cmd.args[0]);
-        command.args(&cmd.args[1..]);
+    // Run part of a list: an error is reported and becomes a failed status, so
+    // the rest of the list can decide what to do about it
+    fn execute_for_status(&mut self, command_line: CommandLine) -> i32 {
+        if let Err(e) = self.execute_command_line(command_line) {
+            eprintln!("aish: {}", e);
+            if self.last_exit_status == 0 {
+                self.last_exit_status = 1;
+            }
+        }
+        self.last_exit_status
+    }
 
-        // Set environment variables
-        for (key, value) in &self.env_vars {
-            command.env(key, value);
+    // Words are expanded when the command runs, so earlier commands of a list
+    // (`export A=1; echo $A`) and `$?` are seen. Assignment values are expanded
+    // last, without splitting or pathname expansion, as in sh.
+    fn expand_command(&mut self, cmd: SimpleCommand) -> io::Result<SimpleCommand> {
+        self.last_substitution_status = None;
+        let mut args = Vec::new();
+        for arg in &cmd.args {
+            args.extend(expand_word(arg, self).inspect_err(|e| self.expansion_failed(e))?);
         }
+        let redirections = self.expand_redirections(cmd.redirections)?;
+        let mut assignments = Vec::new();
+        for (name, value) in cmd.assignments {
+            let value = expand_string(&value, self).inspect_err(|e| self.expansion_failed(e))?;
+            assignments.push((name, value));
+        }
+        Ok(SimpleCommand { assignments, args, redirections })
+    }
 
-        // Handle redirections
-        for redir in &cmd.redirections {
-            match redir.redir_type {
-                RedirectionType::Input => {
-                    command.stdin(Stdio::from(std::fs::File::open(&redir.filename)?));
-                }
-                RedirectionType::Output => {
-                    command.stdout(Stdio::from(std::fs::File::create(&redir.filename)?));
-                }
-                RedirectionType::Append => {
-                    command.stdout(Stdio::from(
-                        std::fs::OpenOptions::new()
-                            .create(true)
-                            .append(true)
-                            .open(&redir.filename)?,
-                    ));
+    fn expand_redirections(&mut self, redirections: Vec<Redirection>) -> io::Result<Vec<Redirection>> {
+        redirections
+            .into_iter()
+            .map(|redir| {
+                let target = match redir.redir_type {
+                    RedirectionType::HereDoc { expand: true } => expand_here_document(&redir.target, self),
+                    RedirectionType::HereDoc { expand: false } => Ok(redir.target.clone()),
+                    // A here-string is one line of input
+                    RedirectionType::HereString => expand_string(&redir.target, self).map(|target| target + "\n"),
+                    _ => expand_string(&redir.target, self),
+                };
+                let target = target.inspect_err(|e| self.expansion_failed(e))?;
+                Ok(Redirection { target, ..redir })
+            })
+            .collect()
+    }
+
+    // A failed expansion fails its command. `${name:?}` and bad substitutions also
+    // stop a shell that is not interactive, as in sh.
+    fn expansion_failed(&mut self, error: &io::Error) {
+        self.last_exit_status = 1;
+        if is_parameter_error(error) && !self.interactive {
+            self.exit_requested = true;
+        }
+    }
+
+    // Value of an .aish function called as `name(args)` or `name args`
+    fn aish_function_value(&mut self, command: &str) -> io::Result<String> {
+        if parse_function_call(command).is_some() {
+            return self.evaluate_function_call(command);
+        }
+        let cmd = match self.parser.parse(command) {
+            Ok(CommandLine::Simple(cmd)) => self.expand_command(cmd)?,
+            _ => return self.capture_command_output(command),
+        };
+        let result = block_on(self.call_aish_function(&cmd.args[0], cmd.args[1..].to_vec()));
+        self.last_exit_status = if result.is_ok() { 0 } else { 1 };
+        result
+    }
+
+    // Run `$(command)` in a forked copy of the shell, so that builtins, functions
+    // and variables work inside it while changes such as `cd` stay there
+    fn capture_command_output(&mut self, command: &str) -> io::Result<String> {
+        io::stdout().flush()?;
+        let (read_fd, write_fd) = pipe().map_err(to_io_error)?;
+
+        // SAFETY: the child only runs the shell's own executor and then _exits
+        match unsafe { fork() }.map_err(to_io_error)? {
+            ForkResult::Child => {
+                self.become_subshell();
+                let _ = close(read_fd);
+                let _ = dup2(write_fd, libc::STDOUT_FILENO);
+                let _ = close(write_fd);
+                if let Err(e) = self.execute_line(command) {
+                    eprintln!("aish: {}", e);
+                    if self.last_exit_status == 0 {
+                        self.last_exit_status = 1;
+                    }
                 }
+                let _ = io::stdout().flush();
+                unsafe { libc::_exit(self.last_exit_status) }
+            }
+            ForkResult::Parent { child } => {
+                let _ = close(write_fd);
+                let mut output = Vec::new();
+                // SAFETY: read_fd is a fresh pipe end owned by nothing else
+                let read_result = unsafe { File::from_raw_fd(read_fd) }.read_to_end(&mut output);
+                self.last_exit_status = wait_for_exit(child)?;
+                read_result?;
+
+                Ok(String::from_utf8_lossy(&output).into_owned())
             }
         }
+    }
 
-        if background {
-            command.stdin(Stdio::null());
-            let child = command.spawn()?;
-            println!("[{}] {}", self.background_jobs.len() + 1, child.id());
-            self.background_jobs.push(child);
-        } else {
-            let status = command.status()?;
-            if !status.success() {
-                if let Some(code) = status.code() {
-                    eprintln!("Command exited with code {}", code);
-                } else {
-                    eprintln!("Command terminated by signal");
-                }
+    fn execute_simple_command(&mut self, cmd: SimpleCommand) -> io::Result<()> {
+        let cmd = self.expand_command(cmd)?;
+        self.execute_expanded_command(cmd)
+    }
+
+    // Is the command an aish function, shell function or builtin, run by the shell
+    // itself? A command of only assignments or redirections is too.
+    fn runs_in_shell(&self, cmd: &SimpleCommand) -> bool {
+        match cmd.args.first() {
+            Some(name) => {
+                self.aish_functions.contains_key(name) || self.functions.contains_key(name) || Builtins::is_builtin(name)
             }
+            None => true,
+        }
+    }
+
+    fn execute_expanded_command(&mut self, cmd: SimpleCommand) -> io::Result<()> {
+        if !self.runs_in_shell(&cmd) {
+            let text = cmd.to_string();
+            return self.run_job(vec![JobStage::Command(cmd)], text, false);
         }
 
+        // Builtins and functions run in the shell itself, so their redirections
+        // are applied to the shell's own descriptors while they run
+        if cmd.redirections.is_empty() {
+            return self.execute_with_assignments(&cmd);
+        }
+        let redirections = Redirections::open(&cmd.redirections).inspect_err(|_| self.last_exit_status = 1)?;
+        let saved = redirections.apply_to_shell().inspect_err(|_| self.last_exit_status = 1)?;
+        // An error goes where the command's stderr does: `type nope 2>/dev/null` is quiet
+        if let Err(e) = self.execute_with_assignments(&cmd) {
+            eprintln!("aish: {}", e);
+        }
+        drop(saved);
         Ok(())
     }
 
-    fn execute_pipeline(&mut self, commands: Vec<SimpleCommand>) -> io::Result<()> {
-        if commands.is_empty() {
+    // Without a command, `name=value` sets shell variables, and the status is that
+    // of the last command substitution. Before a builtin or function, the variables
+    // are exported for as long as it runs.
+    fn execute_with_assignments(&mut self, cmd: &SimpleCommand) -> io::Result<()> {
+        if cmd.args.is_empty() {
+            for (name, value) in &cmd.assignments {
+                // As in sh, assigning to a readonly variable stops a script
+                if let Err(e) = self.variables.set(name, value.clone()) {
+                    let error = parameter_error(e.to_string());
+                    self.expansion_failed(&error);
+                    return Err(error);
+                }
+            }
+            self.last_exit_status = self.last_substitution_status.take().unwrap_or(0);
             return Ok(());
         }
 
-        let mut children = Vec::new();
-        let mut previous_stdout = None;
+        let mut saved = Vec::new();
+        for (name, value) in &cmd.assignments {
+            saved.push((name.clone(), self.variables.save(name)));
+            if let Err(e) = self.variables.export(name, Some(value.clone())) {
+                self.restore_variables(saved);
+                self.last_exit_status = 1;
+                return Err(e);
+            }
+        }
+        let result = self.execute_in_shell(&cmd.args);
+        self.restore_variables(saved);
+        result
+    }
 
-        for (i, cmd) in commands.iter().enumerate() {
-            let mut command = Command::new(&cmd.args[0]);
-            command.args(&cmd.args[1..]);
+    // Put back variables saved before a function call or a command with assignments,
+    // the last saved first, so that a name saved twice ends up as it was at first
+    fn restore_variables(&mut self, saved: Vec<(String, Option<Variable>)>) {
+        for (name, variable) in saved.into_iter().rev() {
+            self.variables.restore(&name, variable);
+        }
+    }
 
-            // Set environment variables
-            for (key, value) in &self.env_vars {
-                command.env(key, value);
+    // Run an aish function, shell function or builtin
+    fn execute_in_shell(&mut self, words: &[String]) -> io::Result<()> {
+        let Some((command_name, args)) = words.split_first() else {
+            return Ok(());
+        };
+
+        if self.aish_functions.contains_key(command_name) {
+            let result = block_on(self.call_aish_function(command_name, args.to_vec())).map(|_| ());
+            self.last_exit_status = if result.is_ok() { 0 } else { 1 };
+            return result;
+        }
+
+        // Functions come before builtins and PATH, so they can wrap commands
+        if let Some(body) = self.functions.get(command_name).cloned() {
+            return self.call_function(command_name, body, args.to_vec());
+        }
+
+        self.builtin_status = None;
+        let result = match self.builtins.execute(command_name, args) {
+            Some(builtin) => builtin(&mut *self),
+            None => Ok(()),
+        };
+        self.last_exit_status = match result {
+            Ok(()) => self.builtin_status.take().unwrap_or(0),
+            Err(_) => 1,
+        };
+        result
+    }
+
+    // Run a shell function with its arguments as `$1`, `$2`, ...; its `local`
+    // variables are restored afterwards and `return N` gives its status
+    fn call_function(&mut self, name: &str, body: CommandLine, args: Vec<String>) -> io::Result<()> {
+        if self.call_stack.len() >= MAX_FUNCTION_DEPTH {
+            self.last_exit_status = 1;
+            return Err(io::Error::other(format!(
+                "{}: maximum function nesting level exceeded ({})",
+                name, MAX_FUNCTION_DEPTH
+            )));
+        }
+
+        let caller_positional = std::mem::replace(&mut self.positional, args);
+        self.call_stack.push(Vec::new());
+        let result = self.execute_command_line(body);
+        let locals = self.call_stack.pop().unwrap_or_default();
+        self.positional = caller_positional;
+        self.restore_variables(locals);
+
+        match self.function_return.take() {
+            Some(status) => {
+                self.last_exit_status = status;
+                Ok(())
             }
+            None => result,
+        }
+    }
+
+    // Open the command's redirections and arrange for the child to apply them; the
+    // result holds the files open and must be kept until the command has started
+    fn apply_redirections(&mut self, command: &mut Command, redirections: &[Redirection]) -> io::Result<Redirections> {
+        let redirections = Redirections::open(redirections).inspect_err(|_| self.last_exit_status = 1)?;
+        redirections.apply_on_spawn(command);
+        Ok(redirections)
+    }
 
-            // Set up stdin
-            if i == 0 {
-                command.stdin(Stdio::inherit());
+    // Start a command, setting the status the way sh does when it cannot be run
+    fn spawn(&mut self, command: &mut Command, name: &str) -> io::Result<Child> {
+        command.spawn().map_err(|e| {
+            if e.kind() == io::ErrorKind::NotFound {
+                self.last_exit_status = 127;
+                io::Error::new(e.kind(), format!("{}: command not found", name))
             } else {
-                command.stdin(previous_stdout.unwrap());
+                self.last_exit_status = 126;
+                io::Error::new(e.kind(), format!("{}: {}", name, e))
             }
+        })
+    }
+
+    fn execute_pipeline(&mut self, commands: Vec<CommandLine>) -> io::Result<()> {
+        let text = CommandLine::Pipeline(commands.clone()).to_string();
+        let stages = commands
+            .into_iter()
+            .map(|command| self.job_stage(command))
+            .collect::<io::Result<Vec<_>>>()?;
+        self.run_job(stages, text, false)
+    }
 
-            // Set up stdout
-            if i == commands.len() - 1 {
-                command.stdout(Stdio::inherit());
+    // `command &`: start it as a job and go on without waiting
+    fn execute_background(&mut self, command: CommandLine) -> io::Result<()> {
+        let text = command.to_string();
+        let stages = match command {
+            CommandLine::Pipeline(commands) => commands
+                .into_iter()
+                .map(|command| self.job_stage(command))
+                .collect::<io::Result<Vec<_>>>()?,
+            command => vec![self.job_stage(command)?],
+        };
+        self.run_job(stages, text, true)
+    }
+
+    // How a command runs as part of a job: a simple command with its words
+    // expanded now, anything else in a forked copy of the shell. That copy is
+    // already the subshell that `( list )` asks for.
+    fn job_stage(&mut self, command: CommandLine) -> io::Result<JobStage> {
+        Ok(match command {
+            CommandLine::Simple(cmd) => JobStage::Command(self.expand_command(cmd)?),
+            CommandLine::Subshell(body) => JobStage::Shell(*body),
+            command => JobStage::Shell(command),
+        })
+    }
+
+    // Start the stages of a job side by side, each one's output feeding the next
+    // one's input, then wait for it unless it runs in the background. With job
+    // control the job gets a process group of its own, which has the terminal
+    // while the job runs in the foreground. The status is the last stage's, or with
+    // `set -o pipefail` the last failing stage's.
+    fn run_job(&mut self, stages: Vec<JobStage>, text: String, background: bool) -> io::Result<()> {
+        let count = stages.len();
+        let mut pgid: Option<Pid> = None;
+        let mut processes = Vec::new();
+        // Without job control, background jobs must not read the shell's input
+        let mut input: Option<OwnedFd> = if background && self.terminal.is_none() {
+            Some(File::open("/dev/null")?.into())
+        } else {
+            None
+        };
+
+        for (i, stage) in stages.into_iter().enumerate() {
+            let (next_input, output) = if i + 1 < count {
+                let (read_fd, write_fd) = pipe2(OFlag::O_CLOEXEC).map_err(to_io_error)?;
+                // SAFETY: both ends are fresh descriptors owned by nothing else
+                unsafe { (Some(OwnedFd::from_raw_fd(read_fd)), Some(OwnedFd::from_raw_fd(write_fd))) }
             } else {
-                command.stdout(Stdio::piped());
+                (None, None)
+            };
+
+            match self.start_job_stage(stage, input.take(), output, next_input.as_ref(), pgid, !background) {
+                Ok(pid) => {
+                    if self.terminal.is_some() {
+                        // Also done by the child; whichever runs first avoids a race
+                        let group = *pgid.get_or_insert(pid);
+                        let _ = setpgid(pid, group);
+                        if !background && group == pid {
+                            give_terminal(group, None);
+                        }
+                    }
+                    processes.push(Process { pid: Some(pid), state: ProcessState::Running });
+                }
+                // A single command that cannot start is reported by the caller
+                Err(e) if count == 1 => return Err(e),
+                Err(e) => {
+                    eprintln!("aish: {}", e);
+                    processes.push(Process { pid: None, state: ProcessState::Exited(self.last_exit_status) });
+                }
             }
+            input = next_input;
+        }
 
-            command.stderr(Stdio::inherit());
+        let id = self.jobs.add(pgid, processes, text);
+        if !background {
+            return self.wait_for_job_in_foreground(id);
+        }
+        self.last_background_pid = self.jobs.get(id).and_then(Job::last_pid);
+        if let Some(pid) = self.last_background_pid {
+            println!("[{}] {}", id, pid);
+        }
+        self.last_exit_status = 0;
+        Ok(())
+    }
+
+    // Start one process of a job reading `input` and writing `output` (the
+    // terminal when None). `other_end` is the pipe end the next stage reads from,
+    // which a forked shell must not keep open; `pgid` is the job's process group
+    // once its first process has started.
+    fn start_job_stage(
+        &mut self,
+        stage: JobStage,
+        input: Option<OwnedFd>,
+        output: Option<OwnedFd>,
+        other_end: Option<&OwnedFd>,
+        pgid: Option<Pid>,
+        foreground: bool,
+    ) -> io::Result<Pid> {
+        let job_control = self.terminal.is_some();
+        if let JobStage::Command(cmd) = &stage {
+            if !self.runs_in_shell(cmd) {
+                let mut command = Command::new(&cmd.args[0]);
+                command.args(&cmd.args[1..]);
+                command.env_clear();
+                command.envs(self.variables.environment());
+                command.envs(cmd.assignments.iter().map(|(name, value)| (name, value)));
+                command.stdin(input.map_or_else(Stdio::inherit, Stdio::from));
+                command.stdout(output.map_or_else(Stdio::inherit, Stdio::from));
+                if job_control {
+                    // SAFETY: enter_job only makes async-signal-safe calls
+                    unsafe {
+                        command.pre_exec(move || {
+                            enter_job(pgid, foreground);
+                            Ok(())
+                        });
+                    }
+                }
+                let _redirections = self.apply_redirections(&mut command, &cmd.redirections)?;
 
-            let mut child = command.spawn()?;
-            previous_stdout = child.stdout.take().map(Stdio::from);
-            children.push(child);
+                let child = self.spawn(&mut command, &cmd.args[0])?;
+                return Ok(Pid::from_raw(child.id() as i32));
+            }
         }
 
-        // Wait for all commands to complete
-        for mut child in children {
-            let _ = child.wait()?;
+        io::stdout().flush()?;
+        // SAFETY: the child only runs the shell's own executor and then _exits
+        match unsafe { fork() }.map_err(to_io_error)? {
+            ForkResult::Child => {
+                if job_control {
+                    enter_job(pgid, foreground);
+                }
+                self.become_subshell();
+                if let Some(input) = input {
+                    let _ = dup2(input.as_raw_fd(), libc::STDIN_FILENO);
+                }
+                if let Some(output) = output {
+                    let _ = dup2(output.as_raw_fd(), libc::STDOUT_FILENO);
+                }
+                if let Some(other_end) = other_end {
+                    let _ = close(other_end.as_raw_fd());
+                }
+                let result = match stage {
+                    JobStage::Command(cmd) => self.execute_expanded_command(cmd),
+                    JobStage::Shell(command_line) => self.execute_command_line(command_line),
+                };
+                if let Err(e) = result {
+                    eprintln!("aish: {}", e);
+                    if self.last_exit_status == 0 {
+                        self.last_exit_status = 1;
+                    }
+                }
+                let _ = io::stdout().flush();
+                unsafe { libc::_exit(self.last_exit_status) }
+            }
+            ForkResult::Parent { child } => Ok(child),
         }
+    }
 
-        Ok(())
+    // A forked copy of the shell does no job control of its own: its commands stay
+    // in the job it belongs to
+    fn become_subshell(&mut self) {
+        self.interactive = false;
+        self.terminal = None;
+        self.jobs = JobTable::default();
     }
 
-    fn cleanup_background_jobs(&mut self) {
-        self.background_jobs.retain_mut(|job| {
-            match job.try_wait() {
-                Ok(Some(_status)) => {
-                    println!("[{}] Done", job.id());
-                    false // Remove completed job
-                }
-                Ok(None) => true, // Job still running
-                Err(_) => false,  // Job errored, remove it
+    // Wait until a job started or continued in the foreground finishes or stops,
+    // then take the terminal back. A stopped job stays in the table for `fg`/`bg`.
+    fn wait_for_job_in_foreground(&mut self, id: usize) -> io::Result<()> {
+        let Some(job) = self.jobs.get_mut(id) else {
+            return Ok(());
+        };
+        let result = job.wait(self.terminal.is_some());
+        if let Some(terminal) = &self.terminal {
+            if job.is_stopped() {
+                job.modes = terminal_modes();
             }
-        });
+            give_terminal(terminal.shell_pgid, Some(&terminal.modes));
+        }
+        result?;
+
+        self.last_exit_status = job.status(self.pipefail);
+        if job.is_stopped() {
+            job.notified = true;
+            self.jobs.make_current(id);
+            if let Some(job) = self.jobs.get(id) {
+                println!("\n{}", self.jobs.describe(job, false));
+            }
+        } else {
+            self.jobs.remove(id);
+        }
+        Ok(())
     }
 
+    // Report background jobs that finished or stopped since the last prompt
+    fn notify_jobs(&mut self) {
+        self.jobs.poll();
+        for line in self.jobs.take_notifications() {
+            println!("{}", line);
+        }
+    }
+
+    // Hang up the jobs still running when the shell exits, continuing stopped ones
+    // so that they see the signal
     fn cleanup_all_jobs(&mut self) {
-        for mut job in self.background_jobs.drain(..) {
-            let _ = job.kill();
-            let _ = job.wait();
+        self.jobs.poll();
+        for id in self.jobs.ids() {
+            if let Some(job) = self.jobs.remove(id) {
+                let _ = job.signal(Signal::SIGHUP);
+                if job.is_stopped() {
+                    let _ = job.signal(Signal::SIGCONT);
+                }
+            }
         }
     }
 
     fn setup_signal_handlers(&self) -> io::Result<()> {
-        // Signal handling setup would go here
-        // For now, we'll rely on rustyline's built-in handling
+        watch_children()
+    }
+
+    pub fn job_control(&self) -> bool {
+        self.terminal.is_some()
+    }
+
+    pub fn jobs(&mut self) -> &mut JobTable {
+        self.jobs.poll();
+        &mut self.jobs
+    }
+
+    // fg and bg: continue a stopped job, in the foreground (waiting for it) or in
+    // the background
+    pub fn continue_job(&mut self, id: usize, foreground: bool) -> io::Result<()> {
+        let Some(job) = self.jobs.get_mut(id) else {
+            return Err(io::Error::other(format!("%{}: no such job", id)));
+        };
+        if foreground {
+            println!("{}", job.command);
+            if let Some(pgid) = job.pgid {
+                give_terminal(pgid, job.modes.as_ref());
+            }
+            job.resume()?;
+            self.jobs.make_current(id);
+            return self.wait_for_job_in_foreground(id);
+        }
+
+        if !job.is_stopped() {
+            println!("job {} already in background", id);
+            return Ok(());
+        }
+        job.resume()?;
+        let command = job.command.clone();
+        self.jobs.make_current(id);
+        println!("[{}]+ {} &", id, command);
         Ok(())
     }
 
-    pub fn set_env_var(&mut self, key: String, value: String) {
-        self.env_vars.insert(key, value);
+    pub fn set_builtin_status(&mut self, status: i32) {
+        self.builtin_status = Some(status);
     }
 
-    pub fn get_env_var(&self, key: &str) -> Option<&String> {
-        self.env_vars.get(key)
+    // wait: block until a job finishes and give its status; it leaves the table.
+    // With job control Ctrl+C stops the waiting with an Interrupted error.
+    pub fn wait_for_job(&mut self, id: usize) -> io::Result<i32> {
+        let Some(job) = self.jobs.get_mut(id) else {
+            return Ok(127);
+        };
+        // Ctrl+C gives an Interrupted error
+        if self.terminal.is_some() {
+            catch_interrupts(|| job.wait(false))??;
+        } else {
+            job.wait(false)?;
+        }
+        let status = job.status(self.pipefail);
+        self.jobs.remove(id);
+        Ok(status)
     }
 
-    pub fn unset_env_var(&mut self, key: &str) {
-        self.env_vars.remove(key);
+    pub fn variables(&self) -> &Variables {
+        &self.variables
+    }
+
+    pub fn variables_mut(&mut self) -> &mut Variables {
+        &mut self.variables
+    }
+
+    pub fn in_function(&self) -> bool {
+        !self.call_stack.is_empty()
+    }
+
+    pub fn llm_settings(&mut self) -> &mut GenerationConfig {
+        self.llm_processor.generation_config_mut()
+    }
+
+    pub fn llm_description(&self) -> String {
+        self.llm_processor.llm_description()
+    }
+
+    pub fn alias(&self, name: &str) -> Option<&String> {
+        self.aliases.get(name)
+    }
+
+    // All aliases, sorted by name
+    pub fn aliases(&self) -> Vec<(&String, &String)> {
+        let mut aliases: Vec<_> = self.aliases.iter().collect();
+        aliases.sort();
+        aliases
+    }
+
+    pub fn set_alias(&mut self, name: String, value: String) {
+        self.aliases.insert(name, value);
+    }
+
+    pub fn remove_alias(&mut self, name: &str) -> bool {
+        self.aliases.remove(name).is_some()
+    }
+
+    pub fn clear_aliases(&mut self) {
+        self.aliases.clear();
+    }
+
+    pub fn has_function(&self, name: &str) -> bool {
+        self.functions.contains_key(name)
+    }
+
+    pub fn remove_function(&mut self, name: &str) {
+        self.functions.remove(name);
+    }
+
+    // `return N` from the running shell function
+    pub fn return_from_function(&mut self, status: i32) -> io::Result<()> {
+        if self.call_stack.is_empty() {
+            return Err(io::Error::other("return: can only `return' from a function"));
+        }
+        self.function_return = Some(status);
+        Ok(())
+    }
+
+    // `local name[=value]`: the variable gets its current value back when the
+    // running function returns. A variable exported outside stays exported.
+    pub fn declare_local(&mut self, name: &str, value: Option<String>) -> io::Result<()> {
+        let previous = self.variables.save(name);
+        let Some(locals) = self.call_stack.last_mut() else {
+            return Err(io::Error::other("local: can only be used in a function"));
+        };
+        if previous.as_ref().is_some_and(|variable| variable.readonly) {
+            return Err(io::Error::other(format!("{}: readonly variable", name)));
+        }
+        if !locals.iter().any(|(local, _)| local == name) {
+            locals.push((name.to_string(), previous));
+        }
+
+        match value {
+            Some(value) => self.variables.set(name, value),
+            None => self.variables.unset(name),
+        }
     }
 
     pub fn request_exit(&mut self) {
         self.exit_requested = true;
     }
 
+    // $0 and $1... for a script run as `aish script.sh a b c`
+    pub fn set_script_parameters(&mut self, name: &str, parameters: Vec<String>) {
+        self.script_name = name.to_string();
+        self.positional = parameters;
+    }
+
+    // set -- args: replace $1... of the script or the running function
+    pub fn set_positional_parameters(&mut self, parameters: Vec<String>) {
+        self.positional = parameters;
+    }
+
+    // shift n: drop $1 to $n and renumber the rest; false when there are fewer than n
+    pub fn shift_positional_parameters(&mut self, count: usize) -> bool {
+        if count > self.positional.len() {
+            return false;
+        }
+        self.positional.drain(..count);
+        true
+    }
+
     pub fn get_current_dir(&self) -> io::Result<String> {
         Ok(env::current_dir()?.display().to_string())
     }
 
     pub fn change_directory(&mut self, path: &str) -> io::Result<()> {
+        let home = self.variables.get("HOME").unwrap_or("/").to_string();
         let new_dir = if path.is_empty() || path == "~" {
-            env::var("HOME").unwrap_or_else(|_| "/".to_string())
+            home
         } else if path.starts_with("~/") {
-            let home = env::var("HOME").unwrap_or_else(|_| "/".to_string());
             format!("{}/{}", home, &path[2..])
         } else {
             path.to_string()
         };
 
         env::set_current_dir(&new_dir)?;
-        self.set_env_var("PWD".to_string(), new_dir);
-        Ok(())
+        self.variables.set("PWD", new_dir)
     }
 }
 
+impl Expander for Shell {
+    // Value of `$name` in a word, including the special parameters
+    fn variable(&self, name: &str) -> Option<String> {
+        match name {
+            "?" => Some(self.last_exit_status.to_string()),
+            "0" => Some(self.script_name.clone()),
+            "$" => Some(self.pid.to_string()),
+            "!" => self.last_background_pid.map(|pid| pid.to_string()),
+            "#" => Some(self.positional.len().to_string()),
+            "@" => Some(self.positional.join(" ")),
+            "*" => Some(self.positional.join(star_separator(self.variables.get("IFS")))),
+            _ if name.chars().all(|c| c.is_ascii_digit()) => match name.parse::<usize>() {
+                Ok(index) if index > 0 => self.positional.get(index - 1).cloned(),
+                _ => self.variables.get(name).map(str::to_string),
+            },
+            _ => self.variables.get(name).map(str::to_string),
+        }
+    }
+
+    fn positional_parameters(&self) -> Vec<String> {
+        self.positional.clone()
+    }
+
+    fn assign_variable(&mut self, name: &str, value: &str) -> io::Result<()> {
+        if !is_name(name) {
+            return Err(parameter_error(format!("${}: cannot assign in this way", name)));
+        }
+        self.variables.set(name, value.to_string()).map_err(|e| parameter_error(e.to_string()))
+    }
+
+    fn glob_options(&self) -> GlobOptions {
+        self.glob_options
+    }
+
+    fn command_output(&mut self, command: &str) -> String {
+        // An .aish function talks to the LLM, whose conversation lives in this
+        // process, so it runs here and its value becomes the output
+        let result = if self.is_aish_function_call(command.trim()) && parse_call_assignment(command).is_none() {
+            self.aish_function_value(command.trim())
+        } else {
+            self.capture_command_output(command)
+        };
+        let output = result.map(|mut output| {
+            output.truncate(output.trim_end_matches('\n').len());
+            output
+        }).unwrap_or_else(|e| {
+            eprintln!("aish: command substitution: {}", e);
+            self.last_exit_status = 1;
+            String::new()
+        });
+        self.last_substitution_status = Some(self.last_exit_status);
+        output
+    }
+}
+
+// Could more lines complete this command, as in an unfinished `if` or quote?
+fn needs_more_input(source: &str) -> bool {
+    matches!(Parser::new().parse(source), Err(e) if e.is_incomplete())
+}
+
+// Group script lines into complete commands, each with the number of its first
+// line. A command continues on the following lines while it is incomplete; blank
+// lines and comments between commands are skipped.
+fn complete_commands(script: &str) -> Vec<(usize, String)> {
+    let mut commands = Vec::new();
+    let mut pending = String::new();
+    let mut first_line = 0;
+
+    for (index, line) in script.lines().enumerate() {
+        if pending.is_empty() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            first_line = index + 1;
+            pending.push_str(line);
+        } else {
+            pending.push('\n');
+            pending.push_str(line);
+        }
+
+        if !needs_more_input(&pending) {
+            commands.push((first_line, std::mem::take(&mut pending)));
+        }
+    }
+
+    // An unfinished command at the end still runs, so its parse error is reported
+    if !pending.is_empty() {
+        commands.push((first_line, pending));
+    }
+    commands
+}
+
+fn to_io_error(err: nix::Error) -> io::Error {
+    io::Error::from_raw_os_error(err as i32)
+}
+
+// Wait for a forked or spawned process and give its status the way sh reports it
+fn wait_for_exit(pid: Pid) -> io::Result<i32> {
+    loop {
+        match waitpid(pid, None) {
+            Ok(WaitStatus::Exited(_, code)) => return Ok(code),
+            Ok(WaitStatus::Signaled(_, signal, _)) => return Ok(128 + signal as i32),
+            Ok(_) | Err(nix::Error::EINTR) => continue,
+            Err(e) => return Err(to_io_error(e)),
+        }
+    }
+}
+
+// Run an async operation (such as an .aish function call, which may talk to the
+// LLM) from the synchronous command executor
+fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::task::block_in_place(|| tokio::runtime::Handle::current().block_on(future))
+}
+
+// The value part of a `return` line, if the line is one
+fn return_expression(line: &str) -> Option<&str> {
+    match line.strip_prefix("return") {
+        Some(rest) if rest.is_empty() || rest.starts_with(char::is_whitespace) => Some(rest.trim()),
+        _ => None,
+    }
+}
+
+// Replace `$param` and `${param}` references to function parameters, or to
+// positional parameters such as `$1` and `$@`, in prose
+fn substitute_parameters(text: &str, bindings: &[(String, String)]) -> String {
+    if bindings.is_empty() {
+        return text.to_string();
+    }
+    // One pass over all names, so that a value is not itself substituted again.
+    // `$1` is not the start of `$10`, while `$@` ends where it is.
+    let names: Vec<String> = bindings.iter().map(|(name, _)| regex::escape(name)).collect();
+    let bare: Vec<String> = bindings
+        .iter()
+        .map(|(name, _)| {
+            let boundary = if name.ends_with(|c: char| c.is_alphanumeric() || c == '_') { r"\b" } else { "" };
+            format!("{}{}", regex::escape(name), boundary)
+        })
+        .collect();
+    let pattern = format!(r"\$\{{({})\}}|\$({})", names.join("|"), bare.join("|"));
+    let Ok(re) = Regex::new(&pattern) else {
+        return text.to_string();
+    };
+    re.replace_all(text, |captures: &regex::Captures| {
+        let name = captures.get(1).or_else(|| captures.get(2)).map_or("", |m| m.as_str());
+        bindings.iter().find(|(param, _)| param == name).map(|(_, value)| value.clone()).unwrap_or_default()
+    })
+    .into_owned()
+}
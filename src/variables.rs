@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::env;
+use std::io;
+
+/// One shell variable. A variable can have attributes without a value, as after
+/// `export NAME` or `readonly NAME` with NAME unset.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Variable {
+    pub value: Option<String>,
+    // Passed to the commands the shell starts, in their environment
+    pub exported: bool,
+    pub readonly: bool,
+}
+
+impl Variable {
+    // The flags `declare -p` shows: `-rx`, or `--` for a plain variable
+    pub fn flags(&self) -> String {
+        let mut flags = String::from("-");
+        if self.readonly {
+            flags.push('r');
+        }
+        if self.exported {
+            flags.push('x');
+        }
+        if flags.len() == 1 {
+            flags.push('-');
+        }
+        flags
+    }
+}
+
+/// The shell's variables: what `$NAME` expands to, what builtins read and change,
+/// and, for the exported ones, the environment of every command the shell starts
+#[derive(Debug, Default)]
+pub struct Variables {
+    variables: HashMap<String, Variable>,
+    // Keep the process environment the same as the exported variables, for what
+    // reads it directly, such as the LLM's tools and its settings
+    sync_environment: bool,
+}
+
+impl Variables {
+    /// The variables of a new shell: the environment it was started with, all
+    /// exported, which it then keeps in step with them
+    pub fn from_environment() -> Self {
+        let variables = env::vars()
+            .map(|(name, value)| (name, Variable { value: Some(value), exported: true, readonly: false }))
+            .collect();
+        Variables { variables, sync_environment: true }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.variables.get(name)?.value.as_deref()
+    }
+
+    pub fn variable(&self, name: &str) -> Option<&Variable> {
+        self.variables.get(name)
+    }
+
+    pub fn is_readonly(&self, name: &str) -> bool {
+        self.variables.get(name).is_some_and(|variable| variable.readonly)
+    }
+
+    /// `name=value`: the variable keeps its attributes, and a new one is not exported
+    pub fn set(&mut self, name: &str, value: String) -> io::Result<()> {
+        self.check_writable(name)?;
+        self.variables.entry(name.to_string()).or_default().value = Some(value);
+        self.sync(name);
+        Ok(())
+    }
+
+    /// `export name[=value]`
+    pub fn export(&mut self, name: &str, value: Option<String>) -> io::Result<()> {
+        if value.is_some() {
+            self.check_writable(name)?;
+        }
+        let variable = self.variables.entry(name.to_string()).or_default();
+        variable.exported = true;
+        if value.is_some() {
+            variable.value = value;
+        }
+        self.sync(name);
+        Ok(())
+    }
+
+    /// `export -n name`: a shell variable again, left out of commands' environment
+    pub fn unexport(&mut self, name: &str) {
+        if let Some(variable) = self.variables.get_mut(name) {
+            variable.exported = false;
+            self.sync(name);
+        }
+    }
+
+    /// `readonly name[=value]`
+    pub fn set_readonly(&mut self, name: &str, value: Option<String>) -> io::Result<()> {
+        if value.is_some() {
+            self.check_writable(name)?;
+        }
+        let variable = self.variables.entry(name.to_string()).or_default();
+        variable.readonly = true;
+        if value.is_some() {
+            variable.value = value;
+        }
+        self.sync(name);
+        Ok(())
+    }
+
+    pub fn unset(&mut self, name: &str) -> io::Result<()> {
+        if self.is_readonly(name) {
+            return Err(io::Error::other(format!("{}: cannot unset: readonly variable", name)));
+        }
+        self.variables.remove(name);
+        self.sync(name);
+        Ok(())
+    }
+
+    /// The variable as it is now, to put back with `restore` once a function
+    /// call or a command with `name=value` before it is over
+    pub fn save(&self, name: &str) -> Option<Variable> {
+        self.variables.get(name).cloned()
+    }
+
+    pub fn restore(&mut self, name: &str, saved: Option<Variable>) {
+        match saved {
+            Some(variable) => self.variables.insert(name.to_string(), variable),
+            None => self.variables.remove(name),
+        };
+        self.sync(name);
+    }
+
+    /// All variables, sorted by name
+    pub fn all(&self) -> Vec<(&String, &Variable)> {
+        let mut variables: Vec<_> = self.variables.iter().collect();
+        variables.sort_by(|a, b| a.0.cmp(b.0));
+        variables
+    }
+
+    /// The environment of a command: the exported variables that have a value,
+    /// sorted by name
+    pub fn environment(&self) -> Vec<(&String, &String)> {
+        let mut environment: Vec<_> = self
+            .variables
+            .iter()
+            .filter(|(_, variable)| variable.exported)
+            .filter_map(|(name, variable)| Some((name, variable.value.as_ref()?)))
+            .collect();
+        environment.sort();
+        environment
+    }
+
+    fn check_writable(&self, name: &str) -> io::Result<()> {
+        if self.is_readonly(name) {
+            return Err(io::Error::other(format!("{}: readonly variable", name)));
+        }
+        Ok(())
+    }
+
+    fn sync(&self, name: &str) {
+        if !self.sync_environment {
+            return;
+        }
+        match self.variables.get(name) {
+            Some(Variable { value: Some(value), exported: true, .. }) => env::set_var(name, value),
+            _ => env::remove_var(name),
+        }
+    }
+}
+
+// A value as `declare -p` shows it, in double quotes that `declare` would read back
+pub fn quote_value(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for ch in value.chars() {
+        if matches!(ch, '"' | '\\' | '$' | '`') {
+            quoted.push('\\');
+        }
+        quoted.push(ch);
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attributes() {
+        let mut variables = Variables::default();
+        variables.set("LOCAL", "1".to_string()).unwrap();
+        variables.export("SHARED", Some("2".to_string())).unwrap();
+        variables.export("LATER", None).unwrap();
+        assert_eq!(variables.get("LOCAL"), Some("1"));
+        assert_eq!(variables.get("LATER"), None);
+        let environment: Vec<_> = variables.environment().into_iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(environment, ["SHARED"]);
+
+        // Setting an exported variable keeps it exported
+        variables.set("SHARED", "3".to_string()).unwrap();
+        assert_eq!(variables.environment(), [(&"SHARED".to_string(), &"3".to_string())]);
+        variables.unexport("SHARED");
+        assert!(variables.environment().is_empty());
+
+        assert_eq!(variables.variable("LATER").unwrap().flags(), "-x");
+        assert_eq!(variables.variable("LOCAL").unwrap().flags(), "--");
+    }
+
+    #[test]
+    fn test_readonly() {
+        let mut variables = Variables::default();
+        variables.set_readonly("FIXED", Some("1".to_string())).unwrap();
+        let error = variables.set("FIXED", "2".to_string()).err().unwrap();
+        assert_eq!(error.to_string(), "FIXED: readonly variable");
+        let error = variables.unset("FIXED").err().unwrap();
+        assert_eq!(error.to_string(), "FIXED: cannot unset: readonly variable");
+        assert!(variables.export("FIXED", Some("3".to_string())).is_err());
+        variables.export("FIXED", None).unwrap();
+        assert_eq!(variables.variable("FIXED").unwrap().flags(), "-rx");
+        assert_eq!(variables.get("FIXED"), Some("1"));
+
+        // A function's local variables and prefix assignments are put back as they were
+        let saved = variables.save("NEW");
+        variables.set("NEW", "temporary".to_string()).unwrap();
+        variables.restore("NEW", saved);
+        assert_eq!(variables.variable("NEW"), None);
+    }
+
+    #[test]
+    fn test_quote_value() {
+        assert_eq!(quote_value("plain"), "\"plain\"");
+        assert_eq!(quote_value("a\"b $c\\"), "\"a\\\"b \\$c\\\\\"");
+    }
+}
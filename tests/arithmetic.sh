@@ -0,0 +1,20 @@
+# Arithmetic expansion, (( )) and let
+echo $(( 1 + 2 * 3 )) $(( (1 + 2) * 3 )) $(( 2 ** 10 )) $(( -7 / 2 )) $(( 7 % 3 ))
+echo $(( 0x1f )) $(( 010 )) $(( 2#1010 )) $(( 1 << 4 )) $(( 5 > 3 && 2 > 3 ))
+export count=0
+while (( count < 3 )); do
+    echo "attempt $(( count + 1 ))"
+    (( count++ ))
+done
+echo "count is $count"
+(( count == 3 )) && echo "three"
+(( 0 )) || echo "zero is false: $?"
+let "total = count * 10" "total += 2"
+echo "total is $total, let status $?"
+let 0
+echo "let 0 status $?"
+echo $(( count > 2 ? 100 : 200 )) $(( total / count )) "$(( ${#total} + $count ))"
+export limit=5
+echo $(( limit * 2 ))
+(( 1 / 0 ))
+echo "division status $?"
@@ -0,0 +1,52 @@
+# Compound commands spanning several lines
+for name in alpha beta gamma; do
+  if [ "$name" = beta ]; then
+    continue
+  fi
+  echo "item: $name"
+done
+
+export count=0
+while [ $count -lt 10 ]; do
+  export count=$(expr $count + 1)
+  if test $count -eq 3; then break; fi
+done
+echo "count: $count"
+
+until true
+do
+  echo never
+done
+
+for file in tests/fixtures/*.sse; do
+  case $file in
+    *anthropic*) echo "anthropic: $file" ;;
+    *openai* | *other*)
+      echo "openai: $file"
+      ;;
+    *) echo "unknown" ;;
+  esac
+done
+
+case "a b" in
+  'a b') echo "quoted pattern" ;;
+esac
+
+if false; then
+  echo wrong
+elif [ -d tests ]; then
+  echo "elif taken"
+else
+  echo wrong
+fi
+
+for outer in 1 2; do
+  for inner in x y; do
+    [ $inner = y ] && continue 2
+    echo "pair: $outer$inner"
+  done
+done
+
+echo "multi
+line"
+echo "end"
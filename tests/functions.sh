@@ -0,0 +1,45 @@
+# POSIX shell functions
+greet() {
+  echo "hello $1 ($# args)"
+}
+
+count_args() { echo "count: $#"; for arg in "$@"; do echo "arg: $arg"; done; }
+
+scoped() {
+  local name=inner other
+  echo "inside: $name"
+  export name=changed
+  return 3
+  echo "not reached"
+}
+
+is_even() {
+  case $1 in
+    *[02468]) return 0 ;;
+  esac
+  return 1
+}
+
+factorial() {
+  if [ $1 -le 1 ]; then
+    echo 1
+    return
+  fi
+  local below=$(factorial $(expr $1 - 1))
+  expr $1 \* $below
+}
+
+greet world extra
+count_args "a b" c
+count_args
+export name=outer
+scoped
+echo "status: $? name: $name"
+for n in 1 2 3 4; do
+  is_even $n && echo "$n is even"
+done
+echo "5! = $(factorial 5)"
+type greet
+unset -f greet
+greet || echo "greet removed"
+return || echo "return outside a function fails"
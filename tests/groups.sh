@@ -0,0 +1,35 @@
+# Subshells and brace groups
+cd /tmp
+(cd / && pwd)
+pwd
+(export GREETING=hello; echo "$GREETING from a subshell")
+echo "after: [$GREETING]"
+(exit 3)
+echo "subshell status: $?"
+(echo "exit ends only the subshell"; exit 0; echo "not reached")
+echo "still here"
+
+{ echo one; echo two; } > /tmp/aish-group-$$.txt
+cat /tmp/aish-group-$$.txt
+{ cd /; }
+pwd
+cd /tmp
+{ echo "to stderr" >&2; echo "to stdout"; } 2>&1 | sed 's/^/| /'
+(echo b; echo a) | sort
+echo "piped into a group" | { echo "got:"; cat; }
+{ echo "through descriptor 3" >&3; } 3>/tmp/aish-group-$$.txt
+cat /tmp/aish-group-$$.txt
+for i in 1 2 3; do echo "line $i"; done > /tmp/aish-group-$$.txt
+if true; then sed "s/^/read /"; fi < /tmp/aish-group-$$.txt
+if true; then echo "if output"; fi | tr a-z A-Z
+{ echo nested; (echo deeper) ; } | wc -l
+(
+  cd /
+  echo "multi-line subshell in $(pwd)"
+)
+log() { echo "$@"; } >> /tmp/aish-group-$$.txt
+log "appended by a function"
+tail -1 /tmp/aish-group-$$.txt
+rm /tmp/aish-group-$$.txt
+{ nope-not-a-command; } 2>/dev/null
+echo "group status: $?"
@@ -0,0 +1,19 @@
+# Here-documents and here-strings
+export name=world
+cat <<EOF
+hello $name
+quotes stay: "$name" '$name'
+escaped: \$name $(echo substituted)
+EOF
+cat <<'EOF'
+literal: $name `date`
+EOF
+cat <<-END | tr a-z A-Z
+	tabs stripped for $name
+	END
+tr a-z A-Z <<< "here-string for $name"
+count() { wc -l; }
+count <<EOF
+one
+two
+EOF
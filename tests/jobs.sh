@@ -0,0 +1,28 @@
+# Background jobs, without job control as scripts have none
+sleep 5 &
+sleep 5 | cat &
+jobs
+kill %1
+wait %1
+echo "killed: $?"
+kill -s KILL %2
+wait
+jobs
+echo "jobs left after wait"
+
+sh -c 'exit 3' &
+wait %%
+echo "exit status: $?"
+wait %7
+echo "no such job: $?"
+
+echo "in the background" | tr a-z A-Z &
+wait
+true && echo "an and-list in the background" &
+wait
+
+sleep 1 &
+disown
+jobs
+echo "disowned"
+kill -l | grep -c TERM
@@ -0,0 +1,3 @@
+# A script that ends without `exit`, on a failing command
+echo checked
+sh -c 'exit 3'
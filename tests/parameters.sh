@@ -0,0 +1,16 @@
+# Parameter expansion operators
+export FILE=/usr/src/archive.tar.gz
+export EMPTY=
+echo "${MISSING:-default} [${EMPTY-unset}] [${EMPTY:-empty}]"
+echo "${GREETING:=hello} $GREETING"
+echo "${FILE:+set}[${MISSING:+set}]"
+echo "length ${#FILE}"
+echo "${FILE##*/} ${FILE#/usr} ${FILE%.gz} ${FILE%%.*}"
+echo "${FILE/src/lib} ${FILE//r/R}"
+echo "${FILE:9:7} ${FILE: -6}"
+export NAME="ada lovelace"
+echo "${NAME^} ${NAME^^}"
+for f in a.txt b.txt; do echo "${f%.txt}.md"; done
+echo "${MISSING:-${FILE##*.}}"
+(echo "${MISSING:?is required}"; echo "not reached")
+echo "status $?"
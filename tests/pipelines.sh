@@ -0,0 +1,19 @@
+# Builtins, functions and redirections as pipeline stages
+echo "from a builtin" | tr a-z A-Z
+pwd | grep -c /
+shout() { tr a-z A-Z; echo "done shouting" >&2; }
+echo "into a function" | shout 2>&1 | sed 's/^/> /'
+cd / | true
+test "$(pwd)" = / || echo "cd in a pipeline stays in its stage"
+ls /nonexistent 2>&1 | wc -l
+
+true | false
+echo "last stage fails: $?"
+false | true
+echo "first stage fails: $?"
+set -o pipefail
+false | true
+echo "with pipefail: $?"
+set +o pipefail
+false | true
+echo "without pipefail: $?"
@@ -0,0 +1,15 @@
+# Positional and special parameters of a script
+echo "0=$0 #=$# 1=$1 2=${2} *=$*"
+for arg; do echo "arg [$arg]"; done
+shift
+echo "after shift: $# [$1]"
+shift 5
+echo "shift 5 status $?, still $#"
+set -- x "y z"
+echo "set: $# [$2]"
+(IFS=-; echo "IFS joins \$*: $* ${*:1}" split: $*; IFS=; echo "empty IFS: $*")
+set --
+echo "cleared: $#"
+show() { echo "in a function: $0 $# $1"; }
+show one two
+echo "same pid in a substitution: $([ $$ = $(echo $$) ] && echo yes)"
@@ -0,0 +1,27 @@
+# File-descriptor redirections, for external commands and builtins
+export dir=$(mktemp -d)
+
+ls /nonexistent 2> $dir/err
+echo "stderr lines: $(wc -l < $dir/err)"
+ls /nonexistent > $dir/all 2>&1
+echo "with 2>&1: $(wc -l < $dir/all)"
+ls /nonexistent 2>&1 > $dir/none | sed 's/.*/piped stderr/'
+ls -d /nonexistent $dir &> $dir/both
+echo "with &>: $(wc -l < $dir/both)"
+
+echo one > $dir/file
+echo two >> $dir/file
+cat 3< $dir/file <&3
+echo "to stderr" 2> $dir/echo >&2
+cat $dir/echo
+type no-such-command 2> /dev/null || echo "type failed quietly"
+echo lost >&5 || echo "5 is not open"
+
+say() { echo "out $1"; echo "err $1" >&2; }
+say both > $dir/say 2>&1
+cat $dir/say
+say closed 2>&-
+
+> $dir/created
+test -f $dir/created && echo "created by a bare redirection"
+rm -r $dir
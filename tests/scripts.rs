@@ -0,0 +1,578 @@
+// End-to-end runs of the tests/*.aish scripts against the scripted mock LLM.
+// Each script has a fixture in tests/fixtures/mock with the replies it expects;
+// the mock fails a request that does not match and reports unused replies.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+struct ScriptRun {
+    stdout: String,
+}
+
+impl ScriptRun {
+    fn lines_starting_with(&self, prefix: &str) -> Vec<&str> {
+        self.stdout.lines().filter(|line| line.starts_with(prefix)).collect()
+    }
+
+    fn assert_contains(&self, expected: &str) {
+        assert!(self.stdout.contains(expected), "missing {:?} in output:\n{}", expected, self.stdout);
+    }
+}
+
+fn run_script(script: &str) -> ScriptRun {
+    let fixture = format!("tests/fixtures/mock/{}.json", script.trim_end_matches(".aish"));
+    run_aish(&["--mock-llm", &fixture, &format!("tests/{}", script)])
+}
+
+fn run_aish(args: &[&str]) -> ScriptRun {
+    run_aish_with_input(args, "")
+}
+
+// Lines piped to an interactive session are read like typed ones
+fn run_aish_with_input(args: &[&str], input: &str) -> ScriptRun {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_aish"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to run aish");
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    let output = child.wait_with_output().expect("failed to run aish");
+
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+
+    assert!(output.status.success(), "aish failed:\n{}\n{}", stdout, stderr);
+    assert!(
+        !stdout.lines().any(|line| line.starts_with("[SYS] LLM processing failed")),
+        "unexpected LLM request:\n{}",
+        stdout
+    );
+    assert!(!stderr.contains("never requested"), "{}\n{}", stderr, stdout);
+
+    ScriptRun { stdout }
+}
+
+#[test]
+fn test_read_then_explain() {
+    let run = run_script("test.aish");
+
+    run.assert_contains("[TOOL] Calling read_file...");
+    run.assert_contains("[TOOL] read_file: {\"content\":\"use std::env;");
+    run.assert_contains("[LLM] It is the entry point of the aish binary:\n[LLM] it parses the CLI flags");
+}
+
+#[test]
+fn test_read_and_explain_in_one_paragraph() {
+    let run = run_script("test2.aish");
+
+    assert_eq!(run.lines_starting_with("[LLM]"), vec![
+        "[LLM] main.rs parses the command line and runs the shell.",
+        "[LLM] done",
+    ]);
+}
+
+// Each paragraph runs after the blocks before it and sees their output and
+// status; a background job still writing does not hold up the next block
+#[test]
+fn test_code_block_results_reach_the_next_paragraph() {
+    let run = run_script("block_results.aish");
+
+    let position = |text: &str| run.stdout.find(text).unwrap_or_else(|| panic!("missing {:?}", text));
+    assert!(position("[LLM] The build failed with status 3.") < position("ticking in the background"));
+    assert!(position("ticking in the background") < position("[CMD] Executing sh block 3"));
+    run.assert_contains("\n[LLM] The background job is stopped.\n");
+}
+
+// A call binds the parameters for its code blocks and prompts, starts the LLM
+// on a fresh history seeded with the arguments, and leaves the caller's
+// variables as they were
+#[test]
+fn test_function_calls() {
+    let run = run_script("functions.aish");
+
+    assert_eq!(run.lines_starting_with("block sees"), vec!["block sees Ada, cheerful", "block sees Grace, calm"]);
+    run.assert_contains("\nafter the call: name=caller mood=unset\n");
+    assert_eq!(run.lines_starting_with("[LLM]"), vec!["[LLM] Hello Ada, lovely to see you!", "[LLM] Good evening, Grace."]);
+
+    // Declared and called as `name arg` at the prompt
+    let input = "func hello(name) {\n  Say hi to $name.\n}\nhello Linus\necho \"name=${name-unset}\"\n";
+    let run = run_aish_with_input(&["--mock-llm", "tests/fixtures/mock/functions_interactive.json"], input);
+    run.assert_contains("[SYS] Calling func hello(Linus)\n");
+    run.assert_contains("\n[LLM] Hi Linus!\n");
+    run.assert_contains("\nname=unset\n");
+}
+
+// `x = f(arg)` stores what `return` gives, or else the function's last answer,
+// and a call's value can be the argument of another call
+#[test]
+fn test_function_values() {
+    let run = run_script("returns.aish");
+
+    assert_eq!(run.lines_starting_with("[SYS] Calling func"), vec![
+        "[SYS] Calling func slug(Hello)",
+        "[SYS] Calling func slug(World)",
+        "[SYS] Calling func summarize(world-page)",
+    ]);
+    run.assert_contains("\nslug: hello-page\n");
+    run.assert_contains("\nsummary: A world page.\n");
+}
+
+// A function calling itself stops at the nesting limit instead of overflowing the stack
+#[test]
+fn test_function_recursion_is_limited() {
+    let run = run_aish(&["--mock-llm", "tests/fixtures/mock/empty.json", "tests/recursion.aish"]);
+
+    assert_eq!(run.lines_starting_with("[SYS] Calling func forever").len(), 200);
+    run.assert_contains("\nstill running\n");
+}
+
+#[test]
+fn test_existing_file() {
+    let run = run_script("existing_file.aish");
+
+    run.assert_contains("[LLM] It defines the Shell struct and its REPL.");
+}
+
+#[test]
+fn test_nonexistent_file_is_reported_as_error() {
+    let run = run_script("nonexistent_file.aish");
+
+    run.assert_contains("\"success\":false");
+    run.assert_contains("[LLM] The file ./kek.lol does not exist.");
+    run.assert_contains("[LLM] Reading ./kek.lol failed, so here is ./src/main.rs instead");
+}
+
+#[test]
+fn test_token_usage_is_tracked() {
+    let run = run_script("test_tokens.aish");
+
+    assert_eq!(run.lines_starting_with("[SYS] Paragraph complete"), vec![
+        "[SYS] Paragraph complete: 2K/200K TOK",
+        "[SYS] Paragraph complete: 7K/200K TOK",
+        "[SYS] Paragraph complete: 17K/200K TOK",
+        "[SYS] Paragraph complete: 35K/200K TOK",
+        "[SYS] Paragraph complete: 48K/200K TOK",
+    ]);
+}
+
+#[test]
+fn test_agentic_tool_chain() {
+    let run = run_script("test_agentic.aish");
+
+    assert_eq!(run.lines_starting_with("[TOOL] Calling"), vec![
+        "[TOOL] Calling read_file...",
+        "[TOOL] Calling execute_command...",
+        "[TOOL] Calling read_file...",
+        "[TOOL] Calling read_file...",
+        "[TOOL] Calling execute_command...",
+        "[TOOL] Calling add_to_context...",
+    ]);
+    run.assert_contains("Error reading file 'src/missing.rs'");
+    run.assert_contains("[SYS] Agentic iteration 4");
+    run.assert_contains("[LLM] The only configuration file is a test fixture; it holds no secrets.");
+}
+
+#[test]
+fn test_multiline_answer_is_prefixed() {
+    let run = run_script("test_multiline.aish");
+
+    assert_eq!(run.lines_starting_with("[LLM]"), vec![
+        "[LLM] ## Analysis",
+        "[LLM] ",
+        "[LLM] The package is named aish.",
+        "[LLM] ",
+        "[LLM] - tokio for async",
+        "[LLM] - reqwest for HTTP",
+        "[LLM] ",
+        "[LLM] ```toml",
+        "[LLM] edition = \"2021\"",
+        "[LLM] ```",
+    ]);
+}
+
+#[test]
+fn test_record_then_replay() {
+    let cassette = std::env::temp_dir().join(format!("aish-session-{}.json", std::process::id()));
+    let cassette = cassette.to_str().unwrap();
+
+    let recorded = run_aish(&["--mock-llm", "tests/fixtures/mock/test.json", "--record", cassette, "tests/test.aish"]);
+    let replayed = run_aish(&["--replay", cassette, "tests/test.aish"]);
+    std::fs::remove_file(cassette).unwrap();
+
+    assert!(replayed.stdout.starts_with(&format!("[SYS] LLM provider: replay of {} (3 responses, recorded with mock", cassette)));
+    let without_provider = |run: &ScriptRun| run.stdout.lines().skip(1).collect::<Vec<_>>().join("\n");
+    assert_eq!(without_provider(&replayed), without_provider(&recorded));
+}
+
+#[test]
+fn test_max_tokens_answer_is_continued() {
+    let run = run_script("test_max_tokens.aish");
+
+    run.assert_contains("[SYS] Response stopped at max_tokens (16), continuing (1/3)");
+    assert_eq!(run.lines_starting_with("[LLM]"), vec![
+        "[LLM] 1. Running programs",
+        "[LLM] 2. Pipelines",
+        "[LLM]  of commands",
+        "[LLM] 3. Scripting",
+    ]);
+}
+
+#[test]
+fn test_generation_flags_override_environment() {
+    let run = run_aish(&["--mock-llm", "tests/fixtures/mock/empty.json", "--model", "small", "--stop", "END", "--stop", "a,b", "-c", "llm"]);
+
+    run.assert_contains("model: small\n");
+    run.assert_contains("stop: [\"END\", \"a,b\"]\n");
+}
+
+#[test]
+fn test_interactive_lines_are_routed() {
+    let input = "echo routed to the shell\nls Cargo.toml\nexplain the manifest\n?echo hi\nroute ai\n!echo forced\n";
+    let run = run_aish_with_input(&["--mock-llm", "tests/fixtures/mock/interactive_routing.json"], input);
+
+    run.assert_contains("routed to the shell\nCargo.toml\n");
+    assert_eq!(run.lines_starting_with("[SYS] Asking AI"), vec![
+        "[SYS] Asking AI: explain is not a command (start the line with ! to run it in the shell)",
+        "[SYS] Asking AI: ? prefix (start the line with ! to run it in the shell)",
+    ]);
+    run.assert_contains("\nforced\n");
+}
+
+#[test]
+fn test_command_lists_and_exit_status() {
+    let run = run_aish(&["--mock-llm", "tests/fixtures/mock/empty.json", "-c",
+        "export VAR=value; echo $VAR; false || echo \"fallback $?\"; true && echo and; false && echo skipped; echo done"]);
+
+    assert_eq!(run.stdout.lines().skip(1).collect::<Vec<_>>(), vec!["value", "fallback 1", "and", "done"]);
+}
+
+// Exit status of aish run with `args`, whether it succeeded or not
+fn aish_status(args: &[&str]) -> Option<i32> {
+    Command::new(env!("CARGO_BIN_EXE_aish"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .expect("failed to run aish")
+        .code()
+}
+
+#[test]
+fn test_script_exit_status() {
+    // A script exits with the status of its last command, with or without `exit`
+    assert_eq!(aish_status(&["--mock-llm", "tests/fixtures/mock/empty.json", "tests/last_status.sh"]), Some(3));
+    assert_eq!(aish_status(&["--mock-llm", "tests/fixtures/mock/empty.json", "-c", "echo checked; sh -c 'exit 3'"]), Some(3));
+}
+
+#[test]
+fn test_command_substitution() {
+    let run = run_script("substitution.aish");
+    let manifest_dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR"));
+
+    run.assert_contains(&format!("\nname: aish in {}\n", manifest_dir.file_name().unwrap().to_str().unwrap()));
+    run.assert_contains("\nbuiltin: cd is a shell builtin\n");
+    run.assert_contains("\n/ a b\n");
+    run.assert_contains("\nanswer: Rust is a fast, memory-safe systems language.\n");
+}
+
+#[test]
+fn test_globbing_and_brace_expansion() {
+    let run = run_aish(&["--mock-llm", "tests/fixtures/mock/empty.json", "-c",
+        "echo tests/fixtures/*.sse; echo 'tests/*.rs' tests/s*.rs; echo tests/fixtures/mock/test{,2}.json; shopt -s nullglob; echo none: tests/*.none"]);
+
+    assert_eq!(run.stdout.lines().skip(1).collect::<Vec<_>>(), vec![
+        "tests/fixtures/anthropic_stream.sse tests/fixtures/openai_stream.sse",
+        "tests/*.rs tests/scripts.rs",
+        "tests/fixtures/mock/test.json tests/fixtures/mock/test2.json",
+        "none:",
+    ]);
+}
+
+#[test]
+fn test_control_flow_in_shell_script() {
+    let run = run_aish(&["--mock-llm", "tests/fixtures/mock/empty.json", "tests/control_flow.sh"]);
+
+    assert_eq!(run.stdout.lines().skip(1).collect::<Vec<_>>(), vec![
+        "item: alpha",
+        "item: gamma",
+        "count: 3",
+        "anthropic: tests/fixtures/anthropic_stream.sse",
+        "openai: tests/fixtures/openai_stream.sse",
+        "quoted pattern",
+        "elif taken",
+        "pair: 1x",
+        "pair: 2x",
+        "multi",
+        "line",
+        "end",
+    ]);
+}
+
+#[test]
+fn test_control_flow_in_code_block_and_prompt() {
+    let run = run_script("control_flow.aish");
+    run.assert_contains("\n> done\nrust: compiled, memory safe\ngo: compiled\n");
+    run.assert_contains("\nmanifest found\n");
+
+    let run = run_aish_with_input(&["--mock-llm", "tests/fixtures/mock/empty.json"], "for n in 1 2\ndo echo n$n\ndone\n");
+    run.assert_contains("n1\nn2\n");
+}
+
+#[test]
+fn test_shell_functions() {
+    let run = run_aish(&["--mock-llm", "tests/fixtures/mock/empty.json", "tests/functions.sh"]);
+
+    assert_eq!(run.stdout.lines().skip(1).collect::<Vec<_>>(), vec![
+        "hello world (2 args)",
+        "count: 2",
+        "arg: a b",
+        "arg: c",
+        "count: 0",
+        "inside: inner",
+        "status: 3 name: outer",
+        "2 is even",
+        "4 is even",
+        "5! = 120",
+        "greet is a function",
+        "greet removed",
+        "return outside a function fails",
+    ]);
+
+    let run = run_aish_with_input(&["--mock-llm", "tests/fixtures/mock/empty.json"], "greet() {\n  echo \"hi $1\"\n}\ngreet you\n");
+    run.assert_contains("hi you\n");
+}
+
+#[test]
+fn test_aliases_from_rc_file() {
+    let run = run_aish_with_input(
+        &["--mock-llm", "tests/fixtures/mock/empty.json", "--rcfile", "tests/aliases.rc"],
+        "ll src\ngreet world\nsay planet\ntype ll\nalias q=\"it's\"\nalias q\nunalias ll\nalias\n",
+    );
+
+    run.assert_contains("\nsrc\nhello world\nworld\nll is aliased to `ls -d'\nalias q='it'\\''s'\n");
+    run.assert_contains("\nalias greet='echo hello'\nalias planet='world'\nalias q='it'\\''s'\nalias say='echo '\n");
+}
+
+#[test]
+fn test_redirections() {
+    let run = run_aish(&["--mock-llm", "tests/fixtures/mock/empty.json", "tests/redirections.sh"]);
+
+    assert_eq!(run.stdout.lines().skip(1).collect::<Vec<_>>(), vec![
+        "stderr lines: 1",
+        "with 2>&1: 1",
+        "piped stderr",
+        "with &>: 2",
+        "one",
+        "two",
+        "to stderr",
+        "type failed quietly",
+        "5 is not open",
+        "out both",
+        "err both",
+        "out closed",
+        "created by a bare redirection",
+    ]);
+}
+
+#[test]
+fn test_here_documents() {
+    let run = run_aish(&["--mock-llm", "tests/fixtures/mock/empty.json", "tests/here_documents.sh"]);
+
+    assert_eq!(run.stdout.lines().skip(1).collect::<Vec<_>>(), vec![
+        "hello world",
+        "quotes stay: \"world\" 'world'",
+        "escaped: $name substituted",
+        "literal: $name `date`",
+        "TABS STRIPPED FOR WORLD",
+        "HERE-STRING FOR WORLD",
+        "2",
+    ]);
+
+    let run = run_aish_with_input(&["--mock-llm", "tests/fixtures/mock/empty.json"], "cat <<EOF\ntyped at the prompt\nEOF\n");
+    run.assert_contains("typed at the prompt\n");
+
+    let run = run_aish(&["--mock-llm", "tests/fixtures/mock/empty.json", "tests/here_documents.md"]);
+    run.assert_contains("$ cat <<EOF > $dir/config.yml\n> name: demo\n> EOF\n");
+    run.assert_contains("\nname: demo\n");
+}
+
+#[test]
+fn test_pipelines_with_builtins_and_functions() {
+    let run = run_aish(&["--mock-llm", "tests/fixtures/mock/empty.json", "tests/pipelines.sh"]);
+
+    assert_eq!(run.stdout.lines().skip(1).collect::<Vec<_>>(), vec![
+        "FROM A BUILTIN",
+        "1",
+        "> INTO A FUNCTION",
+        "> done shouting",
+        "cd in a pipeline stays in its stage",
+        "1",
+        "last stage fails: 1",
+        "first stage fails: 0",
+        "with pipefail: 1",
+        "without pipefail: 0",
+    ]);
+}
+
+#[test]
+fn test_background_jobs() {
+    let run = run_aish(&["--mock-llm", "tests/fixtures/mock/empty.json", "tests/jobs.sh"]);
+
+    // `[1] 12345` as each job starts may come after the output of a quick job
+    let started = regex::Regex::new(r"^\[\d+\] \d+$").unwrap();
+    assert_eq!(run.lines_starting_with("[").into_iter().filter(|line| started.is_match(line)).count(), 6);
+    assert_eq!(run.stdout.lines().skip(1).filter(|line| !started.is_match(line)).collect::<Vec<_>>(), vec![
+        "[1]-  Running                 sleep 5 &",
+        "[2]+  Running                 sleep 5 | cat &",
+        "killed: 143",
+        "jobs left after wait",
+        "exit status: 3",
+        "no such job: 127",
+        "IN THE BACKGROUND",
+        "an and-list in the background",
+        "disowned",
+        "1",
+    ]);
+}
+
+#[test]
+fn test_subshells_and_groups() {
+    let run = run_aish(&["--mock-llm", "tests/fixtures/mock/empty.json", "tests/groups.sh"]);
+
+    assert_eq!(run.stdout.lines().skip(1).collect::<Vec<_>>(), vec![
+        "/",
+        "/tmp",
+        "hello from a subshell",
+        "after: []",
+        "subshell status: 3",
+        "exit ends only the subshell",
+        "still here",
+        "one",
+        "two",
+        "/",
+        "| to stderr",
+        "| to stdout",
+        "a",
+        "b",
+        "got:",
+        "piped into a group",
+        "through descriptor 3",
+        "read line 1",
+        "read line 2",
+        "read line 3",
+        "IF OUTPUT",
+        "2",
+        "multi-line subshell in /",
+        "appended by a function",
+        "group status: 127",
+    ]);
+}
+
+#[test]
+fn test_parameter_expansion() {
+    let run = run_aish(&["--mock-llm", "tests/fixtures/mock/empty.json", "tests/parameters.sh"]);
+
+    assert_eq!(run.stdout.lines().skip(1).collect::<Vec<_>>(), vec![
+        "default [] [empty]",
+        "hello hello",
+        "set[]",
+        "length 23",
+        "archive.tar.gz /src/archive.tar.gz /usr/src/archive.tar /usr/src/archive",
+        "/usr/lib/archive.tar.gz /usR/sRc/aRchive.taR.gz",
+        "archive tar.gz",
+        "Ada lovelace ADA LOVELACE",
+        "a.md",
+        "b.md",
+        "gz",
+        "status 1",
+    ]);
+}
+
+#[test]
+fn test_arithmetic() {
+    let run = run_aish(&["--mock-llm", "tests/fixtures/mock/empty.json", "tests/arithmetic.sh"]);
+
+    assert_eq!(run.stdout.lines().skip(1).collect::<Vec<_>>(), vec![
+        "7 9 1024 -3 1",
+        "31 8 10 16 0",
+        "attempt 1",
+        "attempt 2",
+        "attempt 3",
+        "count is 3",
+        "three",
+        "zero is false: 1",
+        "total is 32, let status 0",
+        "let 0 status 1",
+        "100 10 5",
+        "10",
+        "division status 1",
+    ]);
+
+    let run = run_aish(&["--mock-llm", "tests/fixtures/mock/empty.json", "tests/arithmetic.md"]);
+    run.assert_contains("\ntry 1 of 2\ntry 2 of 2\n");
+    run.assert_contains("\ndone after 2 tries\n");
+}
+
+#[test]
+fn test_script_arguments() {
+    let run = run_aish(&["--mock-llm", "tests/fixtures/mock/empty.json", "tests/positional.sh", "a", "b c", "--env", "staging"]);
+
+    assert_eq!(run.stdout.lines().skip(1).collect::<Vec<_>>(), vec![
+        "0=tests/positional.sh #=4 1=a 2=b c *=a b c --env staging",
+        "arg [a]",
+        "arg [b c]",
+        "arg [--env]",
+        "arg [staging]",
+        "after shift: 3 [b c]",
+        "shift 5 status 1, still 3",
+        "set: 2 [y z]",
+        "IFS joins $*: x-y z x-y z split: x y z",
+        "empty IFS: xy z",
+        "cleared: 0",
+        "in a function: tests/positional.sh 2 one",
+        "same pid in a substitution: yes",
+    ]);
+
+    let run = run_aish(&["--mock-llm", "tests/fixtures/mock/empty.json", "-c", "echo \"$0: $1 $#\"", "name", "first"]);
+    run.assert_contains("\nname: first 1\n");
+
+    // Paragraphs see the arguments of a markdown script too
+    let run = run_aish(&["--mock-llm", "tests/fixtures/mock/arguments.json", "tests/arguments.aish", "--env", "staging", "extra"]);
+    run.assert_contains("\ntarget: staging\nunknown: extra\n");
+    run.assert_contains("\nstill 3 arguments in tests/arguments.aish\n");
+    run.assert_contains("\n[LLM] staging is valid.\n");
+
+    // A value that looks like a parameter is not replaced again, in a function's
+    // paragraphs either
+    let run = run_aish(&["--mock-llm", "tests/fixtures/mock/literal_arguments.json", "tests/literal_arguments.aish", "$2", "secret"]);
+    run.assert_contains("\n[LLM] They differ.\n");
+    run.assert_contains("\n[LLM] Noted.\n");
+}
+
+#[test]
+fn test_variables() {
+    let run = run_aish(&["--mock-llm", "tests/fixtures/mock/empty.json", "tests/variables.sh"]);
+
+    assert_eq!(run.stdout.lines().skip(1).collect::<Vec<_>>(), vec![
+        "hello []",
+        "prefix hi",
+        "after hello",
+        "exported hello",
+        "0",
+        "1 hello there",
+        "status 1",
+        "show fast",
+        "child fast",
+        "mode [unset]",
+        "declare -r VERSION=\"1.0\"",
+        "status 1",
+        "unset status 1 1.0",
+        "declare -x PLAN=\"a \\\"b\\\" \\$c\"",
+        "inner [unset] outer [2]",
+    ]);
+}
@@ -0,0 +1,27 @@
+# Shell variables, exported variables and readonly ones
+greeting=hello
+echo "$greeting [$(sh -c 'echo $greeting')]"
+greeting=hi sh -c 'echo "prefix $greeting"'
+echo "after $greeting"
+export greeting
+sh -c 'echo "exported $greeting"'
+export -n greeting
+env | grep -c '^greeting='
+A=1 B="$greeting there"
+echo "$A $B"
+A=$(false)
+echo "status $?"
+show() { echo "show $MODE"; sh -c 'echo "child $MODE"'; }
+MODE=fast show
+echo "mode [${MODE-unset}]"
+readonly VERSION=1.0
+declare -p VERSION
+(VERSION=2.0; echo "not reached")
+echo "status $?"
+unset VERSION 2>/dev/null
+echo "unset status $? $VERSION"
+declare -x PLAN='a "b" $c'
+export -p | grep PLAN
+scoped() { declare inner=1; declare -g outer=2; }
+scoped
+echo "inner [${inner-unset}] outer [$outer]"